@@ -1,31 +1,22 @@
-use bytes::Buf;
+use bytes::{Buf, Bytes, BytesMut};
 use criterion::async_executor::FuturesExecutor;
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
-use raknet_rs::{micro_bench_codec_decode, micro_bench_codec_gen_data, CodecConfig};
+use raknet_rs::micro_bench::{decode, encode, gen_data, parse_frame_set, CodecConfig, Options};
 
 pub fn codec_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("codec");
 
     {
-        let data = micro_bench_codec_gen_data(
-            200_000,
-            200,
-            false,
-            false,
-            false,
-            0.0,
-            0.0,
-            &mut rand::thread_rng(),
-        );
+        let data = gen_data(Options::default(), &mut rand::thread_rng());
         group.throughput(Throughput::Bytes(
             data.iter().map(|p| p.remaining() as u64).sum(),
         ));
         group.bench_function(
-            "decode(pack:200k,chunk:200,no shuffled,no dup)",
+            "decode(frames:200k,body:200,no shuffled,no dup)",
             |bencher| {
                 bencher.to_async(FuturesExecutor).iter_batched(
                     || data.clone(),
-                    |data| micro_bench_codec_decode(data, CodecConfig::default()),
+                    |data| decode(data, CodecConfig::default()),
                     BatchSize::SmallInput,
                 );
             },
@@ -33,29 +24,46 @@ pub fn codec_benchmark(c: &mut Criterion) {
     }
 
     {
-        let data = micro_bench_codec_gen_data(
-            200_000,
-            200,
-            true,
-            true,
-            true,
-            0.2,
-            0.2,
-            &mut rand::thread_rng(),
-        );
+        let options = Options {
+            shuffle: true,
+            dup_ratio: 0.2,
+            ..Options::default()
+        };
+        let data = gen_data(options, &mut rand::thread_rng());
         group.throughput(Throughput::Bytes(
             data.iter().map(|p| p.remaining() as u64).sum(),
         ));
-        group.bench_function(
-            "decode(pack:200k,chunk:200,shuffled,frame_dup_threshold:0.2,chunk_dup_threshold:0.2)",
-            |bencher| {
-                bencher.to_async(FuturesExecutor).iter_batched(
-                    || data.clone(),
-                    |data| micro_bench_codec_decode(data, CodecConfig::default()),
-                    BatchSize::SmallInput,
-                );
+        group.bench_function("decode(frames:200k,body:200,shuffled,dup:0.2)", |bencher| {
+            bencher.to_async(FuturesExecutor).iter_batched(
+                || data.clone(),
+                |data| decode(data, CodecConfig::default()),
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    {
+        let data = gen_data(
+            Options {
+                frames: 1,
+                ..Options::default()
             },
+            &mut rand::thread_rng(),
         );
+        let datagram = data[0].clone();
+        group.throughput(Throughput::Bytes(datagram.len() as u64));
+        group.bench_function("parse_frame_set", |bencher| {
+            bencher.iter_batched(|| datagram.clone(), parse_frame_set, BatchSize::SmallInput);
+        });
+    }
+
+    {
+        let bodies = vec![Bytes::from(vec![0xfe; 200]); 10_000];
+        let mut buf = BytesMut::with_capacity(1400);
+        group.throughput(Throughput::Bytes(200 * 10_000));
+        group.bench_function("encode(frames:10k,body:200,mtu:1400)", |bencher| {
+            bencher.iter(|| encode(&bodies, 1400, &mut buf));
+        });
     }
 
     group.finish();
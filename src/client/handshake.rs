@@ -0,0 +1,512 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::errors::Error;
+use crate::packet::version::LATEST_PROTOCOL_VERSION;
+use crate::packet::{unconnected, Packet};
+
+/// Options of the offline handshake
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeOptions {
+    /// The raknet protocol version offered to the server
+    pub protocol_version: u8,
+    /// The mtu offered to the server
+    pub mtu: u16,
+    /// The guid of the client
+    pub client_guid: u64,
+    /// How many times a new guid is generated to retry the handshake when the server replies
+    /// `AlreadyConnected`, e.g. the guid collides with an existing session
+    pub guid_retries: u32,
+    /// The token of [`Opened::migration_token`] to continue the connection from a new address,
+    /// e.g. the NAT of the client rebound the mapping, together with the same
+    /// [`HandshakeOptions::client_guid`]
+    pub migration_token: Option<u64>,
+    /// How many times a request is sent again when its reply does not arrive in time, e.g. the
+    /// request or the reply is lost
+    pub retransmits: u32,
+    /// How long to wait for the reply of each sent request, the other datagrams received
+    /// meanwhile do not extend it
+    pub timeout: Duration,
+}
+
+impl Default for HandshakeOptions {
+    fn default() -> Self {
+        Self {
+            protocol_version: LATEST_PROTOCOL_VERSION,
+            mtu: 1400,
+            client_guid: 0x7261_6b6e_6574_2d72,
+            guid_retries: 3,
+            migration_token: None,
+            retransmits: 3,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// The outcome of the offline handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opened {
+    /// The guid of the server
+    pub server_guid: u64,
+    /// The mtu accepted by the server
+    pub mtu: u16,
+    /// The guid the client is connected with, it differs from [`HandshakeOptions::client_guid`]
+    /// if the handshake was retried after a guid collision
+    pub client_guid: u64,
+    /// The address of the client seen by the server, e.g. the public address behind a NAT
+    pub client_address: SocketAddr,
+    /// The token issued by the server allowing the migration, proving the ownership of the
+    /// connection when it is continued from a new address
+    pub migration_token: Option<u64>,
+}
+
+/// Perform the offline handshake (`OpenConnectionRequest1` and `OpenConnectionRequest2`) with the
+/// server at `addr`, the socket must be connected to it. The connection is established by the
+/// `ConnectionRequest` sent in the frame sets afterwards.
+///
+/// # Errors
+///
+/// Returns [`Error::IncompatibleProtocol`] carrying the protocol version and the guid of the
+/// server if it does not speak [`HandshakeOptions::protocol_version`],
+/// [`Error::ConnectionClosed`] if the server refuses the connection or still regards the client
+/// as connected after the guid retries, and
+/// [`Error::RequestTimeout`] if the server does not reply in time after the retransmits.
+pub async fn open_connection(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    options: HandshakeOptions,
+) -> Result<Opened, Error> {
+    let mut client_guid = options.client_guid;
+    for retry in 0..=options.guid_retries {
+        if retry > 0 {
+            client_guid = random_guid();
+        }
+        // the server forgets the handshake once it refuses `OpenConnectionRequest2`, so each
+        // retry starts over from `OpenConnectionRequest1`
+        let (server_guid, mtu) = request1(socket, &options).await?;
+        if let Some((client_address, migration_token)) =
+            request2(socket, addr, mtu, client_guid, &options).await?
+        {
+            return Ok(Opened {
+                server_guid,
+                mtu,
+                client_guid,
+                client_address,
+                migration_token,
+            });
+        }
+    }
+    Err(Error::ConnectionClosed(
+        "the server regards the client as already connected after the guid retries",
+    ))
+}
+
+/// Send `OpenConnectionRequest1`, returns the guid of the server and the mtu it accepts
+pub(crate) async fn request1(
+    socket: &UdpSocket,
+    options: &HandshakeOptions,
+) -> Result<(u64, u16), Error> {
+    exchange(
+        socket,
+        options,
+        unconnected::Packet::OpenConnectionRequest1 {
+            magic: (),
+            protocol_version: options.protocol_version,
+            mtu: options.mtu,
+        },
+        |pack| match pack {
+            unconnected::Packet::OpenConnectionReply1 {
+                server_guid, mtu, ..
+            } => Ok(Some((server_guid, mtu))),
+            unconnected::Packet::IncompatibleProtocol {
+                server_protocol,
+                server_guid,
+                ..
+            } => Err(Error::IncompatibleProtocol {
+                server_protocol,
+                server_guid,
+            }),
+            _ => Ok(None),
+        },
+    )
+    .await
+}
+
+/// Send `OpenConnectionRequest2` with the mtu accepted by the server, returns the address of the
+/// client seen by the server and the migration token, or None if the server replies
+/// `AlreadyConnected` to the guid.
+pub(crate) async fn request2(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    mtu: u16,
+    client_guid: u64,
+    options: &HandshakeOptions,
+) -> Result<Option<(SocketAddr, Option<u64>)>, Error> {
+    exchange(
+        socket,
+        options,
+        unconnected::Packet::OpenConnectionRequest2 {
+            magic: (),
+            server_address: addr,
+            mtu,
+            client_guid,
+            migration_token: options.migration_token,
+        },
+        |pack| match pack {
+            unconnected::Packet::OpenConnectionReply2 {
+                client_address,
+                migration_token,
+                ..
+            } => Ok(Some(Some((client_address, migration_token)))),
+            unconnected::Packet::AlreadyConnected { .. } => Ok(Some(None)),
+            unconnected::Packet::NoFreeIncomingConnections { .. } => {
+                Err(Error::ConnectionClosed("the server is full"))
+            }
+            _ => Ok(None),
+        },
+    )
+    .await
+}
+
+/// Generate a random guid from the randomly seeded hasher, which avoids depending on `rand`
+pub(crate) fn random_guid() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish()
+}
+
+/// Send the request and wait for the reply picked by `matches`, the request is sent again each
+/// time [`HandshakeOptions::timeout`] elapses, up to [`HandshakeOptions::retransmits`] times.
+async fn exchange<T>(
+    socket: &UdpSocket,
+    options: &HandshakeOptions,
+    request: unconnected::Packet,
+    mut matches: impl FnMut(unconnected::Packet) -> Result<Option<T>, Error>,
+) -> Result<T, Error> {
+    let mut raw = BytesMut::new();
+    Packet::<Bytes>::Unconnected(request).write(&mut raw);
+    let mut buf = vec![0; usize::from(options.mtu.max(1500))];
+    for _ in 0..=options.retransmits {
+        socket.send(&raw).await.map_err(Error::IO)?;
+        // one deadline for all the datagrams received after the request
+        let deadline = Instant::now() + options.timeout;
+        if let Ok(reply) =
+            tokio::time::timeout_at(deadline, recv_offline(socket, &mut buf, &mut matches)).await
+        {
+            return reply;
+        }
+    }
+    Err(Error::RequestTimeout)
+}
+
+/// Wait for the unconnected reply picked by `matches`, the other datagrams are ignored
+async fn recv_offline<T>(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    matches: &mut impl FnMut(unconnected::Packet) -> Result<Option<T>, Error>,
+) -> Result<T, Error> {
+    loop {
+        let len = socket.recv(buf).await?;
+        let mut datagram = BytesMut::from(&buf[..len]);
+        let Ok(Some(Packet::Unconnected(pack))) = Packet::read(&mut datagram) else {
+            continue;
+        };
+        if let Some(reply) = matches(pack)? {
+            return Ok(reply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Answer each datagram from the client with the reply made of it
+    async fn replier(
+        server: UdpSocket,
+        replies: usize,
+        reply: impl Fn(unconnected::Packet, SocketAddr) -> unconnected::Packet + Send + 'static,
+    ) -> Vec<unconnected::Packet> {
+        let mut buf = vec![0; 1500];
+        let mut received = Vec::new();
+        while received.len() < replies {
+            let (len, client) = server.recv_from(&mut buf).await.unwrap();
+            let Some(Packet::Unconnected(pack)) =
+                Packet::read(&mut BytesMut::from(&buf[..len])).unwrap()
+            else {
+                continue;
+            };
+            received.push(pack.clone());
+            let mut raw = BytesMut::new();
+            Packet::<Bytes>::Unconnected(reply(pack, client)).write(&mut raw);
+            server.send_to(&raw, client).await.unwrap();
+        }
+        received
+    }
+
+    async fn connected(addr: SocketAddr) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(addr).await.unwrap();
+        socket
+    }
+
+    #[tokio::test]
+    async fn test_open_connection_incompatible_protocol() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let replier = tokio::spawn(replier(server, 1, |_, _| {
+            unconnected::Packet::IncompatibleProtocol {
+                server_protocol: 10,
+                magic: (),
+                server_guid: 42,
+            }
+        }));
+        let socket = connected(addr).await;
+        let options = HandshakeOptions {
+            protocol_version: 11,
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let err = open_connection(&socket, addr, options).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::IncompatibleProtocol {
+                server_protocol: 10,
+                server_guid: 42,
+            }
+        ));
+        assert!(matches!(
+            replier.await.unwrap()[..],
+            [unconnected::Packet::OpenConnectionRequest1 {
+                protocol_version: 11,
+                ..
+            }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_open_connection_works() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let replier = tokio::spawn(replier(server, 2, |pack, client| match pack {
+            unconnected::Packet::OpenConnectionRequest1 { .. } => {
+                unconnected::Packet::OpenConnectionReply1 {
+                    magic: (),
+                    server_guid: 42,
+                    use_encryption: false,
+                    mtu: 1200,
+                }
+            }
+            _ => unconnected::Packet::OpenConnectionReply2 {
+                magic: (),
+                server_guid: 42,
+                client_address: client,
+                mtu: 1200,
+                encryption_enabled: false,
+                migration_token: None,
+            },
+        }));
+        let socket = connected(addr).await;
+        let options = HandshakeOptions {
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let opened = open_connection(&socket, addr, options).await.unwrap();
+        assert_eq!(
+            opened,
+            Opened {
+                server_guid: 42,
+                mtu: 1200,
+                client_guid: options.client_guid,
+                client_address: socket.local_addr().unwrap(),
+                migration_token: None,
+            }
+        );
+        // the mtu accepted by the server is requested
+        assert!(matches!(
+            replier.await.unwrap()[1],
+            unconnected::Packet::OpenConnectionRequest2 { mtu: 1200, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_open_connection_retransmit_lost_requests() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let socket = connected(addr).await;
+        let client = socket.local_addr().unwrap();
+        let options = HandshakeOptions {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let opening = tokio::spawn(async move { open_connection(&socket, addr, options).await });
+
+        let mut buf = vec![0; 1500];
+        let mut requests = Vec::new();
+        while requests.len() < 4 {
+            let len = server.recv(&mut buf).await.unwrap();
+            let Some(Packet::Unconnected(pack)) =
+                Packet::read(&mut BytesMut::from(&buf[..len])).unwrap()
+            else {
+                continue;
+            };
+            requests.push(pack.clone());
+            // the first one of each request is lost
+            let reply = match pack {
+                unconnected::Packet::OpenConnectionRequest1 { .. } if requests.len() == 1 => {
+                    continue
+                }
+                unconnected::Packet::OpenConnectionRequest1 { .. } => {
+                    unconnected::Packet::OpenConnectionReply1 {
+                        magic: (),
+                        server_guid: 42,
+                        use_encryption: false,
+                        mtu: 1200,
+                    }
+                }
+                _ if requests.len() == 3 => continue,
+                _ => unconnected::Packet::OpenConnectionReply2 {
+                    magic: (),
+                    server_guid: 42,
+                    client_address: client,
+                    mtu: 1200,
+                    encryption_enabled: false,
+                    migration_token: None,
+                },
+            };
+            let mut raw = BytesMut::new();
+            Packet::<Bytes>::Unconnected(reply).write(&mut raw);
+            server.send_to(&raw, client).await.unwrap();
+        }
+        let opened = opening.await.unwrap().unwrap();
+        assert_eq!(opened.mtu, 1200);
+        assert_eq!(opened.client_address, client);
+        assert!(matches!(
+            requests[..],
+            [
+                unconnected::Packet::OpenConnectionRequest1 { .. },
+                unconnected::Packet::OpenConnectionRequest1 { .. },
+                unconnected::Packet::OpenConnectionRequest2 { .. },
+                unconnected::Packet::OpenConnectionRequest2 { .. },
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_open_connection_junk_not_extend_deadline() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let socket = connected(addr).await;
+        let client = socket.local_addr().unwrap();
+        // keep sending the datagrams which are not the reply
+        let junk = tokio::spawn(async move {
+            loop {
+                server.send_to(&[0xfe, 0xff], client).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        let options = HandshakeOptions {
+            retransmits: 1,
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let err = open_connection(&socket, addr, options).await.unwrap_err();
+        junk.abort();
+        assert!(matches!(err, Error::RequestTimeout));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_open_connection_guid_collision_retry() {
+        use crate::server::{ConfigBuilder, ServerBuilder};
+
+        let server = ServerBuilder::new(ConfigBuilder::default().sever_guid(42).build().unwrap())
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        let options = HandshakeOptions {
+            guid_retries: 0,
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let holder = connected(addr).await;
+        let held = open_connection(&holder, addr, options).await.unwrap();
+        assert_eq!(held.server_guid, 42);
+
+        // the guid is held by the connection of another address
+        let socket = connected(addr).await;
+        let err = open_connection(&socket, addr, options).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectionClosed(_)));
+
+        let opened = open_connection(
+            &socket,
+            addr,
+            HandshakeOptions {
+                guid_retries: 1,
+                ..options
+            },
+        )
+        .await
+        .unwrap();
+        assert_ne!(opened.client_guid, held.client_guid);
+        assert_eq!(opened.server_guid, 42);
+        assert_eq!(opened.client_address, socket.local_addr().unwrap());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_open_connection_migrate() {
+        use crate::server::{ConfigBuilder, ServerBuilder};
+
+        let server = ServerBuilder::new(
+            ConfigBuilder::default()
+                .sever_guid(42)
+                .migration(true)
+                .build()
+                .unwrap(),
+        )
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+        let addr = server.local_addr();
+        let options = HandshakeOptions {
+            guid_retries: 0,
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let opened = open_connection(&connected(addr).await, addr, options)
+            .await
+            .unwrap();
+        assert!(opened.migration_token.is_some());
+
+        // the client continues from a new address with the token
+        let socket = connected(addr).await;
+        let migrated = open_connection(
+            &socket,
+            addr,
+            HandshakeOptions {
+                migration_token: opened.migration_token,
+                ..options
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(migrated.client_guid, opened.client_guid);
+        assert_eq!(migrated.client_address, socket.local_addr().unwrap());
+        assert_ne!(migrated.migration_token, opened.migration_token);
+    }
+}
@@ -0,0 +1,5 @@
+mod handshake;
+
+pub use handshake::{open_connection, HandshakeOptions, Opened};
+#[cfg(feature = "interop")]
+pub(crate) use handshake::{random_guid, request1, request2};
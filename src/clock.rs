@@ -0,0 +1,124 @@
+#[cfg(feature = "server")]
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The source of the timestamps (in milliseconds) carried by the connected ping/pong and the
+/// connection requests
+pub trait Clock {
+    /// Get the current timestamp in milliseconds
+    fn timestamp(&self) -> i64;
+}
+
+/// Milliseconds since the unix epoch, it is the default clock source
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn timestamp(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before the unix epoch")
+            .as_millis() as i64
+    }
+}
+
+/// Milliseconds since this clock was created, it never goes backwards when the system time is
+/// adjusted
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    epoch: Instant,
+}
+
+impl MonotonicClock {
+    /// Create a clock starting from now
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn timestamp(&self) -> i64 {
+        self.epoch.elapsed().as_millis() as i64
+    }
+}
+
+#[cfg(feature = "server")]
+/// Estimate the smoothed clock differential between the peer and us from the connected pongs,
+/// shared between the connection task and the user.
+#[derive(Debug, Default)]
+pub(crate) struct ClockDifferential {
+    // The remote clock minus the local clock, in milliseconds
+    offset: AtomicI64,
+    measured: AtomicBool,
+}
+
+#[cfg(feature = "server")]
+impl ClockDifferential {
+    /// Record a connected pong received at `received` (local clock), which answered the ping
+    /// sent at `client_timestamp` (local clock) with `server_timestamp` (remote clock).
+    pub(crate) fn record(&self, client_timestamp: i64, server_timestamp: i64, received: i64) {
+        let rtt = received - client_timestamp;
+        if rtt < 0 {
+            // the pong does not answer our ping
+            return;
+        }
+        // assume the path is symmetric, so the remote timestamp was taken at the middle of rtt
+        let sample = server_timestamp - (client_timestamp + rtt / 2);
+        if !self.measured.swap(true, Ordering::Relaxed) {
+            self.offset.store(sample, Ordering::Relaxed);
+            return;
+        }
+        // smooth the samples like the rtt estimator (alpha = 1/8)
+        let offset = self.offset.load(Ordering::Relaxed);
+        self.offset
+            .store(offset + (sample - offset) / 8, Ordering::Relaxed);
+    }
+
+    /// The smoothed remote clock minus the local clock in milliseconds, None if no pong has been
+    /// received yet.
+    pub(crate) fn offset(&self) -> Option<i64> {
+        self.measured
+            .load(Ordering::Relaxed)
+            .then(|| self.offset.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_clock_differential_works() {
+        let diff = ClockDifferential::default();
+        assert_eq!(diff.offset(), None);
+
+        // the remote clock is 1000ms ahead, rtt is 100ms
+        diff.record(0, 1050, 100);
+        assert_eq!(diff.offset(), Some(1000));
+
+        // a jittered sample only moves the estimation a bit
+        diff.record(200, 1330, 300);
+        assert_eq!(diff.offset(), Some(1010));
+
+        // ignore the pongs from the future
+        diff.record(500, 0, 400);
+        assert_eq!(diff.offset(), Some(1010));
+    }
+
+    #[test]
+    fn test_monotonic_clock() {
+        let clock = MonotonicClock::new();
+        let first = clock.timestamp();
+        assert!(first >= 0);
+        assert!(clock.timestamp() >= first);
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+
+use bytes::Buf;
+#[cfg(any(test, feature = "micro-bench"))]
+use bytes::BytesMut;
+
+#[cfg(any(test, feature = "micro-bench"))]
+use crate::packet::connected::{put_frame_set_flag, Uint24le};
+use crate::packet::connected::{Frame, FRAME_SET_HEADER_SIZE};
+
+/// Pack a batch of frames into as few frame sets as possible, each of them fits in a datagram of
+/// `max_size` bytes, see [`max_datagram_size`](crate::packet::connected::max_datagram_size).
+/// The order of frames is kept so that the frames are sent in the order they are submitted.
+pub(crate) fn pack_frames<B: Buf>(
+    frames: impl IntoIterator<Item = Frame<B>>,
+    max_size: usize,
+) -> Vec<Vec<Frame<B>>> {
+    let mut packed = Vec::new();
+    let mut current = Vec::new();
+    let mut current_size = FRAME_SET_HEADER_SIZE;
+    for frame in frames {
+        let size = frame.size();
+        debug_assert!(
+            FRAME_SET_HEADER_SIZE + size <= max_size,
+            "frame should be fragmented based on mtu"
+        );
+        if !current.is_empty() && current_size + size > max_size {
+            packed.push(std::mem::take(&mut current));
+            current_size = FRAME_SET_HEADER_SIZE;
+        }
+        current_size += size;
+        current.push(frame);
+    }
+    if !current.is_empty() {
+        packed.push(current);
+    }
+    packed
+}
+
+/// Pack the frames to be retransmitted into new frame sets, and top up the room left in the last
+/// one with the fresh frames at the front of the queue, so that a few small retransmits do not
+/// occupy a datagram alone. The fresh frames are taken in order and stop at the first one that
+/// does not fit, the rest are left in the queue.
+pub(crate) fn repack_frames<B: Buf>(
+    retransmits: impl IntoIterator<Item = Frame<B>>,
+    fresh: &mut VecDeque<Frame<B>>,
+    max_size: usize,
+) -> Vec<Vec<Frame<B>>> {
+    let mut packed = pack_frames(retransmits, max_size);
+    let Some(last) = packed.last_mut() else {
+        return packed;
+    };
+    let mut size = FRAME_SET_HEADER_SIZE + last.iter().map(Frame::size).sum::<usize>();
+    while let Some(frame) = fresh.pop_front() {
+        if size + frame.size() > max_size {
+            fresh.push_front(frame);
+            break;
+        }
+        size += frame.size();
+        last.push(frame);
+    }
+    packed
+}
+
+/// Encode the frames into datagrams of at most `max_size` bytes in place, it does not collect the
+/// frames into frame sets, and the buffer is reused for every datagram, so no allocation will be
+/// made once the buffer has grown to `max_size`. `emit` receives every encoded datagram.
+#[cfg(any(test, feature = "micro-bench"))]
+pub(crate) fn encode_packed<B: Buf>(
+    frames: impl IntoIterator<Item = Frame<B>>,
+    max_size: usize,
+    seq_num: &mut Uint24le,
+    buf: &mut BytesMut,
+    mut emit: impl FnMut(&[u8]),
+) {
+    buf.clear();
+    // 0 means no datagram is being encoded
+    let mut size = 0;
+    for frame in frames {
+        let frame_size = frame.size();
+        if size != 0 && size + frame_size > max_size {
+            emit(buf);
+            buf.clear();
+            size = 0;
+        }
+        if size == 0 {
+            put_frame_set_flag(buf, frame.flags.parted());
+            seq_num.write(buf);
+            *seq_num = seq_num.next();
+            size = FRAME_SET_HEADER_SIZE;
+        }
+        frame.write(buf);
+        size += frame_size;
+    }
+    if size != 0 {
+        emit(buf);
+        buf.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::packet::connected::{self, Flags, Ordered};
+    use crate::packet::Packet;
+
+    fn frame(len: usize) -> Frame<Bytes> {
+        Frame {
+            flags: Flags::parse(0b011_00000),
+            reliable_frame_index: Some(Uint24le(0)),
+            seq_frame_index: None,
+            ordered: Some(Ordered {
+                frame_index: Uint24le(0),
+                channel: 0,
+            }),
+            fragment: None,
+            body: Bytes::from(vec![0; len]),
+        }
+    }
+
+    #[test]
+    fn test_pack_frames_works() {
+        // every frame takes 10 + 90 = 100 bytes
+        assert_eq!(frame(90).size(), 100);
+        let packed = pack_frames((0..10).map(|_| frame(90)), 4 + 300);
+        assert_eq!(
+            packed.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![3, 3, 3, 1]
+        );
+
+        let mixed = pack_frames([frame(90), frame(190), frame(10)], 4 + 300);
+        assert_eq!(mixed.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 1]);
+        assert!(pack_frames(Vec::<Frame<Bytes>>::new(), 300).is_empty());
+    }
+
+    #[test]
+    fn test_repack_frames_works() {
+        let mut fresh: VecDeque<_> = [frame(90), frame(90), frame(10)].into();
+        let packed = repack_frames([frame(90)], &mut fresh, 4 + 300);
+        // the third fresh frame fits but must not overtake the second one
+        assert_eq!(packed.len(), 1);
+        assert_eq!(packed[0].len(), 3);
+        assert_eq!(fresh.len(), 1);
+
+        // no room left for the fresh frame
+        let full = repack_frames([frame(290)], &mut fresh, 4 + 300);
+        assert_eq!(full.iter().map(Vec::len).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(fresh.len(), 1);
+        assert!(repack_frames(Vec::new(), &mut fresh, 4 + 300).is_empty());
+        assert_eq!(fresh.len(), 1);
+    }
+
+    #[test]
+    fn test_encode_packed_works() {
+        let mut buf = BytesMut::new();
+        let mut seq_num = Uint24le(0);
+        let mut datagrams = Vec::new();
+        encode_packed(
+            (0..10).map(|_| frame(90)),
+            4 + 300,
+            &mut seq_num,
+            &mut buf,
+            |datagram| datagrams.push(BytesMut::from(datagram)),
+        );
+        assert_eq!(seq_num, Uint24le(4));
+        assert_eq!(datagrams.len(), 4);
+        for (i, mut datagram) in datagrams.into_iter().enumerate() {
+            assert!(datagram.len() <= 304);
+            let Some(Packet::Connected(connected::Packet::FrameSet(frame_set))) =
+                Packet::read(&mut datagram).unwrap()
+            else {
+                panic!("expect a frame set");
+            };
+            assert_eq!(frame_set.seq_num, Uint24le(i as u32));
+            assert_eq!(frame_set.frames.len(), if i == 3 { 1 } else { 3 });
+        }
+    }
+}
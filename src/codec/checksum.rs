@@ -0,0 +1,183 @@
+use std::net::SocketAddr;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use super::filter::Verdict;
+use super::hook::DatagramHook;
+
+/// The CRC32 (IEEE) lookup table
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Append the CRC32 of each datagram to its tail and drop the incoming datagrams not matching
+/// it. It is not a part of raknet protocol, so it must be negotiated out of band and enabled on
+/// both ends.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Crc32;
+
+impl Crc32 {
+    const LEN: usize = 4;
+}
+
+impl DatagramHook for Crc32 {
+    fn on_send(&self, _: SocketAddr, buf: &mut BytesMut) {
+        let crc = crc32(buf);
+        buf.put_u32_le(crc);
+    }
+
+    fn on_recv(&self, _: SocketAddr, buf: &mut BytesMut) -> Verdict {
+        if buf.len() < Self::LEN {
+            return Verdict::Drop;
+        }
+        let payload = buf.len() - Self::LEN;
+        let expected = (&buf[payload..]).get_u32_le();
+        if crc32(&buf[..payload]) != expected {
+            return Verdict::Drop;
+        }
+        buf.truncate(payload);
+        Verdict::Pass
+    }
+
+    fn overhead(&self) -> usize {
+        Self::LEN
+    }
+}
+
+/// XOR each datagram with a repeating key to deter the trivial inspection and tampering, which
+/// is not an encryption. The key must be shared out of band and be identical on both ends.
+#[derive(Debug, Clone)]
+pub struct XorObfuscation {
+    key: Box<[u8]>,
+}
+
+impl XorObfuscation {
+    /// # Panics
+    ///
+    /// Panics if the key is empty
+    pub fn new(key: impl Into<Box<[u8]>>) -> Self {
+        let key = key.into();
+        assert!(!key.is_empty(), "empty obfuscation key");
+        Self { key }
+    }
+
+    fn apply(&self, buf: &mut [u8]) {
+        for (byte, k) in buf.iter_mut().zip(self.key.iter().cycle()) {
+            *byte ^= k;
+        }
+    }
+}
+
+impl DatagramHook for XorObfuscation {
+    fn on_send(&self, _: SocketAddr, buf: &mut BytesMut) {
+        self.apply(buf);
+    }
+
+    fn on_recv(&self, _: SocketAddr, buf: &mut BytesMut) -> Verdict {
+        self.apply(buf);
+        Verdict::Pass
+    }
+}
+
+/// Chain two hooks, the outgoing datagrams pass the first then the second one, and the incoming
+/// datagrams pass them in reverse, e.g. `(Crc32, XorObfuscation::new(key))` obfuscates the
+/// datagrams with their checksums.
+impl<A: DatagramHook, B: DatagramHook> DatagramHook for (A, B) {
+    fn on_send(&self, addr: SocketAddr, buf: &mut BytesMut) {
+        self.0.on_send(addr, buf);
+        self.1.on_send(addr, buf);
+    }
+
+    fn on_recv(&self, addr: SocketAddr, buf: &mut BytesMut) -> Verdict {
+        if self.1.on_recv(addr, buf) == Verdict::Drop {
+            return Verdict::Drop;
+        }
+        self.0.on_recv(addr, buf)
+    }
+
+    fn overhead(&self) -> usize {
+        self.0.overhead() + self.1.overhead()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:1".parse().unwrap()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_crc32_hook() {
+        let mut buf = BytesMut::from(&b"hello"[..]);
+        Crc32.on_send(addr(), &mut buf);
+        assert_eq!(buf.len(), 5 + Crc32.overhead());
+
+        let mut tampered = buf.clone();
+        tampered[0] ^= 1;
+        assert_eq!(Crc32.on_recv(addr(), &mut tampered), Verdict::Drop);
+        assert_eq!(
+            Crc32.on_recv(addr(), &mut BytesMut::from(&b"abc"[..])),
+            Verdict::Drop
+        );
+
+        assert_eq!(Crc32.on_recv(addr(), &mut buf), Verdict::Pass);
+        assert_eq!(buf, BytesMut::from(&b"hello"[..]));
+    }
+
+    #[test]
+    fn test_xor_obfuscation_symmetric() {
+        let xor = XorObfuscation::new(*b"key");
+        let mut buf = BytesMut::from(&b"hello"[..]);
+        xor.on_send(addr(), &mut buf);
+        assert_ne!(buf, BytesMut::from(&b"hello"[..]));
+        assert_eq!(xor.on_recv(addr(), &mut buf), Verdict::Pass);
+        assert_eq!(buf, BytesMut::from(&b"hello"[..]));
+    }
+
+    #[test]
+    fn test_chained_hooks() {
+        let hook = (Crc32, XorObfuscation::new(*b"key"));
+        assert_eq!(hook.overhead(), 4);
+        let mut buf = BytesMut::from(&b"hello"[..]);
+        hook.on_send(addr(), &mut buf);
+        // the checksum is obfuscated as well
+        assert_eq!(Crc32.on_recv(addr(), &mut buf.clone()), Verdict::Drop);
+        assert_eq!(hook.on_recv(addr(), &mut buf), Verdict::Pass);
+        assert_eq!(buf, BytesMut::from(&b"hello"[..]));
+
+        // obfuscated with another key
+        let mut other = BytesMut::from(&b"hello"[..]);
+        (Crc32, XorObfuscation::new(*b"other")).on_send(addr(), &mut other);
+        assert_eq!(hook.on_recv(addr(), &mut other), Verdict::Drop);
+    }
+}
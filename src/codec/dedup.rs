@@ -1,5 +1,6 @@
 use std::collections::VecDeque;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use futures::{ready, Stream, StreamExt};
@@ -7,6 +8,7 @@ use pin_project_lite::pin_project;
 
 use crate::errors::CodecError;
 use crate::packet::connected::{self, Uint24le};
+use crate::stats::{DropReason, StatsRecorder};
 
 const USIZE_BITS: usize = std::mem::size_of::<usize>() * 8;
 const DEFAULT_BIT_VEC_QUEUE_CAP: usize = 256 * USIZE_BITS;
@@ -132,7 +134,7 @@ impl BitVecQueue {
 #[derive(Debug, Default)]
 struct DuplicateWindow {
     /// First unreceived sequence number, start at 0
-    first_unreceived: u32,
+    first_unreceived: Uint24le,
     /// Record the received status of sequence numbers start at `first_unreceived`
     /// `true` is received and `false` is unreceived
     received_status: BitVecQueue,
@@ -141,10 +143,10 @@ struct DuplicateWindow {
 impl DuplicateWindow {
     /// Check whether a sequence number is duplicated
     fn duplicate(&mut self, seq_num: Uint24le) -> bool {
-        if seq_num.0 < self.first_unreceived {
+        if seq_num.serial_cmp(self.first_unreceived) == std::cmp::Ordering::Less {
             return true;
         }
-        let gap = (seq_num.0 - self.first_unreceived) as usize;
+        let gap = seq_num.distance_from(self.first_unreceived) as usize;
         if gap < self.received_status.len() {
             // received the sequence number that is recorded in received_status
             // check its status to determine whether it is duplicated
@@ -163,7 +165,7 @@ impl DuplicateWindow {
         }
         while let Some(true) = self.received_status.front() {
             self.received_status.pop();
-            self.first_unreceived += 1;
+            self.first_unreceived = self.first_unreceived.next();
         }
         false
     }
@@ -177,20 +179,22 @@ pin_project! {
         frame: F,
         // Limit the maximum reliable_frame_index gap for a connection. 0 means no limit.
         max_gap: usize,
-        window: DuplicateWindow
+        window: DuplicateWindow,
+        recorder: Arc<StatsRecorder>,
     }
 }
 
 pub(super) trait Deduplicated: Sized {
-    fn deduplicated(self, max_gap: usize) -> Dedup<Self>;
+    fn deduplicated(self, max_gap: usize, recorder: Arc<StatsRecorder>) -> Dedup<Self>;
 }
 
 impl<T> Deduplicated for T {
-    fn deduplicated(self, max_gap: usize) -> Dedup<Self> {
+    fn deduplicated(self, max_gap: usize, recorder: Arc<StatsRecorder>) -> Dedup<Self> {
         Dedup {
             frame: self,
             max_gap,
             window: DuplicateWindow::default(),
+            recorder,
         }
     }
 }
@@ -211,18 +215,27 @@ where
             let connected::Packet::FrameSet(mut frame_set) = packet else {
                 return Poll::Ready(Some(Ok(packet)));
             };
+            if frame_set.is_fast_path() {
+                return Poll::Ready(Some(Ok(connected::Packet::FrameSet(frame_set))));
+            }
             if *this.max_gap != 0 && this.window.received_status.len() > *this.max_gap {
                 return Poll::Ready(Some(Err(CodecError::DedupExceed(
                     *this.max_gap,
                     this.window.received_status.len(),
                 ))));
             }
+            let frames_len = frame_set.frames.len();
             frame_set.frames.retain(|frame| {
                 let Some(reliable_frame_index) = frame.reliable_frame_index else {
                     return true;
                 };
                 !this.window.duplicate(reliable_frame_index)
             });
+            let duplicate = frames_len - frame_set.frames.len();
+            if duplicate != 0 {
+                this.recorder
+                    .record_dropped(DropReason::Duplicate, duplicate as u64);
+            }
             if !frame_set.frames.is_empty() {
                 return Poll::Ready(Some(Ok(connected::Packet::FrameSet(frame_set))));
             }
@@ -243,13 +256,14 @@ mod test {
     use super::*;
     use crate::errors::CodecError;
     use crate::packet::connected::{self, Flags, Frame, FrameSet, Uint24le};
+    use crate::test_util::reliable_frame_set;
 
     #[test]
     fn test_duplicate_windows_check_ordered() {
         let mut window = DuplicateWindow::default();
         for i in 0..1024 {
             assert!(!window.duplicate(Uint24le(i)));
-            assert_eq!(window.first_unreceived, i + 1);
+            assert_eq!(window.first_unreceived, Uint24le(i + 1));
             assert!(window.received_status.len() <= 1);
         }
     }
@@ -259,7 +273,7 @@ mod test {
         let mut window = DuplicateWindow::default();
         for i in 0..512 {
             assert!(!window.duplicate(Uint24le(i)));
-            assert_eq!(window.first_unreceived, i + 1);
+            assert_eq!(window.first_unreceived, Uint24le(i + 1));
             assert!(window.received_status.len() <= 1);
         }
         for i in 0..512 {
@@ -278,7 +292,7 @@ mod test {
         assert!(window.duplicate(Uint24le(1001)));
         assert!(!window.duplicate(Uint24le(500)));
         assert!(window.duplicate(Uint24le(500)));
-        assert_eq!(window.first_unreceived, 2);
+        assert_eq!(window.first_unreceived, Uint24le(2));
     }
 
     #[test]
@@ -293,34 +307,17 @@ mod test {
         assert_eq!(window.received_status.len(), 0);
     }
 
-    fn frame_set(idx: impl IntoIterator<Item = u32>) -> connected::Packet<Bytes> {
-        connected::Packet::FrameSet(FrameSet {
-            seq_num: Uint24le(0),
-            frames: idx
-                .into_iter()
-                .map(|i| Frame {
-                    flags: Flags::parse(0b011_11100),
-                    reliable_frame_index: Some(Uint24le(i)),
-                    seq_frame_index: None,
-                    ordered: None,
-                    fragment: None,
-                    body: Bytes::new(),
-                })
-                .collect(),
-        })
-    }
-
     #[tokio::test]
     async fn test_dedup_works() {
         let frame = {
             #[stream]
             async {
-                yield frame_set(0..64);
-                yield frame_set(0..64); // duplicated
-                yield frame_set([65, 66, 68, 69]);
-                yield frame_set([67, 68]);
-                yield frame_set([71, 71, 72]);
-                yield frame_set([70]);
+                yield reliable_frame_set(0..64);
+                yield reliable_frame_set(0..64); // duplicated
+                yield reliable_frame_set([65, 66, 68, 69]);
+                yield reliable_frame_set([67, 68]);
+                yield reliable_frame_set([71, 71, 72]);
+                yield reliable_frame_set([70]);
             }
         };
         tokio::pin!(frame);
@@ -328,16 +325,29 @@ mod test {
             frame: frame.map(Ok),
             max_gap: 100,
             window: DuplicateWindow::default(),
+            recorder: Arc::new(StatsRecorder::new(1)),
         };
 
-        assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set(0..64));
         assert_eq!(
             dedup.next().await.unwrap().unwrap(),
-            frame_set([65, 66, 68, 69])
+            reliable_frame_set(0..64)
+        );
+        assert_eq!(
+            dedup.next().await.unwrap().unwrap(),
+            reliable_frame_set([65, 66, 68, 69])
+        );
+        assert_eq!(
+            dedup.next().await.unwrap().unwrap(),
+            reliable_frame_set([67])
+        );
+        assert_eq!(
+            dedup.next().await.unwrap().unwrap(),
+            reliable_frame_set([71, 72])
+        );
+        assert_eq!(
+            dedup.next().await.unwrap().unwrap(),
+            reliable_frame_set([70])
         );
-        assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set([67]));
-        assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set([71, 72]));
-        assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set([70]));
     }
 
     #[tokio::test]
@@ -345,9 +355,53 @@ mod test {
         let frame = {
             #[stream]
             async {
-                yield frame_set([0]);
-                yield frame_set([101]);
-                yield frame_set([102]);
+                yield reliable_frame_set([0]);
+                yield reliable_frame_set([101]);
+                yield reliable_frame_set([102]);
+            }
+        };
+        tokio::pin!(frame);
+        let mut dedup = Dedup {
+            frame: frame.map(Ok),
+            max_gap: 100,
+            window: DuplicateWindow::default(),
+            recorder: Arc::new(StatsRecorder::new(1)),
+        };
+        assert_eq!(
+            dedup.next().await.unwrap().unwrap(),
+            reliable_frame_set([0])
+        );
+        assert_eq!(
+            dedup.next().await.unwrap().unwrap(),
+            reliable_frame_set([101])
+        );
+        assert!(matches!(
+            dedup.next().await.unwrap(),
+            Err(CodecError::DedupExceed(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dedup_unreliable_fast_path() {
+        let unreliable = connected::Packet::FrameSet(FrameSet {
+            seq_num: Uint24le(0),
+            frames: vec![Frame {
+                flags: Flags::parse(0),
+                reliable_frame_index: None,
+                seq_frame_index: None,
+                ordered: None,
+                fragment: None,
+                body: Bytes::from_static(b"position"),
+            }],
+        });
+        let frame = {
+            let unreliable = unreliable.clone();
+            #[stream]
+            async move {
+                yield reliable_frame_set([0]);
+                yield reliable_frame_set([101]);
+                yield unreliable;
+                yield reliable_frame_set([102]);
             }
         };
         tokio::pin!(frame);
@@ -355,9 +409,12 @@ mod test {
             frame: frame.map(Ok),
             max_gap: 100,
             window: DuplicateWindow::default(),
+            recorder: Arc::new(StatsRecorder::new(1)),
         };
-        assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set([0]));
-        assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set([101]));
+        dedup.next().await.unwrap().unwrap();
+        dedup.next().await.unwrap().unwrap();
+        // bypass the window even if its gap is exceeded
+        assert_eq!(dedup.next().await.unwrap().unwrap(), unreliable);
         assert!(matches!(
             dedup.next().await.unwrap(),
             Err(CodecError::DedupExceed(..))
@@ -369,8 +426,8 @@ mod test {
         let frame = {
             #[stream]
             async {
-                yield frame_set([0, 1, 2, 3]);
-                yield frame_set([0, 1, 2, 3]);
+                yield reliable_frame_set([0, 1, 2, 3]);
+                yield reliable_frame_set([0, 1, 2, 3]);
             }
         };
         tokio::pin!(frame);
@@ -378,10 +435,11 @@ mod test {
             frame: frame.map(Ok),
             max_gap: 100,
             window: DuplicateWindow::default(),
+            recorder: Arc::new(StatsRecorder::new(1)),
         };
         assert_eq!(
             dedup.next().await.unwrap().unwrap(),
-            frame_set([0, 1, 2, 3])
+            reliable_frame_set([0, 1, 2, 3])
         );
         assert!(dedup.next().await.is_none());
     }
@@ -403,8 +461,8 @@ mod test {
         let frame = {
             #[stream]
             async {
-                yield frame_set(idx1);
-                yield frame_set(idx2);
+                yield reliable_frame_set(idx1);
+                yield reliable_frame_set(idx2);
             }
         };
         tokio::pin!(frame);
@@ -412,16 +470,20 @@ mod test {
             frame: frame.map(Ok),
             max_gap: scale,
             window: DuplicateWindow::default(),
+            recorder: Arc::new(StatsRecorder::new(1)),
         };
         assert_eq!(
             dedup.next().await.unwrap().unwrap(),
-            frame_set(idx1_set.clone())
+            reliable_frame_set(idx1_set.clone())
         );
 
         if diff.is_empty() {
             assert!(dedup.next().await.is_none());
         } else {
-            assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set(diff));
+            assert_eq!(
+                dedup.next().await.unwrap().unwrap(),
+                reliable_frame_set(diff)
+            );
         }
     }
 
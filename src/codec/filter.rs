@@ -0,0 +1,170 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+use futures::{ready, Sink, Stream, StreamExt};
+use pin_project_lite::pin_project;
+use tracing::trace;
+
+use crate::stats::{DropCounter, DropReason};
+
+/// The verdict made by a [`PacketFilter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Continue processing the packet
+    Pass,
+    /// Skip all processing of the packet
+    Drop,
+}
+
+/// Pre-parse packet filter, it receives the raw bytes of a datagram before any decoding.
+/// Operators could use it for emergency mitigations (e.g. block a byte pattern) at minimal cost.
+pub trait PacketFilter {
+    /// Make a verdict on the raw datagram from addr
+    fn filter(&self, addr: SocketAddr, raw: &[u8]) -> Verdict;
+}
+
+impl<T> PacketFilter for T
+where
+    T: Fn(SocketAddr, &[u8]) -> Verdict,
+{
+    fn filter(&self, addr: SocketAddr, raw: &[u8]) -> Verdict {
+        self(addr, raw)
+    }
+}
+
+pin_project! {
+    /// Filter the raw datagrams, should be placed before the packets are decoded.
+    pub(crate) struct Filter<F, P> {
+        #[pin]
+        frame: F,
+        filter: P,
+        // The datagrams larger than it are dropped before the filter, 0 means no limit
+        max_size: usize,
+        drops: Arc<DropCounter>,
+    }
+}
+
+pub(crate) trait Filtered: Sized {
+    fn filtered<P: PacketFilter>(
+        self,
+        filter: P,
+        max_size: usize,
+        drops: Arc<DropCounter>,
+    ) -> Filter<Self, P>;
+}
+
+impl<F> Filtered for F {
+    fn filtered<P: PacketFilter>(
+        self,
+        filter: P,
+        max_size: usize,
+        drops: Arc<DropCounter>,
+    ) -> Filter<Self, P> {
+        Filter {
+            frame: self,
+            filter,
+            max_size,
+            drops,
+        }
+    }
+}
+
+impl<F, P, E> Stream for Filter<F, P>
+where
+    F: Stream<Item = Result<(BytesMut, SocketAddr), E>>,
+    P: PacketFilter,
+{
+    type Item = Result<(BytesMut, SocketAddr), E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let Some((raw, addr)) = ready!(this.frame.poll_next_unpin(cx)?) else {
+                return Poll::Ready(None);
+            };
+            if *this.max_size != 0 && raw.len() > *this.max_size {
+                trace!("drop the datagram of {} bytes from {addr}", raw.len());
+                this.drops.record(DropReason::TooLarge, 1);
+                continue;
+            }
+            if this.filter.filter(addr, &raw) == Verdict::Drop {
+                trace!("drop the datagram from {addr} by the filter");
+                this.drops.record(DropReason::Filtered, 1);
+                continue;
+            }
+            return Poll::Ready(Some(Ok((raw, addr))));
+        }
+    }
+}
+
+impl<F, P, T> Sink<T> for Filter<F, P>
+where
+    F: Sink<T>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.project().frame.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use bytes::BytesMut;
+    use futures::StreamExt;
+    use futures_async_stream::stream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_filter_works() {
+        let addr: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let frame = {
+            #[stream]
+            async move {
+                yield (BytesMut::from(&[0x01, 0x02][..]), addr);
+                yield (BytesMut::from(&[0xfe, 0x02][..]), addr);
+                yield (BytesMut::from(&[0x05; 9][..]), addr);
+                yield (BytesMut::from(&[0x05][..]), addr);
+            }
+        };
+        tokio::pin!(frame);
+
+        let drops = Arc::new(DropCounter::default());
+        let mut filtered = frame.map(Ok::<_, Infallible>).filtered(
+            |_: SocketAddr, raw: &[u8]| {
+                if raw.first() == Some(&0xfe) {
+                    Verdict::Drop
+                } else {
+                    Verdict::Pass
+                }
+            },
+            8,
+            Arc::clone(&drops),
+        );
+
+        assert_eq!(filtered.next().await.unwrap().unwrap().0, [0x01, 0x02][..]);
+        assert_eq!(filtered.next().await.unwrap().unwrap().0, [0x05][..]);
+        assert!(filtered.next().await.is_none());
+        assert_eq!(drops.snapshot().filtered, 1);
+        assert_eq!(drops.snapshot().too_large, 1);
+    }
+}
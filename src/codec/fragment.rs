@@ -2,6 +2,7 @@ use std::cmp::Reverse;
 use std::collections::VecDeque;
 use std::num::NonZeroUsize;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use bytes::{BufMut, Bytes, BytesMut};
@@ -12,6 +13,7 @@ use priority_queue::PriorityQueue;
 
 use crate::errors::CodecError;
 use crate::packet::connected::{self, Fragment, Frame, FrameSet};
+use crate::stats::StatsRecorder;
 
 const DEFAULT_DEFRAGMENT_BUF_SIZE: usize = 512;
 
@@ -24,24 +26,49 @@ pin_project! {
         // limit the max size of a parted frames set, 0 means no limit
         // it will abort the split frame if the parted_size reaches limit.
         limit_size: u32,
+        // limit the max bytes of a reassembled frame, 0 means no limit. A split is rejected at
+        // its first fragment if it could exceed the limit with every part as large as the mtu.
+        limit_body: usize,
+        mtu: u16,
         // reassemble parts helper. [`LruCache`] used to protect from causing OOM due to malicious
         // users sending a large number of parted IDs.
         parts: LruCache<u16, PriorityQueue<Frame<BytesMut>, Reverse<u32>>>,
+        // The bytes of the bodies held by parts, reported to the recorder
+        buffered: usize,
         buffer: VecDeque<FrameSet<Bytes>>,
+        recorder: Arc<StatsRecorder>,
     }
 }
 
 pub(super) trait DeFragmented: Sized {
-    fn defragmented(self, limit_size: u32, limit_parted: usize) -> DeFragment<Self>;
+    fn defragmented(
+        self,
+        limit_size: u32,
+        limit_parted: usize,
+        limit_body: usize,
+        mtu: u16,
+        recorder: Arc<StatsRecorder>,
+    ) -> DeFragment<Self>;
 }
 
 impl<F> DeFragmented for F {
-    fn defragmented(self, limit_size: u32, limit_parted: usize) -> DeFragment<Self> {
+    fn defragmented(
+        self,
+        limit_size: u32,
+        limit_parted: usize,
+        limit_body: usize,
+        mtu: u16,
+        recorder: Arc<StatsRecorder>,
+    ) -> DeFragment<Self> {
         DeFragment {
             frame: self,
             limit_size,
+            limit_body,
+            mtu,
             parts: LruCache::new(NonZeroUsize::new(limit_parted).expect("limit_parted > 0")),
+            buffered: 0,
             buffer: VecDeque::with_capacity(DEFAULT_DEFRAGMENT_BUF_SIZE),
+            recorder,
         }
     }
 }
@@ -60,7 +87,7 @@ where
 
         loop {
             // empty buffer
-            while let Some(pack) = this.buffer.pop_front() {
+            if let Some(pack) = this.buffer.pop_front() {
                 return Poll::Ready(Some(Ok(connected::Packet::FrameSet(pack))));
             }
 
@@ -71,13 +98,16 @@ where
             let connected::Packet::FrameSet(frame_set) = packet else {
                 return Poll::Ready(Some(Ok(packet.freeze())));
             };
+            if frame_set.is_fast_path() {
+                return Poll::Ready(Some(Ok(connected::Packet::FrameSet(frame_set.freeze()))));
+            }
 
             for frame in frame_set.frames {
                 if let Some(Fragment {
                     parted_size,
                     parted_id,
                     parted_index,
-                }) = frame.fragment.clone()
+                }) = frame.fragment
                 {
                     // promise that parted_index is always less than parted_size
                     if parted_index >= parted_size {
@@ -87,18 +117,42 @@ where
                         )))));
                     }
                     if *this.limit_size != 0 && parted_size > *this.limit_size {
-                        return Poll::Ready(Some(Err(CodecError::PartedFrame(format!(
-                            "parted_size {} exceed limit_size {}",
-                            parted_size, *this.limit_size
-                        )))));
+                        return Poll::Ready(Some(Err(CodecError::PartedSizeExceed(
+                            parted_size,
+                            *this.limit_size,
+                        ))));
+                    }
+
+                    let estimated = parted_size as usize * usize::from(*this.mtu);
+                    if *this.limit_body != 0
+                        && estimated > *this.limit_body
+                        && !this.parts.contains(&parted_id)
+                    {
+                        return Poll::Ready(Some(Err(CodecError::ReassembledSizeExceed(
+                            estimated,
+                            *this.limit_body,
+                        ))));
                     }
 
-                    let frames_queue = this.parts.get_or_insert_mut(parted_id, || {
+                    if !this.parts.contains(&parted_id) {
                         // init the PriorityQueue with the capacity defined by user.
-                        PriorityQueue::with_capacity(parted_size as usize)
-                    });
-                    frames_queue.push(frame, Reverse(parted_index));
+                        let queue = PriorityQueue::with_capacity(parted_size as usize);
+                        if let Some((_, evicted)) = this.parts.push(parted_id, queue) {
+                            *this.buffered -=
+                                evicted.iter().map(|(f, _)| f.body.len()).sum::<usize>();
+                        }
+                    }
+                    let frames_queue = this
+                        .parts
+                        .get_mut(&parted_id)
+                        .expect("parted_id is set above");
+                    let len = frame.body.len();
+                    // the duplicate fragment is not buffered again
+                    if frames_queue.push(frame, Reverse(parted_index)).is_none() {
+                        *this.buffered += len;
+                    }
                     if frames_queue.len() < parted_size as usize {
+                        this.recorder.record_reassembly_buffered(*this.buffered);
                         continue;
                     }
                     // parted_index is always less than parted_size, frames_queue length
@@ -120,6 +174,8 @@ where
                         })
                         .expect("there is at least one frame")
                         .freeze();
+                    *this.buffered -= acc_frame.body.len();
+                    this.recorder.record_reassembly_buffered(*this.buffered);
 
                     // TODO: optimize vec![]
                     this.buffer.push_back(FrameSet {
@@ -141,8 +197,9 @@ where
 mod test {
     use std::collections::VecDeque;
     use std::num::NonZeroUsize;
+    use std::sync::Arc;
 
-    use bytes::BytesMut;
+    use bytes::{Bytes, BytesMut};
     use futures::StreamExt;
     use futures_async_stream::stream;
     use lru::LruCache;
@@ -150,62 +207,47 @@ mod test {
 
     use super::DeFragment;
     use crate::errors::CodecError;
-    use crate::packet::connected::{self, Flags, Fragment, Frame, FrameSet, Uint24le};
-
-    fn frame_set<'a, T: AsRef<str> + 'a>(
-        idx: impl IntoIterator<Item = &'a (u32, u16, u32, T)>,
-    ) -> connected::Packet<BytesMut> {
+    use crate::packet::connected::{self, Frame, FrameSet};
+    use crate::stats::StatsRecorder;
+    use crate::test_util::{frame_set, message_frame, split};
+
+    /// The fragment layer takes the frame sets decoded in place, with the mutable bodies
+    fn decoded(packet: connected::Packet<Bytes>) -> connected::Packet<BytesMut> {
+        let connected::Packet::FrameSet(set) = packet else {
+            unreachable!("only the frame sets are fabricated")
+        };
         connected::Packet::FrameSet(FrameSet {
-            seq_num: Uint24le(0),
-            frames: idx
+            seq_num: set.seq_num,
+            frames: set
+                .frames
                 .into_iter()
-                .map(|(parted_size, parted_id, parted_index, body)| Frame {
-                    flags: Flags::parse(0b011_11100),
-                    reliable_frame_index: None,
-                    seq_frame_index: None,
-                    ordered: None,
-                    fragment: Some(Fragment {
-                        parted_size: *parted_size,
-                        parted_id: *parted_id,
-                        parted_index: *parted_index,
-                    }),
-                    body: BytesMut::from(body.as_ref()),
+                .map(|frame| Frame {
+                    body: BytesMut::from(&frame.body[..]),
+                    ..frame
                 })
                 .collect(),
         })
     }
 
-    fn no_frag_frame_set<'a>(
-        bodies: impl IntoIterator<Item = &'a str>,
-    ) -> connected::Packet<BytesMut> {
-        connected::Packet::FrameSet(FrameSet {
-            seq_num: Uint24le(0),
-            frames: bodies
-                .into_iter()
-                .map(|body| Frame {
-                    flags: Flags::parse(0b011_11100),
-                    reliable_frame_index: None,
-                    seq_frame_index: None,
-                    ordered: None,
-                    fragment: None,
-                    body: BytesMut::from(body),
-                })
-                .collect(),
-        })
+    /// Pick the parts in the order of the indices
+    fn parts(
+        frames: &[Frame<Bytes>],
+        indices: impl IntoIterator<Item = usize>,
+    ) -> Vec<Frame<Bytes>> {
+        indices.into_iter().map(|idx| frames[idx].clone()).collect()
     }
 
     #[tokio::test]
     async fn test_defragment_works() {
+        let happy = split(&Bytes::from_static(b"happy"), 7, 1);
+        let k = split(&Bytes::from_static(b"....k"), 6, 1);
         let frame = {
             #[stream]
             async {
-                yield frame_set([
-                    &(5, 7, 0, "h"),
-                    &(5, 7, 3, "p"),
-                    &(5, 7, 4, "y"),
-                    &(5, 6, 4, "k"),
-                ]);
-                yield frame_set([&(5, 7, 2, "p"), &(5, 7, 1, "a"), &(5, 7, 4, "y")]);
+                let mut first = parts(&happy, [0, 3, 4]);
+                first.push(k[4].clone());
+                yield decoded(frame_set(0, first));
+                yield decoded(frame_set(0, parts(&happy, [2, 1, 4])));
             }
         };
 
@@ -213,8 +255,12 @@ mod test {
         let mut frag = DeFragment {
             frame: frame.map(Ok),
             limit_size: 0,
+            limit_body: 0,
+            mtu: 1400,
             parts: LruCache::new(NonZeroUsize::new(512).expect("limit_parted > 0")),
+            buffered: 0,
             buffer: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(1)),
         };
 
         let set = frag.next().await.unwrap().unwrap();
@@ -234,15 +280,20 @@ mod test {
 
         // could only be polled once
         assert!(frag.next().await.is_none());
+        // the part of 6 and the late part of 7 are still waiting
+        assert_eq!(frag.recorder.reassembly_buffered(), 2);
     }
 
     #[tokio::test]
     async fn test_defragment_bad_parted_index() {
+        let mut beyond = split(&Bytes::from_static(b"h"), 7, 1);
+        beyond[0].fragment.as_mut().unwrap().parted_index = 1;
+        let oversized = split(&Bytes::from(vec![b'h'; 22]), 7, 1);
         let frame = {
             #[stream]
             async {
-                yield frame_set([&(10, 7, 10, "h")]);
-                yield frame_set([&(22, 7, 6, "h")]);
+                yield decoded(frame_set(0, beyond));
+                yield decoded(frame_set(0, parts(&oversized, [6])));
             }
         };
 
@@ -250,8 +301,12 @@ mod test {
         let mut frag = DeFragment {
             frame: frame.map(Ok),
             limit_size: 20,
+            limit_body: 0,
+            mtu: 1400,
             parts: LruCache::new(NonZeroUsize::new(512).expect("limit_parted > 0")),
+            buffered: 0,
             buffer: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(1)),
         };
 
         assert!(matches!(
@@ -261,25 +316,67 @@ mod test {
 
         assert!(matches!(
             frag.next().await.unwrap(),
-            Err(CodecError::PartedFrame(..))
+            Err(CodecError::PartedSizeExceed(22, 20))
         ));
 
         assert!(frag.next().await.is_none());
     }
 
+    #[tokio::test]
+    async fn test_defragment_reassembled_size_exceed() {
+        let large = split(&Bytes::from_static(b"h.."), 7, 1);
+        let hi = split(&Bytes::from_static(b"hi"), 8, 1);
+        let frame = {
+            #[stream]
+            async {
+                yield decoded(frame_set(0, parts(&large, [0])));
+                yield decoded(frame_set(0, hi));
+            }
+        };
+
+        tokio::pin!(frame);
+        let mut frag = DeFragment {
+            frame: frame.map(Ok),
+            limit_size: 0,
+            limit_body: 2000,
+            mtu: 1000,
+            parts: LruCache::new(NonZeroUsize::new(512).expect("limit_parted > 0")),
+            buffered: 0,
+            buffer: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(1)),
+        };
+
+        // rejected before buffering anything
+        assert!(matches!(
+            frag.next().await.unwrap(),
+            Err(CodecError::ReassembledSizeExceed(3000, 2000))
+        ));
+        assert!(frag.parts.is_empty());
+        let set = frag.next().await.unwrap().unwrap();
+        let connected::Packet::FrameSet(set) = set else {
+            panic!("expect a frame set");
+        };
+        assert_eq!(set.frames[0].body, Bytes::from_static(b"hi"));
+        assert!(frag.next().await.is_none());
+    }
+
     #[tokio::test]
     async fn test_defragment_lru_dropped() {
+        let body = Bytes::from_static(b"012");
+        let [zero, one, two] = [0, 1, 2].map(|parted_id| split(&body, parted_id, 1));
         let frame = {
             #[stream]
             async {
-                yield frame_set([&(3, 0, 0, "0")]);
-                yield frame_set([&(3, 1, 0, "0")]);
-                yield frame_set([&(3, 2, 0, "0")]); // 3rd one will motivate lru to drop 1st one
+                yield decoded(frame_set(0, parts(&zero, [0])));
+                yield decoded(frame_set(0, parts(&one, [0])));
+                // 3rd one will motivate lru to drop 1st one
+                yield decoded(frame_set(0, parts(&two, [0])));
 
-                yield frame_set([&(3, 0, 1, "1")]);
-                yield frame_set([&(3, 0, 2, "2")]); // cannot collect parted_id 0
+                yield decoded(frame_set(0, parts(&zero, [1])));
+                // cannot collect parted_id 0
+                yield decoded(frame_set(0, parts(&zero, [2])));
 
-                yield frame_set([&(3, 2, 2, "2")]);
+                yield decoded(frame_set(0, parts(&two, [2])));
             }
         };
 
@@ -287,8 +384,12 @@ mod test {
         let mut frag = DeFragment {
             frame: frame.map(Ok),
             limit_size: 0,
+            limit_body: 0,
+            mtu: 1400,
             parts: LruCache::new(NonZeroUsize::new(2).expect("limit_parted > 0")),
+            buffered: 0,
             buffer: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(1)),
         };
 
         assert!(frag.next().await.is_none());
@@ -300,17 +401,19 @@ mod test {
 
     #[tokio::test]
     async fn test_defragment_mixed() {
+        let happy = split(&Bytes::from_static(b"happy"), 7, 1);
+        let k = split(&Bytes::from_static(b"....k"), 6, 1);
         let frame = {
             #[stream]
             async {
-                yield frame_set([
-                    &(5, 7, 0, "h"),
-                    &(5, 7, 3, "p"),
-                    &(5, 7, 4, "y"),
-                    &(5, 6, 4, "k"),
-                ]);
-                yield no_frag_frame_set(["funny"]);
-                yield frame_set([&(5, 7, 2, "p"), &(5, 7, 1, "a"), &(5, 7, 4, "y")]);
+                let mut first = parts(&happy, [0, 3, 4]);
+                first.push(k[4].clone());
+                yield decoded(frame_set(0, first));
+                yield decoded(frame_set(
+                    0,
+                    [message_frame(None, Bytes::from_static(b"funny"))],
+                ));
+                yield decoded(frame_set(0, parts(&happy, [2, 1, 4])));
             }
         };
 
@@ -318,8 +421,12 @@ mod test {
         let mut frag = DeFragment {
             frame: frame.map(Ok),
             limit_size: 0,
+            limit_body: 0,
+            mtu: 1400,
             parts: LruCache::new(NonZeroUsize::new(2).expect("limit_parted > 0")),
+            buffered: 0,
             buffer: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(1)),
         };
 
         {
@@ -348,24 +455,16 @@ mod test {
     }
 
     async fn test_defragment_fuzzing_with_scale(scale: usize) {
-        let mut parted_slice = (0..scale).collect::<Vec<_>>();
-        let final_body = parted_slice
-            .iter()
-            .fold(String::new(), |acc, next| format!("{acc}{next}"));
-
-        parted_slice.shuffle(&mut rand::thread_rng());
+        let final_body = (0..scale).fold(String::new(), |acc, next| format!("{acc}{}", next % 10));
+        let mut parted = split(&Bytes::from(final_body.clone()), 0, 1);
+        parted.shuffle(&mut rand::thread_rng());
 
         let frame = {
             #[stream]
             async {
                 let chunk_size = rand::random::<usize>() % scale + 1; // non zero
-                for chunk in std::iter::repeat((scale as u32, 0))
-                    .zip(parted_slice)
-                    .map(|(l, r)| (l.0, l.1, r as u32, r.to_string()))
-                    .collect::<Vec<_>>()
-                    .chunks(chunk_size)
-                {
-                    yield frame_set(chunk);
+                for chunk in parted.chunks(chunk_size) {
+                    yield decoded(frame_set(0, chunk.to_vec()));
                 }
             }
         };
@@ -374,8 +473,12 @@ mod test {
         let mut frag = DeFragment {
             frame: frame.map(Ok),
             limit_size: 0,
+            limit_body: 0,
+            mtu: 1400,
             parts: LruCache::new(NonZeroUsize::new(1).expect("limit_parted > 0")),
+            buffered: 0,
             buffer: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(1)),
         };
 
         let set = frag.next().await.unwrap().unwrap();
@@ -0,0 +1,189 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::{ready, Sink, Stream, StreamExt};
+use pin_project_lite::pin_project;
+use tracing::trace;
+
+use super::filter::Verdict;
+use crate::stats::{DropCounter, DropReason};
+
+/// Hooks over the raw datagrams, invoked with each outgoing datagram just before it is sent and
+/// each incoming datagram just after it is received, before parsing. The community extensions
+/// such as checksums, padding or obfuscation could be built on it without forking the codec.
+pub trait DatagramHook {
+    /// Transform the outgoing datagram to addr in place
+    fn on_send(&self, addr: SocketAddr, buf: &mut BytesMut);
+
+    /// Transform the incoming datagram from addr in place, returns [`Verdict::Drop`] to discard
+    /// it, e.g. its checksum does not match.
+    fn on_recv(&self, addr: SocketAddr, buf: &mut BytesMut) -> Verdict;
+
+    /// The bytes added to every outgoing datagram, which are reserved from the mtu
+    fn overhead(&self) -> usize {
+        0
+    }
+}
+
+/// No hook
+impl DatagramHook for () {
+    fn on_send(&self, _: SocketAddr, _: &mut BytesMut) {}
+
+    fn on_recv(&self, _: SocketAddr, _: &mut BytesMut) -> Verdict {
+        Verdict::Pass
+    }
+}
+
+impl<H: DatagramHook + ?Sized> DatagramHook for Box<H> {
+    fn on_send(&self, addr: SocketAddr, buf: &mut BytesMut) {
+        (**self).on_send(addr, buf);
+    }
+
+    fn on_recv(&self, addr: SocketAddr, buf: &mut BytesMut) -> Verdict {
+        (**self).on_recv(addr, buf)
+    }
+
+    fn overhead(&self) -> usize {
+        (**self).overhead()
+    }
+}
+
+pin_project! {
+    /// Apply the [`DatagramHook`] to the raw datagrams, should be placed next to the socket.
+    pub(crate) struct Hook<F, H> {
+        #[pin]
+        frame: F,
+        hook: H,
+        drops: Arc<DropCounter>,
+    }
+}
+
+pub(crate) trait Hooked: Sized {
+    fn hooked<H: DatagramHook>(self, hook: H, drops: Arc<DropCounter>) -> Hook<Self, H>;
+}
+
+impl<F> Hooked for F {
+    fn hooked<H: DatagramHook>(self, hook: H, drops: Arc<DropCounter>) -> Hook<Self, H> {
+        Hook {
+            frame: self,
+            hook,
+            drops,
+        }
+    }
+}
+
+impl<F, H, E> Stream for Hook<F, H>
+where
+    F: Stream<Item = Result<(BytesMut, SocketAddr), E>>,
+    H: DatagramHook,
+{
+    type Item = Result<(BytesMut, SocketAddr), E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let Some((mut raw, addr)) = ready!(this.frame.poll_next_unpin(cx)?) else {
+                return Poll::Ready(None);
+            };
+            if this.hook.on_recv(addr, &mut raw) == Verdict::Drop {
+                trace!("drop the datagram from {addr} by the hook");
+                this.drops.record(DropReason::Filtered, 1);
+                continue;
+            }
+            return Poll::Ready(Some(Ok((raw, addr))));
+        }
+    }
+}
+
+/// The datagrams are frozen after the hook, which is the last to modify them before the socket
+impl<F, H> Sink<(BytesMut, SocketAddr)> for Hook<F, H>
+where
+    F: Sink<(Bytes, SocketAddr)>,
+    H: DatagramHook,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        (mut raw, addr): (BytesMut, SocketAddr),
+    ) -> Result<(), Self::Error> {
+        let this = self.project();
+        this.hook.on_send(addr, &mut raw);
+        this.frame.start_send((raw.freeze(), addr))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use futures::channel::mpsc;
+    use futures::SinkExt;
+    use futures_async_stream::stream;
+
+    use super::*;
+
+    /// Append a byte to the outgoing datagrams and strip it from the incoming ones
+    struct Trailer(u8);
+
+    impl DatagramHook for Trailer {
+        fn on_send(&self, _: SocketAddr, buf: &mut BytesMut) {
+            buf.extend_from_slice(&[self.0]);
+        }
+
+        fn on_recv(&self, _: SocketAddr, buf: &mut BytesMut) -> Verdict {
+            if buf.last() != Some(&self.0) {
+                return Verdict::Drop;
+            }
+            buf.truncate(buf.len() - 1);
+            Verdict::Pass
+        }
+
+        fn overhead(&self) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hook_works() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let frame = {
+            #[stream]
+            async move {
+                yield (BytesMut::from(&b"hello\xff"[..]), addr);
+                yield (BytesMut::from(&b"tampered"[..]), addr);
+            }
+        };
+        let drops = Arc::new(DropCounter::default());
+        let received = frame
+            .map(Ok::<_, Infallible>)
+            .hooked(Trailer(0xff), Arc::clone(&drops))
+            .map(|res| res.unwrap().0)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(received, vec![BytesMut::from(&b"hello"[..])]);
+        assert_eq!(drops.snapshot().filtered, 1);
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut sink = tx.hooked(Trailer(0xff), drops);
+        sink.send((BytesMut::from(&b"hello"[..]), addr))
+            .await
+            .unwrap();
+        assert_eq!(rx.next().await.unwrap().0, Bytes::from_static(b"hello\xff"));
+    }
+}
@@ -1,45 +1,109 @@
+pub(crate) mod batch;
+pub(crate) mod checksum;
 mod dedup;
+pub(crate) mod filter;
 mod fragment;
 mod frame;
-mod ordered;
+pub(crate) mod hook;
+pub(crate) mod ordered;
+pub(crate) mod parse;
+mod replay;
+mod tally;
 
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::BytesMut;
 use derive_builder::Builder;
 use futures::{ready, Stream};
 use pin_project_lite::pin_project;
-use tokio_util::codec::{Decoder, Encoder};
 use tracing::{debug, trace};
 
 use self::frame::FrameDecoded;
-use self::ordered::Ordered;
+use self::ordered::{Ordered, StalledPolicy};
+use self::replay::AntiReplayed;
+use self::tally::Tallied;
 use crate::codec::dedup::Deduplicated;
 use crate::codec::fragment::DeFragmented;
-use crate::errors::CodecError;
+use crate::errors::{CodecError, ConfigError};
+use crate::packet::connected;
 use crate::packet::connected::FrameBody;
-use crate::packet::{connected, Packet};
+use crate::stats::{DropReason, StatsRecorder};
 
 /// Codec config
 #[derive(Clone, Copy, Debug, Builder)]
+#[builder(
+    default,
+    build_fn(private, name = "build_unchecked", error = "ConfigError")
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct CodecConfig {
     /// Limit the max size of a parted frames set, 0 means no limit
-    /// It will abort the split frame if the parted_size reaches limit.
-    /// Enable it to avoid DoS attack.
-    /// The maximum number of inflight parted frames is max_parted_size * max_parted_count
+    /// It will abort the split frame if the `parted_size` reaches limit.
+    /// Enable it to avoid the denial of service attack.
+    /// The maximum number of inflight parted frames is `max_parted_size * max_parted_count`
     max_parted_size: u32,
     /// Limit the max count of **all** parted frames sets from an address.
     /// It might cause client resending frames if the limit is reached.
-    /// Enable it to avoid DoS attack.
-    /// The maximum number of inflight parted frames is max_parted_size * max_parted_count
+    /// Enable it to avoid the denial of service attack.
+    /// The maximum number of inflight parted frames is `max_parted_size * max_parted_count`
     max_parted_count: usize,
-    /// Maximum ordered channel, the value should be less than 256
+    /// Limit the max bytes of a reassembled message, 0 means no limit. A split is rejected at
+    /// its first fragment if `parted_size * mtu` exceeds it, before buffering anything.
+    max_reassembled_size: usize,
+    /// Maximum ordered channels in `1..=256`. The channels are created
+    /// lazily when first referenced by the peer, the frames on the channels beyond it are
+    /// dropped without closing the connection.
     max_channels: usize,
     // Limit the maximum deduplication gap for a connection, 0 means no limit.
     // Enable it to avoid D-DoS attack based on deduplication.
     max_dedup_gap: usize,
+    // The size in bits of the anti-replay window of datagram sequence numbers, rounded up to a
+    // power of two, 0 means disabled.
+    // Datagrams with sequence numbers seen in the window or older than it will be dropped.
+    replay_window: u32,
+    /// The max duration an ordered channel could be blocked by a missing frame index, None
+    /// means no limit.
+    ordered_stalled_timeout: Option<Duration>,
+    /// What to do when an ordered channel is stalled
+    ordered_stalled_policy: StalledPolicy,
+}
+
+impl CodecConfig {
+    /// Maximum ordered channel
+    pub(crate) fn max_channels(&self) -> usize {
+        self.max_channels
+    }
+
+    /// Check the fields, the config deserialized without the builder should be checked by it
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError::MaxChannels`] if the max channels is out of `1..=256`
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // the channel is encoded in a byte
+        if !(1..=usize::from(u8::MAX) + 1).contains(&self.max_channels) {
+            return Err(ConfigError::MaxChannels(self.max_channels));
+        }
+        Ok(())
+    }
+}
+
+impl CodecConfigBuilder {
+    /// Build the config and check the fields, the unset fields take the default values
+    ///
+    /// # Errors
+    ///
+    /// See [`CodecConfig::validate`]
+    pub fn build(&self) -> Result<CodecConfig, ConfigError> {
+        let config = self.build_unchecked()?;
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 impl Default for CodecConfig {
@@ -48,8 +112,13 @@ impl Default for CodecConfig {
         Self {
             max_parted_size: 256,
             max_parted_count: 256,
-            max_channels: 1,
+            max_reassembled_size: 8 * 1024 * 1024,
+            // the same as the ordering streams of the reference implementation
+            max_channels: 32,
             max_dedup_gap: 1024,
+            replay_window: 1024,
+            ordered_stalled_timeout: None,
+            ordered_stalled_policy: StalledPolicy::Close,
         }
     }
 }
@@ -58,7 +127,9 @@ pub(crate) trait Decoded {
     fn decoded(
         self,
         addr: SocketAddr,
+        mtu: u16,
         config: CodecConfig,
+        recorder: Arc<StatsRecorder>,
     ) -> impl Stream<Item = connected::Packet<FrameBody>>;
 }
 
@@ -69,33 +140,53 @@ where
     fn decoded(
         self,
         addr: SocketAddr,
+        mtu: u16,
         config: CodecConfig,
+        recorder: Arc<StatsRecorder>,
     ) -> impl Stream<Item = connected::Packet<FrameBody>> {
-        self.deduplicated(config.max_dedup_gap)
-            .defragmented(config.max_parted_size, config.max_parted_count)
-            .ordered(config.max_channels)
+        self.anti_replayed(config.replay_window, Arc::clone(&recorder))
+            .deduplicated(config.max_dedup_gap, Arc::clone(&recorder))
+            .defragmented(
+                config.max_parted_size,
+                config.max_parted_count,
+                config.max_reassembled_size,
+                mtu,
+                Arc::clone(&recorder),
+            )
+            .ordered(
+                config.max_channels,
+                config.ordered_stalled_timeout,
+                config.ordered_stalled_policy,
+                Arc::clone(&recorder),
+            )
+            .tallied(Arc::clone(&recorder))
             .frame_decoded()
-            .logged(addr)
+            .logged(addr, recorder)
     }
 }
 
 pin_project! {
     /// Log the error of the packet codec while reading.
     /// We probably don't care about the codec error while decoding request packets.
-    struct Log<F> {
+    pub(crate) struct Log<F> {
         #[pin]
         frame: F,
         addr: SocketAddr,
+        recorder: Arc<StatsRecorder>,
     }
 }
 
 pub(crate) trait Logged: Sized {
-    fn logged(self, addr: SocketAddr) -> Log<Self>;
+    fn logged(self, addr: SocketAddr, recorder: Arc<StatsRecorder>) -> Log<Self>;
 }
 
 impl<F> Logged for F {
-    fn logged(self, addr: SocketAddr) -> Log<Self> {
-        Log { frame: self, addr }
+    fn logged(self, addr: SocketAddr, recorder: Arc<StatsRecorder>) -> Log<Self> {
+        Log {
+            frame: self,
+            addr,
+            recorder,
+        }
     }
 }
 
@@ -118,6 +209,7 @@ where
                         "raknet codec error: {err} from {}, ignore this packet",
                         this.addr
                     );
+                    this.recorder.record_dropped(DropReason::of(&err), 1);
                     continue;
                 }
             };
@@ -131,24 +223,28 @@ where
     }
 }
 
-/// The raknet codec
-pub(crate) struct Codec;
-
-impl<B: Buf> Encoder<Packet<B>> for Codec {
-    type Error = CodecError;
-
-    fn encode(&mut self, item: Packet<B>, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        item.write(dst);
-        Ok(())
-    }
-}
-
-impl Decoder for Codec {
-    type Error = CodecError;
-    // we might want to update the package during codec
-    type Item = Packet<BytesMut>;
-
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Packet::read(src)
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_codec_config_max_channels() {
+        assert_eq!(
+            CodecConfigBuilder::default().build().unwrap().max_channels,
+            32
+        );
+        assert!(CodecConfigBuilder::default()
+            .max_channels(256)
+            .build()
+            .is_ok());
+        for max_channels in [0, 257] {
+            assert_eq!(
+                CodecConfigBuilder::default()
+                    .max_channels(max_channels)
+                    .build()
+                    .unwrap_err(),
+                ConfigError::MaxChannels(max_channels)
+            );
+        }
     }
 }
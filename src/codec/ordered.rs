@@ -1,61 +1,293 @@
-use std::collections::HashMap;
-use std::ops::AddAssign;
+use std::collections::VecDeque;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use bytes::Buf;
 use futures::{ready, Stream, StreamExt};
 use pin_project_lite::pin_project;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::errors::CodecError;
-use crate::packet::connected::{self, Frame};
+use crate::packet::connected::{self, Frame, Reliability, Uint24le};
+use crate::stats::{DropReason, StatsRecorder};
 
-const INITIAL_ORDERING_MAP_CAP: usize = 64;
+pub(crate) const ORDERING_WINDOW_SIZE: usize = 1024;
+/// The max sequenced frames of a channel waiting for the ordered frames before them
+const MAX_SEQUENCED: usize = ORDERING_WINDOW_SIZE;
 
+/// What to do when an ordered channel is blocked by a missing frame index for too long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StalledPolicy {
+    /// Skip the hole and continue reading from the next received frame index
+    FastForward,
+    /// Report an error and close the connection
+    Close,
+}
+
+/// The ordering window of a channel. Frames waiting to be read are stored in a fixed-size ring
+/// buffer indexed by `frame_index % ORDERING_WINDOW_SIZE`, the valid frame indices are in
+/// `read..read + ORDERING_WINDOW_SIZE`.
+///
+/// The sequenced frames share the channel with the ordered frames as the reference
+/// implementation: a sequenced frame carries the ordered index of the next ordered frame at the
+/// time it was sent, so it is read after the ordered frames before it, and the sequenced index
+/// restarts from 0 after every ordered frame.
 struct Ordering<B> {
-    map: HashMap<u32, Frame<B>>,
-    read: u32,
+    // Lazily allocated for the channels which never receive out of order frames, along with the
+    // time they arrive
+    window: Vec<Option<(Frame<B>, Instant)>>,
+    // The count of buffered frames in window
+    buffered: usize,
+    // The bytes of the frames in window and sequenced
+    bytes: usize,
+    read: Uint24le,
+    // The next sequenced index accepted in the current ordered index, the older ones are dropped
+    next_sequenced: Uint24le,
+    // The sequenced frames waiting for the ordered frames before them
+    sequenced: Vec<Frame<B>>,
+    // The time since the channel has been waiting for a missing frame index
+    blocked_since: Option<Instant>,
 }
 
 impl<B> Default for Ordering<B> {
     fn default() -> Self {
         Self {
-            map: HashMap::with_capacity(INITIAL_ORDERING_MAP_CAP),
-            read: 0,
+            window: Vec::new(),
+            buffered: 0,
+            bytes: 0,
+            read: Uint24le(0),
+            next_sequenced: Uint24le(0),
+            sequenced: Vec::new(),
+            blocked_since: None,
         }
     }
 }
 
+impl<B: Buf> Ordering<B> {
+    fn slot(index: Uint24le) -> usize {
+        // the serial number space is a multiple of the window size, so the slots are continuous
+        // when the index wraps
+        index.0 as usize % ORDERING_WINDOW_SIZE
+    }
+
+    /// Buffer a frame which index is larger than read index. Returns false if the index
+    /// exceeds the window.
+    fn insert(&mut self, index: Uint24le, frame: Frame<B>) -> bool {
+        if index.distance_from(self.read) as usize >= ORDERING_WINDOW_SIZE {
+            return false;
+        }
+        if self.window.is_empty() {
+            self.window
+                .resize_with(ORDERING_WINDOW_SIZE, Option::default);
+        }
+        let slot = &mut self.window[Self::slot(index)];
+        match slot {
+            Some((old, _)) => self.bytes -= old.size(),
+            None => self.buffered += 1,
+        }
+        self.bytes += frame.size();
+        *slot = Some((frame, Instant::now()));
+        true
+    }
+
+    /// Take the frame at read index and the time it arrived if it has been received
+    fn pop(&mut self) -> Option<(Frame<B>, Instant)> {
+        if self.buffered == 0 {
+            return None;
+        }
+        let buffered = self.window[Self::slot(self.read)].take()?;
+        self.buffered -= 1;
+        self.bytes -= buffered.0.size();
+        self.read = self.read.next();
+        Some(buffered)
+    }
+
+    /// Read the frame at read index and all continuous frames after it, along with the sequenced
+    /// frames released by them.
+    fn read(&mut self, frame: Frame<B>, frames: &mut Vec<Frame<B>>, recorder: &StatsRecorder) {
+        self.read = self.read.next();
+        frames.push(frame);
+        recorder.record_ordered_wait(Duration::ZERO);
+        self.release_sequenced(frames, recorder);
+        self.read_continuous(frames, recorder);
+    }
+
+    fn read_continuous(&mut self, frames: &mut Vec<Frame<B>>, recorder: &StatsRecorder) {
+        while let Some((next, arrived)) = self.pop() {
+            frames.push(next);
+            recorder.record_ordered_wait(arrived.elapsed());
+            self.release_sequenced(frames, recorder);
+        }
+    }
+
+    /// Accept a sequenced frame of the ordered index. Returns false if it is older than the last
+    /// read one.
+    fn accept_sequenced(&mut self, sequenced: Uint24le) -> bool {
+        if sequenced.serial_cmp(self.next_sequenced) == std::cmp::Ordering::Less {
+            return false;
+        }
+        self.next_sequenced = sequenced.next();
+        true
+    }
+
+    /// The read index has been advanced, restart the sequenced index and read the buffered
+    /// sequenced frames of the new read index. The stale ones are dropped and counted.
+    fn release_sequenced(&mut self, frames: &mut Vec<Frame<B>>, recorder: &StatsRecorder) {
+        self.next_sequenced = Uint24le(0);
+        if self.sequenced.is_empty() {
+            return;
+        }
+        let read = self.read;
+        let index = |frame: &Frame<B>| frame.ordered.as_ref().map(|ordered| ordered.frame_index);
+        let buffered = self.sequenced.len();
+        let taken: usize = self.sequenced.iter().map(Frame::size).sum();
+        let (mut ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.sequenced)
+            .into_iter()
+            .filter(|frame| index(frame).is_some_and(|idx| idx.serial_cmp(read).is_ge()))
+            .partition(|frame| index(frame) == Some(read));
+        let stale = buffered - ready.len() - waiting.len();
+        self.bytes -= taken;
+        self.bytes += waiting.iter().map(Frame::size).sum::<usize>();
+        self.sequenced = waiting;
+        ready.sort_by(|a, b| {
+            let (a, b) = (
+                a.seq_frame_index.unwrap_or_default(),
+                b.seq_frame_index.unwrap_or_default(),
+            );
+            a.serial_cmp(b)
+        });
+        let mut dropped = stale;
+        for frame in ready {
+            if self.accept_sequenced(frame.seq_frame_index.unwrap_or_default()) {
+                frames.push(frame);
+            } else {
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            recorder.record_dropped(DropReason::StaleOrdered, dropped as u64);
+        }
+    }
+
+    /// Mark the channel blocked or unblocked based on the buffered frames
+    fn update_blocked(&mut self) {
+        if self.buffered == 0 {
+            self.blocked_since = None;
+        } else if self.blocked_since.is_none() {
+            self.blocked_since = Some(Instant::now());
+        }
+    }
+
+    fn stalled(&self, timeout: Duration) -> bool {
+        self.blocked_since
+            .is_some_and(|since| since.elapsed() >= timeout)
+    }
+
+    /// Skip the missing frame indices and read all continuous frames after the hole
+    fn fast_forward(&mut self, frames: &mut Vec<Frame<B>>, recorder: &StatsRecorder) {
+        if self.buffered == 0 {
+            return;
+        }
+        let Some(skip) = (1..ORDERING_WINDOW_SIZE as u32)
+            .find(|i| self.window[Self::slot(self.read.add(*i))].is_some())
+        else {
+            return;
+        };
+        debug!(
+            "fast forward ordered read index from {} to {}",
+            self.read,
+            self.read.add(skip)
+        );
+        self.read = self.read.add(skip);
+        self.read_continuous(frames, recorder);
+        self.blocked_since = None;
+        self.update_blocked();
+    }
+}
+
+/// Assign the indices of the outgoing frames on a channel, the counterpart of [`Ordering`]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct OrderingWriter {
+    ordered: Uint24le,
+    sequenced: Uint24le,
+}
+
+impl OrderingWriter {
+    /// The `(sequenced index, ordered index)` of the next frame of the reliability. An ordered
+    /// frame takes a new ordered index and restarts the sequenced index, a sequenced frame
+    /// shares the ordered index of the next ordered frame.
+    pub(crate) fn next(
+        &mut self,
+        reliability: Reliability,
+    ) -> (Option<Uint24le>, Option<Uint24le>) {
+        if reliability.is_sequenced() {
+            let sequenced = self.sequenced;
+            self.sequenced = sequenced.next();
+            return (Some(sequenced), Some(self.ordered));
+        }
+        if reliability.is_sequenced_or_ordered() {
+            let ordered = self.ordered;
+            self.ordered = ordered.next();
+            self.sequenced = Uint24le(0);
+            return (None, Some(ordered));
+        }
+        (None, None)
+    }
+}
+
 pin_project! {
     // Ordering layer, ordered the packets based on ordering_frame_index.
+    // Every channel has its own window, a channel blocked by a missing frame index (e.g.
+    // awaiting the retransmission) never holds back the frames of the other channels, even the
+    // ones carried by the same frame set.
     pub(crate) struct Order<F, B> {
         #[pin]
         frame: F,
         // Max ordered channel that will be used in detailed protocol
         max_channels: usize,
+        // Created lazily up to max_channels when a channel is first referenced
         ordering: Vec<Ordering<B>>,
+        // The max duration a channel could be blocked by a missing frame index, None means no
+        // limit. It is checked while new packets arrive.
+        stalled_timeout: Option<Duration>,
+        stalled_policy: StalledPolicy,
+        closed: bool,
+        // Reported one per poll after the frames read along with them
+        pending_err: VecDeque<CodecError>,
+        recorder: Arc<StatsRecorder>,
     }
 }
 
 pub(super) trait Ordered: Sized {
-    fn ordered<B: Buf>(self, max_channels: usize) -> Order<Self, B>;
+    fn ordered<B: Buf>(
+        self,
+        max_channels: usize,
+        stalled_timeout: Option<Duration>,
+        stalled_policy: StalledPolicy,
+        recorder: Arc<StatsRecorder>,
+    ) -> Order<Self, B>;
 }
 
 impl<T> Ordered for T {
-    fn ordered<B: Buf>(self, max_channels: usize) -> Order<Self, B> {
-        assert!(
-            max_channels < usize::from(u8::MAX),
-            "max channels should not be larger than u8::MAX"
-        );
-        assert!(max_channels > 0, "max_channels > 0");
-
+    fn ordered<B: Buf>(
+        self,
+        max_channels: usize,
+        stalled_timeout: Option<Duration>,
+        stalled_policy: StalledPolicy,
+        recorder: Arc<StatsRecorder>,
+    ) -> Order<Self, B> {
         Order {
             frame: self,
             max_channels,
-            ordering: std::iter::repeat_with(Ordering::default)
-                .take(max_channels)
-                .collect(),
+            ordering: Vec::new(),
+            stalled_timeout,
+            stalled_policy,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder,
         }
     }
 }
@@ -63,6 +295,7 @@ impl<T> Ordered for T {
 impl<F, B> Stream for Order<F, B>
 where
     F: Stream<Item = Result<connected::Packet<B>, CodecError>>,
+    B: Buf,
 {
     type Item = Result<connected::Packet<B>, CodecError>;
 
@@ -70,6 +303,12 @@ where
         let mut this = self.project();
 
         loop {
+            if let Some(err) = this.pending_err.pop_front() {
+                return Poll::Ready(Some(Err(err)));
+            }
+            if *this.closed {
+                return Poll::Ready(None);
+            }
             let Some(packet) = ready!(this.frame.poll_next_unpin(cx)?) else {
                 return Poll::Ready(None);
             };
@@ -78,52 +317,98 @@ where
                 return Poll::Ready(Some(Ok(packet)));
             };
 
-            let mut frames = None;
             let frames_len = frame_set.frames.len();
-            for frame in frame_set.frames {
+            // the frames of the fast path have nothing to order, the stalled channels are still
+            // checked below
+            let (mut frames, to_order) = if frame_set.is_fast_path() {
+                (Some(frame_set.frames), Vec::new())
+            } else {
+                (None, frame_set.frames)
+            };
+            for frame in to_order {
                 if let Some(connected::Ordered {
                     frame_index,
                     channel,
-                }) = frame.ordered.clone()
+                }) = frame.ordered
                 {
+                    if usize::from(channel) >= *this.max_channels {
+                        // drop the frame only, the other channels keep working
+                        this.pending_err
+                            .push_back(CodecError::ChannelExceeded(channel, *this.max_channels));
+                        continue;
+                    }
                     let channel = usize::from(channel);
-                    if channel >= *this.max_channels {
-                        return Poll::Ready(Some(Err(CodecError::OrderedFrame(format!(
-                            "channel {} >= max_channels {}",
-                            channel, *this.max_channels
-                        )))));
+                    if channel >= this.ordering.len() {
+                        this.ordering.resize_with(channel + 1, Ordering::default);
                     }
-                    let ordering = this
-                        .ordering
-                        .get_mut(channel)
-                        .expect("channel < max_channels");
+                    let ordering = &mut this.ordering[channel];
 
-                    match frame_index.0.cmp(&ordering.read) {
+                    if let Some(sequenced) = frame.seq_frame_index {
+                        match frame_index.serial_cmp(ordering.read) {
+                            std::cmp::Ordering::Equal if ordering.accept_sequenced(sequenced) => {
+                                frames
+                                    .get_or_insert_with(|| Vec::with_capacity(frames_len))
+                                    .push(frame);
+                            }
+                            std::cmp::Ordering::Greater
+                                if (frame_index.distance_from(ordering.read) as usize)
+                                    < ORDERING_WINDOW_SIZE =>
+                            {
+                                if ordering.sequenced.len() >= MAX_SEQUENCED {
+                                    // drop the frame only, the same as exceeding the window
+                                    this.pending_err.push_back(CodecError::OrderedFrame(format!(
+                                        "sequenced frames waiting for frame index {} exceed \
+                                         {MAX_SEQUENCED}",
+                                        ordering.read
+                                    )));
+                                    continue;
+                                }
+                                // wait for the ordered frames sent before it
+                                ordering.bytes += frame.size();
+                                ordering.sequenced.push(frame);
+                            }
+                            _ => {
+                                debug!("ignore old sequenced frame index {sequenced}");
+                                this.recorder.record_dropped(DropReason::StaleOrdered, 1);
+                            }
+                        }
+                        continue;
+                    }
+
+                    match frame_index.serial_cmp(ordering.read) {
                         std::cmp::Ordering::Less => {
                             debug!("ignore old ordered frame index {frame_index}");
+                            this.recorder.record_dropped(DropReason::StaleOrdered, 1);
                             continue;
                         }
                         std::cmp::Ordering::Greater => {
-                            ordering.map.insert(frame_index.0, frame);
+                            let depth = frame_index.distance_from(ordering.read);
+                            if !ordering.insert(frame_index, frame) {
+                                // drop the frame only, the other channels keep working
+                                this.pending_err.push_back(CodecError::OrderedFrame(format!(
+                                    "frame index {} exceeds ordering window {}..{}",
+                                    frame_index,
+                                    ordering.read,
+                                    ordering.read.add(ORDERING_WINDOW_SIZE as u32)
+                                )));
+                                continue;
+                            }
+                            this.recorder.record_reorder_depth(depth);
+                            ordering.update_blocked();
                             continue;
                         }
-                        std::cmp::Ordering::Equal => {
-                            ordering.read.add_assign(1);
-                        }
+                        std::cmp::Ordering::Equal => {}
                     }
+                    this.recorder.record_reorder_depth(0);
 
-                    // then we got a frame index equal to read index, we could read it
-                    frames
-                        .get_or_insert_with(|| Vec::with_capacity(frames_len))
-                        .push(frame);
-
-                    // check if we could read more
-                    while let Some(next) = ordering.map.remove(&ordering.read) {
-                        ordering.read.add_assign(1);
-                        frames
-                            .get_or_insert_with(|| Vec::with_capacity(frames_len))
-                            .push(next);
-                    }
+                    // then we got a frame index equal to read index, we could read it and the
+                    // continuous frames after it
+                    ordering.read(
+                        frame,
+                        frames.get_or_insert_with(|| Vec::with_capacity(frames_len)),
+                        this.recorder,
+                    );
+                    ordering.update_blocked();
 
                     // we cannot read anymore
                     continue;
@@ -133,12 +418,52 @@ where
                     .get_or_insert_with(|| Vec::with_capacity(frames_len))
                     .push(frame);
             }
+            if let Some(timeout) = *this.stalled_timeout {
+                for (channel, ordering) in this.ordering.iter_mut().enumerate() {
+                    if !ordering.stalled(timeout) {
+                        continue;
+                    }
+                    match this.stalled_policy {
+                        StalledPolicy::FastForward => {
+                            ordering.fast_forward(
+                                frames.get_or_insert_with(|| Vec::with_capacity(frames_len)),
+                                this.recorder,
+                            );
+                        }
+                        StalledPolicy::Close => {
+                            warn!(
+                                "ordered channel {channel} is stalled at frame index {}",
+                                ordering.read
+                            );
+                            *this.closed = true;
+                            return Poll::Ready(Some(Err(CodecError::OrderedFrame(format!(
+                                "channel {channel} stalled at frame index {} for {timeout:?}",
+                                ordering.read
+                            )))));
+                        }
+                    }
+                }
+            }
+            let blocked = this
+                .ordering
+                .iter()
+                .enumerate()
+                .filter_map(|(channel, ordering)| {
+                    ordering.blocked_since.map(|since| (channel as u8, since))
+                })
+                .min_by_key(|(_, since)| *since);
+            this.recorder.record_blocked_channel(blocked);
+            this.recorder
+                .record_reorder_buffered(this.ordering.iter().map(|ordering| ordering.bytes).sum());
             if let Some(frames) = frames {
                 return Poll::Ready(Some(Ok(connected::Packet::FrameSet(connected::FrameSet {
                     frames,
                     ..frame_set
                 }))));
             }
+            if let Some(err) = this.pending_err.pop_front() {
+                return Poll::Ready(Some(Err(err)));
+            }
         }
     }
 }
@@ -152,34 +477,15 @@ mod test {
     use super::*;
     use crate::errors::CodecError;
     use crate::packet::connected::{self, Flags, Frame, FrameSet, Ordered, Uint24le};
-
-    fn frame_set(idx: impl IntoIterator<Item = (u8, u32)>) -> connected::Packet<Bytes> {
-        connected::Packet::FrameSet(FrameSet {
-            seq_num: Uint24le(0),
-            frames: idx
-                .into_iter()
-                .map(|(channel, frame_index)| Frame {
-                    flags: Flags::parse(0b011_11100),
-                    reliable_frame_index: None,
-                    seq_frame_index: None,
-                    ordered: Some(Ordered {
-                        frame_index: Uint24le(frame_index),
-                        channel,
-                    }),
-                    fragment: None,
-                    body: Bytes::new(),
-                })
-                .collect(),
-        })
-    }
+    use crate::test_util::ordered_frame_set;
 
     #[tokio::test]
     async fn test_ordered_works() {
         let frame = {
             #[stream]
             async {
-                yield frame_set([(0, 1), (0, 0), (0, 2), (0, 4), (0, 3)]);
-                yield frame_set([(1, 1)]);
+                yield ordered_frame_set([(0, 1), (0, 0), (0, 2), (0, 4), (0, 3)]);
+                yield ordered_frame_set([(1, 1)]);
             }
         };
         tokio::pin!(frame);
@@ -188,21 +494,65 @@ mod test {
             frame: frame.map(Ok),
             max_channels: 10,
             ordering: std::iter::repeat_with(Ordering::default).take(10).collect(),
+            stalled_timeout: None,
+            stalled_policy: StalledPolicy::FastForward,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(10)),
         };
 
         assert_eq!(
             ordered.next().await.unwrap().unwrap(),
-            frame_set([(0, 0), (0, 1), (0, 2), (0, 3), (0, 4)])
+            ordered_frame_set([(0, 0), (0, 1), (0, 2), (0, 3), (0, 4)])
         );
         assert!(ordered.next().await.is_none());
     }
 
+    #[tokio::test]
+    async fn test_ordered_metrics() {
+        let frame = {
+            #[stream]
+            async {
+                yield ordered_frame_set([(0, 1), (0, 0), (0, 2), (0, 5), (0, 3)]);
+                yield ordered_frame_set([(1, 0)]);
+            }
+        };
+        tokio::pin!(frame);
+
+        let recorder = Arc::new(StatsRecorder::new(10));
+        let mut ordered = Order {
+            frame: frame.map(Ok),
+            max_channels: 10,
+            ordering: Vec::new(),
+            stalled_timeout: None,
+            stalled_policy: StalledPolicy::FastForward,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::clone(&recorder),
+        };
+        ordered.next().await.unwrap().unwrap();
+        ordered.next().await.unwrap().unwrap();
+
+        let stats = recorder.snapshot().ordering;
+        // 1 and 5 arrive ahead of the read index
+        assert_eq!(stats.reorder_depth.count, 6);
+        assert_eq!(stats.reorder_depth.buckets[0], 4);
+        assert_eq!(stats.reorder_depth.max, 2);
+        // 5 is still waiting for 4
+        assert_eq!(stats.wait_micros.count, 5);
+        // the header of the reliable ordered frame 5 without a body: flags, length, reliable
+        // frame index, ordered frame index and channel
+        assert_eq!(recorder.reorder_buffered(), 1 + 2 + 3 + 3 + 1);
+    }
+
     #[tokio::test]
     async fn test_ordered_channel_exceed() {
         let frame = {
             #[stream]
             async {
-                yield frame_set([(10, 1)]);
+                yield ordered_frame_set([(10, 1), (12, 0), (3, 0)]);
+                yield ordered_frame_set([(11, 0)]);
+                yield ordered_frame_set([(3, 1)]);
             }
         };
         tokio::pin!(frame);
@@ -210,12 +560,332 @@ mod test {
         let mut ordered = Order {
             frame: frame.map(Ok),
             max_channels: 10,
-            ordering: std::iter::repeat_with(Ordering::default).take(10).collect(),
+            ordering: Vec::new(),
+            stalled_timeout: None,
+            stalled_policy: StalledPolicy::FastForward,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(10)),
+        };
+
+        // the frames on the other channels are still read
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            ordered_frame_set([(3, 0)])
+        );
+        assert_eq!(ordered.ordering.len(), 4);
+        assert!(matches!(
+            ordered.next().await.unwrap().unwrap_err(),
+            CodecError::ChannelExceeded(10, 10)
+        ));
+        // every dropped frame is reported
+        assert!(matches!(
+            ordered.next().await.unwrap().unwrap_err(),
+            CodecError::ChannelExceeded(12, 10)
+        ));
+        assert!(matches!(
+            ordered.next().await.unwrap().unwrap_err(),
+            CodecError::ChannelExceeded(11, 10)
+        ));
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            ordered_frame_set([(3, 1)])
+        );
+        assert!(ordered.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sequenced_share_ordered_channel() {
+        let mut writer = OrderingWriter::default();
+        // ordered a, sequenced b and c, ordered d, sequenced e
+        let [a, b, c, d, e] = [
+            Reliability::ReliableOrdered,
+            Reliability::ReliableSequenced,
+            Reliability::ReliableSequenced,
+            Reliability::ReliableOrdered,
+            Reliability::UnreliableSequenced,
+        ]
+        .map(|reliability| {
+            let (seq_frame_index, frame_index) = writer.next(reliability);
+            Frame {
+                flags: Flags::parse((reliability as u8) << 5),
+                reliable_frame_index: None,
+                seq_frame_index,
+                ordered: frame_index.map(|frame_index| Ordered {
+                    frame_index,
+                    channel: 0,
+                }),
+                fragment: None,
+                body: Bytes::new(),
+            }
+        });
+        assert_eq!(d.seq_frame_index, None);
+        assert_eq!(e.seq_frame_index, Some(Uint24le(0)));
+        assert_eq!(writer.next(Reliability::Unreliable), (None, None));
+
+        let set = |frames: Vec<Frame<Bytes>>| {
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: Uint24le(0),
+                frames,
+            })
+        };
+        let expected = set(vec![a.clone(), b.clone(), c.clone(), d.clone(), e.clone()]);
+        let frame = {
+            #[stream]
+            async move {
+                // arrive in reverse
+                yield set(vec![e]);
+                yield set(vec![d, c]);
+                yield set(vec![b.clone()]);
+                yield set(vec![a]);
+                // the stale sequenced frame is dropped
+                yield set(vec![b]);
+            }
+        };
+        tokio::pin!(frame);
+
+        let mut ordered = Order {
+            frame: frame.map(Ok),
+            max_channels: 1,
+            ordering: Vec::new(),
+            stalled_timeout: None,
+            stalled_policy: StalledPolicy::Close,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(1)),
+        };
+        assert_eq!(ordered.next().await.unwrap().unwrap(), expected);
+        assert!(ordered.next().await.is_none());
+        assert_eq!(ordered.recorder.snapshot().drops.stale_ordered, 1);
+    }
+
+    #[tokio::test]
+    async fn test_sequenced_buffer_limited() {
+        let sequenced = |frame_index: u32, seq_frame_index: u32| Frame {
+            flags: Flags::parse((Reliability::ReliableSequenced as u8) << 5),
+            reliable_frame_index: None,
+            seq_frame_index: Some(Uint24le(seq_frame_index)),
+            ordered: Some(Ordered {
+                frame_index: Uint24le(frame_index),
+                channel: 0,
+            }),
+            fragment: None,
+            body: Bytes::new(),
+        };
+        let set = |frames: Vec<Frame<Bytes>>| {
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: Uint24le(0),
+                frames,
+            })
+        };
+        let frame = {
+            #[stream]
+            async move {
+                // waiting for the ordered frame 0, the later one of the same sequenced index is
+                // dropped when they are released
+                yield set((0..=MAX_SEQUENCED as u32)
+                    .map(|i| sequenced(1, i.min(1)))
+                    .collect());
+                yield ordered_frame_set([(0, 0)]);
+            }
         };
+        tokio::pin!(frame);
 
+        let mut ordered = Order {
+            frame: frame.map(Ok),
+            max_channels: 1,
+            ordering: Vec::new(),
+            stalled_timeout: None,
+            stalled_policy: StalledPolicy::Close,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(1)),
+        };
         assert!(matches!(
             ordered.next().await.unwrap().unwrap_err(),
             CodecError::OrderedFrame(_)
         ));
+        let connected::Packet::FrameSet(read) = ordered.next().await.unwrap().unwrap() else {
+            unreachable!()
+        };
+        // the ordered frame and the sequenced 0 and 1
+        assert_eq!(read.frames.len(), 3);
+        assert!(ordered.ordering[0].sequenced.is_empty());
+        assert!(ordered.next().await.is_none());
+        assert_eq!(
+            ordered.recorder.snapshot().drops.stale_ordered,
+            MAX_SEQUENCED as u64 - 2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ordered_stalled_fast_forward() {
+        let frame = {
+            #[stream]
+            async {
+                yield ordered_frame_set([(0, 0), (0, 2), (0, 3)]);
+                yield ordered_frame_set([(0, 1), (0, 5)]);
+            }
+        };
+        tokio::pin!(frame);
+
+        let mut ordered = Order {
+            frame: frame.map(Ok),
+            max_channels: 1,
+            ordering: vec![Ordering::default()],
+            stalled_timeout: Some(Duration::ZERO),
+            stalled_policy: StalledPolicy::FastForward,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(10)),
+        };
+
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            ordered_frame_set([(0, 0), (0, 2), (0, 3)])
+        );
+        // frame index 1 is skipped and 4 is missing
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            ordered_frame_set([(0, 5)])
+        );
+        assert!(ordered.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ordered_stalled_close() {
+        let frame = {
+            #[stream]
+            async {
+                yield ordered_frame_set([(0, 1)]);
+                yield ordered_frame_set([(0, 0)]);
+            }
+        };
+        tokio::pin!(frame);
+
+        let mut ordered = Order {
+            frame: frame.map(Ok),
+            max_channels: 1,
+            ordering: vec![Ordering::default()],
+            stalled_timeout: Some(Duration::ZERO),
+            stalled_policy: StalledPolicy::Close,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(10)),
+        };
+
+        assert!(matches!(
+            ordered.next().await.unwrap().unwrap_err(),
+            CodecError::OrderedFrame(_)
+        ));
+        assert!(ordered.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ordered_window_exceed() {
+        let frame = {
+            #[stream]
+            async {
+                yield ordered_frame_set([(0, ORDERING_WINDOW_SIZE as u32 - 1)]);
+                yield ordered_frame_set([(0, ORDERING_WINDOW_SIZE as u32)]);
+            }
+        };
+        tokio::pin!(frame);
+
+        let mut ordered = Order {
+            frame: frame.map(Ok),
+            max_channels: 1,
+            ordering: vec![Ordering::default()],
+            stalled_timeout: None,
+            stalled_policy: StalledPolicy::FastForward,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(10)),
+        };
+
+        assert!(matches!(
+            ordered.next().await.unwrap().unwrap_err(),
+            CodecError::OrderedFrame(_)
+        ));
+        assert_eq!(ordered.ordering[0].buffered, 1);
+    }
+
+    #[tokio::test]
+    async fn test_ordered_channels_isolated() {
+        let frame = {
+            #[stream]
+            async {
+                // channel 0 is blocked by the missing frame index 0
+                yield ordered_frame_set([(0, 1), (1, 0), (0, 2), (1, 1)]);
+                // channel 0 exceeds its window in the same frame set as channel 1
+                yield ordered_frame_set([(0, ORDERING_WINDOW_SIZE as u32), (1, 2)]);
+                yield ordered_frame_set([(0, 0)]);
+            }
+        };
+        tokio::pin!(frame);
+
+        let mut ordered = Order {
+            frame: frame.map(Ok),
+            max_channels: 2,
+            ordering: Vec::new(),
+            stalled_timeout: None,
+            stalled_policy: StalledPolicy::FastForward,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(10)),
+        };
+
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            ordered_frame_set([(1, 0), (1, 1)])
+        );
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            ordered_frame_set([(1, 2)])
+        );
+        assert!(matches!(
+            ordered.next().await.unwrap().unwrap_err(),
+            CodecError::OrderedFrame(_)
+        ));
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            ordered_frame_set([(0, 0), (0, 1), (0, 2)])
+        );
+        assert!(ordered.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ordered_window_wrapping() {
+        let size = ORDERING_WINDOW_SIZE as u32;
+        let frame = {
+            #[stream]
+            async move {
+                yield ordered_frame_set((1..size).map(|i| (0, i)));
+                yield ordered_frame_set([(0, 0)]);
+                yield ordered_frame_set([(0, size + 1), (0, size)]);
+            }
+        };
+        tokio::pin!(frame);
+
+        let mut ordered = Order {
+            frame: frame.map(Ok),
+            max_channels: 1,
+            ordering: vec![Ordering::default()],
+            stalled_timeout: None,
+            stalled_policy: StalledPolicy::FastForward,
+            closed: false,
+            pending_err: VecDeque::new(),
+            recorder: Arc::new(StatsRecorder::new(10)),
+        };
+
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            ordered_frame_set((0..size).map(|i| (0, i)))
+        );
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            ordered_frame_set([(0, size), (0, size + 1)])
+        );
+        assert!(ordered.next().await.is_none());
     }
 }
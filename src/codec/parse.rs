@@ -0,0 +1,116 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use futures::{ready, Sink, Stream};
+use pin_project_lite::pin_project;
+
+use crate::errors::CodecError;
+use crate::packet::Packet;
+
+pin_project! {
+    /// Parse the raw datagrams into packets and encode the outgoing packets into datagrams, it
+    /// sits between the raw datagram layers and the packet layers.
+    pub(crate) struct Parse<F> {
+        #[pin]
+        frame: F,
+    }
+}
+
+pub(crate) trait Parsed: Sized {
+    fn parsed(self) -> Parse<Self>;
+}
+
+impl<F> Parsed for F {
+    fn parsed(self) -> Parse<Self> {
+        Parse { frame: self }
+    }
+}
+
+impl<F> Stream for Parse<F>
+where
+    F: Stream<Item = Result<(BytesMut, SocketAddr), io::Error>>,
+{
+    type Item = Result<(Packet<BytesMut>, SocketAddr), CodecError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let Some((mut raw, addr)) = ready!(this.frame.as_mut().poll_next(cx)?) else {
+                return Poll::Ready(None);
+            };
+            // an empty datagram carries no packet
+            let Some(packet) = Packet::read(&mut raw)? else {
+                continue;
+            };
+            return Poll::Ready(Some(Ok((packet, addr))));
+        }
+    }
+}
+
+impl<F, B> Sink<(Packet<B>, SocketAddr)> for Parse<F>
+where
+    F: Sink<(BytesMut, SocketAddr), Error = io::Error>,
+    B: Buf,
+{
+    type Error = CodecError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        (packet, addr): (Packet<B>, SocketAddr),
+    ) -> Result<(), Self::Error> {
+        let mut raw = BytesMut::new();
+        packet.write(&mut raw);
+        self.project().frame.start_send((raw, addr))?;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx).map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::packet::unconnected;
+
+    #[tokio::test]
+    async fn test_parse_works() {
+        let addr: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let ping = Packet::<BytesMut>::Unconnected(unconnected::Packet::UnconnectedPing {
+            send_timestamp: 1,
+            magic: (),
+            client_guid: 2,
+        });
+        let mut raw = BytesMut::new();
+        ping.clone().write(&mut raw);
+        let inbound = futures::stream::iter([
+            Ok((raw.clone(), addr)),
+            Ok((BytesMut::new(), addr)),
+            Ok((BytesMut::from(&[0xff, 0x00][..]), addr)),
+        ]);
+        let mut parsed = inbound.parsed();
+        assert_eq!(parsed.next().await.unwrap().unwrap(), (ping.clone(), addr));
+        assert!(parsed.next().await.unwrap().is_err());
+        assert!(parsed.next().await.is_none());
+
+        let mut sink = Vec::new()
+            .sink_map_err(|_| io::Error::from(io::ErrorKind::Other))
+            .parsed();
+        sink.send((ping, addr)).await.unwrap();
+        assert_eq!(sink.frame.into_inner(), vec![(raw, addr)]);
+    }
+}
@@ -0,0 +1,210 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::{ready, Stream, StreamExt};
+use pin_project_lite::pin_project;
+use tracing::trace;
+
+use crate::errors::CodecError;
+use crate::packet::connected::{self, Uint24le};
+use crate::stats::{DropReason, StatsRecorder};
+
+/// The anti-replay window of datagram sequence numbers, it is a bitmap ring indexed by the low
+/// bits of `seq_num`. The size is a power of two dividing the 24-bit sequence space, so that the
+/// slots stay continuous when the sequence number wraps. Sequence numbers older than
+/// `highest - size` are treated as replayed.
+#[derive(Debug)]
+struct ReplayWindow {
+    bits: Vec<u64>,
+    // size in bits, a power of two not less than 64
+    size: u32,
+    highest: Option<Uint24le>,
+}
+
+impl ReplayWindow {
+    fn new(size: u32) -> Self {
+        let size = size.clamp(64, Uint24le::MAX + 1).next_power_of_two();
+        Self {
+            bits: vec![0; (size / 64) as usize],
+            size,
+            highest: None,
+        }
+    }
+
+    fn slot(&self, seq_num: Uint24le) -> (usize, u64) {
+        let idx = seq_num.0 & (self.size - 1);
+        ((idx / 64) as usize, 1 << (idx % 64))
+    }
+
+    fn test(&self, seq_num: Uint24le) -> bool {
+        let (word, mask) = self.slot(seq_num);
+        self.bits[word] & mask != 0
+    }
+
+    fn set(&mut self, seq_num: Uint24le, v: bool) {
+        let (word, mask) = self.slot(seq_num);
+        if v {
+            self.bits[word] |= mask;
+        } else {
+            self.bits[word] &= !mask;
+        }
+    }
+
+    /// Check whether a sequence number is replayed, and record it if not.
+    /// The 24-bit sequence numbers are compared with wrapping arithmetic.
+    fn replayed(&mut self, seq_num: Uint24le) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(seq_num);
+            self.set(seq_num, true);
+            return false;
+        };
+        if seq_num.serial_cmp(highest) == std::cmp::Ordering::Greater {
+            let ahead = seq_num.distance_from(highest);
+            // slide the window forward, clear the slots that are reused
+            if ahead >= self.size {
+                self.bits.iter_mut().for_each(|w| *w = 0);
+            } else {
+                for i in 1..=ahead {
+                    self.set(highest.add(i), false);
+                }
+            }
+            self.highest = Some(seq_num);
+            self.set(seq_num, true);
+            return false;
+        }
+        if highest.distance_from(seq_num) >= self.size || self.test(seq_num) {
+            return true;
+        }
+        self.set(seq_num, true);
+        false
+    }
+}
+
+pin_project! {
+    /// Anti-replay layer, drop the frame sets with replayed datagram sequence numbers. It should be
+    /// placed before the deduplication layer to make duplicate floods cheap to absorb.
+    pub(crate) struct AntiReplay<F> {
+        #[pin]
+        frame: F,
+        window: Option<ReplayWindow>,
+        recorder: Arc<StatsRecorder>,
+    }
+}
+
+pub(super) trait AntiReplayed: Sized {
+    fn anti_replayed(self, window_size: u32, recorder: Arc<StatsRecorder>) -> AntiReplay<Self>;
+}
+
+impl<T> AntiReplayed for T {
+    fn anti_replayed(self, window_size: u32, recorder: Arc<StatsRecorder>) -> AntiReplay<Self> {
+        AntiReplay {
+            frame: self,
+            window: (window_size != 0).then(|| ReplayWindow::new(window_size)),
+            recorder,
+        }
+    }
+}
+
+impl<F, B> Stream for AntiReplay<F>
+where
+    F: Stream<Item = Result<connected::Packet<B>, CodecError>>,
+{
+    type Item = Result<connected::Packet<B>, CodecError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let Some(packet) = ready!(this.frame.poll_next_unpin(cx)?) else {
+                return Poll::Ready(None);
+            };
+            let Some(window) = this.window.as_mut() else {
+                return Poll::Ready(Some(Ok(packet)));
+            };
+            if let connected::Packet::FrameSet(frame_set) = &packet {
+                if window.replayed(frame_set.seq_num) {
+                    trace!("drop replayed frame set {}", frame_set.seq_num);
+                    this.recorder.record_dropped(DropReason::Replayed, 1);
+                    continue;
+                }
+            }
+            return Poll::Ready(Some(Ok(packet)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+    use futures_async_stream::stream;
+
+    use super::*;
+    use crate::test_util::frame_set;
+
+    #[test]
+    fn test_replay_window_works() {
+        let mut window = ReplayWindow::new(64);
+        assert!(!window.replayed(Uint24le(0)));
+        assert!(window.replayed(Uint24le(0)));
+        assert!(!window.replayed(Uint24le(2)));
+        assert!(!window.replayed(Uint24le(1)));
+        assert!(window.replayed(Uint24le(1)));
+        assert!(!window.replayed(Uint24le(100)));
+        // too old
+        assert!(window.replayed(Uint24le(36)));
+        assert!(!window.replayed(Uint24le(37)));
+        assert!(window.replayed(Uint24le(37)));
+    }
+
+    #[test]
+    fn test_replay_window_wrapping() {
+        let mut window = ReplayWindow::new(64);
+        assert!(!window.replayed(Uint24le(Uint24le::MAX - 1)));
+        assert!(!window.replayed(Uint24le(Uint24le::MAX)));
+        assert!(!window.replayed(Uint24le(0)));
+        assert!(!window.replayed(Uint24le(1)));
+        assert!(window.replayed(Uint24le(Uint24le::MAX)));
+        assert!(window.replayed(Uint24le(0)));
+    }
+
+    #[test]
+    fn test_replay_window_wrapping_uneven_size() {
+        // rounded up to 128
+        let mut window = ReplayWindow::new(100);
+        assert_eq!(window.size, 128);
+        let start = Uint24le::MAX - 63;
+        for i in 0..128 {
+            assert!(!window.replayed(Uint24le(start).add(i)));
+        }
+        // every slot in the window is still marked across the wrap
+        for i in 0..128 {
+            assert!(window.replayed(Uint24le(start).add(i)));
+        }
+        assert!(!window.replayed(Uint24le(64).add(1)));
+        // slid out of the window
+        assert!(window.replayed(Uint24le(start)));
+        assert!(window.replayed(Uint24le(start).add(1)));
+    }
+
+    #[tokio::test]
+    async fn test_anti_replay_works() {
+        let frame = {
+            #[stream]
+            async {
+                yield frame_set(0, []);
+                yield frame_set(1, []);
+                yield frame_set(0, []);
+                yield frame_set(2, []);
+                yield frame_set(1, []);
+            }
+        };
+        tokio::pin!(frame);
+        let recorder = Arc::new(StatsRecorder::new(1));
+        let mut replay = frame.map(Ok).anti_replayed(1024, Arc::clone(&recorder));
+        assert_eq!(replay.next().await.unwrap().unwrap(), frame_set(0, []));
+        assert_eq!(replay.next().await.unwrap().unwrap(), frame_set(1, []));
+        assert_eq!(replay.next().await.unwrap().unwrap(), frame_set(2, []));
+        assert!(replay.next().await.is_none());
+        assert_eq!(recorder.snapshot().drops.replayed, 2);
+    }
+}
@@ -0,0 +1,103 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Buf;
+use futures::{ready, Stream, StreamExt};
+use pin_project_lite::pin_project;
+
+use crate::errors::CodecError;
+use crate::packet::connected;
+use crate::stats::StatsRecorder;
+
+pin_project! {
+    /// Tally the received messages of each ordering channel, should be placed after the frames
+    /// are reassembled.
+    pub(crate) struct Tally<F> {
+        #[pin]
+        frame: F,
+        recorder: Arc<StatsRecorder>,
+    }
+}
+
+pub(super) trait Tallied: Sized {
+    fn tallied(self, recorder: Arc<StatsRecorder>) -> Tally<Self>;
+}
+
+impl<F> Tallied for F {
+    fn tallied(self, recorder: Arc<StatsRecorder>) -> Tally<Self> {
+        Tally {
+            frame: self,
+            recorder,
+        }
+    }
+}
+
+impl<F, B> Stream for Tally<F>
+where
+    F: Stream<Item = Result<connected::Packet<B>, CodecError>>,
+    B: Buf,
+{
+    type Item = Result<connected::Packet<B>, CodecError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let Some(packet) = ready!(this.frame.poll_next_unpin(cx)?) else {
+            return Poll::Ready(None);
+        };
+        if let connected::Packet::FrameSet(frame_set) = &packet {
+            for frame in &frame_set.frames {
+                this.recorder.record_received(
+                    frame.ordered.as_ref().map(|ordered| ordered.channel),
+                    frame.body.remaining(),
+                );
+            }
+        }
+        Poll::Ready(Some(Ok(packet)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use futures_async_stream::stream;
+
+    use super::*;
+    use crate::test_util::{frame_set, message_frame};
+
+    #[tokio::test]
+    async fn test_tally_works() {
+        let frame = {
+            #[stream]
+            async {
+                yield frame_set(
+                    0,
+                    [
+                        message_frame(Some(0), Bytes::from_static(b"chat")),
+                        message_frame(Some(1), Bytes::from_static(b"movement")),
+                    ],
+                );
+                yield frame_set(
+                    1,
+                    [
+                        message_frame(Some(1), Bytes::from_static(b"movement")),
+                        message_frame(None, Bytes::from_static(b"ping")),
+                    ],
+                );
+            }
+        };
+        tokio::pin!(frame);
+
+        let recorder = Arc::new(StatsRecorder::new(2));
+        let mut tally = frame.map(Ok).tallied(recorder.clone());
+        while tally.next().await.is_some() {}
+
+        let stats = recorder.snapshot();
+        assert_eq!(stats.channels[0].received_messages, 1);
+        assert_eq!(stats.channels[0].received_bytes, 4);
+        assert_eq!(stats.channels[1].received_messages, 2);
+        assert_eq!(stats.channels[1].received_bytes, 16);
+        assert_eq!(stats.unordered.received_messages, 1);
+    }
+}
@@ -1,10 +1,16 @@
+use std::time::Duration;
+
+use derive_builder::UninitializedFieldError;
+
+use crate::event::DisconnectReason;
+
 #[derive(thiserror::Error, Debug)]
 pub enum CodecError {
     #[error("io error {0}")]
     IO(#[from] std::io::Error),
     #[error("invalid ip version {0}")]
     InvalidIPVer(u8),
-    #[error("expect IPv6 family 0x17, got {0}")]
+    #[error("unknown IPv6 family {0}")]
     InvalidIPV6Family(u16),
     #[error("invalid packet length when decode {0}")]
     InvalidPacketLength(&'static str),
@@ -14,8 +20,14 @@ pub enum CodecError {
     InvalidPacketType(u8),
     #[error("parted frame error, reason: {0}")]
     PartedFrame(String),
+    #[error("parted size {0} exceeds limit {1}")]
+    PartedSizeExceed(u32, u32),
+    #[error("reassembled size {0} exceeds the limit {1}")]
+    ReassembledSizeExceed(usize, usize),
     #[error("ordered frame error, reason: {0}")]
     OrderedFrame(String),
+    #[error("ordered channel {0} exceeds the limit {1}")]
+    ChannelExceeded(u8, usize),
     #[error("maximum amount of packets in acknowledgement exceeded")]
     AckCountExceed,
     #[error("exceed deduplication maximum gap {0}, current gap {1}")]
@@ -24,10 +36,192 @@ pub enum CodecError {
     MagicNotMatched(usize, u8),
 }
 
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("field {0} is not initialized")]
+    UninitializedField(&'static str),
+    #[error("mtu {0} is less than the minimum {1}")]
+    MtuTooSmall(u16, u16),
+    #[error("mtu {0} is greater than the maximum {1}")]
+    MtuTooLarge(u16, u16),
+    #[error("min mtu {0} is greater than max mtu {1}")]
+    MtuRange(u16, u16),
+    #[error("no supported raknet version")]
+    NoSupportVersion,
+    #[error("unknown raknet version {0}")]
+    UnknownVersion(u8),
+    #[error("ack flush delay {0:?} is not less than the retransmission timeout {1:?}")]
+    AckDelayExceedsRto(Duration, Duration),
+    #[error("min retransmission timeout {0:?} is greater than max {1:?}")]
+    RtoRange(Duration, Duration),
+    #[error("low watermark {0} is greater than high watermark {1}")]
+    Watermark(usize, usize),
+    #[error("advertisement of {0} bytes is larger than the maximum {1}")]
+    AdvertisementTooLarge(usize, usize),
+    #[error("max datagram size {0} is less than the max mtu {1}")]
+    MaxDatagramSize(usize, u16),
+    #[error("field {0} must be positive")]
+    Zero(&'static str),
+    #[error("max channels {0} is out of 1..=256")]
+    MaxChannels(usize),
+}
+
+impl From<UninitializedFieldError> for ConfigError {
+    fn from(err: UninitializedFieldError) -> Self {
+        Self::UninitializedField(err.field_name())
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error(transparent)]
     Codec(#[from] CodecError),
     #[error("connection closed, reason {0}")]
     ConnectionClosed(&'static str),
+    #[error("disconnected, reason {0}")]
+    Disconnected(DisconnectReason),
+    #[error(
+        "incompatible protocol, the server {server_guid} speaks raknet protocol {server_protocol}"
+    )]
+    IncompatibleProtocol {
+        /// The raknet protocol version the server speaks
+        server_protocol: u8,
+        /// The GUID of the server
+        server_guid: u64,
+    },
+    #[error("io error {0}")]
+    IO(#[from] std::io::Error),
+    #[error("transfer cancelled")]
+    TransferCancelled,
+    #[error("request timeout")]
+    RequestTimeout,
+}
+
+impl CodecError {
+    /// The stable numeric code of the error in `1000..2000`, for the FFI and the alerting rules.
+    /// The codes are never reused, the new variants take new codes.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::IO(_) => 1001,
+            Self::InvalidIPVer(_) => 1002,
+            Self::InvalidIPV6Family(_) => 1003,
+            Self::InvalidPacketLength(_) => 1004,
+            Self::InvalidRecordType(_) => 1005,
+            Self::InvalidPacketType(_) => 1006,
+            Self::PartedFrame(_) => 1007,
+            Self::PartedSizeExceed(..) => 1008,
+            Self::ReassembledSizeExceed(..) => 1009,
+            Self::OrderedFrame(_) => 1010,
+            Self::ChannelExceeded(..) => 1011,
+            Self::AckCountExceed => 1012,
+            Self::DedupExceed(..) => 1013,
+            Self::MagicNotMatched(..) => 1014,
+        }
+    }
+}
+
+impl ConfigError {
+    /// The stable numeric code of the error in `2000..3000`, see [`CodecError::code`]
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::UninitializedField(_) => 2001,
+            Self::MtuTooSmall(..) => 2002,
+            Self::MtuTooLarge(..) => 2003,
+            Self::MtuRange(..) => 2004,
+            Self::NoSupportVersion => 2005,
+            Self::UnknownVersion(_) => 2006,
+            Self::AckDelayExceedsRto(..) => 2007,
+            Self::RtoRange(..) => 2008,
+            Self::Watermark(..) => 2009,
+            Self::AdvertisementTooLarge(..) => 2010,
+            Self::MaxDatagramSize(..) => 2011,
+            Self::Zero(_) => 2012,
+            Self::MaxChannels(_) => 2013,
+        }
+    }
+}
+
+impl Error {
+    /// The stable numeric code of the error in `1..1000`, or the code of the inner
+    /// [`CodecError`], see [`CodecError::code`]
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Codec(err) => err.code(),
+            Self::ConnectionClosed(_) => 1,
+            Self::Disconnected(_) => 2,
+            Self::IncompatibleProtocol { .. } => 3,
+            Self::IO(_) => 4,
+            Self::TransferCancelled => 5,
+            Self::RequestTimeout => 6,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_error_codes_unique() {
+        let io = || std::io::Error::from(std::io::ErrorKind::Other);
+        let codec = [
+            CodecError::IO(io()),
+            CodecError::InvalidIPVer(0),
+            CodecError::InvalidIPV6Family(0),
+            CodecError::InvalidPacketLength(""),
+            CodecError::InvalidRecordType(0),
+            CodecError::InvalidPacketType(0),
+            CodecError::PartedFrame(String::new()),
+            CodecError::PartedSizeExceed(0, 0),
+            CodecError::ReassembledSizeExceed(0, 0),
+            CodecError::OrderedFrame(String::new()),
+            CodecError::ChannelExceeded(0, 0),
+            CodecError::AckCountExceed,
+            CodecError::DedupExceed(0, 0),
+            CodecError::MagicNotMatched(0, 0),
+        ];
+        let config = [
+            ConfigError::UninitializedField(""),
+            ConfigError::MtuTooSmall(0, 0),
+            ConfigError::MtuTooLarge(0, 0),
+            ConfigError::MtuRange(0, 0),
+            ConfigError::NoSupportVersion,
+            ConfigError::UnknownVersion(0),
+            ConfigError::AckDelayExceedsRto(Duration::ZERO, Duration::ZERO),
+            ConfigError::RtoRange(Duration::ZERO, Duration::ZERO),
+            ConfigError::Watermark(0, 0),
+            ConfigError::AdvertisementTooLarge(0, 0),
+            ConfigError::MaxDatagramSize(0, 0),
+            ConfigError::Zero(""),
+            ConfigError::MaxChannels(0),
+        ];
+        let errors = [
+            Error::ConnectionClosed(""),
+            Error::Disconnected(DisconnectReason::Closed),
+            Error::IncompatibleProtocol {
+                server_protocol: 0,
+                server_guid: 0,
+            },
+            Error::IO(io()),
+            Error::TransferCancelled,
+            Error::RequestTimeout,
+        ];
+
+        let mut seen = HashSet::new();
+        for code in codec.iter().map(CodecError::code) {
+            assert!((1000..2000).contains(&code));
+            assert!(seen.insert(code), "duplicate code {code}");
+        }
+        for code in config.iter().map(ConfigError::code) {
+            assert!((2000..3000).contains(&code));
+            assert!(seen.insert(code), "duplicate code {code}");
+        }
+        for code in errors.iter().map(Error::code) {
+            assert!((1..1000).contains(&code));
+            assert!(seen.insert(code), "duplicate code {code}");
+        }
+        assert_eq!(Error::Codec(CodecError::AckCountExceed).code(), 1012);
+    }
 }
@@ -0,0 +1,166 @@
+/// Events of a connection, which could be used by upper layers to react to the connection state.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    /// The congestion controller entered recovery because of packet loss
+    CongestionRecovery {
+        /// Congestion window in bytes after entering recovery
+        cwnd: u64,
+        /// Slow start threshold in bytes after entering recovery
+        ss_thresh: u64,
+    },
+    /// The unacked and unsent bytes rose to the high watermark
+    HighWatermark {
+        /// Unacked and unsent bytes
+        pending_bytes: u64,
+    },
+    /// The unacked and unsent bytes fell to the low watermark
+    LowWatermark {
+        /// Unacked and unsent bytes
+        pending_bytes: u64,
+    },
+    /// Datagrams near the mtu were lost while smaller ones were acknowledged, the mtu of the
+    /// connection is reduced
+    MtuReduced {
+        /// The mtu before reduced
+        from: u16,
+        /// The reduced mtu
+        to: u16,
+    },
+    /// The bytes buffered by the connection exceeded its memory budget
+    MemoryBudgetExceeded {
+        /// Buffered bytes of the connection
+        used_bytes: u64,
+        /// The memory budget in bytes
+        limit_bytes: u64,
+    },
+    /// The connection has made no forward progress for a while, it may time out soon
+    Stalled(StallDiagnostic),
+    /// A reliable frame was abandoned after exceeding the retransmission limits of the
+    /// connection, the peer may never receive it
+    ReceiptLost {
+        /// The reliable index of the abandoned frame
+        reliable_frame_index: u32,
+    },
+    /// The peer continued the connection from a new address, e.g. its NAT rebound the mapping
+    AddressChanged {
+        /// The address before changed
+        old: std::net::SocketAddr,
+        /// The current address
+        new: std::net::SocketAddr,
+    },
+    /// The connection is closed
+    Disconnected {
+        /// Why the connection is closed
+        reason: DisconnectReason,
+    },
+}
+
+/// The internal state of a stalled connection, to debug the connections frozen then timed out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StallDiagnostic {
+    /// How long the datagrams in flight have been waiting without any of them acknowledged
+    pub unacked_for: Option<std::time::Duration>,
+    /// The current retransmission timeout
+    pub rto: std::time::Duration,
+    /// Datagrams sent but not acknowledged
+    pub datagrams_in_flight: usize,
+    /// Bytes sent but not acknowledged
+    pub bytes_in_flight: u64,
+    /// The ordering channel blocked by a missing frame for the longest, and for how long
+    pub stalled_channel: Option<(u8, std::time::Duration)>,
+}
+
+/// Events of a server, which are not bound to any connection
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ServerEvent {
+    /// The socket kept failing, the server loop is still running and retrying it
+    SocketError {
+        /// The kind of the last error
+        kind: std::io::ErrorKind,
+    },
+    /// The socket is rebound after failing
+    Rebound {
+        /// The address the socket is rebound to
+        local_addr: std::net::SocketAddr,
+    },
+    /// A handshake advertised the mtu or the protocol version below the expected minimum, it is
+    /// accepted in the lenient mode and refused with `IncompatibleProtocol` otherwise. It may
+    /// reveal a middlebox or a spoofer degrading the sessions
+    HandshakeDowngraded {
+        /// The address of the peer
+        addr: std::net::SocketAddr,
+        /// What is degraded
+        downgrade: Downgrade,
+        /// Whether the handshake is refused
+        refused: bool,
+    },
+}
+
+/// A field of the handshake below the expected minimum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Downgrade {
+    /// The mtu probed by the padding of `OpenConnectionRequest1`
+    Mtu {
+        /// The mtu advertised by the peer
+        advertised: u16,
+        /// The expected minimum
+        minimum: u16,
+    },
+    /// The raknet protocol version
+    ProtocolVersion {
+        /// The version advertised by the peer
+        advertised: u8,
+        /// The expected minimum
+        minimum: u8,
+    },
+}
+
+/// Why a connection is closed. It is appended to the `DisconnectNotification` so that the remote
+/// side could surface it, other raknet implementations ignore the trailing bytes and see
+/// [`DisconnectReason::Closed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DisconnectReason {
+    /// Closed without a specific reason
+    Closed,
+    /// The peer stopped responding
+    Timeout,
+    /// The peer was kicked by the server
+    Kicked,
+    /// The server is shutting down
+    ServerShutdown,
+    /// The peer speaks an incompatible protocol
+    Incompatible,
+    /// Closed by the application with its own code
+    Application(u16),
+    /// The retransmissions exceeded the limits of the connection
+    RetransmissionLimit,
+    /// The buffered bytes exceeded the memory budget of the connection
+    MemoryBudget,
+    /// An ordered channel was blocked by a missing frame longer than the stalled timeout
+    OrderedStalled,
+    /// All the 65536 parted ids are held by the splits the peer has not acknowledged, so the
+    /// large messages could no longer be sent
+    SplitsExhausted,
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Closed => write!(f, "closed"),
+            Self::Timeout => write!(f, "timeout"),
+            Self::Kicked => write!(f, "kicked"),
+            Self::ServerShutdown => write!(f, "server shutdown"),
+            Self::Incompatible => write!(f, "incompatible"),
+            Self::Application(code) => write!(f, "application code {code}"),
+            Self::RetransmissionLimit => write!(f, "retransmission limit"),
+            Self::MemoryBudget => write!(f, "memory budget"),
+            Self::OrderedStalled => write!(f, "ordered channel stalled"),
+            Self::SplitsExhausted => write!(f, "split ids exhausted"),
+        }
+    }
+}
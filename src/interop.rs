@@ -0,0 +1,704 @@
+//! Check the compatibility with an external raknet server (e.g. nukkit, bedrock dedicated server
+//! or the reference C++ implementation) by driving a minimal client over a plain UDP socket, only
+//! available with the `interop` feature.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::net::UdpSocket;
+
+use crate::client::{self, HandshakeOptions};
+use crate::errors::Error;
+use crate::packet::connected::{
+    self, max_body_size, AckOrNack, Flags, Fragment, Frame, FrameSet, Ordered, Record, Reliability,
+    Uint24le,
+};
+use crate::packet::version::LATEST_PROTOCOL_VERSION;
+use crate::packet::{unconnected, PackType, Packet, SocketAddrWrite, PARTED_FLAG};
+
+/// Options of the interop check
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// The raknet protocol version offered to the server
+    pub protocol_version: u8,
+    /// The mtu offered to the server
+    pub mtu: u16,
+    /// The guid of the checking client
+    pub client_guid: u64,
+    /// How many times a new guid is generated to retry the handshake when the server replies
+    /// `AlreadyConnected`, e.g. the guid collides with an existing session
+    pub guid_retries: u32,
+    /// How many times a handshake request is sent again when its reply does not arrive in time
+    pub retransmits: u32,
+    /// How long to wait for each response
+    pub timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            protocol_version: LATEST_PROTOCOL_VERSION,
+            mtu: 1400,
+            client_guid: 0x7261_6b6e_6574_2d72,
+            guid_retries: 3,
+            retransmits: 3,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Options {
+    fn handshake(&self) -> HandshakeOptions {
+        HandshakeOptions {
+            protocol_version: self.protocol_version,
+            mtu: self.mtu,
+            client_guid: self.client_guid,
+            guid_retries: self.guid_retries,
+            retransmits: self.retransmits,
+            timeout: self.timeout,
+            migration_token: None,
+        }
+    }
+}
+
+/// A stage of the interop check, in the order they are run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// `UnconnectedPing` is answered by `UnconnectedPong`
+    Ping,
+    /// `OpenConnectionRequest1` is answered by `OpenConnectionReply1`
+    OpenConnection1,
+    /// `OpenConnectionRequest2` is answered by `OpenConnectionReply2`
+    OpenConnection2,
+    /// `ConnectionRequest` is answered by `ConnectionRequestAccepted`
+    ConnectionRequest,
+    /// `ConnectedPing` sent with the reliability is answered by `ConnectedPong`
+    Reliability(Reliability),
+    /// A `ConnectedPing` split into fragments is reassembled and answered
+    Fragmentation,
+    /// `DisconnectNotification` is acknowledged
+    Disconnect,
+}
+
+/// The outcome of a stage
+#[derive(Debug)]
+pub enum Outcome {
+    /// Passed in the round trip time
+    Passed(Duration),
+    /// Failed with the error, e.g. [`Error::IncompatibleProtocol`] carries the protocol version
+    /// and the guid of the server
+    Failed(Error),
+    /// Not run because an earlier stage failed
+    Skipped,
+}
+
+/// The report of an interop check
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The guid of the server from the `UnconnectedPong`
+    pub server_guid: Option<u64>,
+    /// The advertisement of the server from the `UnconnectedPong`
+    pub advertisement: Option<Bytes>,
+    /// The mtu accepted by the server
+    pub mtu: Option<u16>,
+    /// The guid the client connected with, it differs from [`Options::client_guid`] if the
+    /// handshake was retried after a guid collision
+    pub client_guid: Option<u64>,
+    /// The outcome of each stage
+    pub stages: Vec<(Stage, Outcome)>,
+}
+
+impl Report {
+    /// Whether all stages passed
+    pub fn passed(&self) -> bool {
+        self.stages
+            .iter()
+            .all(|(_, outcome)| matches!(outcome, Outcome::Passed(_)))
+    }
+}
+
+/// Connect to the raknet server at the address and exercise the handshake, each reliability
+/// class, the fragmentation and the disconnection.
+///
+/// # Errors
+///
+/// Returns an error if the local UDP socket could not be set up, the failures of the server are
+/// reported in the [`Report`].
+pub async fn check(addr: SocketAddr, options: Options) -> std::io::Result<Report> {
+    let local = if addr.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    let mut session = Session {
+        socket,
+        addr,
+        options,
+        mtu: options.mtu,
+        seq_num: Uint24le(0),
+        reliable_index: Uint24le(0),
+        seq_index: Uint24le(0),
+        ordered_index: Uint24le(0),
+        parted_id: 0,
+        start: Instant::now(),
+    };
+
+    let mut report = Report::default();
+    let mut stages = vec![
+        Stage::Ping,
+        Stage::OpenConnection1,
+        Stage::OpenConnection2,
+        Stage::ConnectionRequest,
+    ];
+    stages.extend(
+        [
+            Reliability::Unreliable,
+            Reliability::UnreliableSequenced,
+            Reliability::Reliable,
+            Reliability::ReliableOrdered,
+            Reliability::ReliableSequenced,
+        ]
+        .map(Stage::Reliability),
+    );
+    stages.extend([Stage::Fragmentation, Stage::Disconnect]);
+
+    let mut failed = false;
+    for stage in stages {
+        if failed {
+            report.stages.push((stage, Outcome::Skipped));
+            continue;
+        }
+        let start = Instant::now();
+        let outcome = match session.run(stage, &mut report).await {
+            Ok(()) => Outcome::Passed(start.elapsed()),
+            Err(reason) => {
+                failed = true;
+                Outcome::Failed(reason)
+            }
+        };
+        report.stages.push((stage, outcome));
+    }
+    Ok(report)
+}
+
+struct Session {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    options: Options,
+    mtu: u16,
+    seq_num: Uint24le,
+    reliable_index: Uint24le,
+    seq_index: Uint24le,
+    ordered_index: Uint24le,
+    parted_id: u16,
+    start: Instant,
+}
+
+impl Session {
+    async fn run(&mut self, stage: Stage, report: &mut Report) -> Result<(), Error> {
+        match stage {
+            Stage::Ping => {
+                self.send(Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                    send_timestamp: self.timestamp(),
+                    magic: (),
+                    client_guid: self.options.client_guid,
+                }))
+                .await?;
+                self.recv_offline(|pack| match pack {
+                    unconnected::Packet::UnconnectedPong {
+                        server_guid, data, ..
+                    } => {
+                        report.server_guid = Some(server_guid);
+                        report.advertisement = Some(data);
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                })
+                .await
+            }
+            Stage::OpenConnection1 => {
+                let (_, mtu) = client::request1(&self.socket, &self.options.handshake()).await?;
+                self.mtu = mtu;
+                report.mtu = Some(mtu);
+                Ok(())
+            }
+            Stage::OpenConnection2 => {
+                let handshake = self.options.handshake();
+                for retry in 0..=handshake.guid_retries {
+                    if retry > 0 {
+                        // the server forgets the handshake once it refuses the request 2, so the
+                        // new guid starts over from the request 1
+                        self.options.client_guid = client::random_guid();
+                        let (_, mtu) = client::request1(&self.socket, &handshake).await?;
+                        self.mtu = mtu;
+                    }
+                    let client_guid = self.options.client_guid;
+                    if client::request2(&self.socket, self.addr, self.mtu, client_guid, &handshake)
+                        .await?
+                        .is_some()
+                    {
+                        report.client_guid = Some(client_guid);
+                        return Ok(());
+                    }
+                }
+                Err(Error::ConnectionClosed(
+                    "the server regards the client as already connected after the guid retries",
+                ))
+            }
+            Stage::ConnectionRequest => {
+                let mut body = BytesMut::new();
+                body.put_u8(PackType::ConnectionRequest.into());
+                body.put_u64(self.options.client_guid);
+                body.put_i64(self.timestamp());
+                body.put_u8(0);
+                self.send_body(Reliability::ReliableOrdered, body.freeze())
+                    .await?;
+                self.recv_body(|reply| reply[0] == u8::from(PackType::ConnectionRequestAccepted))
+                    .await?;
+
+                let mut incoming = BytesMut::new();
+                incoming.put_u8(PackType::NewIncomingConnection.into());
+                incoming.put_socket_addr(self.addr);
+                for _ in 0..10 {
+                    incoming.put_socket_addr(SocketAddr::V4(SocketAddrV4::new(
+                        Ipv4Addr::UNSPECIFIED,
+                        0,
+                    )));
+                }
+                incoming.put_i64(self.timestamp());
+                incoming.put_i64(self.timestamp());
+                self.send_body(Reliability::ReliableOrdered, incoming.freeze())
+                    .await
+            }
+            Stage::Reliability(reliability) => {
+                let timestamp = self.timestamp();
+                self.send_body(reliability, ping(timestamp, 0)).await?;
+                self.recv_body(|pong| is_pong_of(pong, timestamp)).await
+            }
+            Stage::Fragmentation => {
+                let timestamp = self.timestamp();
+                let body = ping(timestamp, usize::from(self.mtu) * 2);
+                self.send_parted(body).await?;
+                self.recv_body(|pong| is_pong_of(pong, timestamp)).await
+            }
+            Stage::Disconnect => {
+                let seq_num = self.seq_num;
+                let body = Bytes::from_static(&[PackType::DisconnectNotification as u8]);
+                self.send_body(Reliability::ReliableOrdered, body).await?;
+                self.recv_ack_of(seq_num).await
+            }
+        }
+    }
+
+    fn timestamp(&self) -> i64 {
+        self.start.elapsed().as_millis() as i64
+    }
+
+    async fn send(&self, packet: Packet<Bytes>) -> Result<(), Error> {
+        let mut buf = BytesMut::new();
+        packet.write(&mut buf);
+        self.socket.send(&buf).await.map(|_| ()).map_err(Error::IO)
+    }
+
+    fn frame(&mut self, reliability: Reliability, body: Bytes) -> Frame<Bytes> {
+        let mut frame = Frame {
+            flags: Flags::parse((reliability as u8) << 5),
+            reliable_frame_index: None,
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            body,
+        };
+        if reliability.is_reliable() {
+            frame.reliable_frame_index = Some(self.reliable_index);
+            self.reliable_index = self.reliable_index.next();
+        }
+        if reliability.is_sequenced() {
+            frame.seq_frame_index = Some(self.seq_index);
+            self.seq_index = self.seq_index.next();
+            // sequenced frames share the current ordered index
+            frame.ordered = Some(Ordered {
+                frame_index: self.ordered_index,
+                channel: 0,
+            });
+        } else if reliability.is_sequenced_or_ordered() {
+            frame.ordered = Some(Ordered {
+                frame_index: self.ordered_index,
+                channel: 0,
+            });
+            self.ordered_index = self.ordered_index.next();
+        }
+        frame
+    }
+
+    async fn send_frame(&mut self, frame: Frame<Bytes>) -> Result<(), Error> {
+        let frame_set = FrameSet {
+            seq_num: self.seq_num,
+            frames: vec![frame],
+        };
+        self.seq_num = self.seq_num.next();
+        self.send(Packet::Connected(connected::Packet::FrameSet(frame_set)))
+            .await
+    }
+
+    async fn send_body(&mut self, reliability: Reliability, body: Bytes) -> Result<(), Error> {
+        let frame = self.frame(reliability, body);
+        self.send_frame(frame).await
+    }
+
+    async fn send_parted(&mut self, body: Bytes) -> Result<(), Error> {
+        let part_size = max_body_size(self.mtu, Reliability::ReliableOrdered, true);
+        let parted_size = body.len().div_ceil(part_size);
+        let parted_id = self.parted_id;
+        self.parted_id = self.parted_id.wrapping_add(1);
+        let ordered = Ordered {
+            frame_index: self.ordered_index,
+            channel: 0,
+        };
+        self.ordered_index = self.ordered_index.next();
+        for (parted_index, chunk) in body.chunks(part_size).enumerate() {
+            let frame = Frame {
+                flags: Flags::parse(((Reliability::ReliableOrdered as u8) << 5) | PARTED_FLAG),
+                reliable_frame_index: Some(self.reliable_index),
+                seq_frame_index: None,
+                ordered: Some(ordered),
+                fragment: Some(Fragment {
+                    parted_size: parted_size as u32,
+                    parted_id,
+                    parted_index: parted_index as u32,
+                }),
+                body: body.slice_ref(chunk),
+            };
+            self.reliable_index = self.reliable_index.next();
+            self.send_frame(frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next datagram, acknowledge it if it is a frame set
+    async fn recv(&self) -> Result<Packet<BytesMut>, Error> {
+        let mut buf = vec![0; usize::from(self.options.mtu.max(1500))];
+        loop {
+            let len = tokio::time::timeout(self.options.timeout, self.socket.recv(&mut buf))
+                .await
+                .map_err(|_| Error::RequestTimeout)??;
+            let mut datagram = BytesMut::from(&buf[..len]);
+            let packet = match Packet::read(&mut datagram) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                Err(err) => return Err(Error::Codec(err)),
+            };
+            if let Packet::Connected(connected::Packet::FrameSet(frame_set)) = &packet {
+                let ack = AckOrNack {
+                    records: vec![Record::Single(frame_set.seq_num)],
+                };
+                self.send(Packet::Connected(connected::Packet::Ack(ack)))
+                    .await?;
+            }
+            return Ok(packet);
+        }
+    }
+
+    async fn recv_offline(
+        &self,
+        mut matches: impl FnMut(unconnected::Packet) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        loop {
+            if let Packet::Unconnected(pack) = self.recv().await? {
+                if matches(pack)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Wait for a frame whose body matches, parted frames are not reassembled
+    async fn recv_body(&self, matches: impl Fn(&[u8]) -> bool) -> Result<(), Error> {
+        loop {
+            let Packet::Connected(connected::Packet::FrameSet(frame_set)) = self.recv().await?
+            else {
+                continue;
+            };
+            let found = frame_set.frames.iter().any(|frame| {
+                frame.fragment.is_none() && !frame.body.is_empty() && matches(&frame.body)
+            });
+            if found {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn recv_ack_of(&self, seq_num: Uint24le) -> Result<(), Error> {
+        loop {
+            let Packet::Connected(connected::Packet::Ack(ack)) = self.recv().await? else {
+                continue;
+            };
+            let acked = ack.records.iter().any(|record| match record {
+                Record::Single(acked) => *acked == seq_num,
+                Record::Range(start, end) => {
+                    seq_num.distance_from(*start) <= end.distance_from(*start)
+                }
+            });
+            if acked {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A `ConnectedPing` padded to at least `size` bytes
+fn ping(timestamp: i64, size: usize) -> Bytes {
+    let mut body = BytesMut::with_capacity(size.max(9));
+    body.put_u8(PackType::ConnectedPing.into());
+    body.put_i64(timestamp);
+    if body.len() < size {
+        body.resize(size, 0);
+    }
+    body.freeze()
+}
+
+fn is_pong_of(body: &[u8], timestamp: i64) -> bool {
+    body.len() >= 9
+        && body[0] == u8::from(PackType::ConnectedPong)
+        && body[1..9] == timestamp.to_be_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_interop_unreachable_server() {
+        // nobody answers on the port
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let report = check(
+            silent.local_addr().unwrap(),
+            Options {
+                timeout: Duration::from_millis(100),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(!report.passed());
+        assert!(matches!(
+            report.stages[0],
+            (Stage::Ping, Outcome::Failed(Error::RequestTimeout))
+        ));
+        assert_eq!(report.stages.len(), 11);
+        assert!(report.stages[1..]
+            .iter()
+            .all(|(_, outcome)| matches!(outcome, Outcome::Skipped)));
+    }
+
+    #[tokio::test]
+    async fn test_interop_guid_collision_retry() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let options = Options {
+            timeout: Duration::from_millis(500),
+            guid_retries: 1,
+            ..Default::default()
+        };
+        let taken = options.client_guid;
+        let replier = tokio::spawn(async move {
+            let mut buf = vec![0; 1500];
+            let mut guids = Vec::new();
+            while guids.len() < 2 {
+                let (len, client) = server.recv_from(&mut buf).await.unwrap();
+                let reply = match Packet::read(&mut BytesMut::from(&buf[..len])).unwrap() {
+                    Some(Packet::Unconnected(unconnected::Packet::OpenConnectionRequest1 {
+                        ..
+                    })) => {
+                        // the retry starts over from the request 1
+                        assert_eq!(guids, [taken]);
+                        unconnected::Packet::OpenConnectionReply1 {
+                            magic: (),
+                            server_guid: 1,
+                            use_encryption: false,
+                            mtu: 1400,
+                        }
+                    }
+                    Some(Packet::Unconnected(unconnected::Packet::OpenConnectionRequest2 {
+                        client_guid,
+                        ..
+                    })) => {
+                        guids.push(client_guid);
+                        if client_guid == taken {
+                            unconnected::Packet::AlreadyConnected {
+                                magic: (),
+                                server_guid: 1,
+                            }
+                        } else {
+                            unconnected::Packet::OpenConnectionReply2 {
+                                magic: (),
+                                server_guid: 1,
+                                client_address: client,
+                                mtu: 1400,
+                                encryption_enabled: false,
+                                migration_token: None,
+                            }
+                        }
+                    }
+                    _ => continue,
+                };
+                let mut raw = BytesMut::new();
+                Packet::<Bytes>::Unconnected(reply).write(&mut raw);
+                server.send_to(&raw, client).await.unwrap();
+            }
+            guids
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(addr).await.unwrap();
+        let mut session = Session {
+            socket,
+            addr,
+            options,
+            mtu: options.mtu,
+            seq_num: Uint24le(0),
+            reliable_index: Uint24le(0),
+            seq_index: Uint24le(0),
+            ordered_index: Uint24le(0),
+            parted_id: 0,
+            start: Instant::now(),
+        };
+        let mut report = Report::default();
+        session
+            .run(Stage::OpenConnection2, &mut report)
+            .await
+            .unwrap();
+        let guids = replier.await.unwrap();
+        assert_eq!(guids[0], taken);
+        assert_ne!(guids[1], taken);
+        assert_eq!(report.client_guid, Some(guids[1]));
+    }
+
+    #[tokio::test]
+    async fn test_interop_request1_padded() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let options = Options {
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let replier = tokio::spawn(async move {
+            let mut buf = vec![0; 1500];
+            let (len, client) = server.recv_from(&mut buf).await.unwrap();
+            let request = Packet::read(&mut BytesMut::from(&buf[..len])).unwrap();
+            let mut raw = BytesMut::new();
+            Packet::<Bytes>::Unconnected(unconnected::Packet::OpenConnectionReply1 {
+                magic: (),
+                server_guid: 1,
+                use_encryption: false,
+                mtu: 1200,
+            })
+            .write(&mut raw);
+            server.send_to(&raw, client).await.unwrap();
+            (len, request)
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(addr).await.unwrap();
+        let mut session = Session {
+            socket,
+            addr,
+            options,
+            mtu: options.mtu,
+            seq_num: Uint24le(0),
+            reliable_index: Uint24le(0),
+            seq_index: Uint24le(0),
+            ordered_index: Uint24le(0),
+            parted_id: 0,
+            start: Instant::now(),
+        };
+        let mut report = Report::default();
+        session
+            .run(Stage::OpenConnection1, &mut report)
+            .await
+            .unwrap();
+        let (len, request) = replier.await.unwrap();
+        // the mtu is offered by the padding, minus the IP and UDP headers
+        assert_eq!(len, 1400 - 28);
+        assert!(matches!(
+            request,
+            Some(Packet::Unconnected(
+                unconnected::Packet::OpenConnectionRequest1 { mtu: 1400, .. }
+            ))
+        ));
+        assert_eq!(report.mtu, Some(1200));
+        assert_eq!(session.mtu, 1200);
+    }
+
+    #[tokio::test]
+    async fn test_interop_incompatible_protocol() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1500];
+            loop {
+                let (len, client) = server.recv_from(&mut buf).await.unwrap();
+                let reply = match Packet::read(&mut BytesMut::from(&buf[..len])).unwrap() {
+                    Some(Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                        send_timestamp,
+                        ..
+                    })) => unconnected::Packet::UnconnectedPong {
+                        send_timestamp,
+                        server_guid: 7,
+                        magic: (),
+                        data: Bytes::from_static(b"MCPE;"),
+                    },
+                    Some(Packet::Unconnected(unconnected::Packet::OpenConnectionRequest1 {
+                        ..
+                    })) => unconnected::Packet::IncompatibleProtocol {
+                        server_protocol: 10,
+                        magic: (),
+                        server_guid: 7,
+                    },
+                    _ => continue,
+                };
+                let mut raw = BytesMut::new();
+                Packet::<Bytes>::Unconnected(reply).write(&mut raw);
+                server.send_to(&raw, client).await.unwrap();
+            }
+        });
+
+        let report = check(
+            addr,
+            Options {
+                timeout: Duration::from_millis(500),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            report.stages[0],
+            (Stage::Ping, Outcome::Passed(_))
+        ));
+        assert!(matches!(
+            report.stages[1],
+            (
+                Stage::OpenConnection1,
+                Outcome::Failed(Error::IncompatibleProtocol {
+                    server_protocol: 10,
+                    server_guid: 7,
+                })
+            )
+        ));
+    }
+
+    #[test]
+    fn test_interop_ping_pong() {
+        let body = ping(42, 100);
+        assert_eq!(body.len(), 100);
+        let mut pong = vec![u8::from(PackType::ConnectedPong)];
+        pong.extend_from_slice(&42_i64.to_be_bytes());
+        pong.extend_from_slice(&43_i64.to_be_bytes());
+        assert!(is_pong_of(&pong, 42));
+        assert!(!is_pong_of(&pong, 43));
+    }
+}
@@ -36,27 +36,65 @@
     clippy::wildcard_imports
 )]
 #![feature(impl_trait_in_assoc_type)]
-#![feature(type_alias_impl_trait)]
-#![feature(ip_bits)]
-#![feature(exclusive_range_pattern)]
 #![feature(type_changing_struct_update)]
-#![feature(coroutines, proc_macro_hygiene, stmt_expr_attributes, gen_future)]
+#![cfg_attr(
+    feature = "server",
+    feature(coroutines, proc_macro_hygiene, stmt_expr_attributes)
+)]
 
+/// Raknet client
+#[cfg(feature = "client")]
+pub mod client;
+/// Timestamp clock
+pub mod clock;
 /// Protocol codec
+#[cfg(feature = "server")]
 mod codec;
 /// Errors
 mod errors;
+/// Connection events
+pub mod event;
+/// Interop self-test against external servers
+#[cfg(feature = "interop")]
+pub mod interop;
+/// Load testing harness
+#[cfg(feature = "loadtest")]
+pub mod loadtest;
+/// Message
+pub mod message;
+/// Micro benchmarks
+#[cfg(feature = "micro-bench")]
+pub mod micro_bench;
 /// Protocol packet
+#[cfg(any(test, feature = "server", feature = "client", feature = "wire"))]
 mod packet;
 /// Runtime
 pub mod rt;
 /// Raknet server
-mod server;
+#[cfg(feature = "server")]
+pub mod server;
 /// Service
 pub mod service;
+/// Statistics
+pub mod stats;
+/// Helpers to fabricate the protocol inputs in tests
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+/// Echo endpoint to benchmark and integration test against
+#[cfg(feature = "test-util")]
+pub mod testing;
+/// Utilities over the connections
+pub mod utils;
+/// Low-level datagram codec
+#[cfg(any(test, feature = "wire"))]
+pub mod wire;
+
+pub use errors::{ConfigError, Error};
 
+#[cfg(feature = "server")]
 #[derive(Debug, Clone)]
 struct Peer {
     addr: std::net::SocketAddr,
     mtu: u16,
+    guid: u64,
 }
@@ -0,0 +1,279 @@
+//! A stress driver spinning up virtual clients against an echo server and reporting the
+//! throughput and latency percentiles, only available with the `loadtest` feature.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use rand::Rng;
+use tracing::debug;
+
+use crate::errors::Error;
+use crate::message::{Message, Reliability};
+use crate::utils::Connect;
+
+// The send timestamp in nanoseconds since the test started
+const HEADER_SIZE: usize = 8;
+
+/// Options of the virtual clients
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Count of virtual clients
+    pub clients: usize,
+    /// Size of each message, at least 8 bytes to carry the send timestamp
+    pub message_size: usize,
+    /// Messages sent per second by each client
+    pub rate: u32,
+    /// How long the clients keep sending
+    pub duration: Duration,
+    /// How long to wait for the echoes after the clients stop sending
+    pub grace: Duration,
+    /// The reliability of each message is picked by these weights
+    pub mix: Vec<(Reliability, u32)>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            clients: 1000,
+            message_size: 64,
+            rate: 20,
+            duration: Duration::from_secs(10),
+            grace: Duration::from_secs(1),
+            mix: vec![
+                (Reliability::ReliableOrdered, 8),
+                (Reliability::Unreliable, 2),
+            ],
+        }
+    }
+}
+
+impl Options {
+    fn pick_reliability(&self, rng: &mut impl Rng) -> Reliability {
+        let total: u32 = self.mix.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return Reliability::ReliableOrdered;
+        }
+        let mut point = rng.gen_range(0..total);
+        for &(reliability, weight) in &self.mix {
+            if point < weight {
+                return reliability;
+            }
+            point -= weight;
+        }
+        unreachable!("point is less than the total weight")
+    }
+}
+
+/// The result of a load test
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Clients failed to connect
+    pub failed_clients: usize,
+    /// Messages sent by all clients
+    pub sent: u64,
+    /// Echoes received by all clients
+    pub received: u64,
+    /// Bytes of the received echoes
+    pub received_bytes: u64,
+    /// How long the test took
+    pub elapsed: Duration,
+    /// Round trip latencies of the echoes, sorted ascending
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Received echoes per second
+    pub fn throughput(&self) -> f64 {
+        self.received as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Received bytes per second
+    pub fn bandwidth(&self) -> f64 {
+        self.received_bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// The latency at the percentile (0-100), None if nothing is received
+    pub fn latency(&self, percentile: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = (self.latencies.len() - 1) as f64 * percentile.clamp(0.0, 100.0) / 100.0;
+        Some(self.latencies[rank.round() as usize])
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClientResult {
+    sent: u64,
+    received_bytes: u64,
+    latencies: Vec<Duration>,
+}
+
+async fn virtual_client<T>(mut conn: T, options: Options, start: Instant) -> ClientResult
+where
+    T: Sink<Message, Error = Error> + Stream<Item = Bytes> + Unpin,
+{
+    let mut result = ClientResult::default();
+    let deadline = tokio::time::Instant::from_std(start + options.duration);
+    let mut ticker = tokio::time::interval(Duration::from_secs(1) / options.rate.max(1));
+    let size = options.message_size.max(HEADER_SIZE);
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if tokio::time::Instant::now() < deadline => {
+                let mut data = BytesMut::with_capacity(size);
+                data.put_u64(start.elapsed().as_nanos() as u64);
+                data.resize(size, 0);
+                let reliability = options.pick_reliability(&mut rand::thread_rng());
+                if let Err(err) = conn.send(Message::new(data.freeze()).reliability(reliability)).await {
+                    debug!("[loadtest] virtual client stopped, error {err}");
+                    break;
+                }
+                result.sent += 1;
+            }
+            echo = conn.next() => {
+                let Some(mut echo) = echo else {
+                    break;
+                };
+                if echo.len() < HEADER_SIZE {
+                    continue;
+                }
+                result.received_bytes += echo.len() as u64;
+                let sent_at = Duration::from_nanos(echo.get_u64());
+                result.latencies.push(start.elapsed().saturating_sub(sent_at));
+            }
+            () = tokio::time::sleep_until(deadline + options.grace) => break,
+        }
+    }
+    result
+}
+
+/// Spin up the virtual clients connecting to the echo server at the address. Every client sends
+/// timestamped messages at the configured rate and measures the latency of the echoes.
+pub async fn run<C>(connector: C, addr: SocketAddr, options: Options) -> Report
+where
+    C: Connect + Clone + Send + 'static,
+    C::Fut: Send,
+    C::Conn: Sink<Message, Error = Error> + Stream<Item = Bytes> + Unpin + Send + 'static,
+{
+    let start = Instant::now();
+    let handles: Vec<_> = (0..options.clients)
+        .map(|_| {
+            let connector = connector.clone();
+            let options = options.clone();
+            tokio::spawn(async move {
+                let conn = connector.connect(addr).await?;
+                Ok::<_, Error>(virtual_client(conn, options, start).await)
+            })
+        })
+        .collect();
+
+    let mut report = Report::default();
+    for handle in handles {
+        match handle.await {
+            Ok(Ok(result)) => {
+                report.sent += result.sent;
+                report.received += result.latencies.len() as u64;
+                report.received_bytes += result.received_bytes;
+                report.latencies.extend(result.latencies);
+            }
+            Ok(Err(err)) => {
+                debug!("[loadtest] virtual client failed to connect, error {err}");
+                report.failed_clients += 1;
+            }
+            Err(_) => report.failed_clients += 1,
+        }
+    }
+    report.elapsed = start.elapsed();
+    report.latencies.sort_unstable();
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::channel::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn test_report_percentiles() {
+        let report = Report {
+            received: 100,
+            elapsed: Duration::from_secs(2),
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..Default::default()
+        };
+        assert_eq!(report.latency(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(report.latency(50.0), Some(Duration::from_millis(51)));
+        assert_eq!(report.latency(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.latency(100.0), Some(Duration::from_millis(100)));
+        assert!((report.throughput() - 50.0).abs() < f64::EPSILON);
+        assert_eq!(Report::default().latency(50.0), None);
+    }
+
+    #[tokio::test]
+    async fn test_loadtest_against_echo() {
+        let addr: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let connector = |_: SocketAddr| async {
+            let (tx, rx) = mpsc::unbounded();
+            Ok(Echo { tx, rx })
+        };
+        let report = run(
+            connector,
+            addr,
+            Options {
+                clients: 10,
+                rate: 100,
+                duration: Duration::from_millis(200),
+                grace: Duration::from_millis(100),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(report.failed_clients, 0);
+        assert!(report.sent > 0);
+        assert_eq!(report.received, report.sent);
+        assert_eq!(report.received_bytes, report.sent * 64);
+        assert!(report.latency(99.0).is_some());
+    }
+
+    /// A connection to an in-memory echo server
+    struct Echo {
+        tx: mpsc::UnboundedSender<Bytes>,
+        rx: mpsc::UnboundedReceiver<Bytes>,
+    }
+
+    impl Stream for Echo {
+        type Item = Bytes;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_next_unpin(cx)
+        }
+    }
+
+    impl Sink<Message> for Echo {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+            self.tx
+                .unbounded_send(item.data)
+                .map_err(|_| Error::ConnectionClosed("echo dropped"))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::Instant;
+
+use bytes::Bytes;
+
+#[cfg(feature = "server")]
+use crate::packet::connected::Frame;
+
+/// The reliability of a frame
+#[derive(Debug, Clone, Eq, PartialEq, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum Reliability {
+    /// Direct UDP. The unparted frames of it take the fast path: no index is assigned to them,
+    /// and they bypass the resend queue, the deduplication window and the ordering layers, which
+    /// suits the high rate messages superseded by the next ones, e.g. the 20Hz position updates.
+    Unreliable = 0b000,
+
+    /// Ordered
+    UnreliableSequenced = 0b001,
+
+    /// Deduplicated
+    Reliable = 0b010,
+
+    /// Ordered + Deduplicated + Resend  (Most used)
+    ReliableOrdered = 0b011,
+
+    /// Ordered + Deduplicated
+    ReliableSequenced = 0b100,
+
+    /// Not used
+    UnreliableWithAckReceipt = 0b101,
+    UnreliableSequencedWithAckReceipt = 0b110,
+    ReliableWithAckReceipt = 0b111,
+
+    /// Defined but never be used (cannot be used).
+    ReliableOrderedWithAckReceipt = 0b1_000,
+    ReliableSequencedWithAckReceipt = 0b1_001,
+}
+
+/// The priority class of a message, operators could mark the datagrams of each class with
+/// different DSCP values. The classes are ordered from the most urgent one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Priority {
+    /// Latency sensitive messages, e.g. movement, sent without waiting for the tick
+    Immediate,
+    /// Messages should be delivered soon, e.g. chat
+    High,
+    /// Regular messages
+    #[default]
+    Medium,
+    /// Bulk transfers, e.g. resource packs
+    Low,
+}
+
+/// A message sent to the peer with specified reliability and ordering channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The reliability of this message
+    pub reliability: Reliability,
+    /// The ordering channel of this message, only be used when the reliability is sequenced or
+    /// ordered
+    pub channel: u8,
+    /// The priority class of this message
+    pub priority: Priority,
+    /// The payload
+    pub data: Bytes,
+}
+
+impl Message {
+    /// Create a reliable ordered message with medium priority on channel 0
+    pub fn new(data: Bytes) -> Self {
+        Self {
+            reliability: Reliability::ReliableOrdered,
+            channel: 0,
+            priority: Priority::default(),
+            data,
+        }
+    }
+
+    /// Set the reliability of this message
+    #[must_use]
+    pub fn reliability(mut self, reliability: Reliability) -> Self {
+        self.reliability = reliability;
+        self
+    }
+
+    /// Set the ordering channel of this message
+    #[must_use]
+    pub fn channel(mut self, channel: u8) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Set the priority class of this message
+    #[must_use]
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+impl From<Bytes> for Message {
+    fn from(data: Bytes) -> Self {
+        Self::new(data)
+    }
+}
+
+/// A message received from the peer along with how it was delivered, for the applications
+/// routing by the metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    /// The reliability the message was sent with
+    pub reliability: Reliability,
+    /// The ordering channel, None if the message is neither sequenced nor ordered
+    pub channel: Option<u8>,
+    /// The ordered frame index on the channel
+    pub ordered_index: Option<u32>,
+    /// The sequenced frame index, only set for the sequenced messages
+    pub sequenced_index: Option<u32>,
+    /// When the datagram completing the message arrived, stamped by the kernel if the receive
+    /// timestamps are enabled
+    pub received_at: Instant,
+    /// The payload
+    pub data: Bytes,
+}
+
+#[cfg(feature = "server")]
+impl Received {
+    /// Take the metadata of a reassembled and ordered frame
+    pub(crate) fn from_frame(frame: Frame<Bytes>, received_at: Instant) -> Self {
+        Self {
+            reliability: frame.flags.reliability(),
+            channel: frame.ordered.as_ref().map(|ordered| ordered.channel),
+            ordered_index: frame.ordered.as_ref().map(|ordered| ordered.frame_index.0),
+            sequenced_index: frame.seq_frame_index.map(|idx| idx.0),
+            received_at,
+            data: frame.body,
+        }
+    }
+}
+
+impl From<Received> for Bytes {
+    fn from(received: Received) -> Self {
+        received.data
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+    use crate::packet::connected::{Flags, Ordered, Uint24le};
+
+    #[test]
+    fn test_received_from_frame() {
+        let now = Instant::now();
+        let frame = Frame {
+            flags: Flags::parse((Reliability::ReliableSequenced as u8) << 5),
+            reliable_frame_index: Some(Uint24le(7)),
+            seq_frame_index: Some(Uint24le(2)),
+            ordered: Some(Ordered {
+                frame_index: Uint24le(5),
+                channel: 3,
+            }),
+            fragment: None,
+            body: Bytes::from_static(b"hello"),
+        };
+        let received = Received::from_frame(frame, now);
+        assert_eq!(
+            received,
+            Received {
+                reliability: Reliability::ReliableSequenced,
+                channel: Some(3),
+                ordered_index: Some(5),
+                sequenced_index: Some(2),
+                received_at: now,
+                data: Bytes::from_static(b"hello"),
+            }
+        );
+        assert_eq!(Bytes::from(received), Bytes::from_static(b"hello"));
+    }
+}
@@ -0,0 +1,153 @@
+//! Expose the internal encode/decode pipelines to the benchmarks, only available with the
+//! `micro-bench` feature.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::codec::batch::encode_packed;
+use crate::codec::ordered::ORDERING_WINDOW_SIZE;
+pub use crate::codec::CodecConfig;
+use crate::codec::Decoded;
+use crate::errors::CodecError;
+use crate::packet::connected::{self, Flags, Frame, Ordered, Reliability, Uint24le};
+use crate::packet::Packet;
+use crate::stats::StatsRecorder;
+
+/// Options of the generated datagrams
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Count of reliable ordered frames
+    pub frames: usize,
+    /// Size of the body of each frame
+    pub body_size: usize,
+    /// Max size of a datagram
+    pub mtu: usize,
+    /// Shuffle the datagrams, the frames are kept inside the ordering window so that all of them
+    /// are decoded
+    pub shuffle: bool,
+    /// Ratio of the datagrams to be duplicated, each duplication follows its datagram
+    pub dup_ratio: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            frames: 200_000,
+            body_size: 200,
+            mtu: 1400,
+            shuffle: false,
+            dup_ratio: 0.0,
+        }
+    }
+}
+
+fn frames(bodies: impl IntoIterator<Item = Bytes>) -> impl Iterator<Item = Frame<Bytes>> {
+    bodies.into_iter().enumerate().map(|(i, body)| Frame {
+        flags: Flags::parse((Reliability::ReliableOrdered as u8) << 5),
+        reliable_frame_index: Some(Uint24le(i as u32)),
+        seq_frame_index: None,
+        ordered: Some(Ordered {
+            frame_index: Uint24le(i as u32),
+            channel: 0,
+        }),
+        fragment: None,
+        body,
+    })
+}
+
+/// Generate the frame set datagrams to be decoded
+pub fn gen_data(options: Options, rng: &mut impl Rng) -> Vec<BytesMut> {
+    let body = Bytes::from(vec![0xfe; options.body_size]);
+    let mut data = Vec::new();
+    let mut buf = BytesMut::with_capacity(options.mtu);
+    encode_packed(
+        frames(vec![body; options.frames]),
+        options.mtu,
+        &mut Uint24le(0),
+        &mut buf,
+        |datagram| data.push(BytesMut::from(datagram)),
+    );
+    let per_datagram = options.frames.div_ceil(data.len().max(1));
+    let mut data = data
+        .into_iter()
+        .flat_map(|datagram| {
+            let dup = rng.gen_bool(options.dup_ratio).then(|| datagram.clone());
+            std::iter::once(datagram).chain(dup)
+        })
+        .collect::<Vec<_>>();
+    if options.shuffle {
+        // the frames of a chunk span less than the window from the first unread one
+        let chunk = (ORDERING_WINDOW_SIZE / per_datagram.max(1)).max(1);
+        for datagrams in data.chunks_mut(chunk) {
+            datagrams.shuffle(rng);
+        }
+    }
+    data
+}
+
+/// Decode the datagrams through the codec pipeline of a connection, returns the count of the
+/// decoded frames.
+pub async fn decode(data: Vec<BytesMut>, config: CodecConfig) -> usize {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 19132));
+    let recorder = Arc::new(StatsRecorder::new(config.max_channels()));
+    futures::stream::iter(data)
+        .map(|mut raw| match Packet::read(&mut raw)? {
+            Some(Packet::Connected(packet)) => Ok(packet),
+            _ => Err(CodecError::InvalidPacketLength("frame set")),
+        })
+        .decoded(addr, 1400, config, recorder)
+        .map(|packet| match packet {
+            connected::Packet::FrameSet(frame_set) => frame_set.frames.len(),
+            _ => 0,
+        })
+        .fold(0, |decoded, frames| async move { decoded + frames })
+        .await
+}
+
+/// Parse a frame set datagram, returns the count of frames
+pub fn parse_frame_set(mut raw: BytesMut) -> usize {
+    match Packet::read(&mut raw) {
+        Ok(Some(Packet::Connected(connected::Packet::FrameSet(frame_set)))) => {
+            frame_set.frames.len()
+        }
+        _ => 0,
+    }
+}
+
+/// Pack the bodies as reliable ordered frames into datagrams in the reused buffer, returns the
+/// count of datagrams.
+pub fn encode(bodies: &[Bytes], mtu: usize, buf: &mut BytesMut) -> usize {
+    let mut datagrams = 0;
+    encode_packed(
+        frames(bodies.iter().cloned()),
+        mtu,
+        &mut Uint24le(0),
+        buf,
+        |_| {
+            datagrams += 1;
+        },
+    );
+    datagrams
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_decode_shuffled() {
+        let options = Options {
+            frames: 20_000,
+            shuffle: true,
+            dup_ratio: 0.2,
+            ..Options::default()
+        };
+        let data = gen_data(options, &mut rand::thread_rng());
+        assert_eq!(decode(data, CodecConfig::default()).await, 20_000);
+    }
+}
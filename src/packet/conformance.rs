@@ -0,0 +1,410 @@
+//! Byte-exact vectors of every packet type, so that a refactor of the codec could not silently
+//! change the bytes on the wire. The vectors are assembled by hand following the layout of the
+//! reference raknet implementation.
+
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use bytes::{Bytes, BytesMut};
+
+use super::connected::{
+    self, AckOrNack, Flags, Fragment, Frame, FrameSet, Ordered, Record, Reliability, Uint24le,
+};
+use super::{unconnected, Packet, SocketAddrRead, SocketAddrWrite};
+use crate::errors::CodecError;
+
+const MAGIC: &str = "00ffff00fefefefefdfdfdfd12345678";
+const TIMESTAMP: &str = "0102030405060708";
+const GUID: &str = "1122334455667788";
+// 127.0.0.1:19132, the octets are complemented
+const ADDR: &str = "0480fffffe4abc";
+// 1492
+const MTU: &str = "05d4";
+// appended to the handshake of the migration
+const TOKEN: &str = "a1a2a3a4a5a6a7a8";
+
+fn hex(parts: &[&str]) -> BytesMut {
+    let digits: String = parts.concat();
+    assert_eq!(digits.len() % 2, 0, "odd hex digits");
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).expect("invalid hex digit"))
+        .collect::<Vec<_>>()
+        .as_slice()
+        .into()
+}
+
+/// `OpenConnectionRequest1` padded to 1492 bytes minus the IP and UDP headers (28 bytes)
+fn padded_request1() -> BytesMut {
+    let mut raw = hex(&["05", MAGIC, "0b"]);
+    raw.resize(1492 - 28, 0);
+    raw
+}
+
+fn addr() -> SocketAddr {
+    "127.0.0.1:19132".parse().unwrap()
+}
+
+fn offline_vectors() -> Vec<(&'static str, BytesMut, unconnected::Packet)> {
+    let timestamp = 0x0102_0304_0506_0708;
+    let guid = 0x1122_3344_5566_7788;
+    vec![
+        (
+            "UnconnectedPing",
+            hex(&["01", TIMESTAMP, MAGIC, GUID]),
+            unconnected::Packet::UnconnectedPing {
+                send_timestamp: timestamp,
+                magic: (),
+                client_guid: guid,
+            },
+        ),
+        (
+            "UnconnectedPong",
+            hex(&["1c", TIMESTAMP, GUID, MAGIC, "4d4350453b"]),
+            unconnected::Packet::UnconnectedPong {
+                send_timestamp: timestamp,
+                server_guid: guid,
+                magic: (),
+                data: Bytes::from_static(b"MCPE;"),
+            },
+        ),
+        (
+            "OpenConnectionRequest1",
+            padded_request1(),
+            unconnected::Packet::OpenConnectionRequest1 {
+                magic: (),
+                protocol_version: 11,
+                mtu: 1492,
+            },
+        ),
+        (
+            "OpenConnectionReply1",
+            hex(&["06", MAGIC, GUID, "00", MTU]),
+            unconnected::Packet::OpenConnectionReply1 {
+                magic: (),
+                server_guid: guid,
+                use_encryption: false,
+                mtu: 1492,
+            },
+        ),
+        (
+            "OpenConnectionRequest2",
+            hex(&["07", MAGIC, ADDR, MTU, GUID]),
+            unconnected::Packet::OpenConnectionRequest2 {
+                magic: (),
+                server_address: addr(),
+                mtu: 1492,
+                client_guid: guid,
+                migration_token: None,
+            },
+        ),
+        (
+            "OpenConnectionReply2",
+            hex(&["08", MAGIC, GUID, ADDR, MTU, "00"]),
+            unconnected::Packet::OpenConnectionReply2 {
+                magic: (),
+                server_guid: guid,
+                client_address: addr(),
+                mtu: 1492,
+                encryption_enabled: false,
+                migration_token: None,
+            },
+        ),
+        (
+            "OpenConnectionRequest2 with migration token",
+            hex(&["07", MAGIC, ADDR, MTU, GUID, TOKEN]),
+            unconnected::Packet::OpenConnectionRequest2 {
+                magic: (),
+                server_address: addr(),
+                mtu: 1492,
+                client_guid: guid,
+                migration_token: Some(0xa1a2_a3a4_a5a6_a7a8),
+            },
+        ),
+        (
+            "OpenConnectionReply2 with migration token",
+            hex(&["08", MAGIC, GUID, ADDR, MTU, "00", TOKEN]),
+            unconnected::Packet::OpenConnectionReply2 {
+                magic: (),
+                server_guid: guid,
+                client_address: addr(),
+                mtu: 1492,
+                encryption_enabled: false,
+                migration_token: Some(0xa1a2_a3a4_a5a6_a7a8),
+            },
+        ),
+        (
+            "IncompatibleProtocol",
+            hex(&["19", "0b", MAGIC, GUID]),
+            unconnected::Packet::IncompatibleProtocol {
+                server_protocol: 11,
+                magic: (),
+                server_guid: guid,
+            },
+        ),
+        (
+            "AlreadyConnected",
+            hex(&["12", MAGIC, GUID]),
+            unconnected::Packet::AlreadyConnected {
+                magic: (),
+                server_guid: guid,
+            },
+        ),
+        (
+            "ConnectionRequestFailed",
+            hex(&["11", MAGIC, GUID]),
+            unconnected::Packet::ConnectionRequestFailed {
+                magic: (),
+                server_guid: guid,
+            },
+        ),
+        (
+            "NoFreeIncomingConnections",
+            hex(&["14", MAGIC, GUID]),
+            unconnected::Packet::NoFreeIncomingConnections {
+                magic: (),
+                server_guid: guid,
+            },
+        ),
+        (
+            "AdvertiseSystem",
+            hex(&["1d", "6869"]),
+            unconnected::Packet::AdvertiseSystem {
+                data: Bytes::from_static(b"hi"),
+            },
+        ),
+    ]
+}
+
+fn connected_vectors() -> Vec<(&'static str, BytesMut, connected::Packet<BytesMut>)> {
+    vec![
+        (
+            "Ack",
+            hex(&["c0", "0002", "00", "000000", "020000", "01", "040000"]),
+            connected::Packet::Ack(AckOrNack {
+                records: vec![
+                    Record::Range(Uint24le(0), Uint24le(2)),
+                    Record::Single(Uint24le(4)),
+                ],
+            }),
+        ),
+        (
+            "Nack",
+            hex(&["a0", "0001", "01", "010000"]),
+            connected::Packet::Nack(AckOrNack {
+                records: vec![Record::Single(Uint24le(1))],
+            }),
+        ),
+        (
+            "FrameSet(Unreliable)",
+            hex(&["84", "000000", "00", "0010", "fe01"]),
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: Uint24le(0),
+                frames: vec![Frame {
+                    flags: Flags::parse(0x00),
+                    reliable_frame_index: None,
+                    seq_frame_index: None,
+                    ordered: None,
+                    fragment: None,
+                    body: BytesMut::from(&b"\xfe\x01"[..]),
+                }],
+            }),
+        ),
+        (
+            "FrameSet(ReliableOrdered)",
+            hex(&["84", "010000", "60", "0018", "000000", "00000000", "fe6869"]),
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: Uint24le(1),
+                frames: vec![Frame {
+                    flags: Flags::parse((Reliability::ReliableOrdered as u8) << 5),
+                    reliable_frame_index: Some(Uint24le(0)),
+                    seq_frame_index: None,
+                    ordered: Some(Ordered {
+                        frame_index: Uint24le(0),
+                        channel: 0,
+                    }),
+                    fragment: None,
+                    body: BytesMut::from(&b"\xfehi"[..]),
+                }],
+            }),
+        ),
+        (
+            "FrameSet(ReliableSequenced)",
+            hex(&[
+                "84", "020000", "80", "0008", "050000", "030000", "06000000", "fe",
+            ]),
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: Uint24le(2),
+                frames: vec![Frame {
+                    flags: Flags::parse((Reliability::ReliableSequenced as u8) << 5),
+                    reliable_frame_index: Some(Uint24le(5)),
+                    seq_frame_index: Some(Uint24le(3)),
+                    ordered: Some(Ordered {
+                        frame_index: Uint24le(6),
+                        channel: 0,
+                    }),
+                    fragment: None,
+                    body: BytesMut::from(&b"\xfe"[..]),
+                }],
+            }),
+        ),
+        (
+            "FrameSet(Parted)",
+            hex(&[
+                "8c", "030000", "70", "0010", "010000", "00000101", "00000002", "0007", "00000000",
+                "fe68",
+            ]),
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: Uint24le(3),
+                frames: vec![Frame {
+                    flags: Flags::parse(((Reliability::ReliableOrdered as u8) << 5) | 0x10),
+                    reliable_frame_index: Some(Uint24le(1)),
+                    seq_frame_index: None,
+                    ordered: Some(Ordered {
+                        frame_index: Uint24le(0x01_0000),
+                        channel: 1,
+                    }),
+                    fragment: Some(Fragment {
+                        parted_size: 2,
+                        parted_id: 7,
+                        parted_index: 0,
+                    }),
+                    body: BytesMut::from(&b"\xfeh"[..]),
+                }],
+            }),
+        ),
+    ]
+}
+
+#[test]
+fn test_offline_vectors() {
+    for (name, raw, packet) in offline_vectors() {
+        let mut buf = BytesMut::new();
+        Packet::<BytesMut>::Unconnected(packet.clone()).write(&mut buf);
+        assert_eq!(buf, raw, "encode {name}");
+        let decoded = Packet::read(&mut raw.clone()).unwrap();
+        assert_eq!(decoded, Some(Packet::Unconnected(packet)), "decode {name}");
+    }
+}
+
+#[test]
+fn test_connected_vectors() {
+    for (name, raw, packet) in connected_vectors() {
+        let mut buf = BytesMut::new();
+        Packet::Connected(packet.clone()).write(&mut buf);
+        assert_eq!(buf, raw, "encode {name}");
+        let decoded = Packet::read(&mut raw.clone()).unwrap();
+        assert_eq!(decoded, Some(Packet::Connected(packet)), "decode {name}");
+    }
+}
+
+#[test]
+fn test_ipv6_address_encoding() {
+    // [fe80::1%3]:19132 with the flow info 0x12345
+    let addr = SocketAddr::V6(SocketAddrV6::new(
+        Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1),
+        19132,
+        0x12345,
+        3,
+    ));
+    let raw = hex(&[
+        "06",
+        "1700",
+        "4abc",
+        "00012345",
+        "fe800000000000000000000000000001",
+        "03000000",
+    ]);
+    let mut buf = BytesMut::new();
+    buf.put_socket_addr(addr);
+    assert_eq!(buf, raw);
+    assert_eq!(raw.clone().get_socket_addr().unwrap(), addr);
+
+    // the family written on Linux
+    let mut linux = hex(&[
+        "06",
+        "0a00",
+        "4abc",
+        "00012345",
+        "fe800000000000000000000000000001",
+        "03000000",
+    ]);
+    assert_eq!(linux.get_socket_addr().unwrap(), addr);
+}
+
+#[test]
+fn test_address_round_trip() {
+    let addrs = [
+        "0.0.0.0:0",
+        "127.0.0.1:19132",
+        "255.255.255.255:65535",
+        "[::]:0",
+        "[::1]:19132",
+        "[::ffff:192.168.1.1]:19133",
+        "[2001:db8::ff00:42:8329]:65535",
+        "[fe80::1%4294967295]:1",
+    ]
+    .map(|addr| addr.parse::<SocketAddr>().unwrap())
+    .into_iter()
+    .chain([SocketAddr::V6(SocketAddrV6::new(
+        Ipv6Addr::LOCALHOST,
+        19132,
+        0x000f_ffff,
+        7,
+    ))]);
+    for addr in addrs {
+        let mut buf = BytesMut::new();
+        buf.put_socket_addr(addr);
+        let expected = if addr.is_ipv4() { 7 } else { 29 };
+        assert_eq!(buf.len(), expected, "encoded length of {addr}");
+        assert_eq!(buf.get_socket_addr().unwrap(), addr, "round trip {addr}");
+        assert!(buf.is_empty(), "trailing bytes of {addr}");
+    }
+}
+
+#[test]
+fn test_invalid_address() {
+    let mut buf = BytesMut::new();
+    buf.put_socket_addr("[::1]:19132".parse().unwrap());
+    let mut truncated = buf.clone();
+    truncated.truncate(28);
+    assert!(matches!(
+        truncated.get_socket_addr(),
+        Err(CodecError::InvalidPacketLength(..))
+    ));
+
+    let mut bad_family = buf.clone();
+    bad_family[1] = 0x02;
+    assert!(matches!(
+        bad_family.get_socket_addr(),
+        Err(CodecError::InvalidIPV6Family(0x02))
+    ));
+
+    assert!(matches!(
+        hex(&["05", "7f0000014abc"]).get_socket_addr(),
+        Err(CodecError::InvalidIPVer(5))
+    ));
+}
+
+#[test]
+fn test_reference_address_encoding() {
+    let raw = hex(&["07", MAGIC, "0480fffffe4abc", MTU, GUID]);
+    let Some(Packet::Unconnected(unconnected::Packet::OpenConnectionRequest2 {
+        server_address,
+        ..
+    })) = Packet::read(&mut raw.clone()).unwrap()
+    else {
+        panic!("expect OpenConnectionRequest2");
+    };
+    assert_eq!(server_address, addr());
+}
+
+#[test]
+fn test_reference_padded_request1() {
+    let mut raw = padded_request1();
+    let Some(Packet::Unconnected(unconnected::Packet::OpenConnectionRequest1 { mtu, .. })) =
+        Packet::read(&mut raw).unwrap()
+    else {
+        panic!("expect OpenConnectionRequest1");
+    };
+    assert_eq!(mtu, 1492);
+}
@@ -5,25 +5,61 @@ use crate::errors::CodecError;
 use crate::read_buf;
 
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) struct AckOrNack {
-    pub(crate) records: Vec<Record>,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AckOrNack {
+    pub records: Vec<Record>,
 }
 
 impl AckOrNack {
     /// Extend a packet from a sorted sequence numbers iterator based on mtu.
     /// Notice that a uint24le must be unique in the whole iterator
+    #[cfg(any(test, feature = "test-util"))]
     pub(crate) fn extend_from<I: Iterator<Item = u32>>(
+        sorted_seq_nums: I,
+        mtu: u16,
+    ) -> Option<Self> {
+        let mut records = Vec::new();
+        Self::build(sorted_seq_nums, mtu, |record| records.push(record))?;
+        Some(Self { records })
+    }
+
+    #[cfg(feature = "server")]
+    /// Encode the records incrementally from a sorted sequence numbers iterator into buf based
+    /// on mtu, without building the intermediate records. The pack type should be written by
+    /// the caller. Returns false if the iterator is empty.
+    pub(crate) fn write_from<I: Iterator<Item = u32>>(
+        sorted_seq_nums: I,
+        mtu: u16,
+        buf: &mut BytesMut,
+    ) -> bool {
+        let len_pos = buf.len();
+        buf.put_u16(0);
+        let mut cnt: u16 = 0;
+        let written = Self::build(sorted_seq_nums, mtu, |record| {
+            cnt += 1;
+            record.write(buf);
+        });
+        if written.is_none() {
+            buf.truncate(len_pos);
+            return false;
+        }
+        buf[len_pos..len_pos + 2].copy_from_slice(&cnt.to_be_bytes());
+        true
+    }
+
+    #[cfg(any(test, feature = "server", feature = "test-util"))]
+    /// Build records from a sorted sequence numbers iterator based on mtu, each record will be
+    /// emitted once it is determined.
+    fn build<I: Iterator<Item = u32>>(
         mut sorted_seq_nums: I,
         mut mtu: u16,
-    ) -> Option<Self> {
+        mut emit: impl FnMut(Record),
+    ) -> Option<()> {
         // pack_type(1) + length(2) + single record(4) = 7
         debug_assert!(mtu >= 7, "7 is the least size of mtu");
 
-        let Some(mut first) = sorted_seq_nums.next() else {
-            return None;
-        };
+        let mut first = sorted_seq_nums.next()?;
 
-        let mut records = Vec::new();
         let mut last = first;
         let mut upgrade_flag = true;
         // first byte is pack_type, next 2 bytes are length, the first seq_num takes at least 4
@@ -37,7 +73,7 @@ impl AckOrNack {
             let Some(seq_num) = sorted_seq_nums.next() else {
                 break;
             };
-            if seq_num == last + 1 {
+            if Uint24le(seq_num) == Uint24le(last).next() {
                 if upgrade_flag {
                     mtu -= 3;
                     upgrade_flag = false;
@@ -48,28 +84,28 @@ impl AckOrNack {
             mtu -= 4;
             upgrade_flag = true;
             if first != last {
-                records.push(Record::Range(Uint24le(first), Uint24le(last)));
+                emit(Record::Range(Uint24le(first), Uint24le(last)));
             } else {
-                records.push(Record::Single(Uint24le(first)));
+                emit(Record::Single(Uint24le(first)));
             }
             first = seq_num;
             last = seq_num;
         }
 
         if first != last {
-            records.push(Record::Range(Uint24le(first), Uint24le(last)));
+            emit(Record::Range(Uint24le(first), Uint24le(last)));
         } else {
-            records.push(Record::Single(Uint24le(first)));
+            emit(Record::Single(Uint24le(first)));
         }
 
-        Some(Self { records })
+        Some(())
     }
 
     pub(super) fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
         const MAX_ACKNOWLEDGEMENT_PACKETS: u32 = 8192;
 
         let mut ack_cnt = 0;
-        let record_cnt = buf.get_u16();
+        let record_cnt = read_buf!(buf, 2, buf.get_u16());
         let mut records = Vec::with_capacity(record_cnt as usize);
         for _ in 0..record_cnt {
             let record = Record::read(buf)?;
@@ -97,8 +133,9 @@ impl AckOrNack {
 const RECORD_RANGE: u8 = 0;
 const RECORD_SINGLE: u8 = 1;
 
-#[derive(Debug, PartialEq, Clone)]
-pub(crate) enum Record {
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Record {
     Range(Uint24le, Uint24le),
     Single(Uint24le),
 }
@@ -131,9 +168,19 @@ impl Record {
         }
     }
 
+    #[cfg(feature = "server")]
+    /// The sequence numbers covered by the record, a range may wrap around [`Uint24le::MAX`]
+    pub(crate) fn seq_nums(&self) -> impl Iterator<Item = u32> {
+        let (start, cnt) = match *self {
+            Record::Range(start, _) => (start, self.ack_cnt()),
+            Record::Single(single) => (single, 1),
+        };
+        (0..cnt).map(move |n| start.add(n).0)
+    }
+
     fn ack_cnt(&self) -> u32 {
         match self {
-            Record::Range(start, end) => end.0 - start.0 + 1,
+            Record::Range(start, end) => end.distance_from(*start) + 1,
             Record::Single(_) => 1,
         }
     }
@@ -175,4 +222,56 @@ mod test {
             assert_eq!(seq_nums.len(), remain);
         }
     }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_ack_write_from_same_as_extend_from() {
+        let mtu: u16 = 21;
+        let mut expect = BytesMut::with_capacity(mtu as usize);
+        let mut buf = BytesMut::with_capacity(mtu as usize);
+
+        let test_cases = [
+            vec![0, 1, 2, 4, 5, 7, 8],
+            vec![0, 1, 3, 4, 6, 7, 9],
+            vec![0, 2, 4, 6, 8, 10, 12],
+            vec![0, 2, 5, 6, 8, 9, 12],
+            vec![0, 1],
+            vec![0, 2, 3],
+            vec![0, 2, 4],
+        ];
+        for seq_nums in test_cases {
+            expect.clear();
+            let ack = AckOrNack::extend_from(seq_nums.clone().into_iter(), mtu).unwrap();
+            ack.write(&mut expect);
+
+            buf.clear();
+            let mut seq_nums = seq_nums.into_iter();
+            assert!(AckOrNack::write_from(&mut seq_nums, mtu, &mut buf));
+            assert_eq!(buf, expect);
+        }
+
+        buf.clear();
+        assert!(!AckOrNack::write_from(std::iter::empty(), mtu, &mut buf));
+        assert!(buf.is_empty());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_ack_range_wraps() {
+        let max = Uint24le::MAX;
+        let ack = AckOrNack::extend_from([max - 1, max, 0, 1, 3].into_iter(), 1400).unwrap();
+        assert_eq!(
+            ack.records,
+            vec![
+                Record::Range(Uint24le(max - 1), Uint24le(1)),
+                Record::Single(Uint24le(3))
+            ]
+        );
+        assert_eq!(ack.records[0].ack_cnt(), 4);
+        assert_eq!(
+            ack.records[0].seq_nums().collect::<Vec<_>>(),
+            vec![max - 1, max, 0, 1]
+        );
+        assert_eq!(ack.records[1].seq_nums().collect::<Vec<_>>(), vec![3]);
+    }
 }
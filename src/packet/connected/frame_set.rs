@@ -1,20 +1,30 @@
 use std::hash::{Hash, Hasher};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(feature = "server")]
+use bytes::Bytes;
+use bytes::{Buf, BufMut, BytesMut};
 
+#[cfg(feature = "server")]
+use super::overhead::{FRAGMENT_SIZE, FRAME_HEADER_SIZE, FRAME_INDEX_SIZE, ORDERED_SIZE};
 use super::Uint24le;
 use crate::errors::CodecError;
-use crate::packet::{PackType, SocketAddrRead, SocketAddrWrite, NEEDS_B_AND_AS_FLAG, PARTED_FLAG};
+#[cfg(feature = "server")]
+use crate::event::DisconnectReason;
+pub use crate::message::Reliability;
+#[cfg(feature = "server")]
+use crate::packet::{PackType, SocketAddrRead, SocketAddrWrite};
+use crate::packet::{NEEDS_B_AND_AS_FLAG, PARTED_FLAG};
 use crate::read_buf;
 
 #[derive(Eq, PartialEq, Clone)]
-pub(crate) struct Frame<B> {
-    pub(crate) flags: Flags,
-    pub(crate) reliable_frame_index: Option<Uint24le>,
-    pub(crate) seq_frame_index: Option<Uint24le>,
-    pub(crate) ordered: Option<Ordered>,
-    pub(crate) fragment: Option<Fragment>,
-    pub(crate) body: B,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Frame<B> {
+    pub flags: Flags,
+    pub reliable_frame_index: Option<Uint24le>,
+    pub seq_frame_index: Option<Uint24le>,
+    pub ordered: Option<Ordered>,
+    pub fragment: Option<Fragment>,
+    pub body: B,
 }
 
 impl<B> std::fmt::Debug for Frame<B> {
@@ -47,6 +57,7 @@ impl<B: Hash> Hash for Frame<B> {
 }
 
 impl Frame<BytesMut> {
+    #[cfg(feature = "server")]
     pub(crate) fn freeze(self) -> Frame<Bytes> {
         Frame {
             body: self.body.freeze(),
@@ -54,6 +65,7 @@ impl Frame<BytesMut> {
         }
     }
 
+    #[cfg(feature = "server")]
     /// Remove the parted flags & fragment payload
     pub(crate) fn reassembled(&mut self) {
         if !self.flags.parted {
@@ -107,8 +119,39 @@ impl Frame<BytesMut> {
     }
 }
 
+#[cfg(feature = "server")]
+impl<B> Frame<B> {
+    /// Whether the frame carries no reliable, sequenced or ordered index and is not parted, it
+    /// needs no bookkeeping of the reliability layers
+    pub(crate) fn is_fast_path(&self) -> bool {
+        self.reliable_frame_index.is_none()
+            && self.seq_frame_index.is_none()
+            && self.ordered.is_none()
+            && self.fragment.is_none()
+    }
+}
+
 impl<B: Buf> Frame<B> {
-    fn write(self, buf: &mut BytesMut) {
+    #[cfg(feature = "server")]
+    /// The size of this frame when encoded
+    pub(crate) fn size(&self) -> usize {
+        let mut size = FRAME_HEADER_SIZE;
+        if self.reliable_frame_index.is_some() {
+            size += FRAME_INDEX_SIZE;
+        }
+        if self.seq_frame_index.is_some() {
+            size += FRAME_INDEX_SIZE;
+        }
+        if self.ordered.is_some() {
+            size += ORDERED_SIZE;
+        }
+        if self.fragment.is_some() {
+            size += FRAGMENT_SIZE;
+        }
+        size + self.body.remaining()
+    }
+
+    pub(crate) fn write(self, buf: &mut BytesMut) {
         self.flags.write(buf);
         // length in bits
         // self.body will be split up so cast to u16 should not overflow here
@@ -134,9 +177,10 @@ impl<B: Buf> Frame<B> {
 }
 
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) struct FrameSet<B> {
-    pub(crate) seq_num: Uint24le,
-    pub(crate) frames: Vec<Frame<B>>,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameSet<B> {
+    pub seq_num: Uint24le,
+    pub frames: Vec<Frame<B>>,
 }
 
 impl FrameSet<BytesMut> {
@@ -152,7 +196,8 @@ impl FrameSet<BytesMut> {
         Ok(FrameSet { seq_num, frames })
     }
 
-    pub(super) fn freeze(self) -> FrameSet<Bytes> {
+    #[cfg(feature = "server")]
+    pub(crate) fn freeze(self) -> FrameSet<Bytes> {
         FrameSet {
             seq_num: self.seq_num,
             frames: self.frames.into_iter().map(Frame::freeze).collect(),
@@ -160,7 +205,16 @@ impl FrameSet<BytesMut> {
     }
 }
 
+#[cfg(feature = "server")]
+impl<B> FrameSet<B> {
+    /// Whether all the frames take the fast path, see [`Frame::is_fast_path`]
+    pub(crate) fn is_fast_path(&self) -> bool {
+        !self.frames.is_empty() && self.frames.iter().all(Frame::is_fast_path)
+    }
+}
+
 impl<B: Buf> FrameSet<B> {
+    #[cfg(feature = "server")]
     /// Get the inner packet type
     pub(crate) fn first_pack_type(&self) -> PackType {
         // len(frames) > 0 and len(chunks()) > 0
@@ -182,8 +236,13 @@ impl<B: Buf> FrameSet<B> {
 
 /// Top 3 bits are reliability type, fourth bit is 1 when the frame is fragmented and part of a
 /// compound.
-#[derive(Debug, Eq, PartialEq, Clone)]
-pub(crate) struct Flags {
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "u8", into = "u8")
+)]
+pub struct Flags {
     raw: u8,
     reliability: Reliability,
     parted: bool,
@@ -196,34 +255,6 @@ impl Hash for Flags {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Copy)]
-#[repr(u8)]
-pub(crate) enum Reliability {
-    /// Direct UDP
-    Unreliable = 0b000,
-
-    /// Ordered
-    UnreliableSequenced = 0b001,
-
-    /// Deduplicated
-    Reliable = 0b010,
-
-    /// Ordered + Deduplicated + Resend  (Most used)
-    ReliableOrdered = 0b011,
-
-    /// Ordered + Deduplicated
-    ReliableSequenced = 0b100,
-
-    /// Not used
-    UnreliableWithAckReceipt = 0b101,
-    UnreliableSequencedWithAckReceipt = 0b110,
-    ReliableWithAckReceipt = 0b111,
-
-    /// Defined but never be used (cannot be used).
-    ReliableOrderedWithAckReceipt = 0b1_000,
-    ReliableSequencedWithAckReceipt = 0b1_001,
-}
-
 impl Reliability {
     /// Reliable ensures that the packet is not duplicated.
     pub(crate) fn is_reliable(&self) -> bool {
@@ -273,38 +304,61 @@ impl Flags {
         buf.put_u8(self.raw);
     }
 
-    pub(crate) fn parse(raw: u8) -> Self {
+    /// Make the flags of a frame with the reliability, the parted flag is set if it is a part of
+    /// a split
+    pub fn new(reliability: Reliability, parted: bool) -> Self {
+        let mut raw = (reliability as u8) << 5;
+        if parted {
+            raw |= PARTED_FLAG;
+        }
+        Self::parse(raw)
+    }
+
+    pub fn parse(raw: u8) -> Self {
         let r = raw >> 5;
         // Safety:
         // It is checked before transmute
         Self {
             raw,
-            reliability: unsafe { std::mem::transmute(r) },
+            reliability: unsafe { std::mem::transmute::<u8, Reliability>(r) },
             parted: raw & PARTED_FLAG != 0,
             needs_bas: raw & NEEDS_B_AND_AS_FLAG != 0,
         }
     }
 
     /// Get the reliability of this flags
-    pub(crate) fn reliability(&self) -> Reliability {
+    pub fn reliability(&self) -> Reliability {
         self.reliability
     }
 
     /// Return if it is parted
-    pub(crate) fn parted(&self) -> bool {
+    pub fn parted(&self) -> bool {
         self.parted
     }
 
-    pub(crate) fn needs_bas(&self) -> bool {
+    pub fn needs_bas(&self) -> bool {
         self.needs_bas
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
-pub(crate) struct Fragment {
-    pub(crate) parted_size: u32,
-    pub(crate) parted_id: u16,
-    pub(crate) parted_index: u32,
+impl From<u8> for Flags {
+    fn from(raw: u8) -> Self {
+        Self::parse(raw)
+    }
+}
+
+impl From<Flags> for u8 {
+    fn from(flags: Flags) -> Self {
+        flags.raw
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fragment {
+    pub parted_size: u32,
+    pub parted_id: u16,
+    pub parted_index: u32,
 }
 
 impl Fragment {
@@ -323,10 +377,11 @@ impl Fragment {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
-pub(crate) struct Ordered {
-    pub(crate) frame_index: Uint24le,
-    pub(crate) channel: u8,
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ordered {
+    pub frame_index: Uint24le,
+    pub channel: u8,
 }
 
 impl Ordered {
@@ -343,6 +398,7 @@ impl Ordered {
     }
 }
 
+#[cfg(feature = "server")]
 #[derive(Clone)]
 pub(crate) enum FrameBody {
     ConnectedPing {
@@ -370,10 +426,11 @@ pub(crate) enum FrameBody {
         request_timestamp: i64,
         accepted_timestamp: i64,
     },
-    Disconnect,
+    Disconnect(DisconnectReason),
     Game(Bytes),
 }
 
+#[cfg(feature = "server")]
 impl std::fmt::Debug for FrameBody {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -425,48 +482,107 @@ impl std::fmt::Debug for FrameBody {
                 .field("request_timestamp", request_timestamp)
                 .field("accepted_timestamp", accepted_timestamp)
                 .finish(),
-            Self::Disconnect => write!(f, "Disconnect"),
+            Self::Disconnect(reason) => write!(f, "Disconnect({reason})"),
             Self::Game(data) => write!(f, "Game(data_size:{})", data.remaining()),
         }
     }
 }
 
+#[cfg(feature = "server")]
 impl FrameBody {
+    /// Read the body of a frame, the bodies not of the connected control packets are the user
+    /// data delivered as is, along with their ids.
     pub(crate) fn read(mut buf: Bytes) -> Result<Self, CodecError> {
-        let id = PackType::from_u8(buf.get_u8())?;
+        let id = read_buf!(buf, 1, buf[0]);
+        let id = match PackType::from_u8(id) {
+            Ok(
+                id @ (PackType::ConnectedPing
+                | PackType::ConnectedPong
+                | PackType::ConnectionRequest
+                | PackType::ConnectionRequestAccepted
+                | PackType::NewIncomingConnection
+                | PackType::DisconnectNotification),
+            ) => id,
+            _ => return Ok(Self::Game(buf)),
+        };
+        buf.advance(1);
         match id {
-            PackType::ConnectedPing => Ok(Self::ConnectedPing {
-                client_timestamp: buf.get_i64(),
-            }),
-            PackType::ConnectedPong => Ok(Self::ConnectedPong {
-                client_timestamp: buf.get_i64(),
-                server_timestamp: buf.get_i64(),
-            }),
-            PackType::ConnectionRequest => Ok(Self::ConnectionRequest {
-                client_guid: buf.get_u64(),
-                request_timestamp: buf.get_i64(),
-                use_encryption: buf.get_u8() != 0,
-            }),
-            PackType::ConnectionRequestAccepted => Ok(Self::ConnectionRequestAccepted {
-                client_address: buf.get_socket_addr()?,
-                system_index: buf.get_u16(),
-                system_addresses: todo!(),
-                request_timestamp: buf.get_i64(),
-                accepted_timestamp: buf.get_i64(),
-            }),
-            PackType::NewIncomingConnection => Ok(Self::NewIncomingConnection {
-                server_address: buf.get_socket_addr()?,
-                system_addresses: todo!(),
-                request_timestamp: buf.get_i64(),
-                accepted_timestamp: buf.get_i64(),
-            }),
-            PackType::DisconnectNotification => Ok(Self::Disconnect),
-            PackType::Game => Ok(Self::Game(buf)),
-            _ => Err(CodecError::InvalidPacketType(id.into())),
+            PackType::ConnectedPing => read_buf!(
+                buf,
+                8,
+                Ok(Self::ConnectedPing {
+                    client_timestamp: buf.get_i64(),
+                })
+            ),
+            PackType::ConnectedPong => read_buf!(
+                buf,
+                16,
+                Ok(Self::ConnectedPong {
+                    client_timestamp: buf.get_i64(),
+                    server_timestamp: buf.get_i64(),
+                })
+            ),
+            PackType::ConnectionRequest => read_buf!(
+                buf,
+                17,
+                Ok(Self::ConnectionRequest {
+                    client_guid: buf.get_u64(),
+                    request_timestamp: buf.get_i64(),
+                    use_encryption: buf.get_u8() != 0,
+                })
+            ),
+            PackType::ConnectionRequestAccepted => {
+                let client_address = buf.get_socket_addr()?;
+                let system_index = read_buf!(buf, 2, buf.get_u16());
+                let system_addresses = read_system_addresses(&mut buf)?;
+                read_buf!(
+                    buf,
+                    16,
+                    Ok(Self::ConnectionRequestAccepted {
+                        client_address,
+                        system_index,
+                        system_addresses,
+                        request_timestamp: buf.get_i64(),
+                        accepted_timestamp: buf.get_i64(),
+                    })
+                )
+            }
+            PackType::NewIncomingConnection => {
+                let server_address = buf.get_socket_addr()?;
+                let system_addresses = read_system_addresses(&mut buf)?;
+                read_buf!(
+                    buf,
+                    16,
+                    Ok(Self::NewIncomingConnection {
+                        server_address,
+                        system_addresses,
+                        request_timestamp: buf.get_i64(),
+                        accepted_timestamp: buf.get_i64(),
+                    })
+                )
+            }
+            PackType::DisconnectNotification => Ok(Self::Disconnect(read_reason(&mut buf))),
+            _ => unreachable!("checked above"),
+        }
+    }
+
+    pub(crate) fn pack_type(&self) -> PackType {
+        match self {
+            FrameBody::ConnectedPing { .. } => PackType::ConnectedPing,
+            FrameBody::ConnectedPong { .. } => PackType::ConnectedPong,
+            FrameBody::ConnectionRequest { .. } => PackType::ConnectionRequest,
+            FrameBody::ConnectionRequestAccepted { .. } => PackType::ConnectionRequestAccepted,
+            FrameBody::NewIncomingConnection { .. } => PackType::NewIncomingConnection,
+            FrameBody::Disconnect(_) => PackType::DisconnectNotification,
+            FrameBody::Game(_) => PackType::Game,
         }
     }
 
     pub(crate) fn write(self, buf: &mut BytesMut) {
+        // the user data carries its own id
+        if !matches!(self, FrameBody::Game(_)) {
+            buf.put_u8(self.pack_type().into());
+        }
         match self {
             FrameBody::ConnectedPing { client_timestamp } => {
                 buf.put_i64(client_timestamp);
@@ -515,10 +631,169 @@ impl FrameBody {
                 buf.put_i64(request_timestamp);
                 buf.put_i64(accepted_timestamp);
             }
-            FrameBody::Disconnect => {}
+            FrameBody::Disconnect(reason) => write_reason(reason, buf),
             FrameBody::Game(data) => {
                 buf.put(data);
             }
         }
     }
 }
+
+/// Read the system addresses followed by the two timestamps. The count of them differs among
+/// the implementations (e.g. 20 in Minecraft), the ones beyond 10 are skipped and the missing
+/// ones are unspecified.
+#[cfg(feature = "server")]
+fn read_system_addresses(buf: &mut Bytes) -> Result<[std::net::SocketAddr; 10], CodecError> {
+    let mut addrs = [std::net::SocketAddr::from(([0, 0, 0, 0], 0)); 10];
+    let mut idx = 0;
+    while buf.remaining() > 16 {
+        let addr = buf.get_socket_addr()?;
+        if let Some(slot) = addrs.get_mut(idx) {
+            *slot = addr;
+        }
+        idx += 1;
+    }
+    Ok(addrs)
+}
+
+#[cfg(feature = "server")]
+/// Read the reason appended to the `DisconnectNotification`, the unknown or missing reason is
+/// regarded as [`DisconnectReason::Closed`].
+fn read_reason(buf: &mut Bytes) -> DisconnectReason {
+    if !buf.has_remaining() {
+        return DisconnectReason::Closed;
+    }
+    match buf.get_u8() {
+        1 => DisconnectReason::Timeout,
+        2 => DisconnectReason::Kicked,
+        3 => DisconnectReason::ServerShutdown,
+        4 => DisconnectReason::Incompatible,
+        5 if buf.remaining() >= 2 => DisconnectReason::Application(buf.get_u16()),
+        6 => DisconnectReason::RetransmissionLimit,
+        7 => DisconnectReason::MemoryBudget,
+        8 => DisconnectReason::OrderedStalled,
+        9 => DisconnectReason::SplitsExhausted,
+        _ => DisconnectReason::Closed,
+    }
+}
+
+#[cfg(feature = "server")]
+fn write_reason(reason: DisconnectReason, buf: &mut BytesMut) {
+    match reason {
+        DisconnectReason::Closed => {}
+        DisconnectReason::Timeout => buf.put_u8(1),
+        DisconnectReason::Kicked => buf.put_u8(2),
+        DisconnectReason::ServerShutdown => buf.put_u8(3),
+        DisconnectReason::Incompatible => buf.put_u8(4),
+        DisconnectReason::Application(code) => {
+            buf.put_u8(5);
+            buf.put_u16(code);
+        }
+        DisconnectReason::RetransmissionLimit => buf.put_u8(6),
+        DisconnectReason::MemoryBudget => buf.put_u8(7),
+        DisconnectReason::OrderedStalled => buf.put_u8(8),
+        DisconnectReason::SplitsExhausted => buf.put_u8(9),
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn round_trip(body: FrameBody) -> FrameBody {
+        let mut buf = BytesMut::new();
+        body.write(&mut buf);
+        FrameBody::read(buf.freeze()).unwrap()
+    }
+
+    #[test]
+    fn test_disconnect_reason_round_trip() {
+        for reason in [
+            DisconnectReason::Closed,
+            DisconnectReason::Timeout,
+            DisconnectReason::Kicked,
+            DisconnectReason::ServerShutdown,
+            DisconnectReason::Incompatible,
+            DisconnectReason::Application(0),
+            DisconnectReason::Application(u16::MAX),
+            DisconnectReason::RetransmissionLimit,
+            DisconnectReason::MemoryBudget,
+            DisconnectReason::OrderedStalled,
+            DisconnectReason::SplitsExhausted,
+        ] {
+            let mut buf = BytesMut::new();
+            write_reason(reason, &mut buf);
+            assert_eq!(read_reason(&mut buf.freeze()), reason);
+            assert!(matches!(
+                round_trip(FrameBody::Disconnect(reason)),
+                FrameBody::Disconnect(read) if read == reason
+            ));
+        }
+        // the bare notification of the other implementations, the unknown reasons and the
+        // truncated application code
+        for raw in [&[][..], &[0xff], &[5, 1]] {
+            assert_eq!(
+                read_reason(&mut Bytes::copy_from_slice(raw)),
+                DisconnectReason::Closed
+            );
+        }
+    }
+
+    #[test]
+    fn test_frame_body_round_trip() {
+        let addr: SocketAddr = "1.2.3.4:19132".parse().unwrap();
+        let v6: SocketAddr = "[::1]:19133".parse().unwrap();
+        let mut system_addresses = [SocketAddr::from(([0, 0, 0, 0], 0)); 10];
+        system_addresses[0] = v6;
+        let bodies = [
+            FrameBody::ConnectedPing {
+                client_timestamp: 1,
+            },
+            FrameBody::ConnectedPong {
+                client_timestamp: 1,
+                server_timestamp: 2,
+            },
+            FrameBody::ConnectionRequest {
+                client_guid: 114_514,
+                request_timestamp: 3,
+                use_encryption: false,
+            },
+            FrameBody::ConnectionRequestAccepted {
+                client_address: addr,
+                system_index: 0,
+                system_addresses,
+                request_timestamp: 3,
+                accepted_timestamp: 4,
+            },
+            FrameBody::NewIncomingConnection {
+                server_address: addr,
+                system_addresses,
+                request_timestamp: 3,
+                accepted_timestamp: 4,
+            },
+            FrameBody::Game(Bytes::from_static(b"\xfehello")),
+            // the user packets of the other ids
+            FrameBody::Game(Bytes::from_static(b"\x86user")),
+        ];
+        for body in bodies {
+            let expected = format!("{body:?}");
+            assert_eq!(format!("{:?}", round_trip(body)), expected);
+        }
+    }
+
+    #[test]
+    fn test_frame_body_truncated() {
+        let mut buf = BytesMut::new();
+        FrameBody::ConnectionRequest {
+            client_guid: 114_514,
+            request_timestamp: 3,
+            use_encryption: false,
+        }
+        .write(&mut buf);
+        for len in 0..buf.len() {
+            assert!(FrameBody::read(buf.clone().freeze().slice(..len)).is_err());
+        }
+    }
+}
@@ -1,28 +1,35 @@
 use std::fmt::Display;
 use std::hash::Hash;
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(feature = "server")]
+use bytes::Bytes;
+use bytes::{Buf, BufMut, BytesMut};
 
 use crate::errors::CodecError;
+#[cfg(feature = "server")]
 use crate::packet::PackType;
 
 mod ack;
 mod frame_set;
+mod overhead;
 
-pub(crate) use ack::*;
-pub(crate) use frame_set::*;
+pub use ack::*;
+pub use frame_set::*;
+pub(crate) use overhead::*;
 
 use super::{ACK_FLAG, CONTINUOUS_SEND_FLAG, NACK_FLAG, NEEDS_B_AND_AS_FLAG, VALID_FLAG};
 
 // Packet when RakNet has established a connection
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum Packet<B> {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Packet<B> {
     FrameSet(FrameSet<B>),
     Ack(AckOrNack),
     Nack(AckOrNack),
 }
 
 impl<B> Packet<B> {
+    #[cfg(feature = "server")]
     pub(crate) fn pack_type(&self) -> PackType {
         match self {
             Packet::FrameSet(_) => PackType::FrameSet,
@@ -44,11 +51,7 @@ impl<B: Buf> Packet<B> {
     pub(super) fn write(self, buf: &mut BytesMut) {
         match self {
             Packet::FrameSet(frame) => {
-                let mut flag = VALID_FLAG | NEEDS_B_AND_AS_FLAG;
-                if frame.frames[0].flags.parted() {
-                    flag |= CONTINUOUS_SEND_FLAG;
-                }
-                buf.put_u8(flag);
+                put_frame_set_flag(buf, frame.frames[0].flags.parted());
                 frame.write(buf);
             }
             Packet::Ack(ack) => {
@@ -63,11 +66,22 @@ impl<B: Buf> Packet<B> {
     }
 }
 
+/// Write the flag of a frame set datagram, the continuous send flag is set if the first frame is
+/// parted.
+pub(crate) fn put_frame_set_flag(buf: &mut BytesMut, parted: bool) {
+    let mut flag = VALID_FLAG | NEEDS_B_AND_AS_FLAG;
+    if parted {
+        flag |= CONTINUOUS_SEND_FLAG;
+    }
+    buf.put_u8(flag);
+}
+
 impl Packet<BytesMut> {
     pub(super) fn read_frame_set(buf: &mut BytesMut) -> Result<Self, CodecError> {
         Ok(Packet::FrameSet(FrameSet::read(buf)?))
     }
 
+    #[cfg(feature = "server")]
     pub(crate) fn freeze(self) -> Packet<Bytes> {
         match self {
             Packet::FrameSet(frame_set) => Packet::FrameSet(frame_set.freeze()),
@@ -78,16 +92,56 @@ impl Packet<BytesMut> {
 }
 
 /// `uint24` little-endian but actually occupies 4 bytes.
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
-pub(crate) struct Uint24le(pub u32);
+/// It is a serial number (RFC 1982) which wraps to 0 after [`Uint24le::MAX`], compare and
+/// advance it with the methods below instead of the arithmetic of the inner `u32`. It is not
+/// `Ord` since the serial order is not transitive.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Uint24le(pub u32);
 
 impl Uint24le {
+    #[cfg(feature = "server")]
+    /// Half of the serial number space, a serial number is ahead of another if the distance
+    /// between them is less than it.
+    const HALF: u32 = 1 << 23;
+    /// The max value of the serial number
+    pub(crate) const MAX: u32 = 0x00ff_ffff;
+
+    #[cfg(any(test, feature = "server", feature = "interop", feature = "test-util"))]
+    /// Advance the serial number by n
+    #[must_use]
+    pub(crate) fn add(self, n: u32) -> Self {
+        Self(self.0.wrapping_add(n) & Self::MAX)
+    }
+
+    #[cfg(any(test, feature = "server", feature = "interop", feature = "test-util"))]
+    /// The next serial number
+    #[must_use]
+    pub(crate) fn next(self) -> Self {
+        self.add(1)
+    }
+
+    /// The count of steps advancing from `earlier` to self
+    pub(crate) fn distance_from(self, earlier: Self) -> u32 {
+        self.0.wrapping_sub(earlier.0) & Self::MAX
+    }
+
+    #[cfg(feature = "server")]
+    /// Compare the serial numbers with wrapping, e.g. 0 is greater than [`Uint24le::MAX`].
+    pub(crate) fn serial_cmp(self, other: Self) -> std::cmp::Ordering {
+        match self.distance_from(other) {
+            0 => std::cmp::Ordering::Equal,
+            d if d < Self::HALF => std::cmp::Ordering::Greater,
+            _ => std::cmp::Ordering::Less,
+        }
+    }
+
     fn read(buf: &mut BytesMut) -> Self {
         // safe cast because only 3 bytes will not overflow
         Self(buf.get_uint_le(3) as u32)
     }
 
-    fn write(self, buf: &mut BytesMut) {
+    pub(crate) fn write(self, buf: &mut BytesMut) {
         buf.put_uint_le(self.0 as u64, 3);
     }
 }
@@ -97,3 +151,35 @@ impl Display for Uint24le {
         write!(f, "{}", self.0)
     }
 }
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use std::cmp::Ordering;
+
+    use super::*;
+
+    #[test]
+    fn test_uint24le_wraps() {
+        let max = Uint24le(Uint24le::MAX);
+        assert_eq!(max.next(), Uint24le(0));
+        assert_eq!(max.add(3), Uint24le(2));
+        assert_eq!(Uint24le(5).add(Uint24le::MAX), Uint24le(4));
+
+        assert_eq!(Uint24le(0).distance_from(max), 1);
+        assert_eq!(Uint24le(2).distance_from(Uint24le(Uint24le::MAX - 1)), 4);
+        assert_eq!(max.distance_from(Uint24le(0)), Uint24le::MAX);
+
+        assert_eq!(Uint24le(0).serial_cmp(max), Ordering::Greater);
+        assert_eq!(max.serial_cmp(Uint24le(0)), Ordering::Less);
+        assert_eq!(max.serial_cmp(max), Ordering::Equal);
+        // the half space boundary
+        assert_eq!(
+            Uint24le(Uint24le::HALF - 1).serial_cmp(Uint24le(0)),
+            Ordering::Greater
+        );
+        assert_eq!(
+            Uint24le(Uint24le::HALF).serial_cmp(Uint24le(0)),
+            Ordering::Less
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! The sizes of the headers in a raknet datagram, from which the usable payload per datagram is
+//! derived. Both the fragmentation and the coalescing of frames should size their datagrams
+//! here rather than with their own constants.
+
+#[cfg(any(feature = "server", feature = "interop"))]
+use super::Reliability;
+
+/// The IPv4 (20 bytes) and UDP (8 bytes) headers, which are counted in the raknet mtu
+pub(crate) const UDP_HEADER_SIZE: usize = 20 + 8;
+
+#[cfg(any(feature = "server", feature = "interop"))]
+/// The size of the frame set header, flag (1 byte) + sequence number (3 bytes)
+pub(crate) const FRAME_SET_HEADER_SIZE: usize = 1 + 3;
+
+#[cfg(any(feature = "server", feature = "interop"))]
+/// The size of the fixed frame header, flags (1 byte) + length (2 bytes)
+pub(crate) const FRAME_HEADER_SIZE: usize = 1 + 2;
+
+#[cfg(any(feature = "server", feature = "interop"))]
+/// The size of the reliable or sequenced frame index
+pub(crate) const FRAME_INDEX_SIZE: usize = 3;
+
+#[cfg(any(feature = "server", feature = "interop"))]
+/// The size of the ordered fields, frame index (3 bytes) + channel (1 byte)
+pub(crate) const ORDERED_SIZE: usize = 3 + 1;
+
+#[cfg(any(feature = "server", feature = "interop"))]
+/// The size of the fragment fields, parted size (4 bytes) + parted id (2 bytes) + parted index
+/// (4 bytes)
+pub(crate) const FRAGMENT_SIZE: usize = 4 + 2 + 4;
+
+#[cfg(any(feature = "server", feature = "interop"))]
+/// The header size of a frame of the reliability, with the fragment fields if it is parted
+pub(crate) fn frame_header_size(reliability: Reliability, parted: bool) -> usize {
+    let mut size = FRAME_HEADER_SIZE;
+    if reliability.is_reliable() {
+        size += FRAME_INDEX_SIZE;
+    }
+    if reliability.is_sequenced() {
+        size += FRAME_INDEX_SIZE;
+    }
+    if reliability.is_sequenced_or_ordered() {
+        size += ORDERED_SIZE;
+    }
+    if parted {
+        size += FRAGMENT_SIZE;
+    }
+    size
+}
+
+/// The max size of the datagram sent over UDP with the mtu
+pub(crate) fn max_datagram_size(mtu: u16) -> usize {
+    usize::from(mtu).saturating_sub(UDP_HEADER_SIZE)
+}
+
+#[cfg(any(feature = "server", feature = "interop"))]
+/// The max size of the frames coalesced into a frame set with the mtu
+pub(crate) fn max_frames_size(mtu: u16) -> usize {
+    max_datagram_size(mtu).saturating_sub(FRAME_SET_HEADER_SIZE)
+}
+
+#[cfg(any(feature = "server", feature = "interop"))]
+/// The max body of a frame of the reliability which fits in a datagram alone with the mtu, the
+/// larger bodies have to be split into the parts of `max_body_size(mtu, reliability, true)`.
+pub(crate) fn max_body_size(mtu: u16, reliability: Reliability, parted: bool) -> usize {
+    max_frames_size(mtu).saturating_sub(frame_header_size(reliability, parted))
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::packet::connected::{Flags, Fragment, Frame, Ordered, Uint24le};
+    use crate::packet::PARTED_FLAG;
+
+    fn frame(reliability: Reliability, parted: bool, body: usize) -> Frame<Bytes> {
+        let mut raw = (reliability as u8) << 5;
+        if parted {
+            raw |= PARTED_FLAG;
+        }
+        Frame {
+            flags: Flags::parse(raw),
+            reliable_frame_index: reliability.is_reliable().then_some(Uint24le(0)),
+            seq_frame_index: reliability.is_sequenced().then_some(Uint24le(0)),
+            ordered: reliability.is_sequenced_or_ordered().then_some(Ordered {
+                frame_index: Uint24le(0),
+                channel: 0,
+            }),
+            fragment: parted.then_some(Fragment {
+                parted_size: 2,
+                parted_id: 0,
+                parted_index: 0,
+            }),
+            body: Bytes::from(vec![0; body]),
+        }
+    }
+
+    #[test]
+    fn test_frame_header_size() {
+        for reliability in [
+            Reliability::Unreliable,
+            Reliability::UnreliableSequenced,
+            Reliability::Reliable,
+            Reliability::ReliableOrdered,
+            Reliability::ReliableSequenced,
+        ] {
+            for parted in [false, true] {
+                assert_eq!(
+                    frame(reliability, parted, 0).size(),
+                    frame_header_size(reliability, parted),
+                    "{reliability:?} parted {parted}"
+                );
+            }
+        }
+        assert_eq!(frame_header_size(Reliability::Unreliable, false), 3);
+        assert_eq!(frame_header_size(Reliability::ReliableOrdered, false), 10);
+        assert_eq!(frame_header_size(Reliability::ReliableSequenced, true), 23);
+    }
+
+    #[test]
+    fn test_max_body_size_boundary() {
+        for mtu in [576, 1200, 1400, 1492, 1500] {
+            for reliability in [Reliability::Unreliable, Reliability::ReliableOrdered] {
+                for parted in [false, true] {
+                    let max = max_body_size(mtu, reliability, parted);
+                    let fit = frame(reliability, parted, max);
+                    assert_eq!(FRAME_SET_HEADER_SIZE + fit.size(), max_datagram_size(mtu));
+                    let over = frame(reliability, parted, max + 1);
+                    assert!(FRAME_SET_HEADER_SIZE + over.size() > max_datagram_size(mtu));
+                }
+            }
+        }
+        assert_eq!(
+            max_body_size(1492, Reliability::ReliableOrdered, true),
+            1440
+        );
+    }
+
+    #[test]
+    fn test_tiny_mtu_saturates() {
+        assert_eq!(max_datagram_size(20), 0);
+        assert_eq!(max_frames_size(30), 0);
+        assert_eq!(max_body_size(40, Reliability::ReliableOrdered, true), 0);
+    }
+}
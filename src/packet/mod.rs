@@ -1,11 +1,15 @@
-pub(crate) mod connected;
-pub(crate) mod unconnected;
+pub mod connected;
+pub mod unconnected;
+#[cfg(any(test, feature = "server", feature = "client"))]
+pub(crate) mod version;
+
+#[cfg(test)]
+mod conformance;
 
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 
-use self::connected::{Frame, FrameSet};
 use crate::errors::CodecError;
 
 #[macro_export]
@@ -22,7 +26,7 @@ const VALID_FLAG: u8 = 0b1000_0000;
 const ACK_FLAG: u8 = 0b1100_0000;
 const NACK_FLAG: u8 = 0b1010_0000;
 
-const PARTED_FLAG: u8 = 0b0001_0000;
+pub(crate) const PARTED_FLAG: u8 = 0b0001_0000;
 const CONTINUOUS_SEND_FLAG: u8 = 0b0000_1000;
 const NEEDS_B_AND_AS_FLAG: u8 = 0b0000_0100;
 
@@ -31,8 +35,9 @@ const NEEDS_B_AND_AS_FLAG: u8 = 0b0000_0100;
 /// others are encapsulated in a `FrameSet` data packet and appear as the first byte of the body
 /// (like `Game`).
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
-pub(crate) enum PackType {
+pub enum PackType {
     ConnectedPing = 0x00,
     UnconnectedPing1 = 0x01,
     UnconnectedPing2 = 0x02,
@@ -79,11 +84,14 @@ impl PackType {
             0x08 => Ok(PackType::OpenConnectionReply2),
             0x09 => Ok(PackType::ConnectionRequest),
             0x10 => Ok(PackType::ConnectionRequestAccepted),
+            0x11 => Ok(PackType::ConnectionRequestFailed),
             0x12 => Ok(PackType::AlreadyConnected),
             0x13 => Ok(PackType::NewIncomingConnection),
+            0x14 => Ok(PackType::NoFreeIncomingConnections),
             0x15 => Ok(PackType::DisconnectNotification),
             0x19 => Ok(PackType::IncompatibleProtocolVersion),
             0x1c => Ok(PackType::UnconnectedPong),
+            0x1d => Ok(PackType::AdvertiseSystem),
             0xfe => Ok(PackType::Game),
             ACK_FLAG.. => Ok(PackType::Ack),
             NACK_FLAG.. => Ok(PackType::Nack),
@@ -92,14 +100,6 @@ impl PackType {
         }
     }
 
-    /// Check if it is unconnected ping
-    pub(crate) fn is_unconnected_ping(&self) -> bool {
-        matches!(
-            self,
-            PackType::UnconnectedPing1 | PackType::UnconnectedPing2
-        )
-    }
-
     /// Check if it is a frame set packet
     pub(crate) fn is_frame_set(&self) -> bool {
         matches!(self, PackType::FrameSet)
@@ -132,11 +132,13 @@ impl From<PackType> for u8 {
 
 /// Raknet packet
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum Packet<B> {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Packet<B> {
     Unconnected(unconnected::Packet),
     Connected(connected::Packet<B>),
 }
 
+#[cfg(feature = "server")]
 impl<B> Packet<B> {
     /// Get the packet type
     pub(crate) fn pack_type(&self) -> PackType {
@@ -187,7 +189,7 @@ impl Packet<BytesMut> {
             PackType::OpenConnectionRequest1 => {
                 read_buf!(
                     buf,
-                    19,
+                    17,
                     unconnected::Packet::read_open_connection_request1(buf)
                 )
             }
@@ -208,10 +210,25 @@ impl Packet<BytesMut> {
             PackType::AlreadyConnected => {
                 read_buf!(buf, 24, unconnected::Packet::read_already_connected(buf))
             }
+            PackType::ConnectionRequestFailed => {
+                read_buf!(
+                    buf,
+                    24,
+                    unconnected::Packet::read_connection_request_failed(buf)
+                )
+            }
+            PackType::NoFreeIncomingConnections => {
+                read_buf!(
+                    buf,
+                    24,
+                    unconnected::Packet::read_no_free_incoming_connections(buf)
+                )
+            }
             PackType::OpenConnectionRequest2 => {
                 unconnected::Packet::read_open_connection_request2(buf)
             }
             PackType::OpenConnectionReply2 => unconnected::Packet::read_open_connection_reply2(buf),
+            PackType::AdvertiseSystem => unconnected::Packet::read_advertise_system(buf),
             _ => Err(CodecError::InvalidPacketType(pack_type.into())),
         }
         .map(|packet| Some(Self::Unconnected(packet)))
@@ -219,7 +236,7 @@ impl Packet<BytesMut> {
 }
 
 /// Magic sequence is a sequence of bytes which is found in every unconnected message sent in Raknet
-pub(crate) const MAGIC: [u8; 16] = [
+pub const MAGIC: [u8; 16] = [
     0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
 ];
 
@@ -253,6 +270,11 @@ impl<B: BufMut> MagicWrite for B {
     }
 }
 
+/// `AF_INET6` written by the reference implementation on Windows
+const AF_INET6_WINDOWS: u16 = 0x17;
+/// `AF_INET6` written by the reference implementation on Linux
+const AF_INET6_LINUX: u16 = 0x0a;
+
 pub(crate) trait SocketAddrRead {
     fn get_socket_addr(&mut self) -> Result<SocketAddr, CodecError>;
 }
@@ -266,25 +288,27 @@ impl<B: Buf> SocketAddrRead for B {
         let ver = read_buf!(self, 1, self.get_u8());
         match ver {
             4 => {
+                // the octets are complemented by the reference implementation
                 read_buf!(self, 6, {
-                    let ip = Ipv4Addr::from_bits(self.get_u32());
+                    let ip = Ipv4Addr::from(!self.get_u32());
                     let port = self.get_u16();
                     Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
                 })
             }
             6 => {
-                // TODO: to be determined
+                // the raw `sockaddr_in6` of the sender, the family and the scope id are in the
+                // host byte order (little endian), and the rest are in the network byte order
                 read_buf!(self, 28, {
-                    let family = self.get_u16();
-                    if family != 0x17 {
+                    let family = self.get_u16_le();
+                    if family != AF_INET6_WINDOWS && family != AF_INET6_LINUX {
                         return Err(CodecError::InvalidIPV6Family(family));
                     }
                     let port = self.get_u16();
                     let flow_info = self.get_u32();
-                    let ip = Ipv6Addr::from_bits(self.get_u128());
-                    let scope_ip = self.get_u32();
+                    let ip = Ipv6Addr::from(self.get_u128());
+                    let scope_id = self.get_u32_le();
                     Ok(SocketAddr::V6(SocketAddrV6::new(
-                        ip, port, flow_info, scope_ip,
+                        ip, port, flow_info, scope_id,
                     )))
                 })
             }
@@ -298,16 +322,16 @@ impl<B: BufMut> SocketAddrWrite for B {
         match addr {
             SocketAddr::V4(v4) => {
                 self.put_u8(4);
-                self.put_slice(&v4.ip().octets());
+                self.put_u32(!u32::from(*v4.ip()));
                 self.put_u16(v4.port());
             }
             SocketAddr::V6(v6) => {
                 self.put_u8(6);
-                self.put_u16(0x17);
+                self.put_u16_le(AF_INET6_WINDOWS);
                 self.put_u16(v6.port());
                 self.put_u32(v6.flowinfo());
                 self.put_slice(&v6.ip().octets());
-                self.put_u32(v6.scope_id());
+                self.put_u32_le(v6.scope_id());
             }
         }
     }
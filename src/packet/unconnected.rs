@@ -3,12 +3,14 @@ use std::net::SocketAddr;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::errors::CodecError;
-use crate::packet::{MagicRead, MagicWrite, PackType, SocketAddrRead, SocketAddrWrite};
+use crate::packet::connected::{max_datagram_size, UDP_HEADER_SIZE};
+use crate::packet::{MagicRead, MagicWrite, PackType, SocketAddrRead, SocketAddrWrite, MAGIC};
 use crate::read_buf;
 
 /// Request sent before establishing a connection
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum Packet {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Packet {
     UnconnectedPing {
         send_timestamp: i64,
         magic: (),
@@ -36,6 +38,9 @@ pub(crate) enum Packet {
         server_address: SocketAddr,
         mtu: u16,
         client_guid: u64,
+        /// Appended by a client continuing its connection from a new address, the token issued
+        /// by the `OpenConnectionReply2` to its previous address
+        migration_token: Option<u64>,
     },
     OpenConnectionReply2 {
         magic: (),
@@ -43,6 +48,9 @@ pub(crate) enum Packet {
         client_address: SocketAddr,
         mtu: u16,
         encryption_enabled: bool,
+        /// Appended by a server allowing the migration, the token proving the ownership of the
+        /// connection to this address
+        migration_token: Option<u64>,
     },
     IncompatibleProtocol {
         server_protocol: u8,
@@ -57,6 +65,14 @@ pub(crate) enum Packet {
         magic: (),
         server_guid: u64,
     },
+    NoFreeIncomingConnections {
+        magic: (),
+        server_guid: u64,
+    },
+    /// Unconnected user message, it is sent without magic like the original implementation
+    AdvertiseSystem {
+        data: Bytes,
+    },
 }
 
 impl Packet {
@@ -76,6 +92,8 @@ impl Packet {
             Packet::IncompatibleProtocol { .. } => PackType::IncompatibleProtocolVersion,
             Packet::AlreadyConnected { .. } => PackType::AlreadyConnected,
             Packet::ConnectionRequestFailed { .. } => PackType::ConnectionRequestFailed,
+            Packet::NoFreeIncomingConnections { .. } => PackType::NoFreeIncomingConnections,
+            Packet::AdvertiseSystem { .. } => PackType::AdvertiseSystem,
         }
     }
 
@@ -97,10 +115,16 @@ impl Packet {
     }
 
     pub(super) fn read_open_connection_request1(buf: &mut BytesMut) -> Result<Self, CodecError> {
+        buf.get_checked_magic()?; // 16
+        let protocol_version = buf.get_u8(); // 1
+                                             // the mtu is the length of the datagram (the id, the magic, the version and the padding)
+                                             // plus the IP and UDP headers
+        let padding = buf.split().len();
+        let mtu = (1 + MAGIC.len() + 1 + padding + UDP_HEADER_SIZE).min(usize::from(u16::MAX));
         Ok(Packet::OpenConnectionRequest1 {
-            magic: buf.get_checked_magic()?, // 16
-            protocol_version: buf.get_u8(),  // 1
-            mtu: buf.get_u16(),              // 2
+            magic: (),
+            protocol_version,
+            mtu: mtu as u16,
         })
     }
 
@@ -119,6 +143,7 @@ impl Packet {
             server_address: buf.get_socket_addr()?,
             mtu: read_buf!(buf, 2, buf.get_u16()),
             client_guid: read_buf!(buf, 8, buf.get_u64()),
+            migration_token: (buf.remaining() >= 8).then(|| buf.get_u64()),
         })
     }
 
@@ -129,6 +154,7 @@ impl Packet {
             client_address: buf.get_socket_addr()?,
             mtu: read_buf!(buf, 2, buf.get_u16()),
             encryption_enabled: read_buf!(buf, 1, buf.get_u8() != 0),
+            migration_token: (buf.remaining() >= 8).then(|| buf.get_u64()),
         })
     }
 
@@ -147,6 +173,28 @@ impl Packet {
         })
     }
 
+    pub(super) fn read_connection_request_failed(buf: &mut BytesMut) -> Result<Self, CodecError> {
+        Ok(Packet::ConnectionRequestFailed {
+            magic: buf.get_checked_magic()?, // 16
+            server_guid: buf.get_u64(),      // 8
+        })
+    }
+
+    pub(super) fn read_no_free_incoming_connections(
+        buf: &mut BytesMut,
+    ) -> Result<Self, CodecError> {
+        Ok(Packet::NoFreeIncomingConnections {
+            magic: buf.get_checked_magic()?, // 16
+            server_guid: buf.get_u64(),      // 8
+        })
+    }
+
+    pub(super) fn read_advertise_system(buf: &mut BytesMut) -> Result<Self, CodecError> {
+        Ok(Packet::AdvertiseSystem {
+            data: buf.split().freeze(),
+        })
+    }
+
     pub(super) fn write(self, buf: &mut BytesMut) {
         // Fixed id (type)
         buf.put_u8(self.pack_type().into());
@@ -178,7 +226,11 @@ impl Packet {
             } => {
                 buf.put_magic();
                 buf.put_u8(protocol_version);
-                buf.put_u16(mtu);
+                // pad the datagram to the mtu minus the IP and UDP headers
+                buf.put_bytes(
+                    0,
+                    max_datagram_size(mtu).saturating_sub(1 + MAGIC.len() + 1),
+                );
             }
             Packet::OpenConnectionReply1 {
                 magic: _magic,
@@ -196,11 +248,15 @@ impl Packet {
                 server_address,
                 mtu,
                 client_guid,
+                migration_token,
             } => {
                 buf.put_magic();
                 buf.put_socket_addr(server_address);
                 buf.put_u16(mtu);
                 buf.put_u64(client_guid);
+                if let Some(token) = migration_token {
+                    buf.put_u64(token);
+                }
             }
             Packet::OpenConnectionReply2 {
                 magic: _magic,
@@ -208,12 +264,16 @@ impl Packet {
                 client_address,
                 mtu,
                 encryption_enabled: _encryption_enabled,
+                migration_token,
             } => {
                 buf.put_magic();
                 buf.put_u64(server_guid);
                 buf.put_socket_addr(client_address);
                 buf.put_u16(mtu);
                 buf.put_u8(0);
+                if let Some(token) = migration_token {
+                    buf.put_u64(token);
+                }
             }
             Packet::IncompatibleProtocol {
                 server_protocol,
@@ -238,6 +298,16 @@ impl Packet {
                 buf.put_magic();
                 buf.put_u64(server_guid);
             }
+            Packet::NoFreeIncomingConnections {
+                magic: _magic,
+                server_guid,
+            } => {
+                buf.put_magic();
+                buf.put_u64(server_guid);
+            }
+            Packet::AdvertiseSystem { data } => {
+                buf.put(data);
+            }
         }
     }
 }
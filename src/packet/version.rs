@@ -0,0 +1,108 @@
+//! The registry of raknet protocol versions. The handshake and the codecs consult the
+//! capabilities here rather than comparing the version numbers, so supporting a new version only
+//! adds an entry to [`PROTOCOL_VERSIONS`].
+
+use std::ops::BitOr;
+
+/// The features of a protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Capabilities(u8);
+
+impl Capabilities {
+    /// The addresses in the handshake could be IPv6
+    pub(crate) const IPV6: Self = Self(1 << 1);
+    /// The mtu is negotiated by the padding of `OpenConnectionRequest1`
+    pub(crate) const MTU_PADDING: Self = Self(1);
+
+    pub(crate) const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    #[cfg(any(test, feature = "server"))]
+    pub(crate) fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+/// A protocol version and its capabilities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ProtocolVersion {
+    pub(crate) version: u8,
+    pub(crate) capabilities: Capabilities,
+}
+
+/// The protocol versions known by this crate, sorted by version
+pub(crate) const PROTOCOL_VERSIONS: [ProtocolVersion; 3] = [
+    ProtocolVersion {
+        version: 9,
+        capabilities: Capabilities::MTU_PADDING.union(Capabilities::IPV6),
+    },
+    ProtocolVersion {
+        version: 10,
+        capabilities: Capabilities::MTU_PADDING.union(Capabilities::IPV6),
+    },
+    ProtocolVersion {
+        version: 11,
+        capabilities: Capabilities::MTU_PADDING.union(Capabilities::IPV6),
+    },
+];
+
+/// The latest known protocol version
+#[cfg(any(test, feature = "client"))]
+pub(crate) const LATEST_PROTOCOL_VERSION: u8 =
+    PROTOCOL_VERSIONS[PROTOCOL_VERSIONS.len() - 1].version;
+
+#[cfg(any(test, feature = "server"))]
+/// Look up the known protocol version
+pub(crate) fn lookup(version: u8) -> Option<ProtocolVersion> {
+    PROTOCOL_VERSIONS
+        .binary_search_by_key(&version, |known| known.version)
+        .ok()
+        .map(|idx| PROTOCOL_VERSIONS[idx])
+}
+
+#[cfg(any(test, feature = "server"))]
+/// Whether the protocol version is known and has all the capabilities
+pub(crate) fn supports(version: u8, capabilities: Capabilities) -> bool {
+    lookup(version).is_some_and(|known| known.capabilities.contains(capabilities))
+}
+
+#[cfg(any(test, feature = "server"))]
+/// All the known protocol versions, sorted
+pub(crate) fn known_versions() -> Vec<u8> {
+    PROTOCOL_VERSIONS
+        .iter()
+        .map(|known| known.version)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_protocol_versions_sorted() {
+        assert!(PROTOCOL_VERSIONS
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+        assert_eq!(known_versions(), vec![9, 10, 11]);
+        assert_eq!(LATEST_PROTOCOL_VERSION, 11);
+    }
+
+    #[test]
+    fn test_capabilities_lookup() {
+        assert!(supports(11, Capabilities::IPV6));
+        assert!(supports(10, Capabilities::MTU_PADDING | Capabilities::IPV6));
+        assert!(!supports(8, Capabilities::MTU_PADDING));
+        assert_eq!(lookup(12), None);
+        assert!(!Capabilities::IPV6.contains(Capabilities::MTU_PADDING | Capabilities::IPV6));
+    }
+}
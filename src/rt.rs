@@ -1,3 +1,6 @@
+#[cfg(feature = "server")]
+use std::net::SocketAddr;
+
 use futures::Future;
 
 /// Task runtime abstraction
@@ -6,6 +9,56 @@ pub trait Runtime<T: Future> {
 
     /// Spawn a task
     fn spawn(fut: T) -> Self::Output;
+
+    /// Spawn a named task, the name is shown by the task inspectors like tokio-console
+    fn spawn_named(name: &str, fut: T) -> Self::Output {
+        let _ = name;
+        Self::spawn(fut)
+    }
+}
+
+#[cfg(feature = "server")]
+/// Names of the spawned tasks, embedders could prefix them to tell the tasks of different
+/// servers apart.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TaskNaming {
+    prefix: String,
+}
+
+#[cfg(feature = "server")]
+impl TaskNaming {
+    /// Prefix the names of all spawned tasks
+    pub fn with_prefix(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+
+    /// The task receiving datagrams from the socket
+    pub(crate) fn receive_loop(&self) -> String {
+        format!("{}raknet-recv", self.prefix)
+    }
+
+    /// The task driving a connection
+    pub(crate) fn connection(&self, peer: SocketAddr) -> String {
+        format!("{}raknet-conn-{peer}", self.prefix)
+    }
+
+    /// The task answering the pings on a secondary address
+    pub(crate) fn secondary_pong(&self, addr: SocketAddr) -> String {
+        format!("{}raknet-pong-{addr}", self.prefix)
+    }
+
+    /// The thread of a server shard bound by `ServerBuilder::bind_sharded`
+    pub(crate) fn shard(&self, index: usize) -> String {
+        format!("{}raknet-shard-{index}", self.prefix)
+    }
+
+    /// The task driving the connections in `TaskMode::Shared`
+    pub(crate) fn shared_driver(&self, index: usize) -> String {
+        format!("{}raknet-driver-{index}", self.prefix)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -34,4 +87,39 @@ where
     fn spawn(fut: T) -> Self::Output {
         tokio::spawn(fut)
     }
+
+    fn spawn_named(name: &str, fut: T) -> Self::Output {
+        use tracing::Instrument;
+
+        let fut = fut.instrument(tracing::info_span!("task", name));
+        // tokio only names the tasks with `--cfg tokio_unstable`
+        #[cfg(all(feature = "console", tokio_unstable))]
+        {
+            tokio::task::Builder::new()
+                .name(name)
+                .spawn(fut)
+                .expect("failed to spawn task")
+        }
+        #[cfg(not(all(feature = "console", tokio_unstable)))]
+        {
+            tokio::spawn(fut)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_task_naming() {
+        let naming = TaskNaming::with_prefix("lobby-");
+        assert_eq!(naming.receive_loop(), "lobby-raknet-recv");
+        assert_eq!(
+            naming.connection("127.0.0.1:19132".parse().unwrap()),
+            "lobby-raknet-conn-127.0.0.1:19132"
+        );
+        assert_eq!(TaskNaming::default().shared_driver(0), "raknet-driver-0");
+        assert_eq!(naming.shard(1), "lobby-raknet-shard-1");
+    }
 }
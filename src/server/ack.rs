@@ -1,77 +1,308 @@
-use std::collections::HashMap;
-use std::os::unix::net::SocketAddr;
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-use bytes::Bytes;
-use futures::{ready, Stream, StreamExt};
-use pin_project_lite::pin_project;
+use bytes::{BufMut, BytesMut};
 
-use crate::packet::connected::{self, AckOrNack, FrameSet};
+use crate::errors::ConfigError;
+use crate::event::Event;
+use crate::packet::connected::AckOrNack;
+use crate::packet::PackType;
+use crate::stats::CongestionStats;
 
-pin_project! {
-    struct AckHandler<F> {
-        #[pin]
-        frame: F,
-        resending: HashMap<u32, FrameSet<Bytes>>
+/// The lower bound of the retransmission timeout. The peer will resend the datagrams which have
+/// been received if the acks are delayed longer than it.
+pub(super) const MIN_RTO: Duration = Duration::from_millis(100);
+
+/// Acknowledgement policy
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct AckConfig {
+    // The max delay before flushing the pending acks
+    flush_delay: Duration,
+    // Limit the max count of sequence numbers acknowledged in a datagram, 0 means it is limited
+    // by mtu only
+    max_acks_per_datagram: usize,
+    // Whether the pending acks may be flushed along with outgoing data before flush_delay
+    piggyback: bool,
+}
+
+impl AckConfig {
+    /// Set the max delay before flushing the pending acks
+    #[must_use]
+    pub fn with_flush_delay(mut self, flush_delay: Duration) -> Self {
+        self.flush_delay = flush_delay;
+        self
+    }
+
+    /// Limit the max count of sequence numbers acknowledged in a datagram, 0 means it is limited
+    /// by mtu only
+    #[must_use]
+    pub fn with_max_acks_per_datagram(mut self, max_acks_per_datagram: usize) -> Self {
+        self.max_acks_per_datagram = max_acks_per_datagram;
+        self
+    }
+
+    /// Whether the pending acks may be flushed along with outgoing data before the flush delay
+    #[must_use]
+    pub fn with_piggyback(mut self, piggyback: bool) -> Self {
+        self.piggyback = piggyback;
+        self
+    }
+
+    /// The acks must be flushed before the peer's retransmission timeout, bounded below by
+    /// `min_rto`
+    pub(super) fn validate(&self, min_rto: Duration) -> Result<(), ConfigError> {
+        if self.flush_delay >= min_rto {
+            return Err(ConfigError::AckDelayExceedsRto(self.flush_delay, min_rto));
+        }
+        Ok(())
     }
 }
 
-impl<F> AckHandler<F> {
-    fn on_ack(&mut self, ack: AckOrNack) {
-        for record in ack.records {
-            let (start, end) = match record {
-                connected::Record::Range(start, end) => (start.0, end.0),
-                connected::Record::Single(single) => (single.0, single.0),
-            };
-            for seq_num in start..=end {
-                self.resending.remove(&seq_num);
-            }
+impl Default for AckConfig {
+    fn default() -> Self {
+        Self {
+            flush_delay: Duration::from_millis(10),
+            max_acks_per_datagram: 0,
+            piggyback: true,
         }
     }
 }
 
-impl<F> Stream for AckHandler<F>
-where
-    F: Stream<Item = (connected::Packet<Bytes>, SocketAddr)>,
-{
-    type Item = (FrameSet<Bytes>, SocketAddr);
+/// Collect the received sequence numbers and flush them as acks based on [`AckConfig`]
+#[derive(Debug)]
+pub(super) struct AckQueue {
+    config: AckConfig,
+    pending: Vec<u32>,
+    oldest: Option<Instant>,
+}
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut this = self.project();
-        let Some((pack, addr)) = ready!(this.frame.poll_next_unpin(cx)) else {
-            return Poll::Ready(None);
-        };
-        let (ack, is_nack) = match pack {
-            connected::Packet::FrameSet(frame_set) => return Poll::Ready(Some((frame_set, addr))),
-            connected::Packet::Ack(a) => (a, false),
-            connected::Packet::Nack(na) => (na, true),
+impl AckQueue {
+    pub(super) fn new(config: AckConfig) -> Self {
+        Self {
+            config,
+            pending: Vec::new(),
+            oldest: None,
+        }
+    }
+
+    pub(super) fn push(&mut self, seq_num: u32, now: Instant) {
+        self.pending.push(seq_num);
+        self.oldest.get_or_insert(now);
+    }
+
+    /// The instant the pending acks are due without outgoing data to carry them, None if there
+    /// is no pending ack
+    pub(super) fn next_flush(&self) -> Option<Instant> {
+        self.oldest.map(|oldest| oldest + self.config.flush_delay)
+    }
+
+    /// Check whether the pending acks should be flushed now
+    pub(super) fn should_flush(&self, now: Instant, has_outgoing_data: bool) -> bool {
+        let Some(oldest) = self.oldest else {
+            return false;
         };
+        (self.config.piggyback && has_outgoing_data)
+            || now.saturating_duration_since(oldest) >= self.config.flush_delay
+    }
 
-        todo!()
+    /// Write an ack datagram into buf, returns false if there is no pending ack.
+    /// Call it repeatedly until it returns false to flush all pending acks.
+    pub(super) fn flush_into(&mut self, mtu: u16, buf: &mut BytesMut) -> bool {
+        if self.pending.is_empty() {
+            self.oldest = None;
+            return false;
+        }
+        self.pending.sort_unstable();
+        self.pending.dedup();
+        let limit = match self.config.max_acks_per_datagram {
+            0 => usize::MAX,
+            max => max,
+        };
+        let start = buf.len();
+        buf.put_u8(PackType::Ack.into());
+        let mut seq_nums = self.pending.iter().copied().take(limit);
+        if !AckOrNack::write_from(&mut seq_nums, mtu, buf) {
+            buf.truncate(start);
+            return false;
+        }
+        // the sequence numbers not written are left in the iterator
+        let remain = seq_nums.count() + self.pending.len().saturating_sub(limit);
+        let written = self.pending.len() - remain;
+        self.pending.drain(..written);
+        if self.pending.is_empty() {
+            self.oldest = None;
+        }
+        true
     }
 }
 
-struct SlidingWindow {
+/// The congestion window of a connection, it grows on the acks and shrinks on the losses
+#[derive(Debug)]
+pub(super) struct SlidingWindow {
     mtu: u16,
     cwnd: f32,
+    // 0 means it has not been set, the window will grow in slow start until the first loss
     ss_thresh: f32,
-    estimate_rtt: f32,
-    last_rtt: f32,
-    deviation_rtt: f32,
-    oldest_unsent_ack: u64,
+    bytes_in_flight: usize,
+    recovering: bool,
+    // Smoothed ratio of the lost datagrams, each ack or nack is a sample weighted by LOSS_GAIN
+    loss_rate: f32,
 }
 
+const LOSS_GAIN: f32 = 0.125;
+
 impl SlidingWindow {
-    fn new(mtu: u16) -> Self {
+    pub(super) fn new(mtu: u16) -> Self {
         Self {
             mtu,
             cwnd: f32::from(mtu),
             ss_thresh: 0.0,
-            estimate_rtt: -1.0,
-            last_rtt: -1.0,
-            deviation_rtt: -1.0,
-            oldest_unsent_ack: 0,
+            bytes_in_flight: 0,
+            recovering: false,
+            loss_rate: 0.0,
+        }
+    }
+
+    fn in_slow_start(&self) -> bool {
+        self.ss_thresh <= 0.0 || self.cwnd < self.ss_thresh
+    }
+
+    pub(super) fn on_send(&mut self, bytes: usize) {
+        self.bytes_in_flight += bytes;
+    }
+
+    pub(super) fn on_ack(&mut self, bytes: usize) {
+        self.bytes_in_flight = self.bytes_in_flight.saturating_sub(bytes);
+        self.recovering = false;
+        self.loss_rate -= self.loss_rate * LOSS_GAIN;
+        let mtu = f32::from(self.mtu);
+        if self.in_slow_start() {
+            self.cwnd += mtu;
+        } else {
+            // congestion avoidance, grow one mtu per window
+            self.cwnd += mtu * mtu / self.cwnd;
+        }
+    }
+
+    /// Shrink the window on the loss of the bytes, returns an event when entering recovery
+    pub(super) fn on_nack(&mut self, bytes: usize) -> Option<Event> {
+        self.bytes_in_flight = self.bytes_in_flight.saturating_sub(bytes);
+        self.loss_rate += (1.0 - self.loss_rate) * LOSS_GAIN;
+        if self.recovering {
+            return None;
+        }
+        self.recovering = true;
+        let mtu = f32::from(self.mtu);
+        self.ss_thresh = (self.cwnd / 2.0).max(mtu);
+        self.cwnd = self.ss_thresh;
+        Some(Event::CongestionRecovery {
+            cwnd: self.cwnd as u64,
+            ss_thresh: self.ss_thresh as u64,
+        })
+    }
+
+    /// The bytes could be sent without exceeding the window
+    pub(super) fn available(&self) -> usize {
+        (self.cwnd as usize).saturating_sub(self.bytes_in_flight)
+    }
+
+    pub(super) fn loss_rate(&self) -> f32 {
+        self.loss_rate
+    }
+
+    pub(super) fn stats(&self) -> CongestionStats {
+        CongestionStats {
+            cwnd: self.cwnd as u64,
+            bytes_in_flight: self.bytes_in_flight as u64,
+            ss_thresh: self.ss_thresh as u64,
+            slow_start: self.in_slow_start(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ack_queue_flush_delay() {
+        let mut queue = AckQueue::new(AckConfig {
+            flush_delay: Duration::from_millis(10),
+            max_acks_per_datagram: 0,
+            piggyback: false,
+        });
+        let now = Instant::now();
+        assert!(!queue.should_flush(now, false));
+        queue.push(0, now);
+        assert!(!queue.should_flush(now, true));
+        assert!(queue.should_flush(now + Duration::from_millis(10), false));
+    }
+
+    #[test]
+    fn test_ack_queue_piggyback() {
+        let mut queue = AckQueue::new(AckConfig::default());
+        let now = Instant::now();
+        queue.push(0, now);
+        assert!(!queue.should_flush(now, false));
+        assert!(queue.should_flush(now, true));
+    }
+
+    #[test]
+    fn test_ack_queue_max_acks_per_datagram() {
+        let mut queue = AckQueue::new(AckConfig {
+            flush_delay: Duration::ZERO,
+            max_acks_per_datagram: 3,
+            piggyback: false,
+        });
+        let now = Instant::now();
+        for seq_num in [4, 0, 2, 1, 3, 3] {
+            queue.push(seq_num, now);
+        }
+        let mut buf = BytesMut::new();
+        assert!(queue.flush_into(1400, &mut buf));
+        // pack_type(1) + length(2) + range 0-2(7)
+        assert_eq!(buf.len(), 10);
+        assert_eq!(queue.pending, vec![3, 4]);
+
+        buf.clear();
+        assert!(queue.flush_into(1400, &mut buf));
+        assert!(queue.pending.is_empty());
+        assert!(!queue.should_flush(now, true));
+        assert!(!queue.flush_into(1400, &mut buf));
+    }
+
+    #[test]
+    fn test_sliding_window_recovery() {
+        let mut window = SlidingWindow::new(1000);
+        window.on_send(5000);
+        assert_eq!(window.stats().bytes_in_flight, 5000);
+        window.on_ack(1000);
+        window.on_ack(1000);
+        assert_eq!(
+            window.stats(),
+            CongestionStats {
+                cwnd: 3000,
+                bytes_in_flight: 3000,
+                ss_thresh: 0,
+                slow_start: true,
+            }
+        );
+
+        assert_eq!(
+            window.on_nack(1000),
+            Some(Event::CongestionRecovery {
+                cwnd: 1500,
+                ss_thresh: 1500
+            })
+        );
+        // only report once in a recovery
+        assert!(window.on_nack(1000).is_none());
+        assert!((window.loss_rate() - 0.234_375).abs() < f32::EPSILON);
+
+        window.on_ack(1000);
+        assert!(!window.stats().slow_start);
+        assert_eq!(window.stats().bytes_in_flight, 0);
+        assert_eq!(window.stats().cwnd, 2166);
+    }
+}
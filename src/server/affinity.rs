@@ -0,0 +1,110 @@
+use std::io;
+
+/// How the threads of the server shards bound by
+/// [`ServerBuilder::bind_sharded`](crate::server::ServerBuilder::bind_sharded) are pinned to the
+/// CPU cores. The migration of a busy receive thread across cores hurts the tail latency at high
+/// packet rates.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CpuPinning {
+    /// Leave the threads to the scheduler
+    #[default]
+    Disabled,
+    /// Pin the receive thread of shard `i` to the core `cores[i % cores.len()]`
+    Cores(Vec<usize>),
+}
+
+impl CpuPinning {
+    /// Pin the shards to the cores `0..shards` in order
+    pub fn sequential(shards: usize) -> Self {
+        Self::Cores((0..shards).collect())
+    }
+
+    /// The core the receive thread of the shard is pinned to, None if it is not pinned
+    pub(super) fn core_of(&self, shard: usize) -> Option<usize> {
+        match self {
+            CpuPinning::Disabled => None,
+            CpuPinning::Cores(cores) if cores.is_empty() => None,
+            CpuPinning::Cores(cores) => Some(cores[shard % cores.len()]),
+        }
+    }
+
+    /// Pin the calling thread, which should be the receive thread of the shard
+    pub(super) fn pin_shard(&self, shard: usize) -> io::Result<()> {
+        self.core_of(shard).map_or(Ok(()), pin_current_thread)
+    }
+}
+
+/// Pin the calling thread to the CPU core
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) -> io::Result<()> {
+    // SAFETY: cpu_set_t is a plain bitmask, all zeros is an empty set
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cpu core {core} is out of the cpu set"),
+        ));
+    }
+    // SAFETY: the core is in the range of the set, and the set outlives the call which only
+    // reads it, pid 0 means the calling thread
+    let ret = unsafe {
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The thread affinity is not supported by the platform
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cpu pinning is only supported on linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cpu_pinning_cores() {
+        assert_eq!(CpuPinning::default().core_of(3), None);
+        assert_eq!(CpuPinning::Cores(vec![]).core_of(0), None);
+        let pinning = CpuPinning::Cores(vec![2, 4]);
+        assert_eq!(pinning.core_of(0), Some(2));
+        assert_eq!(pinning.core_of(1), Some(4));
+        assert_eq!(pinning.core_of(2), Some(2));
+        assert_eq!(CpuPinning::sequential(4).core_of(3), Some(3));
+        assert!(CpuPinning::Disabled.pin_shard(0).is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pin_current_thread() {
+        std::thread::spawn(|| {
+            CpuPinning::sequential(1).pin_shard(0).unwrap();
+            // SAFETY: the set is written by the kernel
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            let ret = unsafe {
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set)
+            };
+            assert_eq!(ret, 0);
+            assert_eq!(unsafe { libc::CPU_COUNT(&set) }, 1);
+            assert!(unsafe { libc::CPU_ISSET(0, &set) });
+
+            assert_eq!(
+                pin_current_thread(libc::CPU_SETSIZE as usize)
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::InvalidInput
+            );
+        })
+        .join()
+        .unwrap();
+    }
+}
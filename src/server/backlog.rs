@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limit the connections which completed the offline handshake but have not been accepted by
+/// the application yet. While it is full, the `OpenConnectionRequest2` is left unanswered so that
+/// the client retries it later, which delays the handshake instead of buffering the connection.
+///
+/// A slot is reserved by the address when the handshake completes, then claimed by the
+/// connection made for the address and released once the connection is accepted or dropped. The
+/// slots of the handshakes abandoned before the connection is made are released when they
+/// expire.
+#[derive(Debug, Default)]
+pub(super) struct AcceptBacklog {
+    pending: AtomicUsize,
+    // 0 means unlimited
+    capacity: usize,
+    // The reserved slots not claimed by a connection yet, by when they are reserved
+    handshaking: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl AcceptBacklog {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            capacity,
+            handshaking: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The connections waiting to be accepted
+    #[cfg(test)]
+    pub(super) fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Reserve a slot for the address completing the handshake, returns false if it is full.
+    /// The address holding a reserved slot keeps it.
+    pub(super) fn try_reserve(&self, addr: SocketAddr, now: Instant) -> bool {
+        let mut handshaking = self.handshaking.lock().expect("backlog lock poisoned");
+        if handshaking.contains_key(&addr) {
+            return true;
+        }
+        if self.capacity == 0 {
+            self.pending.fetch_add(1, Ordering::AcqRel);
+        } else if self
+            .pending
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                (pending < self.capacity).then_some(pending + 1)
+            })
+            .is_err()
+        {
+            return false;
+        }
+        handshaking.insert(addr, now);
+        true
+    }
+
+    /// Claim the slot reserved by the address for its connection, the slot is released when the
+    /// returned guard is dropped. None if the address reserved nothing.
+    pub(super) fn claim(self: &Arc<Self>, addr: &SocketAddr) -> Option<BacklogSlot> {
+        self.handshaking
+            .lock()
+            .expect("backlog lock poisoned")
+            .remove(addr)
+            .map(|_| BacklogSlot(Arc::clone(self)))
+    }
+
+    /// Whether the address holds a reserved slot not claimed yet
+    pub(super) fn is_reserved(&self, addr: &SocketAddr) -> bool {
+        self.handshaking
+            .lock()
+            .expect("backlog lock poisoned")
+            .contains_key(addr)
+    }
+
+    /// Release the slot reserved by the address if it is not claimed, e.g. the peer disconnected
+    /// before its connection is made
+    pub(super) fn forget(&self, addr: &SocketAddr) {
+        if self
+            .handshaking
+            .lock()
+            .expect("backlog lock poisoned")
+            .remove(addr)
+            .is_some()
+        {
+            self.release();
+        }
+    }
+
+    /// Release the slots reserved longer than the timeout without being claimed, returns their
+    /// addresses so that the abandoned handshakes are forgotten as well.
+    pub(super) fn expire(&self, now: Instant, timeout: Duration) -> Vec<SocketAddr> {
+        let mut handshaking = self.handshaking.lock().expect("backlog lock poisoned");
+        let mut expired = Vec::new();
+        handshaking.retain(|addr, reserved| {
+            if now.saturating_duration_since(*reserved) < timeout {
+                return true;
+            }
+            expired.push(*addr);
+            false
+        });
+        drop(handshaking);
+        for _ in &expired {
+            self.release();
+        }
+        expired
+    }
+
+    fn release(&self) {
+        let prev = self.pending.fetch_sub(1, Ordering::AcqRel);
+        debug_assert!(prev > 0, "release more than reserved");
+    }
+}
+
+/// A slot of the [`AcceptBacklog`] claimed by a connection, released when it is dropped
+#[derive(Debug)]
+pub(super) struct BacklogSlot(Arc<AcceptBacklog>);
+
+impl Drop for BacklogSlot {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_accept_backlog_works() {
+        let now = Instant::now();
+        let backlog = Arc::new(AcceptBacklog::new(2));
+        assert!(backlog.try_reserve(addr(1), now));
+        assert!(backlog.try_reserve(addr(2), now));
+        // the reserved address keeps its slot
+        assert!(backlog.try_reserve(addr(2), now));
+        assert!(!backlog.try_reserve(addr(3), now));
+
+        assert!(backlog.is_reserved(&addr(1)));
+        let slot = backlog.claim(&addr(1)).unwrap();
+        assert!(!backlog.is_reserved(&addr(1)));
+        assert!(backlog.claim(&addr(1)).is_none());
+        assert!(!backlog.try_reserve(addr(3), now));
+        drop(slot);
+        assert!(backlog.try_reserve(addr(3), now));
+        assert_eq!(backlog.pending(), 2);
+
+        backlog.forget(&addr(2));
+        assert_eq!(backlog.pending(), 1);
+
+        let unlimited = AcceptBacklog::default();
+        for port in 0..100 {
+            assert!(unlimited.try_reserve(addr(port), now));
+        }
+    }
+
+    #[test]
+    fn test_accept_backlog_expire() {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(10);
+        let backlog = Arc::new(AcceptBacklog::new(1));
+        assert!(backlog.try_reserve(addr(1), now));
+        assert!(backlog.expire(now + timeout / 2, timeout).is_empty());
+        assert!(!backlog.try_reserve(addr(2), now + timeout / 2));
+        assert_eq!(backlog.expire(now + timeout, timeout), vec![addr(1)]);
+        assert_eq!(backlog.pending(), 0);
+        assert!(backlog.try_reserve(addr(2), now + timeout));
+        // the claimed slot never expires
+        let _slot = backlog.claim(&addr(2)).unwrap();
+        assert!(backlog.expire(now + timeout * 3, timeout).is_empty());
+        assert_eq!(backlog.pending(), 1);
+    }
+}
@@ -0,0 +1,159 @@
+use std::io;
+use std::net::SocketAddr;
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+use crate::stats::EventLoopRecorder;
+
+/// The max datagrams sent by a batch
+pub(super) const MAX_BATCH: usize = 64;
+
+/// Send the datagrams from the front of the batch in as few syscalls as the platform allows,
+/// returns the count of the datagrams sent, which may be less than the batch. The batching of the
+/// syscalls is only supported on Linux and Android by `sendmmsg`, the other platforms send the
+/// datagrams one syscall each within a single readiness of the socket.
+///
+/// macOS `sendmsg_x` ignores the destination of each message and only sends on a connected
+/// socket, so it cannot serve the peers of a listener. Windows RIO needs registered buffers and
+/// its own completion queue, which does not fit the readiness model of the tokio socket. Both
+/// stay on the fallback until the socket layer owns a completion based driver.
+pub(super) fn poll_send_batch(
+    socket: &UdpSocket,
+    cx: &mut Context<'_>,
+    batch: &[(Bytes, SocketAddr)],
+    recorder: &EventLoopRecorder,
+) -> Poll<io::Result<usize>> {
+    if batch.is_empty() {
+        return Poll::Ready(Ok(0));
+    }
+    let batch = &batch[..batch.len().min(MAX_BATCH)];
+    loop {
+        ready!(socket.poll_send_ready(cx))?;
+        match socket.try_io(Interest::WRITABLE, || imp::send_batch(socket, batch)) {
+            Ok(sent) => {
+                recorder.record_send(sent);
+                return Poll::Ready(Ok(sent));
+            }
+            // the readiness is cleared, wait for the next one
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Poll::Ready(Err(err)),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod imp {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::os::fd::AsRawFd;
+
+    use bytes::Bytes;
+    use socket2::SockAddr;
+    use tokio::net::UdpSocket;
+
+    pub(super) fn send_batch(
+        socket: &UdpSocket,
+        batch: &[(Bytes, SocketAddr)],
+    ) -> io::Result<usize> {
+        let names: Vec<SockAddr> = batch
+            .iter()
+            .map(|(_, addr)| SockAddr::from(*addr))
+            .collect();
+        let mut iovecs: Vec<libc::iovec> = batch
+            .iter()
+            .map(|(data, _)| libc::iovec {
+                iov_base: data.as_ptr().cast_mut().cast(),
+                iov_len: data.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = names
+            .iter()
+            .zip(iovecs.iter_mut())
+            .map(|(name, iovec)| {
+                // SAFETY: mmsghdr is a plain C struct, all zeros is a valid empty header
+                let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+                msg.msg_hdr.msg_name = name.as_ptr().cast_mut().cast();
+                msg.msg_hdr.msg_namelen = name.len();
+                msg.msg_hdr.msg_iov = iovec;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+        // SAFETY: the headers point to the names and the iovecs, which outlive the call, and the
+        // kernel only reads the datagrams
+        let sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod imp {
+    use std::io;
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use socket2::SockRef;
+    use tokio::net::UdpSocket;
+
+    /// The syscall batching is unsupported (e.g. Windows and macOS, see
+    /// [`super::poll_send_batch`]), send the datagrams one by one until the socket buffer is
+    /// full, which saves the wakeups of the event loop but not the syscalls.
+    pub(super) fn send_batch(
+        socket: &UdpSocket,
+        batch: &[(Bytes, SocketAddr)],
+    ) -> io::Result<usize> {
+        let sock = SockRef::from(socket);
+        let mut sent = 0;
+        for (data, addr) in batch {
+            match sock.send_to(data, &(*addr).into()) {
+                Ok(_) => sent += 1,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && sent > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::poll_fn;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_batch_works() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let batch: Vec<_> = (0..3_u8)
+            .map(|i| (Bytes::from(vec![i; usize::from(i) + 1]), addr))
+            .collect();
+        let recorder = EventLoopRecorder::default();
+
+        let sent = poll_fn(|cx| poll_send_batch(&sender, cx, &batch, &recorder))
+            .await
+            .unwrap();
+        assert_eq!(sent, 3);
+        assert_eq!(recorder.snapshot().datagrams_sent, 3);
+
+        let mut buf = [0; 16];
+        for i in 0..3_u8 {
+            let (len, _) = receiver.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], vec![i; usize::from(i) + 1].as_slice());
+        }
+    }
+}
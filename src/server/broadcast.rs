@@ -0,0 +1,106 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use super::Outgoing;
+use crate::message::Message;
+
+/// Broadcast messages to the connections of a server. The payload is shared by all connections
+/// since cloning a [`Message`] only bumps the reference count of its [`bytes::Bytes`].
+#[derive(Debug, Clone, Default)]
+pub(super) struct Broadcaster {
+    peers: Arc<RwLock<HashMap<SocketAddr, flume::Sender<Outgoing>>>>,
+}
+
+impl Broadcaster {
+    /// Register the outgoing queue of a connection
+    pub(super) fn register(&self, addr: SocketAddr, dst: flume::Sender<Outgoing>) {
+        self.peers
+            .write()
+            .expect("broadcaster lock poisoned")
+            .insert(addr, dst);
+    }
+
+    /// Unregister the connection, returns its outgoing queue if it was registered
+    pub(super) fn unregister(&self, addr: &SocketAddr) -> Option<flume::Sender<Outgoing>> {
+        self.peers
+            .write()
+            .expect("broadcaster lock poisoned")
+            .remove(addr)
+    }
+
+    /// Broadcast the message with its reliability and channel to all connections except the
+    /// excluded ones, returns the count of connections the message was queued to.
+    pub(super) fn broadcast(&self, msg: &Message, exclude: &HashSet<SocketAddr>) -> usize {
+        let mut queued = 0;
+        let mut closed = Vec::new();
+        {
+            let peers = self.peers.read().expect("broadcaster lock poisoned");
+            for (addr, dst) in peers.iter() {
+                if exclude.contains(addr) {
+                    continue;
+                }
+                if dst.send(Ok(msg.clone())).is_err() {
+                    closed.push(*addr);
+                    continue;
+                }
+                queued += 1;
+            }
+        }
+        if !closed.is_empty() {
+            // the connection tasks have exited
+            let mut peers = self.peers.write().expect("broadcaster lock poisoned");
+            for addr in &closed {
+                peers.remove(addr);
+            }
+        }
+        queued
+    }
+
+    /// The count of registered connections
+    pub(super) fn len(&self) -> usize {
+        self.peers.read().expect("broadcaster lock poisoned").len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::message::Reliability;
+
+    #[test]
+    fn test_broadcast_works() {
+        let broadcaster = Broadcaster::default();
+        let addrs: Vec<SocketAddr> = (1..=3)
+            .map(|port| format!("127.0.0.1:{port}").parse().unwrap())
+            .collect();
+        let receivers = addrs
+            .iter()
+            .map(|addr| {
+                let (tx, rx) = flume::unbounded();
+                broadcaster.register(*addr, tx);
+                rx
+            })
+            .collect::<Vec<_>>();
+
+        let msg = Message::new(Bytes::from_static(b"hello"))
+            .reliability(Reliability::ReliableSequenced)
+            .channel(2);
+        let queued = broadcaster.broadcast(&msg, &HashSet::from([addrs[0]]));
+        assert_eq!(queued, 2);
+        assert!(receivers[0].is_empty());
+        for rx in &receivers[1..] {
+            let received = rx.try_recv().unwrap().unwrap();
+            assert_eq!(received, msg);
+            // the payload is shared rather than copied
+            assert_eq!(received.data.as_ptr(), msg.data.as_ptr());
+        }
+
+        // closed connections are removed
+        drop(receivers);
+        assert_eq!(broadcaster.broadcast(&msg, &HashSet::new()), 0);
+        assert_eq!(broadcaster.len(), 0);
+    }
+}
@@ -0,0 +1,281 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::event::Event;
+
+/// What to do when a connection exceeds its memory budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BudgetPolicy {
+    /// Drop the oldest unreliable frames in the send queue until it is back under the budget
+    #[default]
+    DropOldestUnreliable,
+    /// Disconnect the connection
+    Disconnect,
+}
+
+/// The memory budget of each connection
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct BudgetConfig {
+    // The max bytes buffered by a connection, 0 means unlimited
+    limit: usize,
+    // What to do when the limit is exceeded
+    policy: BudgetPolicy,
+}
+
+impl BudgetConfig {
+    /// Limit the buffered bytes of a connection
+    #[must_use]
+    pub fn with_limit(mut self, limit: usize, policy: BudgetPolicy) -> Self {
+        self.limit = limit;
+        self.policy = policy;
+        self
+    }
+}
+
+/// The buffers of a connection charged to the budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Buffer {
+    /// Frames queued but not sent yet
+    SendQueue,
+    /// Frames sent and waiting for the acknowledgement
+    Resend,
+    /// Ordered frames waiting for the missing ones
+    Reorder,
+    /// Fragments waiting for the reassembly
+    Reassembly,
+}
+
+impl Buffer {
+    fn index(self) -> usize {
+        match self {
+            Buffer::SendQueue => 0,
+            Buffer::Resend => 1,
+            Buffer::Reorder => 2,
+            Buffer::Reassembly => 3,
+        }
+    }
+}
+
+/// What the connection should do when the budget is exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum BudgetAction {
+    /// Drop the oldest unreliable frames in the send queue to free at least these bytes
+    Shed(usize),
+    /// Disconnect the connection
+    Disconnect,
+}
+
+/// The memory pressure of the whole server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Pressure {
+    /// Below 75% of the ceiling
+    Normal,
+    /// Above 75% of the ceiling, the budget of each connection is halved
+    Elevated,
+    /// Above 90% of the ceiling, the budget of each connection is quartered and new handshakes
+    /// are refused
+    Critical,
+}
+
+/// Account the bytes buffered by all connections, shared by the connections and the
+/// [`super::offline::OfflineHandler`].
+#[derive(Debug, Default)]
+pub(super) struct GlobalMemory {
+    used: AtomicUsize,
+    // The max bytes buffered by all connections, 0 means unlimited
+    ceiling: usize,
+}
+
+impl GlobalMemory {
+    pub(super) fn new(ceiling: usize) -> Self {
+        Self {
+            used: AtomicUsize::new(0),
+            ceiling,
+        }
+    }
+
+    /// The total buffered bytes of all connections
+    pub(super) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn pressure(&self) -> Pressure {
+        if self.ceiling == 0 {
+            return Pressure::Normal;
+        }
+        // compare in u128 so that a large ceiling never overflows
+        let used = self.used() as u128 * 100;
+        let ceiling = self.ceiling as u128;
+        if used >= ceiling * 90 {
+            Pressure::Critical
+        } else if used >= ceiling * 75 {
+            Pressure::Elevated
+        } else {
+            Pressure::Normal
+        }
+    }
+
+    /// Whether new handshakes should be accepted
+    pub(super) fn admits_handshake(&self) -> bool {
+        self.pressure() < Pressure::Critical
+    }
+}
+
+/// Account the bytes buffered by a connection against a single budget, so that the worst-case
+/// memory of a peer is bounded no matter which buffer it fills up.
+#[derive(Debug)]
+pub(super) struct MemoryBudget {
+    config: BudgetConfig,
+    used: [usize; 4],
+    global: Arc<GlobalMemory>,
+}
+
+impl MemoryBudget {
+    pub(super) fn new(config: BudgetConfig, global: Arc<GlobalMemory>) -> Self {
+        Self {
+            config,
+            used: [0; 4],
+            global,
+        }
+    }
+
+    /// The budget tightened by the global memory pressure
+    pub(super) fn limit(&self) -> usize {
+        match self.global.pressure() {
+            Pressure::Normal => self.config.limit,
+            Pressure::Elevated => self.config.limit / 2,
+            Pressure::Critical => self.config.limit / 4,
+        }
+    }
+
+    /// The total buffered bytes
+    pub(super) fn used(&self) -> usize {
+        self.used.iter().sum()
+    }
+
+    /// The buffered bytes of a buffer
+    pub(super) fn used_by(&self, buffer: Buffer) -> usize {
+        self.used[buffer.index()]
+    }
+
+    /// Charge the bytes to the buffer, returns the action and the event if the budget is exceeded
+    pub(super) fn grow(&mut self, buffer: Buffer, bytes: usize) -> Option<(BudgetAction, Event)> {
+        self.used[buffer.index()] += bytes;
+        self.global.used.fetch_add(bytes, Ordering::Relaxed);
+        let used = self.used();
+        let limit = self.limit();
+        if self.config.limit == 0 || used <= limit {
+            return None;
+        }
+        let excess = used - limit;
+        let action = match self.config.policy {
+            // only the unsent frames could be dropped, others have been promised to the peer
+            BudgetPolicy::DropOldestUnreliable if excess <= self.used_by(Buffer::SendQueue) => {
+                BudgetAction::Shed(excess)
+            }
+            _ => BudgetAction::Disconnect,
+        };
+        let event = Event::MemoryBudgetExceeded {
+            used_bytes: used as u64,
+            limit_bytes: limit as u64,
+        };
+        Some((action, event))
+    }
+
+    /// Charge the buffer up or down to the bytes, e.g. the buffers measured by the codec
+    pub(super) fn resize(&mut self, buffer: Buffer, bytes: usize) -> Option<(BudgetAction, Event)> {
+        let used = self.used_by(buffer);
+        if bytes > used {
+            return self.grow(buffer, bytes - used);
+        }
+        self.shrink(buffer, used - bytes);
+        None
+    }
+
+    /// Whether the buffered bytes are still above the budget
+    pub(super) fn exceeded(&self) -> bool {
+        self.config.limit != 0 && self.used() > self.limit()
+    }
+
+    /// Release the bytes from the buffer
+    pub(super) fn shrink(&mut self, buffer: Buffer, bytes: usize) {
+        let used = &mut self.used[buffer.index()];
+        debug_assert!(*used >= bytes, "release more bytes than charged");
+        let bytes = bytes.min(*used);
+        *used -= bytes;
+        self.global.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for MemoryBudget {
+    fn drop(&mut self) {
+        self.global.used.fetch_sub(self.used(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_budget_works() {
+        let config = BudgetConfig::default().with_limit(1000, BudgetPolicy::DropOldestUnreliable);
+        let mut budget = MemoryBudget::new(config, Arc::default());
+        assert_eq!(budget.grow(Buffer::Resend, 600), None);
+        assert_eq!(budget.grow(Buffer::SendQueue, 400), None);
+        assert_eq!(
+            budget.grow(Buffer::SendQueue, 100),
+            Some((
+                BudgetAction::Shed(100),
+                Event::MemoryBudgetExceeded {
+                    used_bytes: 1100,
+                    limit_bytes: 1000
+                }
+            ))
+        );
+        budget.shrink(Buffer::SendQueue, 100);
+        // the send queue is not enough to be shed
+        assert!(matches!(
+            budget.grow(Buffer::Reassembly, 600),
+            Some((BudgetAction::Disconnect, _))
+        ));
+        assert_eq!(budget.used(), 1600);
+
+        assert!(budget.exceeded());
+        assert_eq!(budget.resize(Buffer::Reassembly, 0), None);
+        assert!(!budget.exceeded());
+
+        let mut unlimited = MemoryBudget::new(BudgetConfig::default(), Arc::default());
+        assert_eq!(unlimited.grow(Buffer::Reorder, usize::MAX / 2), None);
+    }
+
+    #[test]
+    fn test_global_memory_pressure() {
+        let global = Arc::new(GlobalMemory::new(10_000));
+        let config = BudgetConfig::default().with_limit(4000, BudgetPolicy::Disconnect);
+        let mut first = MemoryBudget::new(config, Arc::clone(&global));
+        let mut second = MemoryBudget::new(BudgetConfig::default(), Arc::clone(&global));
+        assert_eq!(first.grow(Buffer::Resend, 3000), None);
+        assert_eq!(second.grow(Buffer::Resend, 3000), None);
+        assert_eq!(global.pressure(), Pressure::Normal);
+
+        assert_eq!(second.grow(Buffer::Reorder, 2000), None);
+        assert_eq!(global.pressure(), Pressure::Elevated);
+        // the budget is halved
+        assert_eq!(first.limit(), 2000);
+        assert!(matches!(
+            first.grow(Buffer::Reassembly, 1000),
+            Some((BudgetAction::Disconnect, _))
+        ));
+        assert_eq!(global.pressure(), Pressure::Critical);
+        assert!(!global.admits_handshake());
+
+        drop(first);
+        second.shrink(Buffer::Reorder, 2000);
+        assert_eq!(global.used(), 3000);
+        assert!(global.admits_handshake());
+    }
+}
@@ -1,21 +1,1101 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
 
-use pin_project_lite::pin_project;
+use bytes::{Bytes, BytesMut};
+use flume::r#async::RecvStream;
+use futures::channel::{mpsc, oneshot};
+use futures::{Future, Stream, StreamExt};
 
-pub(crate) enum Conn<F> {
-    HandShaking(HandShake<F>),
-    Connected,
-    Closed,
+use super::ack::{AckConfig, AckQueue, SlidingWindow};
+use super::budget::{BudgetAction, BudgetConfig, Buffer, GlobalMemory, MemoryBudget};
+use super::isolation::ChannelWindows;
+use super::keepalive::{Keepalive, KeepaliveAction, KeepaliveConfig};
+use super::linger::Linger;
+use super::pmtu::{probe_body, Pmtu, PmtuAction, PmtuConfig};
+use super::resend::{ResendLimitConfig, ResendLimiter, ResendMap};
+use super::rto::{RtoConfig, RttEstimator};
+use super::split::SplitIds;
+use super::tap::{Direction, Tap};
+use super::tick::{DriveMode, Ticker};
+use super::verbosity::{peer_debug, PeerVerbosity};
+use super::watchdog::{Progress, Watchdog, WatchdogConfig};
+use super::watermark::{Watermark, WatermarkConfig};
+use super::wheel::{TimerId, TimerWheel, DEFAULT_RESOLUTION, DEFAULT_SLOTS};
+use super::Outgoing;
+use crate::clock::{Clock, ClockDifferential, SystemClock};
+use crate::codec::batch::{pack_frames, repack_frames};
+use crate::codec::ordered::OrderingWriter;
+use crate::codec::{CodecConfig, Decoded};
+use crate::errors::CodecError;
+use crate::event::{DisconnectReason, Event};
+use crate::message::{Message, Priority, Received};
+use crate::packet::connected::{
+    self, max_body_size, max_datagram_size, Flags, Fragment, Frame, FrameBody, FrameSet, Ordered,
+    Reliability, Uint24le, UDP_HEADER_SIZE,
+};
+use crate::packet::Packet;
+use crate::stats::StatsRecorder;
+use crate::Peer;
+
+/// What the receive loop routes to a connection
+#[derive(Debug)]
+pub(super) enum Inbound {
+    /// A connected packet, with the instant its datagram arrived
+    Packet(connected::Packet<BytesMut>, Instant),
+    /// The peer continued the connection from the new address
+    Migrated(SocketAddr),
+}
+
+/// The settings of each connection, taken from the server config
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ConnConfig {
+    pub(super) codec: CodecConfig,
+    pub(super) drive_mode: DriveMode,
+    pub(super) channel_window: usize,
+    pub(super) ack: AckConfig,
+    pub(super) keepalive: KeepaliveConfig,
+    pub(super) rto: RtoConfig,
+    pub(super) resend_limit: ResendLimitConfig,
+    pub(super) linger: Linger,
+    pub(super) watchdog: WatchdogConfig,
+    pub(super) send_watermark: WatermarkConfig,
+    pub(super) memory_budget: BudgetConfig,
+    pub(super) pmtu: PmtuConfig,
+    // The upper bound of the path mtu discovery, reduced by the overhead of the datagram hook
+    pub(super) max_mtu: u16,
+}
+
+/// The subscriber of the events of a connection or the server, it could be attached by the
+/// handle at runtime. The events are dropped while no one is subscribing or the subscriber falls
+/// behind.
+#[derive(Debug, Clone)]
+pub(super) struct Events<E = Event> {
+    subscriber: Arc<Mutex<Option<flume::Sender<E>>>>,
+}
+
+impl<E> Default for Events<E> {
+    fn default() -> Self {
+        Self {
+            subscriber: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl<E> Events<E> {
+    /// Subscribe the events, at most `capacity` events are buffered. The previous subscriber
+    /// will be detached.
+    pub(super) fn subscribe(&self, capacity: usize) -> flume::Receiver<E> {
+        let (tx, rx) = flume::bounded(capacity);
+        *self.subscriber.lock().expect("events lock poisoned") = Some(tx);
+        rx
+    }
+
+    pub(super) fn emit(&self, event: E) {
+        if let Some(tx) = self
+            .subscriber
+            .lock()
+            .expect("events lock poisoned")
+            .as_ref()
+        {
+            let _ = tx.try_send(event);
+        }
+    }
+}
+
+/// The channels between a connection task and the others
+#[derive(Debug)]
+pub(super) struct ConnIo {
+    /// The packets routed to the connection by the receive loop
+    pub(super) inbound: flume::Receiver<Inbound>,
+    /// The messages queued by the connection handle and the sessions
+    pub(super) outgoing: flume::Receiver<Outgoing>,
+    /// The messages delivered to the connection handle
+    pub(super) src: flume::Sender<Received>,
+    /// Notified once the reliable data queued before is acknowledged
+    pub(super) acked: flume::Receiver<oneshot::Sender<()>>,
+    /// The encoded datagrams flushed to the socket by the receive loop
+    pub(super) outbound: flume::Sender<(SocketAddr, BytesMut, Priority)>,
+    /// Copy the encoded datagrams to the subscriber of the connection handle
+    pub(super) tap: Tap,
+    /// Notified with the address and the id of the connection once the task exits
+    pub(super) closed: flume::Sender<(SocketAddr, u64)>,
+    pub(super) events: Events,
+    /// The address of the peer shared with the connection handle, updated on migration
+    pub(super) peer_addr: Arc<Mutex<SocketAddr>>,
+    /// Why the connection is torn down, shared with the connection handle
+    pub(super) exit_reason: Arc<Mutex<Option<DisconnectReason>>>,
+    pub(super) recorder: Arc<StatsRecorder>,
+    /// The memory buffered by all connections, charged along with the budget of the connection
+    pub(super) memory: Arc<GlobalMemory>,
+    /// Estimated from the connected pongs, shared with the connection handle
+    pub(super) clock: Arc<ClockDifferential>,
+    pub(super) verbosity: PeerVerbosity,
+}
+
+/// A datagram sent but not acknowledged yet
+#[derive(Debug)]
+struct InFlight {
+    size: usize,
+    timer: TimerId,
+    // The reliable indices of the frames it carries, along with the channels of the ordered ones
+    reliable: Vec<(u32, Option<u8>)>,
+    // The reliable indices and the parted ids of the fragments it carries, the parted id is
+    // released once all fragments of the split are acknowledged
+    fragments: Vec<(u32, u16)>,
+    // The bytes of the reliable frames kept for the retransmission
+    kept: usize,
+    // The size of the path mtu probe it is, which is not counted by the congestion window
+    probe: Option<u16>,
+    // The most urgent priority class of the frames it carries, kept by their retransmission
+    priority: Priority,
+}
+
+/// How the connection is being closed
+#[derive(Debug, Clone, Copy)]
+struct Closing {
+    reason: DisconnectReason,
+    // Give up the pending data at the deadline, None means exit after the notification is sent
+    deadline: Option<Instant>,
+}
+
+type DecodedStream = Pin<Box<dyn Stream<Item = connected::Packet<FrameBody>> + Send>>;
+
+/// The task of a connection. It decodes the packets routed to it, delivers the messages to the
+/// connection handle, and flushes the acks, the retransmissions and the outgoing messages as
+/// the frame sets sent by the receive loop.
+pub(super) struct Conn {
+    id: u64,
+    peer: Peer,
+    local_addr: SocketAddr,
+    config: ConnConfig,
+    inbound: RecvStream<'static, Inbound>,
+    outgoing: RecvStream<'static, Outgoing>,
+    src: flume::Sender<Received>,
+    acked: RecvStream<'static, oneshot::Sender<()>>,
+    outbound: flume::Sender<(SocketAddr, BytesMut, Priority)>,
+    tap: Tap,
+    closed: flume::Sender<(SocketAddr, u64)>,
+    events: Events,
+    peer_addr: Arc<Mutex<SocketAddr>>,
+    exit_reason: Arc<Mutex<Option<DisconnectReason>>>,
+    recorder: Arc<StatsRecorder>,
+    clock: Arc<ClockDifferential>,
+    verbosity: PeerVerbosity,
+    // Feed the frame sets to the decode pipeline of the codec
+    decoder: mpsc::UnboundedSender<Result<connected::Packet<BytesMut>, CodecError>>,
+    decoded: DecodedStream,
+    // When the datagram fed to the decode pipeline last arrived
+    arrival: Instant,
+    writers: HashMap<u8, OrderingWriter>,
+    next_reliable: Uint24le,
+    // The count of the reliable frames ever queued, which orders the reliable frames without
+    // wrapping around like the reliable indices
+    reliable_count: u64,
+    // The ordinals of the reliable frames not acknowledged yet
+    unacked: BTreeSet<u64>,
+    // Waiting for the reliable frames before the ordinals to be acknowledged
+    ack_waiters: Vec<(u64, oneshot::Sender<()>)>,
+    next_seq: Uint24le,
+    // The ack datagrams are encoded into it and split off to be sent, the allocation is
+    // reclaimed once they are sent
+    ack_buf: BytesMut,
+    split_ids: SplitIds,
+    // The frames of the immediate messages and the others with their priority classes, not sent
+    // yet
+    immediate: VecDeque<Frame<Bytes>>,
+    queue: VecDeque<(Frame<Bytes>, Priority)>,
+    // The lost frames waiting to be resent, with the priority classes they were sent with
+    retransmits: Vec<(Frame<Bytes>, Priority)>,
+    in_flight: HashMap<u32, InFlight>,
+    resend: ResendMap,
+    limiter: ResendLimiter,
+    rtt: RttEstimator,
+    window: SlidingWindow,
+    channels: ChannelWindows,
+    acks: AckQueue,
+    // Since when the datagrams in flight have been waiting without any of them acknowledged
+    waiting_since: Option<Instant>,
+    watchdog: Watchdog,
+    watermark: Watermark,
+    // Charged by the send queue, the frames kept for the retransmission and the buffers of the
+    // decode pipeline
+    budget: MemoryBudget,
+    pmtu: Pmtu,
+    timers: TimerWheel<u32>,
+    keepalive: Keepalive,
+    ticker: Ticker,
+    sleep: Pin<Box<tokio::time::Sleep>>,
+    closing: Option<Closing>,
+    // The connection is torn down with the reason
+    exit: Option<DisconnectReason>,
+}
+
+impl std::fmt::Debug for Conn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Conn")
+            .field("id", &self.id)
+            .field("peer", &self.peer)
+            .field("in_flight", &self.in_flight.len())
+            .field("closing", &self.closing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Conn {
+    pub(super) fn new(
+        id: u64,
+        peer: Peer,
+        local_addr: SocketAddr,
+        config: ConnConfig,
+        io: ConnIo,
+    ) -> Self {
+        let now = Instant::now();
+        let (decoder, rx) = mpsc::unbounded();
+        let stream = rx
+            .decoded(peer.addr, peer.mtu, config.codec, Arc::clone(&io.recorder))
+            .boxed();
+        Self {
+            id,
+            local_addr,
+            config,
+            inbound: io.inbound.into_stream(),
+            outgoing: io.outgoing.into_stream(),
+            src: io.src,
+            acked: io.acked.into_stream(),
+            outbound: io.outbound,
+            tap: io.tap,
+            closed: io.closed,
+            events: io.events,
+            peer_addr: io.peer_addr,
+            exit_reason: io.exit_reason,
+            recorder: io.recorder,
+            clock: io.clock,
+            verbosity: io.verbosity,
+            decoder,
+            decoded: stream,
+            arrival: now,
+            writers: HashMap::new(),
+            next_reliable: Uint24le(0),
+            reliable_count: 0,
+            unacked: BTreeSet::new(),
+            ack_waiters: Vec::new(),
+            next_seq: Uint24le(0),
+            ack_buf: BytesMut::new(),
+            split_ids: SplitIds::default(),
+            immediate: VecDeque::new(),
+            queue: VecDeque::new(),
+            retransmits: Vec::new(),
+            in_flight: HashMap::new(),
+            resend: ResendMap::default(),
+            limiter: ResendLimiter::new(config.resend_limit, now),
+            rtt: RttEstimator::new(config.rto),
+            window: SlidingWindow::new(peer.mtu),
+            channels: ChannelWindows::new(config.channel_window),
+            acks: AckQueue::new(config.ack),
+            waiting_since: None,
+            watchdog: Watchdog::new(config.watchdog),
+            watermark: Watermark::new(config.send_watermark),
+            budget: MemoryBudget::new(config.memory_budget, io.memory),
+            pmtu: Pmtu::new(config.pmtu, peer.mtu, config.max_mtu, now),
+            timers: TimerWheel::new(DEFAULT_RESOLUTION, DEFAULT_SLOTS, now),
+            keepalive: Keepalive::new(config.keepalive, now),
+            ticker: Ticker::new(config.drive_mode),
+            sleep: Box::pin(tokio::time::sleep_until(now.into())),
+            closing: None,
+            exit: None,
+            peer,
+        }
+    }
+
+    fn on_inbound(&mut self, pack: connected::Packet<BytesMut>, at: Instant, now: Instant) {
+        self.keepalive.on_received(now);
+        match pack {
+            connected::Packet::FrameSet(frame_set) => {
+                self.acks.push(frame_set.seq_num.0, now);
+                self.arrival = at;
+                // the receiver is owned by this task
+                let _ = self
+                    .decoder
+                    .unbounded_send(Ok(connected::Packet::FrameSet(frame_set)));
+            }
+            connected::Packet::Ack(ack) => {
+                // sampled by the arrival, so that the RTT excludes the delay of the event loop
+                for seq_num in ack.records.iter().flat_map(connected::Record::seq_nums) {
+                    self.on_acked(seq_num, at);
+                }
+            }
+            connected::Packet::Nack(nack) => {
+                for seq_num in nack.records.iter().flat_map(connected::Record::seq_nums) {
+                    self.on_lost(seq_num, now);
+                }
+            }
+        }
+    }
+
+    /// Continue the connection from the new address of the peer
+    fn on_migrated(&mut self, new: SocketAddr) {
+        let old = self.peer.addr;
+        self.peer.addr = new;
+        *self.peer_addr.lock().expect("peer address lock poisoned") = new;
+        self.events.emit(Event::AddressChanged { old, new });
+    }
+
+    fn on_acked(&mut self, seq_num: u32, at: Instant) {
+        let Some(sent) = self.in_flight.remove(&seq_num) else {
+            return;
+        };
+        self.timers.cancel(sent.timer);
+        self.budget.shrink(Buffer::Resend, sent.kept);
+        if let Some(size) = sent.probe {
+            if let Some(mtu) = self.pmtu.on_probe_acked(size, at) {
+                self.set_mtu(mtu);
+            }
+            return;
+        }
+        self.window.on_ack(sent.size);
+        self.pmtu.on_datagram_acked(path_size(sent.size));
+        self.waiting_since = Some(at);
+        for (idx, channel) in sent.reliable {
+            let ordinal = self.ordinal(idx);
+            // a repacked frame is only counted by its first ack
+            if self.unacked.remove(&ordinal) {
+                if let Some(channel) = channel {
+                    self.channels.on_acked(channel);
+                }
+            }
+        }
+        for (idx, parted_id) in sent.fragments {
+            // a retransmitted fragment is only counted by its first ack
+            if self.resend.attempts(idx) > 0 {
+                self.split_ids.on_fragment_settled(parted_id);
+            }
+        }
+        if let Some((sent_at, retransmitted)) = self.resend.on_ack(seq_num) {
+            self.rtt.on_acked(sent_at, at, retransmitted);
+        }
+    }
+
+    /// The datagram is lost, by a nack or the retransmission timeout
+    fn on_lost(&mut self, seq_num: u32, now: Instant) {
+        let Some(sent) = self.in_flight.remove(&seq_num) else {
+            return;
+        };
+        self.timers.cancel(sent.timer);
+        self.budget.shrink(Buffer::Resend, sent.kept);
+        if let Some(size) = sent.probe {
+            self.pmtu.on_probe_lost(size, now);
+            return;
+        }
+        if let Some(event) = self.window.on_nack(sent.size) {
+            self.events.emit(event);
+        }
+        if let Some(event) = self.pmtu.on_datagram_lost(path_size(sent.size), now) {
+            self.set_mtu(self.pmtu.mtu());
+            self.events.emit(event);
+        }
+        let lost = self.resend.on_lost(seq_num);
+        let verdict = self.limiter.judge(&mut self.resend, lost, now);
+        for event in verdict.receipts_lost() {
+            self.events.emit(event);
+        }
+        // the waiters and the split ids are not held forever by the frames never resent
+        for frame in &verdict.abandoned {
+            if let Some(fragment) = frame.fragment {
+                self.split_ids.on_fragment_settled(fragment.parted_id);
+            }
+            let Some(idx) = frame.reliable_frame_index else {
+                continue;
+            };
+            let ordinal = self.ordinal(idx.0);
+            if self.unacked.remove(&ordinal) {
+                if let Some(ordered) = frame.ordered {
+                    self.channels.on_acked(ordered.channel);
+                }
+            }
+        }
+        if let Some(reason) = verdict.disconnect {
+            self.exit = Some(reason);
+        }
+        let resent = verdict.resend.iter().map(Frame::size).sum();
+        self.retransmits.extend(
+            verdict
+                .resend
+                .into_iter()
+                .map(|frame| (frame, sent.priority)),
+        );
+        let charged = self.budget.grow(Buffer::SendQueue, resent);
+        self.on_budget(charged);
+    }
+
+    fn on_frame(&mut self, frame: Frame<FrameBody>) {
+        match frame.body {
+            FrameBody::ConnectedPing { client_timestamp } => {
+                self.push_body(
+                    FrameBody::ConnectedPong {
+                        client_timestamp,
+                        server_timestamp: SystemClock.timestamp(),
+                    },
+                    Reliability::Unreliable,
+                );
+            }
+            FrameBody::ConnectedPong {
+                client_timestamp,
+                server_timestamp,
+            } => {
+                self.clock
+                    .record(client_timestamp, server_timestamp, SystemClock.timestamp());
+            }
+            FrameBody::ConnectionRequest {
+                request_timestamp, ..
+            } => {
+                let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+                let mut system_addresses = [unspecified; 10];
+                system_addresses[0] = self.local_addr;
+                self.push_body(
+                    FrameBody::ConnectionRequestAccepted {
+                        client_address: self.peer.addr,
+                        system_index: 0,
+                        system_addresses,
+                        request_timestamp,
+                        accepted_timestamp: SystemClock.timestamp(),
+                    },
+                    Reliability::ReliableOrdered,
+                );
+            }
+            FrameBody::ConnectionRequestAccepted { .. }
+            | FrameBody::NewIncomingConnection { .. } => {
+                peer_debug!(
+                    self.verbosity,
+                    self.peer.addr,
+                    "connection to {} established",
+                    self.peer.addr
+                );
+            }
+            FrameBody::Disconnect(reason) => {
+                peer_debug!(
+                    self.verbosity,
+                    self.peer.addr,
+                    "disconnected by {}, reason: {reason}",
+                    self.peer.addr
+                );
+                self.exit = Some(reason);
+            }
+            FrameBody::Game(data) => {
+                let frame = Frame {
+                    body: data,
+                    ..frame
+                };
+                // the handle may be dropped while the connection is lingering
+                let _ = self.src.send(Received::from_frame(frame, self.arrival));
+            }
+        }
+    }
+
+    /// Queue a connected control packet
+    fn push_body(&mut self, body: FrameBody, reliability: Reliability) {
+        let mut buf = BytesMut::new();
+        body.write(&mut buf);
+        self.push_message(
+            Message::new(buf.freeze())
+                .reliability(reliability)
+                .priority(Priority::Immediate),
+            Instant::now(),
+        );
+    }
+
+    fn next_reliable(&mut self, reliability: Reliability) -> Option<Uint24le> {
+        if !reliability.is_reliable() {
+            return None;
+        }
+        let idx = self.next_reliable;
+        self.next_reliable = idx.next();
+        self.unacked.insert(self.reliable_count);
+        self.reliable_count += 1;
+        Some(idx)
+    }
+
+    /// The ordinal of the reliable frame by its index, the frames in flight are far less than
+    /// the range of the indices
+    fn ordinal(&self, reliable_frame_index: u32) -> u64 {
+        let behind = self.next_reliable.0.wrapping_sub(reliable_frame_index) & 0x00ff_ffff;
+        self.reliable_count - u64::from(behind)
+    }
+
+    /// Notify the waiters whose reliable frames are all acknowledged
+    fn notify_acked(&mut self) {
+        let acked = self.unacked.first().copied().unwrap_or(self.reliable_count);
+        let (done, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.ack_waiters)
+            .into_iter()
+            .partition(|(mark, _)| *mark <= acked);
+        self.ack_waiters = pending;
+        for (_, waiter) in done {
+            let _ = waiter.send(());
+        }
+    }
+
+    /// Split the message into the frames fitting in the mtu and queue them
+    fn push_message(&mut self, msg: Message, now: Instant) {
+        let Message {
+            reliability,
+            channel,
+            priority,
+            data,
+        } = msg;
+        self.recorder.record_sent(
+            reliability.is_sequenced_or_ordered().then_some(channel),
+            data.len(),
+        );
+        let mut frames = Vec::new();
+        let mtu = self.peer.mtu;
+        if data.len() <= max_body_size(mtu, reliability, false) {
+            let (seq_frame_index, ordered) = ordering(&mut self.writers, reliability, channel);
+            frames.push(Frame {
+                flags: Flags::new(reliability, false),
+                reliable_frame_index: self.next_reliable(reliability),
+                seq_frame_index,
+                ordered,
+                fragment: None,
+                body: data,
+            });
+        } else {
+            // a lost fragment would lose the whole message, so the splits are always reliable
+            let reliability = split_reliability(reliability);
+            let part = max_body_size(mtu, reliability, true);
+            let parts = data.len().div_ceil(part);
+            let Some(parted_id) =
+                self.split_ids
+                    .allocate(parts as u32, reliability.is_reliable(), now)
+            else {
+                // the message could not be sent without mixing up the splits at the peer
+                peer_debug!(
+                    self.verbosity,
+                    self.peer.addr,
+                    "all parted ids of {} are in use, close the connection",
+                    self.peer.addr
+                );
+                self.exit.get_or_insert(DisconnectReason::SplitsExhausted);
+                return;
+            };
+            let (seq_frame_index, ordered) = ordering(&mut self.writers, reliability, channel);
+            for parted_index in 0..parts {
+                let end = data.len().min((parted_index + 1) * part);
+                frames.push(Frame {
+                    flags: Flags::new(reliability, true),
+                    reliable_frame_index: self.next_reliable(reliability),
+                    seq_frame_index,
+                    ordered,
+                    fragment: Some(Fragment {
+                        parted_size: parts as u32,
+                        parted_id,
+                        parted_index: parted_index as u32,
+                    }),
+                    body: data.slice(parted_index * part..end),
+                });
+            }
+        }
+        let queued = frames.iter().map(Frame::size).sum();
+        if priority == Priority::Immediate {
+            self.immediate.extend(frames);
+        } else {
+            self.queue
+                .extend(frames.into_iter().map(|frame| (frame, priority)));
+        }
+        let charged = self.budget.grow(Buffer::SendQueue, queued);
+        self.on_budget(charged);
+    }
+
+    /// Act on the memory budget exceeded by the buffers of the connection
+    fn on_budget(&mut self, charged: Option<(BudgetAction, Event)>) {
+        let Some((action, event)) = charged else {
+            return;
+        };
+        peer_debug!(
+            self.verbosity,
+            self.peer.addr,
+            "memory budget of {} exceeded: {event:?}",
+            self.peer.addr
+        );
+        self.events.emit(event);
+        match action {
+            // the outgoing messages are stalled if the unreliable frames are not enough
+            BudgetAction::Shed(bytes) => self.shed_unreliable(bytes),
+            BudgetAction::Disconnect => {
+                self.exit.get_or_insert(DisconnectReason::MemoryBudget);
+            }
+        }
+    }
+
+    /// Drop the oldest unreliable frames in the send queue to free the bytes
+    fn shed_unreliable(&mut self, bytes: usize) {
+        let mut freed = 0;
+        self.queue.retain(|(frame, _)| {
+            if freed >= bytes || frame.reliable_frame_index.is_some() {
+                return true;
+            }
+            freed += frame.size();
+            false
+        });
+        self.budget.shrink(Buffer::SendQueue, freed);
+    }
+
+    /// Charge the buffers of the decode pipeline measured by the codec
+    fn charge_decoder(&mut self) {
+        for (buffer, bytes) in [
+            (Buffer::Reorder, self.recorder.reorder_buffered()),
+            (Buffer::Reassembly, self.recorder.reassembly_buffered()),
+        ] {
+            let charged = self.budget.resize(buffer, bytes);
+            self.on_budget(charged);
+        }
+    }
+
+    fn emit(&mut self, buf: BytesMut, priority: Priority) {
+        self.tap.capture(Direction::Outbound, self.peer.addr, || {
+            Bytes::copy_from_slice(&buf)
+        });
+        // the receive loop is gone once the server is dropped
+        let _ = self.outbound.send((self.peer.addr, buf, priority));
+    }
+
+    fn send_frame_set(
+        &mut self,
+        frames: Vec<Frame<Bytes>>,
+        priority: Priority,
+        now: Instant,
+        retransmitted: bool,
+        probe: Option<u16>,
+    ) {
+        let seq_num = self.next_seq;
+        self.next_seq = seq_num.next();
+        let fragments = frames
+            .iter()
+            .filter_map(|frame| {
+                frame
+                    .reliable_frame_index
+                    .zip(frame.fragment.map(|fragment| fragment.parted_id))
+                    .map(|(idx, parted_id)| (idx.0, parted_id))
+            })
+            .collect();
+        let reliable: Vec<_> = frames
+            .iter()
+            .filter_map(|frame| {
+                frame
+                    .reliable_frame_index
+                    .map(|idx| (idx.0, frame.ordered.map(|ordered| ordered.channel)))
+            })
+            .collect();
+        let mut buf = BytesMut::new();
+        Packet::Connected(connected::Packet::FrameSet(FrameSet {
+            seq_num,
+            frames: frames.clone(),
+        }))
+        .write(&mut buf);
+        let size = buf.len();
+        let kept = self.resend.record(seq_num.0, frames, now, retransmitted);
+        let timer = self.timers.insert(now + self.rtt.rto(), seq_num.0);
+        if probe.is_none() {
+            self.window.on_send(size);
+            self.waiting_since.get_or_insert(now);
+        }
+        self.in_flight.insert(
+            seq_num.0,
+            InFlight {
+                size,
+                timer,
+                reliable,
+                fragments,
+                kept,
+                probe,
+                priority,
+            },
+        );
+        self.emit(buf, priority);
+        let charged = self.budget.grow(Buffer::Resend, kept);
+        self.on_budget(charged);
+    }
+
+    fn flush_acks(&mut self, now: Instant, has_outgoing_data: bool) {
+        if !self.acks.should_flush(now, has_outgoing_data) {
+            return;
+        }
+        while self.acks.flush_into(self.peer.mtu, &mut self.ack_buf) {
+            let buf = self.ack_buf.split();
+            // the acks carry no message, they are marked like the regular messages
+            self.emit(buf, Priority::default());
+        }
+    }
+
+    /// Take the frames at the front of the queue within the budget of bytes, at least one frame
+    /// is taken if the queue is not empty. The reliable ordered frames of the channels whose
+    /// windows are full are skipped in place, so that the other channels keep flushing.
+    fn take_frames(&mut self, mut budget: usize) -> Vec<(Frame<Bytes>, Priority)> {
+        let mut frames = Vec::new();
+        let mut skipped = Vec::new();
+        while let Some((front, _)) = self.queue.front() {
+            let size = front.size();
+            if !frames.is_empty() && size > budget {
+                break;
+            }
+            let Some((frame, priority)) = self.queue.pop_front() else {
+                break;
+            };
+            if let Some(channel) = reliable_channel(&frame) {
+                if !self.channels.admits(channel) {
+                    skipped.push((frame, priority));
+                    continue;
+                }
+                self.channels.on_sent(channel);
+            }
+            budget = budget.saturating_sub(size);
+            frames.push((frame, priority));
+        }
+        for queued in skipped.into_iter().rev() {
+            self.queue.push_front(queued);
+        }
+        frames
+    }
+
+    /// Perform the pending work of the connection
+    fn flush(&mut self, now: Instant) {
+        let max_size = max_datagram_size(self.peer.mtu);
+        // the immediate messages never wait for the tick or the window
+        let immediate: Vec<_> = self.immediate.drain(..).collect();
+        self.release_queued(&immediate);
+        for channel in immediate.iter().filter_map(reliable_channel) {
+            self.channels.on_sent(channel);
+        }
+        let due = self.ticker.due(now);
+        if due {
+            let mut expired = Vec::new();
+            self.timers.expire(now, |seq_num| expired.push(seq_num));
+            // the lost probes tell nothing about the congestion
+            if expired.iter().any(|seq_num| {
+                self.in_flight
+                    .get(seq_num)
+                    .is_some_and(|sent| sent.probe.is_none())
+            }) {
+                self.rtt.on_timeout();
+            }
+            for seq_num in expired {
+                self.on_lost(seq_num, now);
+            }
+            self.flush_acks(now, !immediate.is_empty() || !self.queue.is_empty());
+        }
+        for frames in pack_frames(immediate, max_size) {
+            self.send_frame_set(frames, Priority::Immediate, now, false, None);
+        }
+        if !due {
+            return;
+        }
+        let (retransmits, mut priorities): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.retransmits).into_iter().unzip();
+        self.release_queued(&retransmits);
+        let (mut fresh, fresh_priorities): (VecDeque<_>, Vec<_>) = self
+            .take_frames(self.window.available())
+            .into_iter()
+            .unzip();
+        self.release_queued(fresh.make_contiguous());
+        // the fresh frames are taken in order, so their priorities still follow the retransmits
+        priorities.extend(fresh_priorities);
+        let mut packed = repack_frames(retransmits, &mut fresh, max_size);
+        let resent = packed.len();
+        packed.extend(pack_frames(fresh, max_size));
+        self.send_packed(packed, &priorities, now, resent);
+        self.split_ids.expire(now);
+    }
+
+    /// Send the packed frame sets, each of them is marked with the most urgent priority class
+    /// of its frames, the priorities are in the order of the frames. The first `resent` frame
+    /// sets carry the retransmits, they are not sampled by the rtt even if topped up with the
+    /// fresh frames.
+    fn send_packed(
+        &mut self,
+        packed: Vec<Vec<Frame<Bytes>>>,
+        priorities: &[Priority],
+        now: Instant,
+        resent: usize,
+    ) {
+        let mut priorities = priorities.iter().copied();
+        for (i, frames) in packed.into_iter().enumerate() {
+            let priority = priorities
+                .by_ref()
+                .take(frames.len())
+                .min()
+                .unwrap_or_default();
+            self.send_frame_set(frames, priority, now, i < resent, None);
+        }
+    }
+
+    /// The frames taken from the send queue, they are charged to the resend buffer once sent
+    fn release_queued(&mut self, frames: &[Frame<Bytes>]) {
+        self.budget
+            .shrink(Buffer::SendQueue, frames.iter().map(Frame::size).sum());
+    }
+
+    /// Probe the path with a ping padded to the probe size alone in a datagram
+    fn probe_path(&mut self, now: Instant) {
+        let PmtuAction::Probe(size) = self.pmtu.poll(now) else {
+            return;
+        };
+        let frame = Frame {
+            flags: Flags::new(Reliability::Unreliable, false),
+            reliable_frame_index: None,
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            body: probe_body(size, SystemClock.timestamp()),
+        };
+        self.send_frame_set(vec![frame], Priority::default(), now, false, Some(size));
+    }
+
+    /// Follow the mtu of the path, the messages queued since then are fragmented by it
+    fn set_mtu(&mut self, mtu: u16) {
+        peer_debug!(
+            self.verbosity,
+            self.peer.addr,
+            "mtu of {} changed from {} to {mtu}",
+            self.peer.addr,
+            self.peer.mtu
+        );
+        self.peer.mtu = mtu;
+        self.recorder.record_mtu(mtu);
+    }
+
+    /// Report the stall of the connection once it makes no forward progress
+    fn inspect(&mut self, now: Instant) {
+        if self.in_flight.is_empty() && self.retransmits.is_empty() {
+            self.waiting_since = None;
+        }
+        let progress = Progress {
+            oldest_unacked: self.waiting_since,
+            rto: self.rtt.rto(),
+            datagrams_in_flight: self.in_flight.len(),
+            bytes_in_flight: self.in_flight.values().map(|sent| sent.size as u64).sum(),
+            blocked_channel: self.recorder.blocked_channel(),
+        };
+        if let Some(diagnostic) = self.watchdog.check(&progress, now) {
+            peer_debug!(
+                self.verbosity,
+                self.peer.addr,
+                "connection to {} stalled: {diagnostic:?}",
+                self.peer.addr
+            );
+            self.events.emit(Event::Stalled(diagnostic));
+        }
+    }
+
+    /// Report the crossing of the watermarks by the unsent and unacked bytes
+    fn check_watermark(&mut self) {
+        let unsent: usize = self
+            .immediate
+            .iter()
+            .chain(
+                self.queue
+                    .iter()
+                    .chain(&self.retransmits)
+                    .map(|(frame, _)| frame),
+            )
+            .map(Frame::size)
+            .sum();
+        let unacked: usize = self.in_flight.values().map(|sent| sent.size).sum();
+        if let Some(event) = self.watermark.update(unsent + unacked) {
+            self.events.emit(event);
+        }
+    }
+
+    /// Start closing the connection, the `DisconnectNotification` and the pending data are
+    /// flushed until the linger deadline
+    fn close(&mut self, reason: DisconnectReason, now: Instant) {
+        self.push_body(FrameBody::Disconnect(reason), Reliability::ReliableOrdered);
+        self.closing = Some(Closing {
+            reason,
+            deadline: self.config.linger.deadline(now),
+        });
+    }
+
+    /// Whether all queued data has been sent and acknowledged
+    fn is_idle(&self) -> bool {
+        self.immediate.is_empty()
+            && self.queue.is_empty()
+            && self.retransmits.is_empty()
+            && self.resend.is_empty()
+    }
+
+    /// The next instant the connection should wake up by itself
+    fn next_wakeup(&self, now: Instant) -> Instant {
+        let mut next = self.keepalive.next_deadline();
+        let mut merge = |deadline: Option<Instant>| {
+            if let Some(deadline) = deadline {
+                next = next.min(deadline);
+            }
+        };
+        merge(self.closing.and_then(|closing| closing.deadline));
+        merge(self.pmtu.next_probe());
+        match self.config.drive_mode {
+            DriveMode::PerPacket => {
+                merge(self.timers.next_wakeup());
+                merge(self.acks.next_flush());
+            }
+            DriveMode::Tick(_) => {
+                let pending = !self.queue.is_empty()
+                    || !self.retransmits.is_empty()
+                    || !self.timers.is_empty()
+                    || self.acks.next_flush().is_some();
+                if pending {
+                    merge(self.ticker.next_wakeup(now));
+                }
+            }
+        }
+        next
+    }
 }
 
-pin_project! {
-    /// Process a raknet handshake, poll it to open a connection to the client
-    pub(crate) struct HandShake<F> {
-        #[pin]
-        frame: F,
-        client_protocol_ver: u8,
-        client_mtu: u16,
-        peer_addr: SocketAddr,
+impl Future for Conn {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let now = Instant::now();
+        while this.exit.is_none() {
+            match this.inbound.poll_next_unpin(cx) {
+                Poll::Ready(Some(Inbound::Packet(pack, at))) => this.on_inbound(pack, at, now),
+                Poll::Ready(Some(Inbound::Migrated(new))) => this.on_migrated(new),
+                // the route is removed, e.g. the server is dropped
+                Poll::Ready(None) => this.exit = Some(DisconnectReason::ServerShutdown),
+                Poll::Pending => break,
+            }
+        }
+        loop {
+            match this.decoded.poll_next_unpin(cx) {
+                Poll::Ready(Some(connected::Packet::FrameSet(frame_set))) => {
+                    for frame in frame_set.frames {
+                        this.on_frame(frame);
+                    }
+                }
+                Poll::Ready(Some(_)) => {}
+                // the codec gave up the connection, i.e. an ordered channel stalled
+                Poll::Ready(None) => {
+                    this.exit.get_or_insert(DisconnectReason::OrderedStalled);
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+        this.charge_decoder();
+        // the waiters are taken before the messages, so that the messages queued before them
+        // are queued by the connection before they are marked
+        let mut waiters = Vec::new();
+        while let Poll::Ready(Some(waiter)) = this.acked.poll_next_unpin(cx) {
+            waiters.push(waiter);
+        }
+        // stall the outgoing messages until the buffers are back under the budget
+        while this.closing.is_none() && !this.budget.exceeded() {
+            match this.outgoing.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(msg))) => this.push_message(msg, now),
+                Poll::Ready(Some(Err(reason))) => this.close(reason, now),
+                Poll::Ready(None) => this.close(DisconnectReason::Closed, now),
+                Poll::Pending => break,
+            }
+        }
+        let mark = this.reliable_count;
+        this.ack_waiters
+            .extend(waiters.into_iter().map(|waiter| (mark, waiter)));
+        if this.closing.is_none() && this.src.is_disconnected() {
+            // the handle is dropped by `Linger::Abort`
+            this.exit = Some(DisconnectReason::Closed);
+        }
+        match this.keepalive.poll(now) {
+            KeepaliveAction::Idle => {}
+            KeepaliveAction::Ping => this.push_body(
+                FrameBody::ConnectedPing {
+                    client_timestamp: SystemClock.timestamp(),
+                },
+                Reliability::Unreliable,
+            ),
+            KeepaliveAction::Timeout => this.exit = Some(DisconnectReason::Timeout),
+        }
+        if this.exit.is_none() {
+            this.flush(now);
+            this.probe_path(now);
+            this.inspect(now);
+            this.check_watermark();
+            this.recorder.record_congestion(this.window.stats());
+            this.recorder.record_loss_rate(this.window.loss_rate());
+            this.recorder.record_pending_splits(this.split_ids.in_use());
+        }
+        this.notify_acked();
+        if let Some(closing) = this.closing {
+            if this.is_idle() || closing.deadline.is_none_or(|deadline| now >= deadline) {
+                this.exit.get_or_insert(closing.reason);
+            }
+        }
+        if let Some(reason) = this.exit {
+            peer_debug!(
+                this.verbosity,
+                this.peer.addr,
+                "connection of {} is torn down, reason: {reason}",
+                this.peer.addr
+            );
+            // set before the channels are dropped, so that the handle sees it once they are
+            *this.exit_reason.lock().expect("exit reason lock poisoned") = Some(reason);
+            this.events.emit(Event::Disconnected { reason });
+            let _ = this.closed.send((this.peer.addr, this.id));
+            return Poll::Ready(());
+        }
+        let next = this.next_wakeup(now);
+        this.sleep.as_mut().reset(next.into());
+        if this.sleep.as_mut().poll(cx).is_ready() {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
     }
 }
 
+/// The size of the datagram on the path, which the mtu is compared with, including the IP and UDP
+/// headers
+fn path_size(size: usize) -> u16 {
+    u16::try_from(size + UDP_HEADER_SIZE).unwrap_or(u16::MAX)
+}
+
+/// The channel of the reliable ordered or sequenced frame, it stays in flight of the channel
+/// until acknowledged
+fn reliable_channel(frame: &Frame<Bytes>) -> Option<u8> {
+    frame
+        .ordered
+        .filter(|_| frame.reliable_frame_index.is_some())
+        .map(|ordered| ordered.channel)
+}
+
+/// The sequenced and ordered indices of the next frame of the reliability on the channel
+fn ordering(
+    writers: &mut HashMap<u8, OrderingWriter>,
+    reliability: Reliability,
+    channel: u8,
+) -> (Option<Uint24le>, Option<Ordered>) {
+    if !reliability.is_sequenced_or_ordered() {
+        return (None, None);
+    }
+    let (seq_frame_index, ordered) = writers.entry(channel).or_default().next(reliability);
+    (
+        seq_frame_index,
+        ordered.map(|frame_index| Ordered {
+            frame_index,
+            channel,
+        }),
+    )
+}
+
+/// The reliability of the fragments of a split message
+fn split_reliability(reliability: Reliability) -> Reliability {
+    match reliability {
+        Reliability::Unreliable | Reliability::UnreliableWithAckReceipt => Reliability::Reliable,
+        Reliability::UnreliableSequenced | Reliability::UnreliableSequencedWithAckReceipt => {
+            Reliability::ReliableSequenced
+        }
+        reliability => reliability,
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::Stream;
+
+use super::session::Sessions;
+
+/// How often the remaining connections are counted while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Drain the server for a maintenance window: the pings are no longer answered so that the
+/// server disappears from the server lists, the new handshakes are refused, and the established
+/// connections are left to close by themselves. It is shared by the server handle and the
+/// [`super::offline::OfflineHandler`].
+#[derive(Debug, Clone, Default)]
+pub(super) struct Drain {
+    draining: Arc<AtomicBool>,
+}
+
+impl Drain {
+    pub(super) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Start draining, the returned stream yields the count of the remaining connections
+    /// whenever it changes, and ends once all of them have left or the draining is resumed.
+    /// Orchestration could wait for it before shutting down the server.
+    pub(super) fn drain(&self, sessions: Sessions) -> impl Stream<Item = usize> {
+        self.draining.store(true, Ordering::Release);
+        let draining = Arc::clone(&self.draining);
+        futures::stream::unfold(None, move |last| {
+            let sessions = sessions.clone();
+            let draining = Arc::clone(&draining);
+            async move {
+                loop {
+                    if last == Some(0) || !draining.load(Ordering::Acquire) {
+                        return None;
+                    }
+                    let remaining = sessions.len();
+                    if last != Some(remaining) {
+                        return Some((remaining, Some(remaining)));
+                    }
+                    tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+                }
+            }
+        })
+    }
+
+    /// Advertise the server and accept the handshakes again, e.g. the maintenance is called off
+    pub(super) fn resume(&self) {
+        self.draining.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::server::session::Session;
+    use crate::stats::StatsRecorder;
+
+    fn session(port: u16) -> Session {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let (tx, _) = flume::unbounded();
+        Session::new(addr, u64::from(port), tx, Arc::new(StatsRecorder::new(1)))
+    }
+
+    #[tokio::test]
+    async fn test_drain_progress() {
+        let sessions = Sessions::default();
+        sessions.insert(session(1));
+        sessions.insert(session(2));
+        let drain = Drain::default();
+        assert!(!drain.is_draining());
+
+        let mut progress = Box::pin(drain.drain(sessions.clone()));
+        assert!(drain.is_draining());
+        assert_eq!(progress.next().await, Some(2));
+        sessions.remove(&SocketAddr::from(([127, 0, 0, 1], 1)));
+        assert_eq!(progress.next().await, Some(1));
+        sessions.remove(&SocketAddr::from(([127, 0, 0, 1], 2)));
+        assert_eq!(progress.next().await, Some(0));
+        assert_eq!(progress.next().await, None);
+
+        // resumed before all connections left
+        sessions.insert(session(3));
+        let mut resumed = Box::pin(drain.drain(sessions));
+        assert_eq!(resumed.next().await, Some(1));
+        drain.resume();
+        assert_eq!(resumed.next().await, None);
+        assert!(!drain.is_draining());
+    }
+}
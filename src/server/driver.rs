@@ -0,0 +1,103 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::FuturesUnordered;
+use futures::{Stream, StreamExt};
+use pin_project_lite::pin_project;
+
+/// How the connections are driven by the runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TaskMode {
+    /// Spawn a task for each connection
+    #[default]
+    PerConnection,
+    /// Multiplex many connections in one driver task to reduce the task-per-connection overhead
+    /// on massive servers
+    Shared,
+}
+
+pin_project! {
+    /// Drive many connections in one task. [`FuturesUnordered`] keeps a readiness list
+    /// internally, so only the connections woken up will be polled.
+    pub(super) struct SharedDriver<S, Fut> {
+        #[pin]
+        incoming: S,
+        incoming_closed: bool,
+        conns: FuturesUnordered<Fut>,
+    }
+}
+
+impl<S, Fut> SharedDriver<S, Fut> {
+    pub(super) fn new(incoming: S) -> Self {
+        Self {
+            incoming,
+            incoming_closed: false,
+            conns: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl<S, Fut> Future for SharedDriver<S, Fut>
+where
+    S: Stream<Item = Fut>,
+    Fut: Future<Output = ()>,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        while !*this.incoming_closed {
+            match this.incoming.as_mut().poll_next(cx) {
+                Poll::Ready(Some(conn)) => this.conns.push(conn),
+                Poll::Ready(None) => *this.incoming_closed = true,
+                Poll::Pending => break,
+            }
+        }
+        // drain all ready connections, FuturesUnordered yields to the runtime if it has polled
+        // too many connections
+        while let Poll::Ready(Some(())) = this.conns.poll_next_unpin(cx) {}
+        if *this.incoming_closed && this.conns.is_empty() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn test_shared_driver_works() {
+        let (tx, rx) = flume::unbounded::<BoxFuture<'static, ()>>();
+        let done = Arc::new(AtomicUsize::new(0));
+        let (notify_tx, notify_rx) = flume::unbounded::<()>();
+        for _ in 0..100 {
+            let done = done.clone();
+            let notify_rx = notify_rx.clone();
+            tx.send(
+                async move {
+                    notify_rx.recv_async().await.unwrap();
+                    done.fetch_add(1, Ordering::Relaxed);
+                }
+                .boxed(),
+            )
+            .unwrap();
+        }
+        drop(tx);
+        for _ in 0..100 {
+            notify_tx.send(()).unwrap();
+        }
+        block_on(SharedDriver::new(rx.into_stream()));
+        assert_eq!(done.load(Ordering::Relaxed), 100);
+    }
+}
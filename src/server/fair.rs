@@ -0,0 +1,331 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+use flume::r#async::RecvStream;
+use futures::{Sink, Stream, StreamExt};
+use pin_project_lite::pin_project;
+use tracing::debug;
+
+use super::qos::Marking;
+use crate::message::Priority;
+
+/// The weight of a connection unless it is set, the quota of a connection per round is the
+/// quantum scaled by its weight over it
+pub(super) const DEFAULT_WEIGHT: u16 = 100;
+
+/// The bandwidth weights of the connections, e.g. the spectators lower than the players. It is
+/// shared by the server handle and the scheduler, so the weights could be changed at runtime.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Weights {
+    weights: Arc<RwLock<HashMap<SocketAddr, u16>>>,
+}
+
+impl Weights {
+    /// Set the weight of the connection, it takes effect from its next round. The weight 0 is
+    /// treated as 1 so that the connection is never starved.
+    pub(super) fn set(&self, addr: SocketAddr, weight: u16) {
+        self.weights
+            .write()
+            .expect("weights lock poisoned")
+            .insert(addr, weight.max(1));
+    }
+
+    /// Restore the default weight of the connection
+    pub(super) fn reset(&self, addr: &SocketAddr) {
+        self.weights
+            .write()
+            .expect("weights lock poisoned")
+            .remove(addr);
+    }
+
+    pub(super) fn get(&self, addr: &SocketAddr) -> u16 {
+        self.weights
+            .read()
+            .expect("weights lock poisoned")
+            .get(addr)
+            .copied()
+            .unwrap_or(DEFAULT_WEIGHT)
+    }
+}
+
+/// The outgoing queue of a connection
+#[derive(Debug)]
+struct Flow<T> {
+    // Bytes this connection is allowed to send in current round
+    deficit: usize,
+    queue: VecDeque<(T, usize)>,
+}
+
+/// Flush the outgoing datagrams of connections in round-robin (deficit round robin) with
+/// per-connection byte quotas, so one peer with a huge backlog can't starve others sharing the
+/// socket. The quotas are weighted by [`Weights`].
+#[derive(Debug)]
+pub(super) struct FairScheduler<T> {
+    // Bytes granted to each connection of the default weight per round
+    quantum: usize,
+    weights: Weights,
+    flows: HashMap<SocketAddr, Flow<T>>,
+    // Connections which have pending datagrams, in round-robin order
+    active: VecDeque<SocketAddr>,
+}
+
+impl<T> FairScheduler<T> {
+    pub(super) fn new(quantum: usize, weights: Weights) -> Self {
+        Self {
+            quantum,
+            weights,
+            flows: HashMap::new(),
+            active: VecDeque::new(),
+        }
+    }
+
+    /// Push an outgoing datagram with its size in bytes
+    pub(super) fn push(&mut self, addr: SocketAddr, item: T, size: usize) {
+        let flow = self.flows.entry(addr).or_insert_with(|| Flow {
+            deficit: 0,
+            queue: VecDeque::new(),
+        });
+        if flow.queue.is_empty() {
+            self.active.push_back(addr);
+        }
+        flow.queue.push_back((item, size));
+    }
+
+    /// Pop the next datagram to be flushed
+    pub(super) fn pop(&mut self) -> Option<(SocketAddr, T)> {
+        loop {
+            let addr = *self.active.front()?;
+            let flow = self.flows.get_mut(&addr).expect("active flow must exist");
+            let &(_, size) = flow.queue.front().expect("active flow must not be empty");
+            if flow.deficit < size {
+                // start a new round for this connection, the quota accumulates until the
+                // datagram fits in
+                let weight = usize::from(self.weights.get(&addr));
+                flow.deficit += (self.quantum * weight / usize::from(DEFAULT_WEIGHT)).max(1);
+                self.active.rotate_left(1);
+                if self.active.front() != Some(&addr) {
+                    continue;
+                }
+                // no one else to be fair to
+            }
+            let (item, _) = flow.queue.pop_front().expect("checked above");
+            flow.deficit = flow.deficit.saturating_sub(size);
+            if flow.queue.is_empty() {
+                // idle connections do not accumulate quota
+                self.flows.remove(&addr);
+                self.active.pop_front();
+            }
+            return Some((addr, item));
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+}
+
+pin_project! {
+    /// Flush the datagrams of the connections to the socket in the order of the
+    /// [`FairScheduler`]. The datagrams are pumped whenever the stream is polled, so it should be
+    /// placed in the receive loop, which is woken up by the connections sending datagrams.
+    pub(super) struct Flush<F> {
+        #[pin]
+        frame: F,
+        outbound: RecvStream<'static, (SocketAddr, BytesMut, Priority)>,
+        scheduler: FairScheduler<(BytesMut, Priority)>,
+        // Tell the socket the priority class of the datagram being sent
+        marking: Marking,
+    }
+}
+
+pub(super) trait Flushed: Sized {
+    fn flushed(
+        self,
+        outbound: flume::Receiver<(SocketAddr, BytesMut, Priority)>,
+        quantum: usize,
+        weights: Weights,
+        marking: Marking,
+    ) -> Flush<Self>;
+}
+
+impl<F> Flushed for F {
+    fn flushed(
+        self,
+        outbound: flume::Receiver<(SocketAddr, BytesMut, Priority)>,
+        quantum: usize,
+        weights: Weights,
+        marking: Marking,
+    ) -> Flush<Self> {
+        Flush {
+            frame: self,
+            outbound: outbound.into_stream(),
+            scheduler: FairScheduler::new(quantum, weights),
+            marking,
+        }
+    }
+}
+
+impl<F> Flush<F>
+where
+    F: Sink<(BytesMut, SocketAddr), Error = io::Error>,
+{
+    /// Send the scheduled datagrams until the frame is not ready
+    fn pump(self: Pin<&mut Self>, cx: &mut Context<'_>) {
+        let mut this = self.project();
+        while let Poll::Ready(Some((addr, data, priority))) = this.outbound.poll_next_unpin(cx) {
+            let size = data.len();
+            this.scheduler.push(addr, (data, priority), size);
+        }
+        while !this.scheduler.is_empty() {
+            match this.frame.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                // the failed datagram is dropped like it is lost on the path
+                Poll::Ready(Err(err)) => {
+                    debug!("failed to flush a datagram: {err}");
+                    continue;
+                }
+                Poll::Pending => return,
+            }
+            let (addr, (data, priority)) = this.scheduler.pop().expect("scheduler is not empty");
+            this.marking.set(priority);
+            if let Err(err) = this.frame.as_mut().start_send((data, addr)) {
+                debug!("failed to flush a datagram to {addr}: {err}");
+            }
+        }
+        if let Poll::Ready(Err(err)) = this.frame.poll_flush(cx) {
+            debug!("failed to flush a datagram: {err}");
+        }
+    }
+}
+
+impl<F> Stream for Flush<F>
+where
+    F: Stream + Sink<(BytesMut, SocketAddr), Error = io::Error>,
+{
+    type Item = F::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.as_mut().pump(cx);
+        self.project().frame.poll_next(cx)
+    }
+}
+
+/// The offline replies are sent straight through, they are few and never starve the connections
+impl<F, T> Sink<T> for Flush<F>
+where
+    F: Sink<T>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.project().frame.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fair_scheduler_round_robin() {
+        let heavy: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let light: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let mut scheduler = FairScheduler::new(1000, Weights::default());
+        for i in 0..100 {
+            scheduler.push(heavy, i, 500);
+        }
+        scheduler.push(light, 100, 500);
+        scheduler.push(light, 101, 500);
+        assert_eq!(
+            scheduler
+                .flows
+                .values()
+                .map(|flow| flow.queue.len())
+                .sum::<usize>(),
+            102
+        );
+
+        let order = std::iter::from_fn(|| scheduler.pop())
+            .take(6)
+            .collect::<Vec<_>>();
+        // every connection sends 1000 bytes per round
+        assert_eq!(
+            order,
+            vec![
+                (heavy, 0),
+                (heavy, 1),
+                (light, 100),
+                (light, 101),
+                (heavy, 2),
+                (heavy, 3)
+            ]
+        );
+        assert_eq!(
+            scheduler
+                .flows
+                .values()
+                .map(|flow| flow.queue.len())
+                .sum::<usize>(),
+            96
+        );
+    }
+
+    #[test]
+    fn test_fair_scheduler_weighted() {
+        let player: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let spectator: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let weights = Weights::default();
+        weights.set(player, 200);
+        weights.set(spectator, 50);
+        let mut scheduler = FairScheduler::new(1000, weights.clone());
+        for i in 0..10 {
+            scheduler.push(player, i, 500);
+            scheduler.push(spectator, 100 + i, 500);
+        }
+
+        let order = std::iter::from_fn(|| scheduler.pop())
+            .take(10)
+            .map(|(_, item)| item)
+            .collect::<Vec<_>>();
+        // 2000 bytes for the player and 500 bytes for the spectator per round
+        assert_eq!(order, vec![0, 1, 2, 3, 100, 4, 5, 6, 7, 101]);
+
+        // the quota granted in the current round is kept
+        weights.reset(&player);
+        assert_eq!(weights.get(&player), DEFAULT_WEIGHT);
+        let kept = std::iter::from_fn(|| scheduler.pop())
+            .take(3)
+            .map(|(_, item)| item)
+            .collect::<Vec<_>>();
+        assert_eq!(kept, vec![8, 9, 102]);
+    }
+
+    #[test]
+    fn test_fair_scheduler_large_datagram() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut scheduler = FairScheduler::new(100, Weights::default());
+        scheduler.push(addr, 0, 1500);
+        scheduler.push(addr, 1, 1500);
+        assert_eq!(scheduler.pop(), Some((addr, 0)));
+        assert_eq!(scheduler.pop(), Some((addr, 1)));
+        assert_eq!(scheduler.pop(), None);
+        assert!(scheduler.is_empty());
+    }
+}
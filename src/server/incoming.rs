@@ -1,90 +1,706 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use bytes::{Bytes, BytesMut};
 use flume::r#async::{RecvStream, SendSink};
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
 use futures::{ready, FutureExt, Sink, SinkExt, Stream, StreamExt};
 use pin_project_lite::pin_project;
-use tracing::error;
 
-use super::handshake::HandShaking;
-use super::IO;
-use crate::codec::{CodecConfig, Decoded};
+use super::backlog::{AcceptBacklog, BacklogSlot};
+use super::broadcast::Broadcaster;
+use super::budget::GlobalMemory;
+use super::conn::{Conn, ConnConfig, ConnIo, Events, Inbound};
+use super::driver::{SharedDriver, TaskMode};
+use super::fair::Weights;
+use super::lifecycle::{Lifecycle, SessionGuard};
+use super::linger::Linger;
+use super::session::{Session, Sessions};
+use super::socket::Arrival;
+use super::tap::{Tap, Tapped};
+use super::verbosity::{peer_debug, PeerVerbosity};
+use super::Outgoing;
+use crate::clock::ClockDifferential;
 use crate::errors::{CodecError, Error};
-use crate::packet::{connected, Packet};
+use crate::event::{DisconnectReason, Event};
+use crate::message::{Message, Priority, Received};
+use crate::packet::{connected, PackType, Packet};
+use crate::rt::{Runtime, TaskNaming, Tokio};
+use crate::stats::{ConnectionStats, EventLoopRecorder, StatsRecorder};
 use crate::Peer;
 
+/// The max packets buffered for a deferred connection before its `ConnectionRequest` arrives
+const MAX_DEFERRED_PACKETS: usize = 16;
+
+/// The route of a connection in the receive loop
+#[derive(Debug)]
+struct Route {
+    // Tell the routes of the connections sharing an address apart, e.g. the torn down one and
+    // the one replacing it
+    id: u64,
+    inbound: flume::Sender<Inbound>,
+}
+
 pin_project! {
-    struct Incoming<F> {
+    /// Route the connected packets to the connection tasks, and yield the handles of the new
+    /// connections once their sessions are opened
+    pub(super) struct Incoming<F> {
         #[pin]
         frame: F,
-        router: HashMap<SocketAddr, SendSink<'static, connected::Packet<BytesMut>>>
+        config: ConnConfig,
+        router: HashMap<SocketAddr, Route>,
+        next_id: u64,
+        broadcaster: Broadcaster,
+        // The address the socket is bound to, with the port resolved when binding to port 0
+        local_addr: SocketAddr,
+        // When the datagram being routed arrived
+        arrival: Arrival,
+        // The connections claim their slots from it, which are released when they are accepted
+        backlog: Arc<AcceptBacklog>,
+        // Recorded by the socket IO tasks
+        event_loop: Arc<EventLoopRecorder>,
+        // Charged by the memory budget of each connection, read by the offline handler
+        memory: Arc<GlobalMemory>,
+        // Shared with the server handle to look up the sessions
+        sessions: Sessions,
+        // Move the connection to the new address of a client handshaking again with the same
+        // GUID, instead of replacing it
+        migration: bool,
+        // Surface a new connection only after its `ConnectionRequest` arrives
+        deferred_accept: bool,
+        // The packets arrived before the `ConnectionRequest` of the deferred connections, fed to
+        // the connections once made. They are dropped with the backlog slots of the abandoned
+        // handshakes
+        deferred: HashMap<SocketAddr, Vec<(connected::Packet<BytesMut>, Instant)>>,
+        // The bytes added to every datagram by the datagram hook, reserved from the mtu
+        overhead: usize,
+        // Shared with the server handle to elevate the logs of some peers
+        verbosity: PeerVerbosity,
+        // Shared with the flush scheduler to weight the bandwidth of the connections
+        weights: Weights,
+        // Provision and release the resources of each session
+        lifecycle: Lifecycle,
+        naming: TaskNaming,
+        // The shared driver tasks the connections are handed to in turn, empty if each
+        // connection is spawned as a task
+        drivers: Vec<flume::Sender<Conn>>,
+        // The encoded datagrams of the connections, flushed by the receive loop
+        outbound: flume::Sender<(SocketAddr, BytesMut, Priority)>,
+        // The connection tasks exited, with their ids
+        closed: RecvStream<'static, (SocketAddr, u64)>,
+        closed_tx: flume::Sender<(SocketAddr, u64)>,
+        // Notify the offline handler of the torn down connections
+        closer: flume::Sender<SocketAddr>,
+        // The connections waiting for their sessions to be opened
+        opening: FuturesUnordered<BoxFuture<'static, Connection>>,
+    }
+}
+
+/// The shared states of a server the receive loop is built with
+#[derive(Debug)]
+pub(super) struct IncomingParts {
+    pub(super) config: ConnConfig,
+    pub(super) local_addr: SocketAddr,
+    pub(super) arrival: Arrival,
+    pub(super) backlog: Arc<AcceptBacklog>,
+    pub(super) event_loop: Arc<EventLoopRecorder>,
+    pub(super) memory: Arc<GlobalMemory>,
+    pub(super) migration: bool,
+    pub(super) deferred_accept: bool,
+    pub(super) overhead: usize,
+    pub(super) verbosity: PeerVerbosity,
+    pub(super) weights: Weights,
+    pub(super) lifecycle: Lifecycle,
+    pub(super) task_mode: TaskMode,
+    pub(super) naming: TaskNaming,
+    pub(super) outbound: flume::Sender<(SocketAddr, BytesMut, Priority)>,
+    pub(super) closer: flume::Sender<SocketAddr>,
+}
+
+pub(super) trait Incomed: Sized {
+    fn incoming(self, parts: IncomingParts) -> Incoming<Self>;
+}
+
+impl<F> Incomed for F {
+    fn incoming(self, parts: IncomingParts) -> Incoming<Self> {
+        let (closed_tx, closed_rx) = flume::unbounded();
+        let drivers = match parts.task_mode {
+            TaskMode::PerConnection => Vec::new(),
+            TaskMode::Shared => {
+                let count = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+                (0..count)
+                    .map(|index| {
+                        let (tx, rx) = flume::unbounded();
+                        Tokio::spawn_named(
+                            &parts.naming.shared_driver(index),
+                            SharedDriver::new(rx.into_stream()),
+                        );
+                        tx
+                    })
+                    .collect()
+            }
+        };
+        Incoming {
+            frame: self,
+            config: parts.config,
+            router: HashMap::new(),
+            next_id: 0,
+            broadcaster: Broadcaster::default(),
+            local_addr: parts.local_addr,
+            arrival: parts.arrival,
+            backlog: parts.backlog,
+            event_loop: parts.event_loop,
+            memory: parts.memory,
+            sessions: Sessions::default(),
+            migration: parts.migration,
+            deferred_accept: parts.deferred_accept,
+            deferred: HashMap::new(),
+            overhead: parts.overhead,
+            verbosity: parts.verbosity,
+            weights: parts.weights,
+            lifecycle: parts.lifecycle,
+            naming: parts.naming,
+            drivers,
+            outbound: parts.outbound,
+            closed: closed_rx.into_stream(),
+            closed_tx,
+            closer: parts.closer,
+            opening: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl<F> Incoming<F> {
+    /// Get the broadcaster to the connections of the server
+    pub(super) fn broadcaster(&self) -> Broadcaster {
+        self.broadcaster.clone()
+    }
+
+    /// Get the sessions of the server, find a session by its address or client GUID
+    pub(super) fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
+}
+
+impl<F> Incoming<F>
+where
+    F: Stream<Item = (connected::Packet<BytesMut>, Peer)>,
+{
+    /// Tear down the route of the connection task exited, unless the address is taken by
+    /// another connection since then
+    fn teardown(self: Pin<&mut Self>, addr: SocketAddr, id: u64) {
+        let this = self.project();
+        if this.router.get(&addr).map(|route| route.id) != Some(id) {
+            return;
+        }
+        this.router.remove(&addr);
+        this.broadcaster.unregister(&addr);
+        this.sessions.remove(&addr);
+        this.weights.reset(&addr);
+        // the offline handler lives as long as the receive loop
+        let _ = this.closer.send(addr);
+    }
+
+    /// Spawn the task of a new connection and open its session
+    fn connect(self: Pin<&mut Self>, pack: connected::Packet<BytesMut>, mut peer: Peer) {
+        let this = self.project();
+        // the datagrams on the wire are still within the negotiated mtu after the hook
+        let overhead = u16::try_from(*this.overhead).unwrap_or(u16::MAX);
+        peer.mtu = peer.mtu.saturating_sub(overhead);
+        let mut config = *this.config;
+        config.max_mtu = config.max_mtu.saturating_sub(overhead);
+        let id = *this.next_id;
+        *this.next_id += 1;
+        let (inbound_tx, inbound_rx) = flume::unbounded();
+        let (src_tx, src_rx) = flume::unbounded();
+        let (dst_tx, dst_rx) = flume::unbounded();
+        let (acked_tx, acked_rx) = flume::unbounded();
+        // the receiver is held below
+        for (early, at) in this.deferred.remove(&peer.addr).unwrap_or_default() {
+            let _ = inbound_tx.send(Inbound::Packet(early, at));
+        }
+        let _ = inbound_tx.send(Inbound::Packet(pack, this.arrival.get()));
+        this.router.insert(
+            peer.addr,
+            Route {
+                id,
+                inbound: inbound_tx.clone(),
+            },
+        );
+        this.broadcaster.register(peer.addr, dst_tx.clone());
+
+        let recorder = Arc::new(StatsRecorder::new(this.config.codec.max_channels()));
+        recorder.record_mtu(peer.mtu);
+        let session = Session::new(peer.addr, peer.guid, dst_tx.clone(), Arc::clone(&recorder));
+        for replaced in this.sessions.insert(session) {
+            // the client reconnected before the previous session timed out
+            replaced.kick(DisconnectReason::Closed);
+        }
+        let events = Events::default();
+        let peer_addr = Arc::new(Mutex::new(peer.addr));
+        let outbound_tap = Tap::default();
+        let exit_reason = Arc::new(Mutex::new(None));
+        let clock = Arc::new(ClockDifferential::default());
+        let conn = Conn::new(
+            id,
+            peer.clone(),
+            *this.local_addr,
+            config,
+            ConnIo {
+                inbound: inbound_rx,
+                outgoing: dst_rx,
+                src: src_tx,
+                acked: acked_rx,
+                outbound: this.outbound.clone(),
+                tap: outbound_tap.clone(),
+                closed: this.closed_tx.clone(),
+                events: events.clone(),
+                peer_addr: Arc::clone(&peer_addr),
+                exit_reason: Arc::clone(&exit_reason),
+                recorder: Arc::clone(&recorder),
+                memory: Arc::clone(this.memory),
+                clock: Arc::clone(&clock),
+                verbosity: this.verbosity.clone(),
+            },
+        );
+        if this.drivers.is_empty() {
+            Tokio::spawn_named(&this.naming.connection(peer.addr), conn);
+        } else {
+            let driver = id as usize % this.drivers.len();
+            // the drivers exit after the receive loop
+            let _ = this.drivers[driver].send(conn);
+        }
+
+        let mut io = Connection {
+            closed: false,
+            dst: dst_tx.into_sink(),
+            src: src_rx.into_stream(),
+            acked: acked_tx,
+            peeked: None,
+            injector: inbound_tx,
+            outbound_tap,
+            events,
+            session: None,
+            backlog: this.backlog.claim(&peer.addr),
+            recorder,
+            local_addr: *this.local_addr,
+            peer_addr,
+            exit_reason,
+            clock,
+            linger: this.config.linger,
+        };
+        let lifecycle = this.lifecycle.clone();
+        this.opening.push(Box::pin(async move {
+            io.session = Some(lifecycle.open(peer.addr, peer.guid).await);
+            io
+        }));
     }
 }
 
 impl<F> Stream for Incoming<F>
 where
-    F: Stream<Item = (connected::Packet<BytesMut>, Peer)>
-        + Sink<(Packet<Bytes>, SocketAddr), Error = CodecError>,
+    F: Stream<Item = (connected::Packet<BytesMut>, Peer)>,
 {
-    type Item = IO;
+    type Item = Connection;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut this = self.project();
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         loop {
-            let Some((pack, peer)) = ready!(this.frame.poll_next_unpin(cx)) else {
-                return Poll::Ready(None::<IOImpl>);
+            while let Poll::Ready(Some((addr, id))) =
+                self.as_mut().project().closed.poll_next_unpin(cx)
+            {
+                self.as_mut().teardown(addr, id);
+            }
+            if let Poll::Ready(Some(io)) = self.as_mut().project().opening.poll_next_unpin(cx) {
+                return Poll::Ready(Some(io));
+            }
+            let mut this = self.as_mut().project();
+            let Some((pack, peer)) = ready!(this.frame.as_mut().poll_next(cx)) else {
+                return Poll::Ready(None);
             };
-            if let Some(sink) = this.router.get_mut(&peer.addr) {
-                if ready!(sink.send(pack).poll_unpin(cx)).is_err() {
-                    error!("connection was dropped before closed");
-                    this.router.remove(&peer.addr);
+            let at = this.arrival.get();
+            if let Some(route) = this.router.get(&peer.addr) {
+                // the torn down connection is cleaned up by its closed notification
+                let _ = route.inbound.send(Inbound::Packet(pack, at));
+                continue;
+            }
+            if *this.migration {
+                if let Some(Event::AddressChanged { old, new }) =
+                    this.sessions.migrate(peer.guid, peer.addr)
+                {
+                    peer_debug!(
+                        this.verbosity,
+                        peer.addr,
+                        "connection of client {} moved from {old} to {new}",
+                        peer.guid
+                    );
+                    if let Some(route) = this.router.remove(&old) {
+                        // the connection switches to the new address before the packet
+                        let _ = route.inbound.send(Inbound::Migrated(new));
+                        let _ = route.inbound.send(Inbound::Packet(pack, at));
+                        this.router.insert(new, route);
+                    }
+                    if let Some(dst) = this.broadcaster.unregister(&old) {
+                        this.broadcaster.register(new, dst);
+                    }
+                    this.weights.set(new, this.weights.get(&old));
+                    this.weights.reset(&old);
+                    // the connection is continued, not made for the handshake of the new address
+                    this.backlog.forget(&new);
+                    continue;
+                }
+            }
+            if *this.deferred_accept && !is_connection_request(&pack) {
+                if !this.deferred.contains_key(&peer.addr) {
+                    let backlog = &this.backlog;
+                    this.deferred.retain(|addr, _| backlog.is_reserved(addr));
+                }
+                let early = this.deferred.entry(peer.addr).or_default();
+                if early.len() >= MAX_DEFERRED_PACKETS {
+                    peer_debug!(
+                        this.verbosity,
+                        peer.addr,
+                        "too many packets before the connection request of {}, drop it",
+                        peer.addr
+                    );
+                    continue;
                 }
+                peer_debug!(
+                    this.verbosity,
+                    peer.addr,
+                    "defer the connection of {} until its connection request arrives",
+                    peer.addr
+                );
+                early.push((pack, at));
                 continue;
             }
-            let (src_tx, src_rx) = flume::unbounded();
-            let (dst_tx, dst_rx) = flume::unbounded();
-            this.router.insert(peer.addr, src_tx.into_sink());
-
-            let src_stream = src_rx
-                .into_stream()
-                .map(Ok)
-                .decoded(peer.addr, CodecConfig::default())
-                .zip(futures::stream::repeat(peer))
-                .handshaking();
-
-            // TODO: add ack for src_stream
-
-            let io = IOImpl {
-                closed: false,
-                dst: dst_tx.into_sink(),
-                src: (),
-            };
-            // TODO: spawn a outgoing task here to retrieve data in dst_rx from IOImpl, and then
-            // send it to frame
+            self.as_mut().connect(pack, peer);
         }
-
-        Poll::Pending
     }
 }
 
-struct IOImpl {
+/// Whether the packet carries a `ConnectionRequest`, which proves the peer could complete the
+/// connected handshake
+fn is_connection_request(pack: &connected::Packet<BytesMut>) -> bool {
+    let connected::Packet::FrameSet(frame_set) = pack else {
+        return false;
+    };
+    frame_set.frames.iter().any(|frame| {
+        frame.fragment.is_none()
+            && frame.body.first() == Some(&u8::from(PackType::ConnectionRequest))
+    })
+}
+
+/// A connection accepted by the server, it receives the messages of the peer as a [`Stream`] and
+/// sends the messages as a [`Sink`]
+pub struct Connection {
     closed: bool,
-    dst: SendSink<'static, Option<Bytes>>, // None means close the connection
-    src: RecvStream<'static, Bytes>,
+    dst: SendSink<'static, Outgoing>, // Err means close the connection
+    src: RecvStream<'static, Received>,
+    // Register the waiters of the acknowledgement to the connection task
+    acked: flume::Sender<oneshot::Sender<()>>,
+    // The message yielded by peek, it is taken first by the next read
+    peeked: Option<Received>,
+    // Feed the packets of the injected datagrams to the codec stack of this connection
+    injector: flume::Sender<Inbound>,
+    // Capture the encoded datagrams sent to the peer
+    outbound_tap: Tap,
+    events: Events,
+    // Runs the disconnect hook once the connection is closed or dropped
+    session: Option<SessionGuard>,
+    // Released once the connection is yielded to the application by the accept stream
+    backlog: Option<BacklogSlot>,
+    recorder: Arc<StatsRecorder>,
+    local_addr: SocketAddr,
+    // Updated by the connection task once the peer migrates
+    peer_addr: Arc<Mutex<SocketAddr>>,
+    // Set by the connection task once it is torn down, e.g. the peer disconnected
+    exit_reason: Arc<Mutex<Option<DisconnectReason>>>,
+    clock: Arc<ClockDifferential>,
+    linger: Linger,
+}
+
+impl std::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("local_addr", &self.local_addr)
+            .field("peer_addr", &self.peer_addr())
+            .field("closed", &self.closed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Connection {
+    /// The error of the connection task being gone, [`Error::Disconnected`] with the reason if
+    /// it is known, e.g. the peer sent it along with the `DisconnectNotification`
+    fn closed_by_peer(&self) -> Error {
+        self.exit_reason
+            .lock()
+            .expect("exit reason lock poisoned")
+            .map_or(
+                Error::ConnectionClosed("connection closed by peer"),
+                Error::Disconnected,
+            )
+    }
+
+    /// Release the accept backlog slot of the connection, it is accepted by the application
+    pub(super) fn accepted(&mut self) {
+        self.backlog.take();
+    }
+
+    /// Get the statistics of this connection
+    pub fn stats(&self) -> ConnectionStats {
+        self.recorder.snapshot()
+    }
+
+    /// Get the current mtu of the path, messages larger than it minus the frame headers will be
+    /// fragmented. It starts at the negotiated mtu and follows the path mtu discovery.
+    pub fn mtu(&self) -> u16 {
+        self.recorder.mtu()
+    }
+
+    /// Get the bytes sent but not acknowledged by the peer
+    pub fn bytes_in_flight(&self) -> u64 {
+        self.recorder.bytes_in_flight()
+    }
+
+    /// Get the smoothed ratio of the lost datagrams, in 0.0..=1.0
+    pub fn loss_rate(&self) -> f32 {
+        self.recorder.loss_rate()
+    }
+
+    /// Receive the events of this connection, e.g. [`Event::CongestionRecovery`]. At most
+    /// `capacity` events are buffered, the rest will be dropped until they are received. The
+    /// previous receiver is detached.
+    pub fn events(&self, capacity: usize) -> flume::Receiver<Event> {
+        self.events.subscribe(capacity)
+    }
+
+    /// Get the local address of this connection
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get the address of the peer, it follows the migrations of the peer, see
+    /// [`Event::AddressChanged`]
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection task panicked while holding the address
+    pub fn peer_addr(&self) -> SocketAddr {
+        *self.peer_addr.lock().expect("peer address lock poisoned")
+    }
+
+    /// Receive the next message along with its reliability, channel, indices and receive
+    /// timestamp. None if the connection is closed.
+    pub async fn recv_message(&mut self) -> Option<Received> {
+        if let Some(peeked) = self.peeked.take() {
+            return Some(peeked);
+        }
+        self.src.next().await
+    }
+
+    /// Look at the next message without consuming it, the next read yields it again. None if
+    /// the connection is closed.
+    pub async fn peek(&mut self) -> Option<&Received> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.src.next().await?);
+        }
+        self.peeked.as_ref()
+    }
+
+    /// Feed a raw datagram to the connection as if it arrived from the peer on the socket, it
+    /// goes through the full codec stack. Useful to replay the recorded sessions or to bridge a
+    /// transport other than UDP. Only the connected datagrams (frame sets, acks and nacks) are
+    /// accepted.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of decoding the datagram, or [`Error::ConnectionClosed`] if the
+    /// connection is closed
+    pub async fn inject_datagram(&self, datagram: Bytes) -> Result<(), Error> {
+        let mut buf = BytesMut::from(&datagram[..]);
+        let pack = match Packet::read(&mut buf)? {
+            Some(Packet::Connected(pack)) => pack,
+            Some(Packet::Unconnected(pack)) => {
+                return Err(CodecError::InvalidPacketType(pack.pack_type().into()).into())
+            }
+            None => return Err(CodecError::InvalidPacketLength("injected datagram").into()),
+        };
+        self.injector
+            .send_async(Inbound::Packet(pack, Instant::now()))
+            .await
+            .map_err(|_| self.closed_by_peer())
+    }
+
+    /// Tap the raw datagrams sent to the peer after the full codec stack and before the datagram
+    /// hook, e.g. to record the sessions or to bridge them to another transport. At most
+    /// `capacity` datagrams are buffered, the datagrams are dropped if the receiver falls behind.
+    /// The previous receiver is detached.
+    pub fn tap_outbound(&self, capacity: usize) -> flume::Receiver<Tapped> {
+        self.outbound_tap.subscribe(capacity)
+    }
+
+    /// Send a batch of messages in one call, e.g. the entity updates of a tick. The messages
+    /// queued together will be packed into as few datagrams as possible.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ConnectionClosed`] or [`Error::Disconnected`] if the connection is closed
+    pub async fn send_batch(
+        &mut self,
+        msgs: impl IntoIterator<Item = Message>,
+    ) -> Result<(), Error> {
+        for msg in msgs {
+            self.feed(msg).await?;
+        }
+        SinkExt::<Message>::flush(self).await
+    }
+
+    /// Send a latency critical message, e.g. the player input, at once. It is marked as
+    /// [`Priority::Immediate`] and flushed without waiting for the tick of the connection, the
+    /// datagram is still limited by the mtu. The other messages keep being batched.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ConnectionClosed`] or [`Error::Disconnected`] if the connection is closed
+    pub async fn send_immediate(&mut self, msg: Message) -> Result<(), Error> {
+        self.send(msg.priority(Priority::Immediate)).await
+    }
+
+    /// Get the smoothed clock offset of the peer in milliseconds (remote minus local), a remote
+    /// timestamp minus the offset is the local time it was taken. None if it has not been
+    /// measured by the connected pings yet.
+    pub fn clock_offset(&self) -> Option<i64> {
+        self.clock.offset()
+    }
+
+    /// Close the connection with the reason, which is sent to the peer along with the
+    /// `DisconnectNotification`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ConnectionClosed`] if the connection was closed before, or
+    /// [`Error::Disconnected`] if the connection task is gone
+    pub async fn disconnect(&mut self, reason: DisconnectReason) -> Result<(), Error> {
+        if self.closed {
+            return Err(Error::ConnectionClosed("connection was closed before"));
+        }
+        self.closed = true;
+        let sent = self
+            .dst
+            .send(Err(reason))
+            .await
+            .map_err(|_| self.closed_by_peer());
+        if let Some(session) = &mut self.session {
+            session.close().await;
+        }
+        sent
+    }
+
+    /// Flush the queued messages and wait until all the reliable data among them has been
+    /// acknowledged by the peer, e.g. before a save point. Unlike `flush`, which returns once
+    /// the messages are handed to the connection task, it guarantees the delivery.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ConnectionClosed`] or [`Error::Disconnected`] if the connection is closed
+    /// before all is acknowledged
+    pub async fn flush_acked(&mut self) -> Result<(), Error> {
+        if self.closed {
+            return Err(Error::ConnectionClosed("connection was closed before"));
+        }
+        SinkExt::<Message>::flush(self).await?;
+        self.wait_acked().await
+    }
+
+    /// Wait until the peer has acknowledged all the reliable data of the messages queued
+    /// before, without flushing them
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ConnectionClosed`] or [`Error::Disconnected`] if the connection is closed
+    /// before all is acknowledged
+    pub async fn wait_acked(&self) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.acked
+            .send_async(tx)
+            .await
+            .map_err(|_| self.closed_by_peer())?;
+        rx.await.map_err(|_| self.closed_by_peer())
+    }
+
+    /// Close the connection handed off to another server, e.g. after a proxy sent the Bedrock
+    /// `Transfer` packet. The pending reliable data is flushed and waited to be acknowledged for
+    /// at most the timeout, then the connection is closed with the `DisconnectNotification` of
+    /// the reason, or silently if it is None so that the client is not kicked before it
+    /// switches, the connection is then torn down once the handle is dropped. Returns whether
+    /// the peer acknowledged everything.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ConnectionClosed`] if the connection was closed before
+    pub async fn close_for_transfer(
+        &mut self,
+        notify: Option<DisconnectReason>,
+        timeout: Duration,
+    ) -> Result<bool, Error> {
+        if self.closed {
+            return Err(Error::ConnectionClosed("connection was closed before"));
+        }
+        SinkExt::<Message>::flush(self).await?;
+        let acked = matches!(
+            tokio::time::timeout(timeout, self.wait_acked()).await,
+            Ok(Ok(()))
+        );
+        // the connection is torn down without notification once the handle is dropped
+        self.closed = true;
+        if let Some(reason) = notify {
+            self.dst
+                .send(Err(reason))
+                .await
+                .map_err(|_| self.closed_by_peer())?;
+        }
+        if let Some(session) = &mut self.session {
+            session.close().await;
+        }
+        Ok(acked)
+    }
+}
+
+/// Close the connection gracefully if the handle is dropped without being closed, the connection
+/// task lingers to flush the pending data according to [`Linger`].
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.closed || self.linger == Linger::Abort {
+            return;
+        }
+        // the connection task may have exited, nothing to flush then
+        let _ = self.dst.sender().try_send(Err(DisconnectReason::Closed));
+    }
 }
 
-impl Stream for IOImpl {
+/// Yield the bodies of the received messages, see [`Connection::recv_message`] for the metadata
+impl Stream for Connection {
     type Item = Bytes;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.src.poll_next_unpin(cx)
+        if let Some(peeked) = self.peeked.take() {
+            return Poll::Ready(Some(peeked.data));
+        }
+        self.src
+            .poll_next_unpin(cx)
+            .map(|received| received.map(Bytes::from))
     }
 }
 
-impl Sink<Bytes> for IOImpl {
+impl Sink<Message> for Connection {
     type Error = Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -93,17 +709,17 @@ impl Sink<Bytes> for IOImpl {
         }
         if ready!(self.dst.poll_ready_unpin(cx)).is_err() {
             // Perhaps the connection was closed by peer, and the task exited.
-            return Poll::Ready(Err(Error::ConnectionClosed("connection closed by peer")));
+            return Poll::Ready(Err(self.closed_by_peer()));
         }
         Poll::Ready(Ok(()))
     }
 
-    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
         if self.closed {
             return Err(Error::ConnectionClosed("connection was closed before"));
         }
         self.dst
-            .start_send_unpin(Some(item))
+            .start_send_unpin(Ok(item))
             .expect("must call poll_ready before start_send");
         Ok(())
     }
@@ -111,7 +727,7 @@ impl Sink<Bytes> for IOImpl {
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         if ready!(self.dst.poll_flush_unpin(cx)).is_err() {
             // Perhaps the connection was closed by peer, and the task exited.
-            return Poll::Ready(Err(Error::ConnectionClosed("connection closed by peer")));
+            return Poll::Ready(Err(self.closed_by_peer()));
         }
         Poll::Ready(Ok(()))
     }
@@ -121,10 +737,31 @@ impl Sink<Bytes> for IOImpl {
             return Poll::Ready(Err(Error::ConnectionClosed("connection was closed before")));
         }
         self.closed = true;
-        if ready!(self.dst.send(None).poll_unpin(cx)).is_err() {
+        if ready!(self.dst.send(Err(DisconnectReason::Closed)).poll_unpin(cx)).is_err() {
             // Perhaps the connection was closed by peer, and the task exited.
-            return Poll::Ready(Err(Error::ConnectionClosed("connection closed by peer")));
+            return Poll::Ready(Err(self.closed_by_peer()));
         }
         Poll::Ready(Ok(()))
     }
 }
+
+/// Send the bytes as reliable ordered messages on channel 0
+impl Sink<Bytes> for Connection {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Message>::poll_ready(self, cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        Sink::<Message>::start_send(self, Message::new(item))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Message>::poll_flush(self, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Message>::poll_close(self, cx)
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+/// Cap the ordered frames of each channel in flight, so that a channel waiting for the
+/// retransmissions of its head frame could not take the whole congestion window from the
+/// others, which keep flushing within their own windows.
+#[derive(Debug, Default)]
+pub(super) struct ChannelWindows {
+    // The max ordered frames of a channel in flight, 0 means no limit
+    limit: usize,
+    in_flight: HashMap<u8, usize>,
+}
+
+impl ChannelWindows {
+    pub(super) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// Whether a frame of the channel could be flushed now, the frames of a full channel should
+    /// be skipped rather than blocking the flush of the other channels.
+    pub(super) fn admits(&self, channel: u8) -> bool {
+        self.limit == 0 || self.in_flight(channel) < self.limit
+    }
+
+    /// A frame of the channel is sent
+    pub(super) fn on_sent(&mut self, channel: u8) {
+        *self.in_flight.entry(channel).or_default() += 1;
+    }
+
+    /// A frame of the channel is acknowledged. The lost frames stay in flight until their
+    /// retransmissions are acknowledged.
+    pub(super) fn on_acked(&mut self, channel: u8) {
+        let Some(cnt) = self.in_flight.get_mut(&channel) else {
+            return;
+        };
+        *cnt -= 1;
+        if *cnt == 0 {
+            self.in_flight.remove(&channel);
+        }
+    }
+
+    /// The frames of the channel sent but not acknowledged yet
+    fn in_flight(&self, channel: u8) -> usize {
+        self.in_flight.get(&channel).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_channel_windows_isolated() {
+        let mut windows = ChannelWindows::new(2);
+        windows.on_sent(0);
+        windows.on_sent(0);
+        // channel 0 is waiting for the retransmissions
+        assert!(!windows.admits(0));
+        // the others keep flushing
+        assert!(windows.admits(1));
+        windows.on_sent(1);
+        assert_eq!(windows.in_flight(1), 1);
+
+        windows.on_acked(0);
+        assert!(windows.admits(0));
+        windows.on_acked(0);
+        windows.on_acked(0);
+        assert_eq!(windows.in_flight(0), 0);
+    }
+
+    #[test]
+    fn test_channel_windows_no_limit() {
+        let mut windows = ChannelWindows::default();
+        for _ in 0..10000 {
+            windows.on_sent(0);
+        }
+        assert!(windows.admits(0));
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::{Duration, Instant};
+
+/// Keepalive strategy of a connection
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct KeepaliveConfig {
+    // Send a ConnectedPing when the connection has been idle for this interval
+    interval: Duration,
+    // Close the connection after this many pings are unanswered in a row, 0 means never
+    max_unanswered: usize,
+    // Do not send pings while data is flowing from the peer, the data proves the connection is
+    // alive
+    suppress_while_active: bool,
+}
+
+impl KeepaliveConfig {
+    /// Send a `ConnectedPing` when the connection has been idle for this interval
+    #[must_use]
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Close the connection after this many pings are unanswered in a row, 0 means never
+    #[must_use]
+    pub fn with_max_unanswered(mut self, max_unanswered: usize) -> Self {
+        self.max_unanswered = max_unanswered;
+        self
+    }
+
+    /// Whether to skip the pings while data is flowing from the peer
+    #[must_use]
+    pub fn with_suppress_while_active(mut self, suppress_while_active: bool) -> Self {
+        self.suppress_while_active = suppress_while_active;
+        self
+    }
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            max_unanswered: 3,
+            suppress_while_active: true,
+        }
+    }
+}
+
+/// What the connection should do for keepalive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum KeepaliveAction {
+    /// Nothing to do for now
+    Idle,
+    /// Send a `ConnectedPing`
+    Ping,
+    /// Too many pings are unanswered, the connection should be closed
+    Timeout,
+}
+
+/// Decide when to send the keepalive pings and when the peer should be considered lost
+#[derive(Debug)]
+pub(super) struct Keepalive {
+    config: KeepaliveConfig,
+    last_received: Instant,
+    last_ping: Option<Instant>,
+    unanswered: usize,
+}
+
+impl Keepalive {
+    pub(super) fn new(config: KeepaliveConfig, now: Instant) -> Self {
+        Self {
+            config,
+            last_received: now,
+            last_ping: None,
+            unanswered: 0,
+        }
+    }
+
+    /// Any datagram received from the peer proves it is alive
+    pub(super) fn on_received(&mut self, now: Instant) {
+        self.last_received = now;
+        self.unanswered = 0;
+    }
+
+    /// Check what should be done at now, the ping is counted as unanswered if it should be sent.
+    pub(super) fn poll(&mut self, now: Instant) -> KeepaliveAction {
+        let interval = self.config.interval;
+        if self
+            .last_ping
+            .is_some_and(|last| now.saturating_duration_since(last) < interval)
+        {
+            return KeepaliveAction::Idle;
+        }
+        if self.config.suppress_while_active
+            && now.saturating_duration_since(self.last_received) < interval
+        {
+            return KeepaliveAction::Idle;
+        }
+        if self.config.max_unanswered != 0 && self.unanswered >= self.config.max_unanswered {
+            return KeepaliveAction::Timeout;
+        }
+        self.last_ping = Some(now);
+        self.unanswered += 1;
+        KeepaliveAction::Ping
+    }
+
+    /// The next instant [`Keepalive::poll`] may return a different action
+    pub(super) fn next_deadline(&self) -> Instant {
+        let after_ping = self.last_ping.map(|last| last + self.config.interval);
+        let after_received = self
+            .config
+            .suppress_while_active
+            .then(|| self.last_received + self.config.interval);
+        match (after_ping, after_received) {
+            (Some(a), Some(b)) => a.max(b),
+            (Some(deadline), None) | (None, Some(deadline)) => deadline,
+            (None, None) => self.last_received,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keepalive_timeout() {
+        let config = KeepaliveConfig {
+            interval: Duration::from_secs(1),
+            max_unanswered: 2,
+            suppress_while_active: true,
+        };
+        let now = Instant::now();
+        let mut keepalive = Keepalive::new(config, now);
+        assert_eq!(keepalive.poll(now), KeepaliveAction::Idle);
+
+        let t1 = now + Duration::from_secs(1);
+        assert_eq!(keepalive.next_deadline(), t1);
+        assert_eq!(keepalive.poll(t1), KeepaliveAction::Ping);
+        assert_eq!(keepalive.poll(t1), KeepaliveAction::Idle);
+        let t2 = t1 + Duration::from_secs(1);
+        assert_eq!(keepalive.poll(t2), KeepaliveAction::Ping);
+        let t3 = t2 + Duration::from_secs(1);
+        assert_eq!(keepalive.poll(t3), KeepaliveAction::Timeout);
+
+        // the peer answered
+        keepalive.on_received(t3);
+        assert_eq!(keepalive.poll(t3), KeepaliveAction::Idle);
+    }
+
+    #[test]
+    fn test_keepalive_not_suppressed() {
+        let config = KeepaliveConfig {
+            interval: Duration::from_secs(1),
+            max_unanswered: 0,
+            suppress_while_active: false,
+        };
+        let now = Instant::now();
+        let mut keepalive = Keepalive::new(config, now);
+        // pings are sent even if data is flowing
+        for i in 0..10 {
+            let at = now + Duration::from_secs(i);
+            keepalive.on_received(at);
+            assert_eq!(keepalive.poll(at), KeepaliveAction::Ping);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::Future;
+use tracing::warn;
+
+/// Provision or release the resources of a session, e.g. a database handle or an entity slot.
+/// It is invoked with the address and the client GUID of the session.
+pub trait SessionHook: Send + Sync {
+    /// Run the hook for the session of the address and the client GUID
+    fn on_session(&self, addr: SocketAddr, guid: u64) -> BoxFuture<'static, ()>;
+}
+
+impl<T, Fut> SessionHook for T
+where
+    T: Fn(SocketAddr, u64) -> Fut + Send + Sync,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn on_session(&self, addr: SocketAddr, guid: u64) -> BoxFuture<'static, ()> {
+        Box::pin(self(addr, guid))
+    }
+}
+
+/// The `on_connect` and `on_disconnect` hooks of the sessions. `on_connect` completes before the
+/// connection is yielded by the accept stream, and `on_disconnect` runs exactly once after it,
+/// when the connection is closed or dropped, so that the hooks never race the application.
+#[derive(Clone, Default)]
+pub(super) struct Lifecycle {
+    on_connect: Option<Arc<dyn SessionHook>>,
+    on_disconnect: Option<Arc<dyn SessionHook>>,
+}
+
+impl std::fmt::Debug for Lifecycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lifecycle")
+            .field("on_connect", &self.on_connect.is_some())
+            .field("on_disconnect", &self.on_disconnect.is_some())
+            .finish()
+    }
+}
+
+impl Lifecycle {
+    pub(super) fn set_on_connect(&mut self, hook: impl SessionHook + 'static) {
+        self.on_connect = Some(Arc::new(hook));
+    }
+
+    pub(super) fn set_on_disconnect(&mut self, hook: impl SessionHook + 'static) {
+        self.on_disconnect = Some(Arc::new(hook));
+    }
+
+    /// Run `on_connect` of the session, the returned guard runs `on_disconnect` once it is
+    /// closed or dropped
+    pub(super) async fn open(&self, addr: SocketAddr, guid: u64) -> SessionGuard {
+        if let Some(hook) = &self.on_connect {
+            hook.on_session(addr, guid).await;
+        }
+        SessionGuard {
+            addr,
+            guid,
+            on_disconnect: self.on_disconnect.clone(),
+        }
+    }
+}
+
+/// Held by the connection of an opened session, `on_disconnect` is taken on the first close so
+/// that it runs exactly once.
+pub(super) struct SessionGuard {
+    addr: SocketAddr,
+    guid: u64,
+    on_disconnect: Option<Arc<dyn SessionHook>>,
+}
+
+impl std::fmt::Debug for SessionGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionGuard")
+            .field("addr", &self.addr)
+            .field("guid", &self.guid)
+            .field("closed", &self.on_disconnect.is_none())
+            .finish()
+    }
+}
+
+impl SessionGuard {
+    /// Run `on_disconnect` and wait for it, it is a no-op if the session was closed before
+    pub(super) async fn close(&mut self) {
+        if let Some(hook) = self.on_disconnect.take() {
+            hook.on_session(self.addr, self.guid).await;
+        }
+    }
+}
+
+/// The connection is dropped without being closed, `on_disconnect` is spawned instead
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let Some(hook) = self.on_disconnect.take() else {
+            return;
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(hook.on_session(self.addr, self.guid));
+            }
+            Err(_) => warn!(
+                "on_disconnect of {} is skipped, the session is dropped outside the runtime",
+                self.addr
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn counting_lifecycle() -> (Lifecycle, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let connected = Arc::new(AtomicUsize::new(0));
+        let disconnected = Arc::new(AtomicUsize::new(0));
+        let mut lifecycle = Lifecycle::default();
+        let connects = Arc::clone(&connected);
+        lifecycle.set_on_connect(move |_, _| {
+            let counter = Arc::clone(&connects);
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let disconnects = Arc::clone(&disconnected);
+        lifecycle.set_on_disconnect(move |_, _| {
+            let counter = Arc::clone(&disconnects);
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        (lifecycle, connected, disconnected)
+    }
+
+    #[tokio::test]
+    async fn test_session_hooks_run_once() {
+        let (lifecycle, connected, disconnected) = counting_lifecycle();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 19132));
+
+        let mut guard = lifecycle.open(addr, 1).await;
+        assert_eq!(connected.load(Ordering::SeqCst), 1);
+        assert_eq!(disconnected.load(Ordering::SeqCst), 0);
+        guard.close().await;
+        guard.close().await;
+        drop(guard);
+        assert_eq!(disconnected.load(Ordering::SeqCst), 1);
+
+        // dropped without being closed
+        let dropped = lifecycle.open(addr, 2).await;
+        drop(dropped);
+        tokio::task::yield_now().await;
+        assert_eq!(connected.load(Ordering::SeqCst), 2);
+        assert_eq!(disconnected.load(Ordering::SeqCst), 2);
+    }
+}
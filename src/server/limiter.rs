@@ -0,0 +1,174 @@
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::time::Instant;
+
+use lru::LruCache;
+
+use crate::errors::ConfigError;
+
+/// Token bucket config
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RateLimitConfig {
+    // The max tokens of a bucket, 0 means no limit
+    capacity: u32,
+    // Tokens refilled per second
+    refill_per_sec: u32,
+    // Limit the max count of tracked sources, the least recently seen source will be dropped
+    max_sources: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 0,
+            refill_per_sec: 0,
+            max_sources: 4096,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Limit each source to the burst of `capacity` packets, refilled by `refill_per_sec`
+    #[must_use]
+    pub fn with_capacity(mut self, capacity: u32, refill_per_sec: u32) -> Self {
+        self.capacity = capacity;
+        self.refill_per_sec = refill_per_sec;
+        self
+    }
+
+    /// Whether any source is limited
+    pub(super) fn is_enabled(&self) -> bool {
+        self.capacity != 0
+    }
+
+    pub(super) fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_sources == 0 {
+            return Err(ConfigError::Zero("rate_limit.max_sources"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn full(capacity: u32, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(capacity),
+            last: now,
+        }
+    }
+
+    /// Refill the bucket and try to take a token from it
+    fn take(&mut self, config: &RateLimitConfig, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * f64::from(config.refill_per_sec))
+            .min(f64::from(config.capacity));
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Per-source rate limiter based on token buckets
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: LruCache<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub(super) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            // validated by the config builder
+            buckets: LruCache::new(
+                NonZeroUsize::new(config.max_sources).unwrap_or(NonZeroUsize::MIN),
+            ),
+        }
+    }
+
+    /// Check if a packet from the source is allowed
+    pub(super) fn check(&mut self, source: IpAddr) -> bool {
+        self.check_at(source, Instant::now())
+    }
+
+    fn check_at(&mut self, source: IpAddr, now: Instant) -> bool {
+        if self.config.capacity == 0 {
+            return true;
+        }
+        let config = self.config;
+        self.buckets
+            .get_or_insert_mut(source, || TokenBucket::full(config.capacity, now))
+            .take(&config, now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_works() {
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            capacity: 3,
+            refill_per_sec: 1,
+            max_sources: 16,
+        });
+        let now = Instant::now();
+        let source: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(source, now));
+        }
+        assert!(!limiter.check_at(source, now));
+        // other sources are not affected
+        assert!(limiter.check_at(other, now));
+
+        // refilled one token after 1 second
+        let now = now + Duration::from_secs(1);
+        assert!(limiter.check_at(source, now));
+        assert!(!limiter.check_at(source, now));
+
+        // never exceed the capacity
+        let now = now + Duration::from_secs(100);
+        for _ in 0..3 {
+            assert!(limiter.check_at(source, now));
+        }
+        assert!(!limiter.check_at(source, now));
+    }
+
+    #[test]
+    fn test_rate_limit_config_validate() {
+        assert!(RateLimitConfig::default().validate().is_ok());
+        let config = RateLimitConfig {
+            max_sources: 0,
+            ..RateLimitConfig::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Zero("rate_limit.max_sources"))
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_no_limit() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let source: IpAddr = "127.0.0.1".parse().unwrap();
+        for _ in 0..10000 {
+            assert!(limiter.check(source));
+        }
+    }
+}
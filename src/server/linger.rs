@@ -0,0 +1,45 @@
+use std::time::{Duration, Instant};
+
+/// What to do with the pending data when a connection handle is dropped without being closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Linger {
+    /// Tear down the connection immediately, the pending data is discarded and the peer will
+    /// find it out by timeout
+    Abort,
+    /// Keep the connection task alive for at most this duration to deliver the pending reliable
+    /// data and the `DisconnectNotification`
+    Flush(Duration),
+}
+
+impl Default for Linger {
+    fn default() -> Self {
+        Self::Flush(Duration::from_secs(1))
+    }
+}
+
+impl Linger {
+    /// The deadline for the connection task to finish after the handle is dropped at now, None
+    /// means it should exit immediately.
+    pub(super) fn deadline(self, now: Instant) -> Option<Instant> {
+        match self {
+            Linger::Abort => None,
+            Linger::Flush(timeout) => Some(now + timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_linger_deadline() {
+        let now = Instant::now();
+        assert_eq!(Linger::Abort.deadline(now), None);
+        assert_eq!(
+            Linger::default().deadline(now),
+            Some(now + Duration::from_secs(1))
+        );
+    }
+}
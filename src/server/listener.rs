@@ -0,0 +1,2730 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::channel::oneshot;
+use futures::{future, Future, FutureExt, Sink, Stream, StreamExt};
+use socket2::SockRef;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tracing::{warn, Level};
+
+use super::broadcast::Broadcaster;
+use super::conn::Events;
+use super::drain::Drain;
+use super::fair::{Flushed, Weights};
+use super::incoming::{Connection, Incomed, IncomingParts};
+use super::lifecycle::{Lifecycle, SessionHook};
+use super::offline::{make_advertise_system, Config, HandleOffline};
+use super::pong::{
+    serve_secondary_pongs, FastPonged, PongHook, SharedAdvertisement, MAX_ADVERTISEMENT,
+};
+use super::qos::{DscpMarker, Marking};
+use super::query::{Queried, QueryInfo, SharedQueryInfo};
+use super::session::Sessions;
+use super::shedder::{ShedCounter, ShedStats};
+use super::socket::{Arrival, Socket, SocketParts, SocketSetup};
+use super::tap::{Tap, Tapped, Tapping};
+use super::verbosity::PeerVerbosity;
+use crate::codec::filter::{Filtered, PacketFilter, Verdict};
+use crate::codec::hook::{DatagramHook, Hooked};
+use crate::codec::parse::Parsed;
+use crate::errors::ConfigError;
+use crate::event::ServerEvent;
+use crate::message::{Message, Priority};
+use crate::rt::{Runtime, Tokio};
+use crate::stats::{DropCounter, DropStats, EventLoopRecorder, EventLoopStats};
+
+/// The raw datagram layers, the fast paths are placed by the config
+type BoxedRaw = Pin<Box<dyn RawFrame>>;
+
+trait RawFrame:
+    Stream<Item = io::Result<(BytesMut, SocketAddr)>>
+    + Sink<(BytesMut, SocketAddr), Error = io::Error>
+    + Send
+{
+}
+
+impl<F> RawFrame for F where
+    F: Stream<Item = io::Result<(BytesMut, SocketAddr)>>
+        + Sink<(BytesMut, SocketAddr), Error = io::Error>
+        + Send
+{
+}
+
+/// The packet filter of the builder, the closures are the packet filters as well
+type BoxedFilter = Box<dyn Fn(SocketAddr, &[u8]) -> Verdict + Send + Sync>;
+
+/// Build a server by the [`Config`]
+pub struct ServerBuilder {
+    config: Config,
+    lifecycle: Lifecycle,
+    hook: Box<dyn DatagramHook + Send + Sync>,
+    filter: BoxedFilter,
+    pong_hook: Option<Arc<dyn PongHook>>,
+}
+
+impl std::fmt::Debug for ServerBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerBuilder")
+            .field("config", &self.config)
+            .field("lifecycle", &self.lifecycle)
+            .field("hook_overhead", &self.hook.overhead())
+            .field("pong_hook", &self.pong_hook.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ServerBuilder {
+    /// Make the builder of a server by the config
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            lifecycle: Lifecycle::default(),
+            hook: Box::new(()),
+            filter: Box::new(|_, _| Verdict::Pass),
+            pong_hook: None,
+        }
+    }
+
+    /// Make the verdicts on the raw datagrams before they are parsed, e.g. to block a byte
+    /// pattern in an emergency. The filter sees the datagrams transformed by the datagram hook.
+    pub fn packet_filter(mut self, filter: impl PacketFilter + Send + Sync + 'static) -> Self {
+        self.filter = Box::new(move |addr, raw| filter.filter(addr, raw));
+        self
+    }
+
+    /// Transform the raw datagrams of the server next to the socket, e.g. by [`Crc32`]. The
+    /// overhead of the hook is reserved from the mtu of each connection. The secondary pong
+    /// addresses are not hooked.
+    ///
+    /// [`Crc32`]: crate::server::Crc32
+    pub fn datagram_hook(mut self, hook: impl DatagramHook + Send + Sync + 'static) -> Self {
+        self.hook = Box::new(hook);
+        self
+    }
+
+    /// Decide the pong of each ping asynchronously instead of the advertisement, e.g. per-region
+    /// MOTD or hiding the server from some ips. The pings are no longer answered by the fast
+    /// path once it is set, and the secondary pong addresses keep answering with the
+    /// advertisement.
+    pub fn pong_hook(mut self, hook: impl PongHook + 'static) -> Self {
+        self.pong_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Provision the resources of each session, the connection is yielded by the [`Server`]
+    /// only after the hook completes
+    pub fn on_connect(mut self, hook: impl SessionHook + 'static) -> Self {
+        self.lifecycle.set_on_connect(hook);
+        self
+    }
+
+    /// Release the resources of each session, it runs exactly once after `on_connect` when the
+    /// connection is closed or dropped
+    pub fn on_disconnect(mut self, hook: impl SessionHook + 'static) -> Self {
+        self.lifecycle.set_on_disconnect(hook);
+        self
+    }
+
+    /// Bind the server to the address and spawn its receive loop, the connections are accepted
+    /// from the returned [`Server`]
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of binding the socket or setting its buffers and DSCP marking
+    pub async fn bind(self, addr: impl ToSocketAddrs) -> io::Result<Server> {
+        let socket = UdpSocket::bind(addr).await?;
+        let naming = self.config.task_naming().clone();
+        let (server, receive) = self.serve(socket).await?;
+        Tokio::spawn_named(&naming.receive_loop(), receive);
+        Ok(server)
+    }
+
+    /// Bind `shards` servers to the same address by `SO_REUSEPORT`, the kernel spreads the peers
+    /// across the shards by their addresses. Each shard runs its receive loop and connections on
+    /// a thread of its own, which is pinned to a core by the `cpu_pinning` of its config. The
+    /// builder of each shard is made by `make` with the index of the shard, and the port of the
+    /// first shard is shared by the others when binding to port 0.
+    ///
+    /// A shard thread exits once its [`Server`] is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of binding the sockets, pinning the threads or building their runtimes
+    #[cfg(unix)]
+    pub fn bind_sharded(
+        addr: SocketAddr,
+        shards: usize,
+        make: impl Fn(usize) -> ServerBuilder,
+    ) -> io::Result<Vec<Server>> {
+        let mut addr = addr;
+        let mut servers = Vec::with_capacity(shards);
+        for shard in 0..shards {
+            let builder = make(shard);
+            let socket = socket2::Socket::new(
+                socket2::Domain::for_address(addr),
+                socket2::Type::DGRAM,
+                Some(socket2::Protocol::UDP),
+            )?;
+            socket.set_reuse_port(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            let socket = std::net::UdpSocket::from(socket);
+            // the port is resolved once the first shard is bound
+            addr = socket.local_addr()?;
+            let (tx, rx) = std::sync::mpsc::sync_channel(1);
+            std::thread::Builder::new()
+                .name(builder.config.task_naming().shard(shard))
+                .spawn(move || {
+                    if let Err(err) = builder.run_shard(shard, socket, &tx) {
+                        let _ = tx.send(Err(err));
+                    }
+                })?;
+            servers.push(
+                rx.recv()
+                    .map_err(|_| io::Error::other("shard thread exited before binding"))??,
+            );
+        }
+        Ok(servers)
+    }
+
+    /// Run a shard on the calling thread until its server is dropped, the server is sent once it
+    /// is built
+    #[cfg(unix)]
+    fn run_shard(
+        self,
+        shard: usize,
+        socket: std::net::UdpSocket,
+        tx: &std::sync::mpsc::SyncSender<io::Result<Server>>,
+    ) -> io::Result<()> {
+        self.config.cpu_pinning().pin_shard(shard)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async move {
+            let (server, receive) = self.serve(UdpSocket::from_std(socket)?).await?;
+            if tx.send(Ok(server)).is_ok() {
+                receive.await;
+            }
+            Ok(())
+        })
+    }
+
+    /// Build the server over the bound socket, returns the server and its receive loop, which
+    /// ends once the server is dropped
+    async fn serve(
+        self,
+        socket: UdpSocket,
+    ) -> io::Result<(Server, impl Future<Output = ()> + Send + 'static)> {
+        let Self {
+            config,
+            lifecycle,
+            hook,
+            filter,
+            pong_hook,
+        } = self;
+        let overhead = hook.overhead();
+        let socket = Arc::new(socket);
+        let local_addr = socket.local_addr()?;
+        let event_loop = Arc::new(EventLoopRecorder::default());
+        let setup = SocketSetup {
+            rx_timestamps: config.rx_timestamps(),
+            buffers: config.socket_buffers().copied(),
+        };
+        setup.apply(&socket, &event_loop)?;
+        // the offline replies and the datagrams of the regular messages take the default class
+        let mut marker = DscpMarker::new(config.dscp());
+        marker.mark(SockRef::from(&*socket), Priority::default())?;
+        let marking = Marking::default();
+        let events = Events::default();
+        let naming = config.task_naming().clone();
+        let arrival = Arrival::default();
+        let weights = Weights::default();
+        let (outbound_tx, outbound_rx) = flume::unbounded();
+        let drain = Drain::default();
+        let advertisement = config.advertisement();
+        let drops = Arc::new(DropCounter::default());
+        let tap = Tap::default();
+        let raw = Socket::new(
+            socket,
+            SocketParts {
+                arrival: arrival.clone(),
+                max_datagram_size: config.max_datagram_size(),
+                marking: marking.clone(),
+                marker,
+                setup,
+                socket_error: config.socket_error(),
+                events: events.clone(),
+                event_loop: Arc::clone(&event_loop),
+            },
+        )?
+        .hooked(hook, Arc::clone(&drops))
+        .tapping(tap.clone())
+        .filtered(filter, config.max_datagram_size(), Arc::clone(&drops));
+        // the pings must reach the pong hook in the offline handler
+        let fast_pong = config
+            .fast_pong(advertisement.clone())
+            .filter(|_| pong_hook.is_none());
+        let raw: BoxedRaw = match fast_pong {
+            Some(cache) => Box::pin(raw.fast_ponged(cache, drain.clone())),
+            None => Box::pin(raw),
+        };
+        let query = config.query();
+        let raw: BoxedRaw = match &query {
+            Some(info) => Box::pin(raw.queried(info.clone())),
+            None => raw,
+        };
+        let mut offline = raw
+            .flushed(
+                outbound_rx,
+                config.flush_quantum(),
+                weights.clone(),
+                marking,
+            )
+            .parsed()
+            .handle_offline(config.clone());
+        offline.share_pongs(drain.clone(), advertisement.clone());
+        offline.share_events(events.clone());
+        offline.share_drops(Arc::clone(&drops));
+        if let Some(on_ping) = pong_hook {
+            offline.set_pong_hook(on_ping);
+        }
+        let verbosity = offline.verbosity();
+        let unconnected = offline.unconnected_messages();
+        let shed = offline.shed_counter();
+        let outbound = outbound_tx.clone();
+        let parts = IncomingParts {
+            config: config.conn_config(),
+            local_addr,
+            arrival,
+            backlog: offline.backlog(),
+            event_loop: Arc::clone(&event_loop),
+            memory: offline.memory(),
+            migration: config.migration(),
+            deferred_accept: config.deferred_accept(),
+            overhead,
+            verbosity: verbosity.clone(),
+            weights: weights.clone(),
+            lifecycle,
+            task_mode: config.task_mode(),
+            naming: naming.clone(),
+            outbound: outbound_tx,
+            closer: offline.closer(),
+        };
+        let mut incoming = offline.incoming(parts);
+        let broadcaster = incoming.broadcaster();
+        let sessions = incoming.sessions();
+
+        let (accept_tx, accept_rx) = flume::unbounded();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let shutdown = shutdown_rx.shared();
+        let mut pong_addrs = Vec::with_capacity(config.pong_addrs().len());
+        for &pong_addr in config.pong_addrs() {
+            let pong_socket = UdpSocket::bind(pong_addr).await?;
+            // the port is resolved once bound
+            let bound = pong_socket.local_addr()?;
+            let cache = config
+                .pong_cache(advertisement.clone())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let serve = serve_secondary_pongs(pong_socket, cache, drain.clone()).map(move |res| {
+                if let Err(err) = res {
+                    warn!("stop answering the pings on {bound}, error {err}");
+                }
+            });
+            Tokio::spawn_named(
+                &naming.secondary_pong(bound),
+                future::select(Box::pin(serve), shutdown.clone()).map(|_| ()),
+            );
+            pong_addrs.push(bound);
+        }
+        let receive = async move {
+            while let Some(conn) = incoming.next().await {
+                if accept_tx.send(conn).is_err() {
+                    break;
+                }
+            }
+        };
+        // the receive loop and the secondary pongs stop once the server is dropped
+        let receive = future::select(Box::pin(receive), shutdown).map(|_| ());
+        let server = Server {
+            accept: accept_rx.into_stream(),
+            handle: ServerHandle {
+                local_addr,
+                pong_addrs: pong_addrs.into(),
+                advertisement,
+                broadcaster,
+                sessions,
+                drain,
+                weights,
+                verbosity,
+                event_loop,
+                events,
+                query,
+                tap,
+                unconnected,
+                outbound,
+                drops,
+                shed,
+            },
+            _shutdown: shutdown_tx,
+        };
+        Ok((server, receive))
+    }
+}
+
+/// A bound server, it yields the connections accepted in order. The receive loop and all
+/// connections are shut down once it is dropped.
+pub struct Server {
+    accept: flume::r#async::RecvStream<'static, Connection>,
+    handle: ServerHandle,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl std::fmt::Debug for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Server")
+            .field("local_addr", &self.handle.local_addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Server {
+    /// Get the handle to manage the server while the connections are being accepted
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Get the address the server is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.local_addr
+    }
+}
+
+impl Stream for Server {
+    type Item = Connection;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut conn = futures::ready!(self.accept.poll_next_unpin(cx));
+        if let Some(conn) = &mut conn {
+            conn.accepted();
+        }
+        Poll::Ready(conn)
+    }
+}
+
+/// The handle of a server, it could be cloned and shared with the tasks of the application
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    pong_addrs: Arc<[SocketAddr]>,
+    advertisement: SharedAdvertisement,
+    broadcaster: Broadcaster,
+    sessions: Sessions,
+    drain: Drain,
+    weights: Weights,
+    verbosity: PeerVerbosity,
+    event_loop: Arc<EventLoopRecorder>,
+    events: Events<ServerEvent>,
+    query: Option<SharedQueryInfo>,
+    tap: Tap,
+    unconnected: Events<(Bytes, SocketAddr)>,
+    outbound: flume::Sender<(SocketAddr, BytesMut, Priority)>,
+    drops: Arc<DropCounter>,
+    shed: Arc<ShedCounter>,
+}
+
+impl ServerHandle {
+    /// Get the address the server is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get the secondary addresses only answering the pings, in the order of the config
+    pub fn pong_addrs(&self) -> &[SocketAddr] {
+        &self.pong_addrs
+    }
+
+    /// Replace the advertisement (MOTD) of the pongs, e.g. with the count of online players. The
+    /// cached pongs follow it at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError::AdvertisementTooLarge`] if it does not fit in a pong
+    pub fn set_advertisement(&self, advertisement: impl Into<Bytes>) -> Result<(), ConfigError> {
+        let advertisement = advertisement.into();
+        if advertisement.len() > MAX_ADVERTISEMENT {
+            return Err(ConfigError::AdvertisementTooLarge(
+                advertisement.len(),
+                MAX_ADVERTISEMENT,
+            ));
+        }
+        self.advertisement.set(advertisement);
+        Ok(())
+    }
+
+    /// Replace the information reported to the query protocol, e.g. the online players. It is
+    /// ignored if the query protocol is not enabled by the config.
+    pub fn set_query_info(&self, info: QueryInfo) {
+        if let Some(query) = &self.query {
+            query.set(info);
+        }
+    }
+
+    /// Receive the unconnected user messages (advertise system) along with the address of the
+    /// sender, e.g. the LAN discovery of some games. At most `capacity` messages are buffered,
+    /// the rest will be dropped until they are received. The previous receiver is detached.
+    pub fn unconnected_messages(&self, capacity: usize) -> flume::Receiver<(Bytes, SocketAddr)> {
+        self.unconnected.subscribe(capacity)
+    }
+
+    /// Send an unconnected user message (advertise system) to the address without establishing
+    /// a connection, it goes through the datagram hook like the other datagrams. It is dropped
+    /// if the server has been dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError::AdvertisementTooLarge`] if it does not fit in a datagram
+    pub fn send_unconnected(
+        &self,
+        addr: SocketAddr,
+        data: impl Into<Bytes>,
+    ) -> Result<(), ConfigError> {
+        let data = data.into();
+        if data.len() > MAX_ADVERTISEMENT {
+            return Err(ConfigError::AdvertisementTooLarge(
+                data.len(),
+                MAX_ADVERTISEMENT,
+            ));
+        }
+        let mut buf = BytesMut::new();
+        make_advertise_system::<Bytes>(data).write(&mut buf);
+        let _ = self.outbound.send((addr, buf, Priority::default()));
+        Ok(())
+    }
+
+    /// Tap the raw datagrams of all peers received and sent by the socket, after the datagram
+    /// hook. At most `capacity` datagrams are buffered, the rest will be dropped until they are
+    /// received. The previous subscriber will be detached.
+    pub fn tap(&self, capacity: usize) -> flume::Receiver<Tapped> {
+        self.tap.subscribe(capacity)
+    }
+
+    /// Detach the subscriber of the tap, dropping the receiver has the same effect
+    pub fn detach_tap(&self) {
+        self.tap.detach();
+    }
+
+    /// Broadcast the message with its reliability and channel to all connections except the
+    /// excluded ones, returns the count of connections the message was queued to.
+    pub fn broadcast(&self, msg: &Message, exclude: &HashSet<SocketAddr>) -> usize {
+        self.broadcaster.broadcast(msg, exclude)
+    }
+
+    /// Get the count of the connections
+    pub fn connections(&self) -> usize {
+        self.broadcaster.len()
+    }
+
+    /// Get the sessions of the server, find a session by its address or client GUID to send to
+    /// or kick it without holding its connection
+    pub fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
+
+    /// Drain the server for a maintenance window: the pings are no longer answered so that the
+    /// server disappears from the server lists, the new handshakes are refused, and the
+    /// established connections are left to close by themselves. The returned stream yields the
+    /// count of the remaining connections whenever it changes, and ends once all of them have
+    /// left or the draining is resumed.
+    pub fn drain(&self) -> impl Stream<Item = usize> + Send + 'static {
+        self.drain.drain(self.sessions.clone())
+    }
+
+    /// Advertise the server and accept the handshakes again, e.g. the maintenance is called off
+    pub fn resume(&self) {
+        self.drain.resume();
+    }
+
+    /// Set the bandwidth weight of the connection of the address, e.g. lower for the
+    /// spectators than the players. A connection of the default weight 100 is granted the flush
+    /// quantum per round, the others are scaled by their weights. The weight 0 is treated as 1
+    /// so that the connection is never starved. It follows the migrations of the connection
+    /// and is reset once the connection is torn down.
+    pub fn set_weight(&self, addr: SocketAddr, weight: u16) {
+        self.weights.set(addr, weight);
+    }
+
+    /// Restore the default bandwidth weight of the connection of the address
+    pub fn reset_weight(&self, addr: &SocketAddr) {
+        self.weights.reset(addr);
+    }
+
+    /// Get the statistics of the socket event loop, e.g. the datagrams sent by each syscall
+    pub fn event_loop_stats(&self) -> EventLoopStats {
+        self.event_loop.snapshot()
+    }
+
+    /// Get the counters of the datagrams and the packets discarded before they reach the
+    /// connections, e.g. rejected by the packet filter or the rate limit. The data discarded by
+    /// a connection is counted by its own statistics.
+    pub fn drop_stats(&self) -> DropStats {
+        self.drops.snapshot()
+    }
+
+    /// Get the counters of the packets shed by the receive budget in the config, they stay zero
+    /// if the budget is unlimited
+    pub fn shed_stats(&self) -> ShedStats {
+        self.shed.snapshot()
+    }
+
+    /// Receive the events of the server, e.g. [`ServerEvent::HandshakeDowngraded`]. At most
+    /// `capacity` events are buffered, the rest will be dropped until they are received. The
+    /// previous receiver is detached.
+    pub fn events(&self, capacity: usize) -> flume::Receiver<ServerEvent> {
+        self.events.subscribe(capacity)
+    }
+
+    /// Emit the logs of the peer up to the level at INFO with the target `raknet::peer`, e.g. to
+    /// debug the connection issues of a single player without raising the global log level. It
+    /// applies to the handshake and the connection of the peer at once.
+    pub fn elevate_peer(&self, addr: SocketAddr, level: Level) {
+        self.verbosity.elevate(addr, level);
+    }
+
+    /// Restore the global log verbosity of the peer
+    pub fn reset_peer(&self, addr: &SocketAddr) {
+        self.verbosity.reset(addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use bytes::{Bytes, BytesMut};
+    use futures::SinkExt;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::clock::{Clock, SystemClock};
+    use crate::codec::ordered::StalledPolicy;
+    use crate::codec::CodecConfigBuilder;
+    use crate::errors::Error;
+    use crate::event::{DisconnectReason, Downgrade, Event};
+    use crate::packet::connected::{
+        self, AckOrNack, Flags, Frame, FrameBody, FrameSet, Ordered, Reliability, Uint24le,
+        UDP_HEADER_SIZE,
+    };
+    use crate::packet::{unconnected, version, PackType, Packet};
+    use crate::rt::TaskNaming;
+    use crate::server::ack::{AckConfig, MIN_RTO};
+    use crate::server::budget::{BudgetConfig, BudgetPolicy};
+    use crate::server::fair::DEFAULT_WEIGHT;
+    use crate::server::limiter::RateLimitConfig;
+    use crate::server::offline::DowngradeConfig;
+    use crate::server::resend::{ResendExceeded, ResendLimitConfig};
+    use crate::server::rto::RtoConfig;
+    use crate::server::sockbuf::SocketBufferConfig;
+    use crate::server::{
+        ConfigBuilder, CpuPinning, Crc32, Direction, DriveMode, KeepaliveConfig, PmtuConfig,
+        TaskMode, Verdict, WatermarkConfig, XorObfuscation,
+    };
+
+    /// A client speaking the raw protocol, the reliability is left to the tests
+    struct RawClient {
+        socket: UdpSocket,
+        server: SocketAddr,
+        guid: u64,
+        next_seq: u32,
+        next_reliable: u32,
+        next_ordered: u32,
+        // Applied to the datagrams like the hook of the server
+        hook: Box<dyn DatagramHook + Send + Sync>,
+    }
+
+    impl RawClient {
+        async fn new(server: SocketAddr, guid: u64) -> Self {
+            Self {
+                socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                server,
+                guid,
+                next_seq: 0,
+                next_reliable: 0,
+                next_ordered: 0,
+                hook: Box::new(()),
+            }
+        }
+
+        fn with_hook(mut self, hook: impl DatagramHook + Send + Sync + 'static) -> Self {
+            self.hook = Box::new(hook);
+            self
+        }
+
+        async fn send(&self, pack: Packet<Bytes>) {
+            let mut buf = BytesMut::new();
+            pack.write(&mut buf);
+            self.hook.on_send(self.server, &mut buf);
+            self.socket.send_to(&buf, self.server).await.unwrap();
+        }
+
+        /// Receive the next packet, None if nothing arrives in the timeout
+        async fn recv(&self, timeout: Duration) -> Option<Packet<BytesMut>> {
+            self.recv_sized(timeout).await.map(|(_, pack)| pack)
+        }
+
+        /// Receive a packet along with the size of the datagram carrying it
+        async fn recv_sized(&self, timeout: Duration) -> Option<(usize, Packet<BytesMut>)> {
+            let mut buf = vec![0; 2048];
+            let (len, _) = tokio::time::timeout(timeout, self.socket.recv_from(&mut buf))
+                .await
+                .ok()?
+                .unwrap();
+            let mut buf = BytesMut::from(&buf[..len]);
+            assert_eq!(self.hook.on_recv(self.server, &mut buf), Verdict::Pass);
+            Some((len, Packet::read(&mut buf).unwrap()?))
+        }
+
+        async fn request1(&self) -> Option<Packet<BytesMut>> {
+            self.send(Packet::Unconnected(
+                unconnected::Packet::OpenConnectionRequest1 {
+                    magic: (),
+                    protocol_version: version::LATEST_PROTOCOL_VERSION,
+                    mtu: 1400,
+                },
+            ))
+            .await;
+            self.recv(Duration::from_millis(200)).await
+        }
+
+        async fn request2(&self) -> Option<Packet<BytesMut>> {
+            self.request2_with(None).await
+        }
+
+        async fn request2_with(&self, migration_token: Option<u64>) -> Option<Packet<BytesMut>> {
+            self.send(Packet::Unconnected(
+                unconnected::Packet::OpenConnectionRequest2 {
+                    magic: (),
+                    server_address: self.server,
+                    mtu: 1400,
+                    client_guid: self.guid,
+                    migration_token,
+                },
+            ))
+            .await;
+            self.recv(Duration::from_millis(200)).await
+        }
+
+        /// Complete the offline handshake, returns whether the server replied
+        async fn handshake(&self) -> bool {
+            self.handshake_with(None).await.is_some()
+        }
+
+        /// Complete the offline handshake with the migration token, returns the reply to
+        /// `OpenConnectionRequest2`
+        async fn handshake_with(&self, migration_token: Option<u64>) -> Option<Packet<BytesMut>> {
+            self.request1().await?;
+            self.request2_with(migration_token).await
+        }
+
+        /// The migration token issued by the `OpenConnectionReply2`
+        fn migration_token(reply: Option<Packet<BytesMut>>) -> Option<u64> {
+            match reply {
+                Some(Packet::Unconnected(unconnected::Packet::OpenConnectionReply2 {
+                    migration_token,
+                    ..
+                })) => migration_token,
+                _ => None,
+            }
+        }
+
+        /// Send the body in a reliable ordered frame on channel 0
+        async fn send_body(&mut self, body: Bytes) {
+            let frame = Frame {
+                flags: Flags::new(Reliability::ReliableOrdered, false),
+                reliable_frame_index: Some(Uint24le(self.next_reliable)),
+                seq_frame_index: None,
+                ordered: Some(Ordered {
+                    frame_index: Uint24le(self.next_ordered),
+                    channel: 0,
+                }),
+                fragment: None,
+                body,
+            };
+            self.next_reliable += 1;
+            self.next_ordered += 1;
+            let seq_num = Uint24le(self.next_seq);
+            self.next_seq += 1;
+            self.send(Packet::Connected(connected::Packet::FrameSet(FrameSet {
+                seq_num,
+                frames: vec![frame],
+            })))
+            .await;
+        }
+
+        /// Receive the frame sets until nothing arrives in the timeout, returns their sequence
+        /// numbers
+        async fn frame_sets(&self, timeout: Duration) -> Vec<u32> {
+            let mut seq_nums = Vec::new();
+            while let Some(pack) = self.recv(timeout).await {
+                if let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack {
+                    seq_nums.push(frame_set.seq_num.0);
+                }
+            }
+            seq_nums
+        }
+
+        async fn ack(&self, mut seq_nums: Vec<u32>) {
+            seq_nums.sort_unstable();
+            seq_nums.dedup();
+            let ack = AckOrNack::extend_from(seq_nums.into_iter(), 1400).unwrap();
+            self.send(Packet::Connected(connected::Packet::Ack(ack)))
+                .await;
+        }
+
+        async fn connection_request(&mut self) {
+            let mut buf = BytesMut::new();
+            FrameBody::ConnectionRequest {
+                client_guid: self.guid,
+                request_timestamp: 0,
+                use_encryption: false,
+            }
+            .write(&mut buf);
+            self.send_body(buf.freeze()).await;
+        }
+    }
+
+    async fn bind(builder: &mut ConfigBuilder) -> Server {
+        ServerBuilder::new(builder.sever_guid(1).build().unwrap())
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_server_accept_connection() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conn.peer_addr(), client.socket.local_addr().unwrap());
+        assert_eq!(server.handle().connections(), 1);
+    }
+
+    /// Append a byte to the outgoing datagrams and strip it from the incoming ones
+    struct Trailer(u8);
+
+    impl DatagramHook for Trailer {
+        fn on_send(&self, _: SocketAddr, buf: &mut BytesMut) {
+            buf.extend_from_slice(&[self.0]);
+        }
+
+        fn on_recv(&self, _: SocketAddr, buf: &mut BytesMut) -> Verdict {
+            if buf.last() != Some(&self.0) {
+                return Verdict::Drop;
+            }
+            buf.truncate(buf.len() - 1);
+            Verdict::Pass
+        }
+
+        fn overhead(&self) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_datagram_hook() {
+        let config = ConfigBuilder::default().sever_guid(1).build().unwrap();
+        let mut server = ServerBuilder::new(config)
+            .datagram_hook(Trailer(0xaa))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+
+        // the datagrams without the trailer are dropped by the hook
+        let plain = RawClient::new(server.local_addr(), 8).await;
+        assert!(plain.request1().await.is_none());
+
+        let mut client = RawClient::new(server.local_addr(), 7)
+            .await
+            .with_hook(Trailer(0xaa));
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        // the trailer is reserved from the negotiated mtu
+        assert_eq!(conn.mtu(), 1400 - 1);
+    }
+
+    #[tokio::test]
+    async fn test_server_checksum_obfuscation() {
+        let config = ConfigBuilder::default().sever_guid(1).build().unwrap();
+        let mut server = ServerBuilder::new(config)
+            .datagram_hook((Crc32, XorObfuscation::new(*b"key")))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+
+        // obfuscated by another key
+        let other = RawClient::new(server.local_addr(), 8)
+            .await
+            .with_hook((Crc32, XorObfuscation::new(*b"other")));
+        assert!(other.request1().await.is_none());
+
+        let mut client = RawClient::new(server.local_addr(), 7)
+            .await
+            .with_hook((Crc32, XorObfuscation::new(*b"key")));
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conn.mtu(), 1400 - 4);
+        client.send_body(Bytes::from_static(b"\xfehello")).await;
+        assert_eq!(conn.next().await.unwrap(), Bytes::from_static(b"\xfehello"));
+
+        // the replies of the server pass the checksum of the client
+        conn.send(Bytes::from_static(b"\xfeworld")).await.unwrap();
+        assert!(!client
+            .frame_sets(Duration::from_millis(200))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_server_tap_and_inject() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let tapped = conn.tap_outbound(16);
+        conn.send(Bytes::from_static(b"\xfehello")).await.unwrap();
+        let datagram = tokio::time::timeout(Duration::from_secs(1), tapped.recv_async())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(datagram.direction, Direction::Outbound);
+        assert_eq!(datagram.addr, client.socket.local_addr().unwrap());
+        // the tapped datagram is the one on the wire
+        let on_wire = async {
+            let mut buf = vec![0; 2048];
+            loop {
+                let (len, _) = client.socket.recv_from(&mut buf).await.unwrap();
+                if buf[..len] == datagram.data[..] {
+                    break;
+                }
+            }
+        };
+        assert!(tokio::time::timeout(Duration::from_secs(1), on_wire)
+            .await
+            .is_ok());
+
+        // replay a frame set recorded from the peer
+        let frame = Frame {
+            flags: Flags::new(Reliability::ReliableOrdered, false),
+            reliable_frame_index: Some(Uint24le(client.next_reliable)),
+            seq_frame_index: None,
+            ordered: Some(Ordered {
+                frame_index: Uint24le(client.next_ordered),
+                channel: 0,
+            }),
+            fragment: None,
+            body: Bytes::from_static(b"\xfeinjected"),
+        };
+        let mut raw = BytesMut::new();
+        Packet::Connected(connected::Packet::FrameSet(FrameSet {
+            seq_num: Uint24le(client.next_seq),
+            frames: vec![frame],
+        }))
+        .write(&mut raw);
+        conn.inject_datagram(raw.freeze()).await.unwrap();
+        assert_eq!(
+            conn.next().await.unwrap(),
+            Bytes::from_static(b"\xfeinjected")
+        );
+
+        // only the connected datagrams are accepted
+        let mut ping = BytesMut::new();
+        Packet::<Bytes>::Unconnected(unconnected::Packet::UnconnectedPing {
+            send_timestamp: 0,
+            magic: (),
+            client_guid: 7,
+        })
+        .write(&mut ping);
+        assert!(conn.inject_datagram(ping.freeze()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_tap() {
+        let server = bind(&mut ConfigBuilder::default()).await;
+        let tapped = server.handle().tap(8);
+        let client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.request1().await.is_some());
+
+        let inbound = tapped.try_recv().unwrap();
+        assert_eq!(inbound.direction, Direction::Inbound);
+        assert_eq!(inbound.addr, client.socket.local_addr().unwrap());
+        assert_eq!(inbound.data[0], u8::from(PackType::OpenConnectionRequest1));
+        let outbound = tapped.try_recv().unwrap();
+        assert_eq!(outbound.direction, Direction::Outbound);
+        assert_eq!(outbound.data[0], u8::from(PackType::OpenConnectionReply1));
+
+        server.handle().detach_tap();
+        assert!(client.request1().await.is_some());
+        assert!(tapped.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_disconnected_by_peer() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let events = conn.events(8);
+
+        let mut buf = BytesMut::new();
+        FrameBody::Disconnect(DisconnectReason::Application(7)).write(&mut buf);
+        client.send_body(buf.freeze()).await;
+        let disconnected = async {
+            loop {
+                if let Event::Disconnected { reason } = events.recv_async().await.unwrap() {
+                    break reason;
+                }
+            }
+        };
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), disconnected)
+                .await
+                .unwrap(),
+            DisconnectReason::Application(7)
+        );
+        assert!(conn.next().await.is_none());
+        assert!(matches!(
+            conn.send(Bytes::from_static(b"\xfehello")).await,
+            Err(Error::Disconnected(DisconnectReason::Application(7)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_server_retransmit_on_timeout() {
+        let rto = RtoConfig::default().with_bounds(MIN_RTO, Duration::from_millis(300));
+        let mut server = bind(ConfigBuilder::default().rto(rto)).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        conn.send(Bytes::from_static(b"\xfehello")).await.unwrap();
+
+        // the frame set carrying the message, it is never acknowledged
+        let sent = || async {
+            loop {
+                let Some(Packet::Connected(connected::Packet::FrameSet(frame_set))) =
+                    client.recv(Duration::from_secs(1)).await
+                else {
+                    continue;
+                };
+                if let Some(frame) = frame_set
+                    .frames
+                    .iter()
+                    .find(|frame| frame.body[..] == b"\xfehello"[..])
+                {
+                    break (frame_set.seq_num.0, frame.reliable_frame_index);
+                }
+            }
+        };
+        let (first_seq, reliable) = sent().await;
+        let (resent_seq, resent_reliable) = tokio::time::timeout(Duration::from_secs(1), sent())
+            .await
+            .unwrap();
+        // retransmitted by the timer in a new frame set
+        assert_ne!(first_seq, resent_seq);
+        assert_eq!(reliable, resent_reliable);
+    }
+
+    #[tokio::test]
+    async fn test_server_report_stalled() {
+        let rto = RtoConfig::default().with_bounds(MIN_RTO, Duration::from_millis(300));
+        let mut server = bind(ConfigBuilder::default().rto(rto)).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let events = conn.events(8);
+        // never acknowledged by the client
+        conn.send(Bytes::from_static(b"\xfehello")).await.unwrap();
+
+        let stalled = async {
+            loop {
+                if let Event::Stalled(diagnostic) = events.recv_async().await.unwrap() {
+                    break diagnostic;
+                }
+            }
+        };
+        let diagnostic = tokio::time::timeout(Duration::from_secs(3), stalled)
+            .await
+            .unwrap();
+        assert!(diagnostic.unacked_for.unwrap() >= diagnostic.rto * 4);
+        assert!(diagnostic.datagrams_in_flight > 0);
+        assert!(diagnostic.bytes_in_flight > 0);
+        assert_eq!(diagnostic.stalled_channel, None);
+    }
+
+    #[tokio::test]
+    async fn test_server_send_watermark() {
+        let mut server =
+            bind(ConfigBuilder::default().send_watermark(WatermarkConfig::new(4000, 1000))).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+        client.ack(seq_nums).await;
+        let events = conn.events(16);
+
+        for _ in 0..8 {
+            let mut data = vec![0; 1000];
+            data[0] = 0xfe;
+            conn.send(Bytes::from(data)).await.unwrap();
+        }
+        let high = tokio::time::timeout(Duration::from_secs(1), events.recv_async())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            matches!(high, Event::HighWatermark { pending_bytes } if pending_bytes >= 4000),
+            "{high:?}"
+        );
+
+        // drained once the client acks
+        let mut low = None;
+        for _ in 0..10 {
+            let pending = client.frame_sets(Duration::from_millis(50)).await;
+            if !pending.is_empty() {
+                client.ack(pending).await;
+            }
+            low = events
+                .try_iter()
+                .find(|event| matches!(event, Event::LowWatermark { .. }));
+            if low.is_some() {
+                break;
+            }
+        }
+        assert!(
+            matches!(low, Some(Event::LowWatermark { pending_bytes }) if pending_bytes <= 1000),
+            "{low:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_keepalive() {
+        let interval = Duration::from_millis(100);
+        let mut server = bind(
+            ConfigBuilder::default().keepalive(
+                KeepaliveConfig::default()
+                    .with_interval(interval)
+                    .with_max_unanswered(2),
+            ),
+        )
+        .await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let events = conn.events(8);
+        let seq_nums = client.frame_sets(Duration::from_millis(50)).await;
+        client.ack(seq_nums).await;
+
+        // pinged while idle, and timed out once the pings are unanswered
+        let mut pings = 0;
+        while let Some(pack) = client.recv(interval * 3).await {
+            let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack else {
+                continue;
+            };
+            pings += frame_set
+                .frames
+                .iter()
+                .filter(|frame| frame.body.first() == Some(&u8::from(PackType::ConnectedPing)))
+                .count();
+        }
+        assert_eq!(pings, 2);
+        let disconnected = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Event::Disconnected { reason } = events.recv_async().await.unwrap() {
+                    break reason;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(disconnected, DisconnectReason::Timeout);
+    }
+
+    #[tokio::test]
+    async fn test_server_path_mtu() {
+        // the path drops the datagrams larger than `limit` including the IP and UDP headers
+        let probe = |limit: usize| async move {
+            let server = bind(
+                ConfigBuilder::default()
+                    .max_mtu(1492)
+                    .rto(RtoConfig::default().with_bounds(MIN_RTO, Duration::from_millis(300)))
+                    .pmtu(PmtuConfig::default().with_max_probes(1)),
+            )
+            .await;
+            let mut client = RawClient::new(server.local_addr(), 7).await;
+            assert!(client.handshake().await);
+            client.connection_request().await;
+            let mut incoming = server;
+            let conn = tokio::time::timeout(Duration::from_secs(1), incoming.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(conn.mtu(), 1400);
+            while let Some((len, pack)) = client.recv_sized(Duration::from_millis(500)).await {
+                let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack else {
+                    continue;
+                };
+                if len + UDP_HEADER_SIZE <= limit {
+                    client.ack(vec![frame_set.seq_num.0]).await;
+                }
+            }
+            conn.mtu()
+        };
+
+        // the search stops once the gap to the upper bound is less than the min step 16
+        let wide = probe(1492).await;
+        assert!((1477..=1492).contains(&wide), "{wide}");
+        let narrow = probe(1440).await;
+        assert!((1425..=1440).contains(&narrow), "{narrow}");
+    }
+
+    #[tokio::test]
+    async fn test_server_mtu_blackhole() {
+        let server = bind(
+            ConfigBuilder::default()
+                .rto(RtoConfig::default().with_bounds(MIN_RTO, Duration::from_millis(300)))
+                .keepalive(
+                    KeepaliveConfig::default()
+                        .with_interval(Duration::from_millis(50))
+                        .with_max_unanswered(1000)
+                        .with_suppress_while_active(false),
+                )
+                .pmtu(
+                    PmtuConfig::default()
+                        .with_enabled(false)
+                        .with_blackhole(3, 64),
+                ),
+        )
+        .await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut incoming = server;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), incoming.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let events = conn.events(16);
+        for _ in 0..3 {
+            conn.send(Bytes::from(vec![0xfe; 1300])).await.unwrap();
+        }
+        // the path drops the datagrams larger than 1200 bytes including the IP and UDP headers,
+        // while the keepalive pings keep getting through
+        let blackhole = async {
+            while let Some((len, pack)) = client.recv_sized(Duration::from_millis(500)).await {
+                let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack else {
+                    continue;
+                };
+                if len + UDP_HEADER_SIZE <= 1200 {
+                    client.ack(vec![frame_set.seq_num.0]).await;
+                }
+            }
+        };
+        let reduced = async {
+            loop {
+                if let Event::MtuReduced { from, to } = events.recv_async().await.unwrap() {
+                    break (from, to);
+                }
+            }
+        };
+        let (from, to) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                mtu = reduced => mtu,
+                () = blackhole => panic!("the path went quiet"),
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(from, 1400);
+        assert!(to < 1200, "{to}");
+        assert_eq!(conn.mtu(), to);
+    }
+
+    #[tokio::test]
+    async fn test_server_resend_limit() {
+        let rto = RtoConfig::default().with_bounds(MIN_RTO, Duration::from_millis(300));
+        let connect = |on_exceeded| async move {
+            let server = bind(
+                ConfigBuilder::default().rto(rto).resend_limit(
+                    ResendLimitConfig::default()
+                        .with_max_attempts(2)
+                        .with_on_exceeded(on_exceeded),
+                ),
+            )
+            .await;
+            let mut client = RawClient::new(server.local_addr(), 7).await;
+            assert!(client.handshake().await);
+            client.connection_request().await;
+            (server, client)
+        };
+
+        let (mut server, _client) = connect(ResendExceeded::Abandon).await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let events = conn.events(8);
+        // never acknowledged by the client
+        conn.send(Bytes::from_static(b"\xfehello")).await.unwrap();
+        let lost = async {
+            loop {
+                if let Event::ReceiptLost { .. } = events.recv_async().await.unwrap() {
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(3), lost)
+            .await
+            .unwrap();
+        // the abandoned frames do not hold the waiters
+        tokio::time::timeout(Duration::from_secs(1), conn.flush_acked())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let (mut disconnecting, _peer) = connect(ResendExceeded::Disconnect).await;
+        let mut disconnected = tokio::time::timeout(Duration::from_secs(1), disconnecting.next())
+            .await
+            .unwrap()
+            .unwrap();
+        disconnected
+            .send(Bytes::from_static(b"\xfehello"))
+            .await
+            .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_secs(3), disconnected.next())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            disconnected.send(Bytes::from_static(b"\xfehello")).await,
+            Err(Error::Disconnected(DisconnectReason::RetransmissionLimit))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_server_abandon_splits() {
+        let rto = RtoConfig::default().with_bounds(MIN_RTO, Duration::from_millis(300));
+        let mut server = bind(
+            ConfigBuilder::default()
+                .rto(rto)
+                .resend_limit(ResendLimitConfig::default().with_max_attempts(2)),
+        )
+        .await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+        client.ack(seq_nums).await;
+
+        // split into fragments never acknowledged by the client
+        let mut body = vec![0xfe];
+        body.resize(4000, 0);
+        conn.send(Bytes::from(body)).await.unwrap();
+        assert!(client.recv(Duration::from_millis(200)).await.is_some());
+        assert_eq!(conn.stats().pending_splits, 1);
+        tokio::time::timeout(Duration::from_secs(3), conn.flush_acked())
+            .await
+            .unwrap()
+            .unwrap();
+        // the id is released along with the abandoned fragments
+        assert_eq!(conn.stats().pending_splits, 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_memory_budget() {
+        let budget = BudgetConfig::default().with_limit(2048, BudgetPolicy::Disconnect);
+        let mut server = bind(ConfigBuilder::default().memory_budget(budget)).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let events = conn.events(8);
+        // never acknowledged by the client, so the resend map keeps growing
+        let body = Bytes::from(vec![0xfe; 512]);
+        for _ in 0..8 {
+            if conn.send(body.clone()).await.is_err() {
+                break;
+            }
+        }
+        let exceeded = async {
+            loop {
+                if let Event::MemoryBudgetExceeded { limit_bytes, .. } =
+                    events.recv_async().await.unwrap()
+                {
+                    break limit_bytes;
+                }
+            }
+        };
+        let limit_bytes = tokio::time::timeout(Duration::from_secs(1), exceeded)
+            .await
+            .unwrap();
+        assert_eq!(limit_bytes, 2048);
+        assert!(tokio::time::timeout(Duration::from_secs(1), conn.next())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            conn.send(body).await,
+            Err(Error::Disconnected(DisconnectReason::MemoryBudget))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_server_ordered_stalled() {
+        let codec = CodecConfigBuilder::default()
+            .ordered_stalled_timeout(Some(Duration::from_millis(100)))
+            .ordered_stalled_policy(StalledPolicy::Close)
+            .build()
+            .unwrap();
+        let mut server = bind(ConfigBuilder::default().codec(codec)).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        // the frame index 0 never arrives
+        client.next_ordered += 1;
+        client.send_body(Bytes::from_static(b"\xfeblocked")).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        client.send_body(Bytes::from_static(b"\xfestalled")).await;
+        assert!(tokio::time::timeout(Duration::from_secs(1), conn.next())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            conn.send(Bytes::from_static(b"\xfehello")).await,
+            Err(Error::Disconnected(DisconnectReason::OrderedStalled))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_server_handshake_downgraded() {
+        let downgrade = DowngradeConfig::default().with_min_mtu(1450);
+        let server = bind(ConfigBuilder::default().downgrade(downgrade)).await;
+        let events = server.handle().events(8);
+        let client = RawClient::new(server.local_addr(), 7).await;
+        // accepted in the lenient mode and reported
+        assert!(client.handshake().await);
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv_async())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            event,
+            ServerEvent::HandshakeDowngraded {
+                addr,
+                downgrade: Downgrade::Mtu { minimum: 1450, .. },
+                refused: false,
+            } if addr == client.socket.local_addr().unwrap()
+        ));
+
+        // refused with the reply and reported otherwise
+        let strict = bind(ConfigBuilder::default().downgrade(downgrade.with_lenient(false))).await;
+        let strict_events = strict.handle().events(8);
+        let refused = RawClient::new(strict.local_addr(), 7).await;
+        assert!(matches!(
+            refused.request1().await,
+            Some(Packet::Unconnected(
+                unconnected::Packet::IncompatibleProtocol { .. }
+            ))
+        ));
+        let refusal = tokio::time::timeout(Duration::from_secs(1), strict_events.recv_async())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            refusal,
+            ServerEvent::HandshakeDowngraded {
+                addr,
+                refused: true,
+                ..
+            } if addr == refused.socket.local_addr().unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_server_socket_buffers() {
+        let server = bind(&mut ConfigBuilder::default()).await;
+        let defaults = server.handle().event_loop_stats();
+        assert!(defaults.recv_buffer_size.is_some_and(|size| size > 0));
+        assert!(defaults.send_buffer_size.is_some_and(|size| size > 0));
+        // reported by SO_RXQ_OVFL, nothing is dropped yet
+        if cfg!(target_os = "linux") {
+            assert_eq!(defaults.socket_drops, Some(0));
+        }
+
+        // well below the default `net.core.rmem_max` and `net.core.wmem_max`
+        let buffers = SocketBufferConfig::default()
+            .with_expected_peers(1)
+            .with_bandwidth(100_000)
+            .with_rtt(Duration::from_secs(1));
+        let sized = bind(ConfigBuilder::default().socket_buffers(Some(buffers))).await;
+        let stats = sized.handle().event_loop_stats();
+        assert!(stats.recv_buffer_size.is_some_and(|size| size >= 100_000));
+        assert!(stats.send_buffer_size.is_some_and(|size| size >= 100_000));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_server_bind_sharded() {
+        let (opened_tx, opened_rx) = flume::unbounded();
+        let servers = ServerBuilder::bind_sharded("127.0.0.1:0".parse().unwrap(), 2, |_| {
+            let opened = opened_tx.clone();
+            ServerBuilder::new(
+                ConfigBuilder::default()
+                    .sever_guid(1)
+                    .cpu_pinning(CpuPinning::sequential(1))
+                    .build()
+                    .unwrap(),
+            )
+            .on_connect(move |_, _| {
+                // the session is opened by the receive loop of the shard
+                let _ = opened.send(std::thread::current().name().map(String::from));
+                future::ready(())
+            })
+        })
+        .unwrap();
+        assert_eq!(servers.len(), 2);
+        let addr = servers[0].local_addr();
+        assert_ne!(addr.port(), 0);
+        assert_eq!(servers[1].local_addr(), addr);
+
+        let mut client = RawClient::new(addr, 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut accepted = futures::stream::select_all(servers);
+        let conn = tokio::time::timeout(Duration::from_secs(1), accepted.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conn.peer_addr(), client.socket.local_addr().unwrap());
+        let shard = opened_rx.recv_async().await.unwrap().unwrap();
+        assert!(shard.starts_with("raknet-shard-"), "{shard}");
+    }
+
+    #[tokio::test]
+    async fn test_server_channel_window() {
+        let mut server = bind(ConfigBuilder::default().channel_window(1)).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        // the bodies and the sequence numbers of the frame sets received in the timeout
+        let received = || async {
+            let mut bodies = Vec::new();
+            let mut seq_nums = Vec::new();
+            while let Some(pack) = client.recv(Duration::from_millis(200)).await {
+                if let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack {
+                    seq_nums.push(frame_set.seq_num.0);
+                    bodies.extend(
+                        frame_set
+                            .frames
+                            .into_iter()
+                            .map(|frame| frame.body.freeze()),
+                    );
+                }
+            }
+            (bodies, seq_nums)
+        };
+        let (_, handshake) = received().await;
+        client.ack(handshake).await;
+
+        for (channel, data) in [(0, b"\xfea"), (0, b"\xfeb"), (1, b"\xfec")] {
+            conn.feed(
+                Message::new(Bytes::from_static(data))
+                    .reliability(Reliability::ReliableOrdered)
+                    .channel(channel),
+            )
+            .await
+            .unwrap();
+        }
+        SinkExt::<Message>::flush(&mut conn).await.unwrap();
+        // channel 0 waits for the ack of its frame in flight, channel 1 keeps flushing
+        let (bodies, seq_nums) = received().await;
+        assert!(bodies.contains(&Bytes::from_static(b"\xfea")));
+        assert!(bodies.contains(&Bytes::from_static(b"\xfec")));
+        assert!(!bodies.contains(&Bytes::from_static(b"\xfeb")));
+
+        client.ack(seq_nums).await;
+        let (resent, _) = received().await;
+        assert!(resent.contains(&Bytes::from_static(b"\xfeb")));
+    }
+
+    #[tokio::test]
+    async fn test_server_channel_stats() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let before = conn.stats();
+
+        conn.send(
+            Message::new(Bytes::from_static(b"\xfehello"))
+                .reliability(Reliability::ReliableOrdered)
+                .channel(1),
+        )
+        .await
+        .unwrap();
+        conn.send(
+            Message::new(Bytes::from_static(b"\xfeworld")).reliability(Reliability::Reliable),
+        )
+        .await
+        .unwrap();
+        client.send_body(Bytes::from_static(b"\xfehi")).await;
+        conn.recv_message().await.unwrap();
+
+        let stats = conn.stats();
+        assert_eq!(stats.channels[1].sent_messages, 1);
+        assert_eq!(stats.channels[1].sent_bytes, 6);
+        assert_eq!(
+            stats.unordered.sent_messages - before.unordered.sent_messages,
+            1
+        );
+        assert_eq!(stats.unordered.sent_bytes - before.unordered.sent_bytes, 6);
+        assert_eq!(
+            stats.channels[0].received_messages - before.channels[0].received_messages,
+            1
+        );
+        assert_eq!(
+            stats.channels[0].received_bytes - before.channels[0].received_bytes,
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_path_accessors() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+        client.ack(seq_nums).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(conn.bytes_in_flight(), 0);
+        assert!(conn.loss_rate() < f32::EPSILON);
+
+        conn.send(Bytes::from_static(b"\xfehello")).await.unwrap();
+        let lost = client.frame_sets(Duration::from_millis(100)).await;
+        assert!(conn.bytes_in_flight() > 0);
+        let nack = AckOrNack::extend_from(lost.into_iter(), 1400).unwrap();
+        client
+            .send(Packet::Connected(connected::Packet::Nack(nack)))
+            .await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(conn.loss_rate() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_server_congestion_stats() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let events = conn.events(8);
+        let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+        client.ack(seq_nums).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let congestion = conn.stats().congestion;
+        assert!(congestion.slow_start);
+        assert_eq!(congestion.ss_thresh, 0);
+        assert_eq!(congestion.bytes_in_flight, 0);
+
+        conn.send(Bytes::from_static(b"\xfehello")).await.unwrap();
+        let lost = client.frame_sets(Duration::from_millis(100)).await;
+        let nack = AckOrNack::extend_from(lost.into_iter(), 1400).unwrap();
+        client
+            .send(Packet::Connected(connected::Packet::Nack(nack)))
+            .await;
+        let recovery = tokio::time::timeout(Duration::from_secs(1), events.recv_async())
+            .await
+            .unwrap()
+            .unwrap();
+        let Event::CongestionRecovery { cwnd, ss_thresh } = recovery else {
+            panic!("unexpected event {recovery:?}");
+        };
+        assert!(cwnd < congestion.cwnd);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let recovered = conn.stats().congestion;
+        assert!(!recovered.slow_start);
+        assert_eq!(recovered.cwnd, cwnd);
+        assert_eq!(recovered.ss_thresh, ss_thresh);
+    }
+
+    #[tokio::test]
+    async fn test_server_clock_offset() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conn.clock_offset(), None);
+
+        // answers a ping sent 100ms ago, the clock of the peer is 5s ahead
+        let client_timestamp = SystemClock.timestamp() - 100;
+        let mut buf = BytesMut::new();
+        FrameBody::ConnectedPong {
+            client_timestamp,
+            server_timestamp: client_timestamp + 50 + 5000,
+        }
+        .write(&mut buf);
+        client.send_body(buf.freeze()).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let offset = conn.clock_offset().unwrap();
+        assert!((4950..=5000).contains(&offset), "offset {offset}");
+    }
+
+    #[tokio::test]
+    async fn test_server_send_batch() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+        client.ack(seq_nums).await;
+
+        conn.send_batch(
+            (0..10_u8).map(|i| Message::new(Bytes::from(vec![0xfe, i])).channel(i % 2)),
+        )
+        .await
+        .unwrap();
+        let mut frame_sets = Vec::new();
+        while let Some(pack) = client.recv(Duration::from_millis(100)).await {
+            if let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack {
+                frame_sets.push(frame_set);
+            }
+        }
+        // packed into a single datagram in order
+        assert_eq!(frame_sets.len(), 1);
+        let bodies = frame_sets[0]
+            .frames
+            .iter()
+            .map(|frame| frame.body[1])
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_server_tick_drive() {
+        let tick = Duration::from_millis(300);
+        let mut server = bind(ConfigBuilder::default().drive_mode(DriveMode::Tick(tick))).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+        client.ack(seq_nums).await;
+        tokio::time::sleep(tick).await;
+
+        // flushed by the due tick at once
+        conn.send(Bytes::from_static(b"\xfefirst")).await.unwrap();
+        assert!(client.recv(Duration::from_millis(100)).await.is_some());
+        // waits for the next tick
+        conn.send(Bytes::from_static(b"\xfesecond")).await.unwrap();
+        assert!(client.recv(Duration::from_millis(100)).await.is_none());
+        assert!(client.recv(tick).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_server_send_immediate() {
+        let tick = Duration::from_millis(300);
+        let mut server = bind(ConfigBuilder::default().drive_mode(DriveMode::Tick(tick))).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+        client.ack(seq_nums).await;
+        tokio::time::sleep(tick).await;
+        conn.send(Bytes::from_static(b"\xfefirst")).await.unwrap();
+        assert!(client.recv(Duration::from_millis(100)).await.is_some());
+
+        // the queued message waits for the next tick, the immediate one skips it
+        conn.send(Bytes::from_static(b"\xfequeued")).await.unwrap();
+        conn.send_immediate(Message::new(Bytes::from_static(b"\xfeinput")))
+            .await
+            .unwrap();
+        let Some(Packet::Connected(connected::Packet::FrameSet(frame_set))) =
+            client.recv(Duration::from_millis(100)).await
+        else {
+            panic!("expect a frame set");
+        };
+        assert_eq!(frame_set.frames.len(), 1);
+        assert_eq!(&frame_set.frames[0].body[..], b"\xfeinput");
+        assert!(client.recv(Duration::from_millis(100)).await.is_none());
+        assert!(client.recv(tick).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_server_retransmit_topped_up() {
+        let tick = Duration::from_millis(300);
+        let rto = RtoConfig::default().with_bounds(MIN_RTO, Duration::from_millis(200));
+        let mut server = bind(
+            ConfigBuilder::default()
+                .drive_mode(DriveMode::Tick(tick))
+                .rto(rto),
+        )
+        .await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+        client.ack(seq_nums).await;
+        tokio::time::sleep(tick).await;
+
+        // never acknowledged, resent by the next tick
+        conn.send(Bytes::from_static(b"\xfehello")).await.unwrap();
+        assert!(client.recv(Duration::from_millis(100)).await.is_some());
+        conn.send(Bytes::from_static(b"\xfeworld")).await.unwrap();
+        let Some(Packet::Connected(connected::Packet::FrameSet(frame_set))) =
+            client.recv(tick * 2).await
+        else {
+            panic!("expect a frame set");
+        };
+        // the fresh message shares the datagram of the retransmit
+        let bodies: Vec<_> = frame_set
+            .frames
+            .iter()
+            .map(|frame| &frame.body[..])
+            .collect();
+        assert_eq!(bodies, vec![&b"\xfehello"[..], &b"\xfeworld"[..]]);
+    }
+
+    #[tokio::test]
+    async fn test_server_shared_tasks() {
+        let mut server = bind(ConfigBuilder::default().task_mode(TaskMode::Shared)).await;
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let tasks = metrics.num_alive_tasks();
+        let mut conns = Vec::new();
+        for guid in 0..3 {
+            let mut client = RawClient::new(server.local_addr(), guid).await;
+            assert!(client.handshake().await);
+            client.connection_request().await;
+            let conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+                .await
+                .unwrap()
+                .unwrap();
+            conns.push((client, conn));
+        }
+        // driven by the shared drivers without spawning more tasks
+        assert_eq!(metrics.num_alive_tasks(), tasks);
+
+        for (client, conn) in &mut conns {
+            let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+            client.ack(seq_nums).await;
+            client.send_body(Bytes::from_static(b"\xfehello")).await;
+            let received = tokio::time::timeout(Duration::from_secs(1), conn.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(received, Bytes::from_static(b"\xfehello"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_ack_policy() {
+        let delay = Duration::from_millis(80);
+        let mut server = bind(
+            ConfigBuilder::default().ack(
+                AckConfig::default()
+                    .with_flush_delay(delay)
+                    .with_max_acks_per_datagram(2),
+            ),
+        )
+        .await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let seq_nums = client.frame_sets(Duration::from_millis(150)).await;
+        client.ack(seq_nums).await;
+
+        for _ in 0..3 {
+            client.send_body(Bytes::from_static(b"\xfehello")).await;
+        }
+        // delayed without the outgoing data to carry them
+        assert!(client.recv(delay / 2).await.is_none());
+        let mut acks = 0;
+        while let Some(pack) = client.recv(delay).await {
+            if let Packet::Connected(connected::Packet::Ack(_)) = pack {
+                acks += 1;
+            }
+        }
+        // 2 sequence numbers at most in each ack
+        assert_eq!(acks, 2);
+        for _ in 0..3 {
+            conn.next().await.unwrap();
+        }
+
+        client.send_body(Bytes::from_static(b"\xfehello")).await;
+        conn.next().await.unwrap();
+        conn.send(Bytes::from_static(b"\xfereply")).await.unwrap();
+        let mut acked = false;
+        while let Some(pack) = client.recv(delay / 2).await {
+            acked |= matches!(pack, Packet::Connected(connected::Packet::Ack(_)));
+        }
+        // piggybacked on the reply before the delay
+        assert!(acked);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_server_task_naming() {
+        let mut server =
+            bind(ConfigBuilder::default().task_naming(TaskNaming::with_prefix("lobby-"))).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        conn.disconnect(DisconnectReason::Closed).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // the logs of the tasks are spanned by their names
+        assert!(logs_contain("lobby-raknet-recv"));
+        assert!(logs_contain(&format!(
+            "lobby-raknet-conn-{}",
+            client.socket.local_addr().unwrap()
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_server_receive_timestamps() {
+        let mut server = bind(ConfigBuilder::default().rx_timestamps(true)).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let before = Instant::now();
+        client.send_body(Bytes::from_static(b"\xfehello")).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let received = conn.recv_message().await.unwrap();
+        assert_eq!(received.data, Bytes::from_static(b"\xfehello"));
+        assert_eq!(received.reliability, Reliability::ReliableOrdered);
+        assert_eq!(received.channel, Some(0));
+        // stamped on the arrival instead of the read, the kernel timestamp is mapped by the wall
+        // clock, allow its precision
+        assert!(received.received_at + Duration::from_millis(10) >= before);
+        assert!(received.received_at + Duration::from_millis(50) <= Instant::now());
+    }
+
+    #[tokio::test]
+    async fn test_server_peek_message() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        client.send_body(Bytes::from_static(b"\xfefirst")).await;
+        client.send_body(Bytes::from_static(b"\xfesecond")).await;
+
+        let peeked = conn.peek().await.unwrap();
+        assert_eq!(peeked.data, Bytes::from_static(b"\xfefirst"));
+        // the connection request took the first ordered index
+        assert_eq!(peeked.ordered_index, Some(1));
+        // peeking again does not consume it
+        assert_eq!(
+            conn.peek().await.unwrap().data,
+            Bytes::from_static(b"\xfefirst")
+        );
+        let first = conn.recv_message().await.unwrap();
+        assert_eq!(first.data, Bytes::from_static(b"\xfefirst"));
+        assert_eq!(conn.peek().await.unwrap().ordered_index, Some(2));
+        assert_eq!(
+            conn.next().await.unwrap(),
+            Bytes::from_static(b"\xfesecond")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_send_in_batches() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let handle = server.handle();
+        let before = handle.event_loop_stats();
+        // larger than the mtu, so that they are not packed into one datagram
+        for _ in 0..32 {
+            conn.feed(Bytes::from(vec![0xfe; 1000])).await.unwrap();
+        }
+        SinkExt::<Bytes>::flush(&mut conn).await.unwrap();
+        // the congestion window is opened by the acks
+        let mut received = 0;
+        loop {
+            let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+            if seq_nums.is_empty() {
+                break;
+            }
+            received += seq_nums.len();
+            client.ack(seq_nums).await;
+        }
+        assert!(received >= 32, "{received}");
+
+        let stats = handle.event_loop_stats();
+        let sent = stats.datagrams_sent - before.datagrams_sent;
+        let syscalls = stats.send_syscalls - before.send_syscalls;
+        assert!(sent >= 32);
+        assert!(syscalls <= sent);
+        if cfg!(target_os = "linux") {
+            // the datagrams queued together are sent by sendmmsg
+            assert!(syscalls < sent);
+        }
+        assert_eq!(stats.recv_syscalls, stats.datagrams_received);
+    }
+
+    #[tokio::test]
+    async fn test_server_sessions() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let sessions = server.handle().sessions();
+        assert_eq!(sessions.len(), 1);
+        let session = sessions.by_guid(7).unwrap();
+        assert_eq!(session.addr(), client.socket.local_addr().unwrap());
+        assert_eq!(sessions.by_addr(&session.addr()).unwrap().guid(), 7);
+        assert!(sessions.by_guid(8).is_none());
+
+        // kicked without holding the connection
+        assert!(session.kick(DisconnectReason::Kicked));
+        let disconnected = async {
+            loop {
+                if let Some(Packet::Connected(connected::Packet::FrameSet(frame_set))) =
+                    client.recv(Duration::from_secs(1)).await
+                {
+                    let kicked = frame_set.frames.into_iter().any(|frame| {
+                        matches!(
+                            FrameBody::read(frame.body.freeze()),
+                            Ok(FrameBody::Disconnect(DisconnectReason::Kicked))
+                        )
+                    });
+                    if kicked {
+                        break;
+                    }
+                }
+            }
+        };
+        assert!(tokio::time::timeout(Duration::from_secs(1), disconnected)
+            .await
+            .is_ok());
+        assert!(conn.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_server_connection_weight() {
+        let mut server = bind(ConfigBuilder::default().migration(true)).await;
+        let handle = server.handle();
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        let token = RawClient::migration_token(client.handshake_with(None).await);
+        client.connection_request().await;
+        let _conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let old = client.socket.local_addr().unwrap();
+        handle.set_weight(old, 0);
+        assert_eq!(handle.weights.get(&old), 1);
+        handle.set_weight(old, 50);
+
+        // the weight follows the connection to its new address
+        client.socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let new = client.socket.local_addr().unwrap();
+        assert!(client.handshake_with(token).await.is_some());
+        client.send_body(Bytes::from_static(b"\xfehello")).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(handle.weights.get(&new), 50);
+        assert_eq!(handle.weights.get(&old), DEFAULT_WEIGHT);
+
+        handle.reset_weight(&new);
+        assert_eq!(handle.weights.get(&new), DEFAULT_WEIGHT);
+    }
+
+    #[tokio::test]
+    async fn test_server_packet_filter() {
+        let config = ConfigBuilder::default().sever_guid(1).build().unwrap();
+        // block the unconnected pings
+        let server = ServerBuilder::new(config)
+            .packet_filter(|_, raw: &[u8]| {
+                if raw.first() == Some(&0x01) {
+                    Verdict::Drop
+                } else {
+                    Verdict::Pass
+                }
+            })
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let client = RawClient::new(server.local_addr(), 7).await;
+        client
+            .send(Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                send_timestamp: 0,
+                magic: (),
+                client_guid: 7,
+            }))
+            .await;
+        assert!(client.recv(Duration::from_millis(200)).await.is_none());
+        assert!(client.handshake().await);
+        // the drops of the raw layers and the offline handler are counted together
+        client
+            .socket
+            .send_to(&[0x05; 32], server.local_addr())
+            .await
+            .unwrap();
+        assert!(client.recv(Duration::from_millis(200)).await.is_none());
+        let drops = server.handle().drop_stats();
+        assert_eq!(drops.filtered, 1);
+        assert_eq!(drops.bad_magic, 1);
+    }
+
+    #[tokio::test]
+    async fn test_server_shed_stats() {
+        let server = bind(ConfigBuilder::default().receive_budget(2)).await;
+        let client = RawClient::new(server.local_addr(), 7).await;
+        for _ in 0..4 {
+            client
+                .send(Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                    send_timestamp: 0,
+                    magic: (),
+                    client_guid: 7,
+                }))
+                .await;
+        }
+        // only the first ping is within the handshake level of the budget
+        assert!(client.recv(Duration::from_millis(200)).await.is_some());
+        assert!(client.recv(Duration::from_millis(200)).await.is_none());
+        assert_eq!(server.handle().shed_stats().handshake, 3);
+    }
+
+    #[tokio::test]
+    async fn test_server_drop_oversized_datagrams() {
+        let server = bind(ConfigBuilder::default().max_datagram_size(1400)).await;
+        let client = RawClient::new(server.local_addr(), 7).await;
+        let mut ping = BytesMut::new();
+        Packet::<Bytes>::Unconnected(unconnected::Packet::UnconnectedPing {
+            send_timestamp: 0,
+            magic: (),
+            client_guid: 7,
+        })
+        .write(&mut ping);
+        client
+            .socket
+            .send_to(&ping, server.local_addr())
+            .await
+            .unwrap();
+        assert!(client.recv(Duration::from_millis(200)).await.is_some());
+
+        // padded beyond the max datagram size
+        ping.resize(1401, 0);
+        client
+            .socket
+            .send_to(&ping, server.local_addr())
+            .await
+            .unwrap();
+        assert!(client.recv(Duration::from_millis(200)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_server_drain() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let handle = server.handle();
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut progress = Box::pin(handle.drain());
+        assert_eq!(progress.next().await, Some(1));
+        // hidden from the server lists and refusing the new connections
+        let other = RawClient::new(server.local_addr(), 8).await;
+        other
+            .send(Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                send_timestamp: 0,
+                magic: (),
+                client_guid: 8,
+            }))
+            .await;
+        assert!(other.recv(Duration::from_millis(200)).await.is_none());
+        assert!(!matches!(
+            other.request1().await,
+            Some(Packet::Unconnected(
+                unconnected::Packet::OpenConnectionReply1 { .. }
+            ))
+        ));
+
+        // the connection leaves by itself
+        drop(conn);
+        let left = tokio::time::timeout(Duration::from_secs(2), progress.next())
+            .await
+            .unwrap();
+        assert_eq!(left, Some(0));
+        assert_eq!(progress.next().await, None);
+
+        handle.resume();
+        assert!(other.handshake().await);
+    }
+
+    #[tokio::test]
+    async fn test_server_session_hooks() {
+        let (opened_tx, opened_rx) = flume::unbounded();
+        let (closed_tx, closed_rx) = flume::unbounded();
+        let config = ConfigBuilder::default().sever_guid(1).build().unwrap();
+        let mut server = ServerBuilder::new(config)
+            .on_connect(move |addr, guid| {
+                let opened = opened_tx.clone();
+                async move {
+                    // the connection waits for the slow provisioning
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    opened.send((addr, guid)).unwrap();
+                }
+            })
+            .on_disconnect(move |addr, guid| {
+                let closed = closed_tx.clone();
+                async move {
+                    closed.send((addr, guid)).unwrap();
+                }
+            })
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        let addr = client.socket.local_addr().unwrap();
+        assert!(client.handshake().await);
+        client.connection_request().await;
+
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(opened_rx.try_recv().unwrap(), (addr, 7));
+        assert!(closed_rx.try_recv().is_err());
+        conn.disconnect(DisconnectReason::Closed).await.unwrap();
+        assert_eq!(closed_rx.try_recv().unwrap(), (addr, 7));
+        drop(conn);
+        tokio::task::yield_now().await;
+        assert!(closed_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_migrate_connection() {
+        let mut server = bind(ConfigBuilder::default().migration(true)).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        let token = RawClient::migration_token(client.handshake_with(None).await);
+        assert!(token.is_some());
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let events = conn.events(8);
+        let old = client.socket.local_addr().unwrap();
+
+        // another address knowing the GUID but not the token could not take the connection
+        let hijacker = RawClient::new(server.local_addr(), 7).await;
+        for forged in [None, token.map(|token| token ^ 1)] {
+            assert!(matches!(
+                hijacker.handshake_with(forged).await,
+                Some(Packet::Unconnected(
+                    unconnected::Packet::AlreadyConnected { .. }
+                ))
+            ));
+        }
+        assert_eq!(conn.peer_addr(), old);
+
+        // the NAT rebinds the client to another port
+        client.socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let new = client.socket.local_addr().unwrap();
+        assert!(client.handshake_with(token).await.is_some());
+        client.send_body(Bytes::from_static(b"\xfehello")).await;
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), events.recv_async())
+                .await
+                .unwrap()
+                .unwrap(),
+            Event::AddressChanged { old, new }
+        );
+        assert_eq!(conn.peer_addr(), new);
+        assert_eq!(conn.next().await.unwrap(), Bytes::from_static(b"\xfehello"));
+        assert_eq!(server.handle().connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_server_flush_acked() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        conn.send(Bytes::from_static(b"\xfehello")).await.unwrap();
+
+        // the peer does not ack
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), conn.flush_acked())
+                .await
+                .is_err()
+        );
+        let seq_nums = client.frame_sets(Duration::from_millis(100)).await;
+        assert!(!seq_nums.is_empty());
+        client.ack(seq_nums).await;
+        tokio::time::timeout(Duration::from_secs(1), conn.wait_acked())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_close_for_transfer() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        // the client acks everything and reports whether it is notified of the disconnection
+        let peer = tokio::spawn(async move {
+            let mut notified = false;
+            while let Some(pack) = client.recv(Duration::from_millis(300)).await {
+                let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack else {
+                    continue;
+                };
+                notified |= frame_set.frames.iter().any(|frame| {
+                    frame.body.first() == Some(&u8::from(PackType::DisconnectNotification))
+                });
+                client.ack(vec![frame_set.seq_num.0]).await;
+            }
+            notified
+        });
+
+        conn.send(Bytes::from_static(b"\xfetransfer"))
+            .await
+            .unwrap();
+        assert!(conn
+            .close_for_transfer(None, Duration::from_secs(1))
+            .await
+            .unwrap());
+        assert!(conn
+            .close_for_transfer(None, Duration::from_secs(1))
+            .await
+            .is_err());
+        drop(conn);
+        assert!(!peer.await.unwrap());
+        assert_eq!(server.handle().connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_answer_pings() {
+        let mut builder = ConfigBuilder::default();
+        builder.advertisement(&b"MCPE;fast"[..]);
+        let fast = bind(&mut builder).await;
+        // the rate limited pings go through the offline handler
+        builder.rate_limit(RateLimitConfig::default().with_capacity(10, 10));
+        let limited = bind(&mut builder).await;
+        let ping = |client: RawClient| async move {
+            client
+                .send(Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                    send_timestamp: 1919,
+                    magic: (),
+                    client_guid: 7,
+                }))
+                .await;
+            match client.recv(Duration::from_millis(200)).await {
+                Some(Packet::Unconnected(unconnected::Packet::UnconnectedPong {
+                    send_timestamp: 1919,
+                    server_guid: 1,
+                    data,
+                    ..
+                })) => data,
+                pack => panic!("unexpected {pack:?}"),
+            }
+        };
+        for server in [&fast, &limited] {
+            let client = RawClient::new(server.local_addr(), 7).await;
+            assert_eq!(ping(client).await, Bytes::from_static(b"MCPE;fast"));
+
+            let handle = server.handle();
+            handle.set_advertisement(&b"MCPE;updated"[..]).unwrap();
+            let updated = RawClient::new(server.local_addr(), 7).await;
+            assert_eq!(ping(updated).await, Bytes::from_static(b"MCPE;updated"));
+            assert!(handle
+                .set_advertisement(vec![0; MAX_ADVERTISEMENT + 1])
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_pong_hook() {
+        let hidden = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let hidden_addr = hidden.local_addr().unwrap();
+        let hook = move |addr: SocketAddr| async move {
+            (addr != hidden_addr).then(|| Bytes::from(format!("MCPE;{}", addr.port())))
+        };
+        let server = ServerBuilder::new(ConfigBuilder::default().sever_guid(1).build().unwrap())
+            .pong_hook(hook)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let ping = || {
+            Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                send_timestamp: 1919,
+                magic: (),
+                client_guid: 7,
+            })
+        };
+        let client = RawClient::new(server.local_addr(), 7).await;
+        let port = client.socket.local_addr().unwrap().port();
+        client.send(ping()).await;
+        match client.recv(Duration::from_millis(200)).await {
+            Some(Packet::Unconnected(unconnected::Packet::UnconnectedPong { data, .. })) => {
+                assert_eq!(data, Bytes::from(format!("MCPE;{port}")));
+            }
+            pack => panic!("unexpected {pack:?}"),
+        }
+
+        // hidden from the peer
+        let mut hidden_client = RawClient::new(server.local_addr(), 8).await;
+        hidden_client.socket = hidden;
+        hidden_client.send(ping()).await;
+        assert!(hidden_client
+            .recv(Duration::from_millis(200))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_server_unconnected_messages() {
+        let server = bind(&mut ConfigBuilder::default()).await;
+        let handle = server.handle();
+        let rx = handle.unconnected_messages(4);
+        let client = RawClient::new(server.local_addr(), 7).await;
+        let client_addr = client.socket.local_addr().unwrap();
+        client
+            .send(Packet::Unconnected(unconnected::Packet::AdvertiseSystem {
+                data: Bytes::from_static(b"lan game"),
+            }))
+            .await;
+        let received = tokio::time::timeout(Duration::from_secs(1), rx.recv_async())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, (Bytes::from_static(b"lan game"), client_addr));
+
+        handle
+            .send_unconnected(client_addr, &b"server"[..])
+            .unwrap();
+        match client.recv(Duration::from_millis(200)).await {
+            Some(Packet::Unconnected(unconnected::Packet::AdvertiseSystem { data })) => {
+                assert_eq!(data, Bytes::from_static(b"server"));
+            }
+            pack => panic!("unexpected {pack:?}"),
+        }
+        assert!(handle
+            .send_unconnected(client_addr, vec![0; MAX_ADVERTISEMENT + 1])
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_elevate_peer() {
+        let mut server = bind(&mut ConfigBuilder::default()).await;
+        let handle = server.handle();
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        let addr = client.socket.local_addr().unwrap();
+        handle.elevate_peer(addr, Level::DEBUG);
+        assert!(client.handshake().await);
+        client.connection_request().await;
+        let _conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // the handles share the verbosity with the handshakes and the connections
+        assert!(server.handle().verbosity.elevated(&addr, Level::DEBUG));
+        handle.reset_peer(&addr);
+        assert!(!server.handle().verbosity.elevated(&addr, Level::DEBUG));
+    }
+
+    #[tokio::test]
+    async fn test_server_answer_queries() {
+        let server =
+            bind(ConfigBuilder::default().query(Some(QueryInfo::default().with_hostname("lobby"))))
+                .await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query = |kind: u8, token: Option<i32>| {
+            let mut datagram = vec![0xfe, 0xfd, kind, 0, 0, 0, 1];
+            if let Some(token) = token {
+                datagram.extend_from_slice(&token.to_be_bytes());
+            }
+            datagram
+        };
+        let recv = || async {
+            let mut buf = vec![0; 2048];
+            let (len, _) =
+                tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            buf.truncate(len);
+            buf
+        };
+
+        socket
+            .send_to(&query(0x09, None), server.local_addr())
+            .await
+            .unwrap();
+        let handshake = recv().await;
+        let token: i32 = std::str::from_utf8(&handshake[5..handshake.len() - 1])
+            .unwrap()
+            .parse()
+            .unwrap();
+        socket
+            .send_to(&query(0x00, Some(token)), server.local_addr())
+            .await
+            .unwrap();
+        assert!(recv().await.starts_with(b"\x00\0\0\0\x01lobby\0"));
+
+        server
+            .handle()
+            .set_query_info(QueryInfo::default().with_hostname("arena"));
+        socket
+            .send_to(&query(0x00, Some(token)), server.local_addr())
+            .await
+            .unwrap();
+        assert!(recv().await.starts_with(b"\x00\0\0\0\x01arena\0"));
+    }
+
+    #[tokio::test]
+    async fn test_server_answer_secondary_pings() {
+        let mut builder = ConfigBuilder::default();
+        builder
+            .advertisement(&b"MCPE;secondary"[..])
+            .pong_addrs(vec!["127.0.0.1:0".parse().unwrap()]);
+        let server = bind(&mut builder).await;
+        let handle = server.handle();
+        assert_eq!(handle.pong_addrs().len(), 1);
+        assert_ne!(handle.pong_addrs()[0], server.local_addr());
+
+        let ping = || async {
+            let client = RawClient::new(handle.pong_addrs()[0], 7).await;
+            client
+                .send(Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                    send_timestamp: 114,
+                    magic: (),
+                    client_guid: 7,
+                }))
+                .await;
+            match client.recv(Duration::from_millis(200)).await {
+                Some(Packet::Unconnected(unconnected::Packet::UnconnectedPong {
+                    send_timestamp: 114,
+                    server_guid: 1,
+                    data,
+                    ..
+                })) => data,
+                pack => panic!("unexpected {pack:?}"),
+            }
+        };
+        assert_eq!(ping().await, Bytes::from_static(b"MCPE;secondary"));
+        handle.set_advertisement(&b"MCPE;updated"[..]).unwrap();
+        assert_eq!(ping().await, Bytes::from_static(b"MCPE;updated"));
+
+        // the connections are not served on the secondary address
+        let client = RawClient::new(handle.pong_addrs()[0], 8).await;
+        assert!(client.request1().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_server_accept_releases_backlog() {
+        let mut server = bind(ConfigBuilder::default().accept_backlog(1)).await;
+        let mut first = RawClient::new(server.local_addr(), 7).await;
+        assert!(first.handshake().await);
+        first.connection_request().await;
+
+        // the backlog is held by the first connection until it is accepted
+        let second = RawClient::new(server.local_addr(), 8).await;
+        assert!(second.request1().await.is_some());
+        assert!(second.request2().await.is_none());
+
+        let _conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(second.request2().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_server_deferred_accept() {
+        let mut builder = ConfigBuilder::default();
+        builder
+            .deferred_accept(true)
+            .accept_backlog(1)
+            .handshake_timeout(Duration::from_millis(300));
+        let mut server = bind(&mut builder).await;
+        let mut client = RawClient::new(server.local_addr(), 7).await;
+        assert!(client.handshake().await);
+        // arrives before the connection request
+        client.send_body(Bytes::from_static(b"\xfehello")).await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), server.next())
+                .await
+                .is_err()
+        );
+        client.connection_request().await;
+        let mut conn = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        // the early packet is kept for the connection
+        let received = tokio::time::timeout(Duration::from_secs(1), conn.recv_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.data, Bytes::from_static(b"\xfehello"));
+
+        // the peer never proves it could complete the connected handshake
+        let mut first = RawClient::new(server.local_addr(), 8).await;
+        assert!(first.handshake().await);
+        first.send_body(Bytes::from_static(b"\xfehello")).await;
+        let second = RawClient::new(server.local_addr(), 9).await;
+        assert!(second.request1().await.is_some());
+        assert!(second.request2().await.is_none());
+        // its backlog slot is released with the abandoned handshake
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(second.request2().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_server_releases_abandoned_handshake() {
+        let mut builder = ConfigBuilder::default();
+        builder
+            .accept_backlog(1)
+            .handshake_timeout(Duration::from_millis(100));
+        let server = bind(&mut builder).await;
+        // the first client never sends a datagram after the handshake
+        let first = RawClient::new(server.local_addr(), 7).await;
+        assert!(first.handshake().await);
+
+        let second = RawClient::new(server.local_addr(), 8).await;
+        assert!(second.request1().await.is_some());
+        assert!(second.request2().await.is_none());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(second.request2().await.is_some());
+    }
+}
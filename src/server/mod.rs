@@ -1,11 +1,79 @@
-use bytes::Bytes;
-use futures::{Sink, Stream};
-
 mod ack;
+mod affinity;
+mod backlog;
+mod batch_io;
+mod broadcast;
+mod budget;
 mod conn;
-mod handshake;
+mod drain;
+mod driver;
+mod fair;
 mod incoming;
+mod isolation;
+mod keepalive;
+mod lifecycle;
+mod limiter;
+mod linger;
+mod listener;
 mod offline;
+mod pmtu;
+mod pong;
+mod preconn;
+mod qos;
+mod query;
+mod resend;
+mod resilience;
+mod rto;
+mod session;
+mod shedder;
+mod sockbuf;
+mod socket;
+mod split;
+mod tap;
+mod tarpit;
+mod tick;
+mod timestamp;
+mod trace;
+mod verbosity;
+mod watchdog;
+mod watermark;
+mod wheel;
+
+// The messages sent to a connection task, Err means close the connection with the reason
+type Outgoing = Result<crate::message::Message, crate::event::DisconnectReason>;
+
+pub use ack::AckConfig;
+pub use affinity::CpuPinning;
+pub use budget::{BudgetConfig, BudgetPolicy};
+pub use driver::TaskMode;
+pub use incoming::Connection;
+pub use keepalive::KeepaliveConfig;
+pub use lifecycle::SessionHook;
+pub use limiter::RateLimitConfig;
+pub use linger::Linger;
+pub use listener::{Server, ServerBuilder, ServerHandle};
+pub use offline::{
+    Config, ConfigBuilder, DowngradeConfig, IncompatibleConfig, Refusal, SilentDropConfig,
+};
+pub use pmtu::PmtuConfig;
+pub use pong::{PongCacheConfig, PongHook};
+pub use preconn::HandshakeOrderConfig;
+pub use qos::DscpConfig;
+pub use query::QueryInfo;
+pub use resend::{ResendExceeded, ResendLimitConfig};
+pub use resilience::{SocketErrorConfig, SocketErrorPolicy};
+pub use rto::RtoConfig;
+pub use session::{Session, Sessions};
+pub use shedder::ShedStats;
+pub use sockbuf::SocketBufferConfig;
+pub use tap::{Direction, Tapped};
+pub use tarpit::TarpitConfig;
+pub use tick::DriveMode;
+pub use watchdog::WatchdogConfig;
+pub use watermark::WatermarkConfig;
 
-// Provide the basic operation for each connection, produced by [`Incoming`]
-type IO = impl Stream<Item = Bytes> + Sink<Bytes>;
+pub use crate::codec::checksum::{Crc32, XorObfuscation};
+pub use crate::codec::filter::{PacketFilter, Verdict};
+pub use crate::codec::hook::DatagramHook;
+pub use crate::codec::ordered::StalledPolicy;
+pub use crate::codec::{CodecConfig, CodecConfigBuilder};
@@ -1,45 +1,886 @@
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-use bytes::Bytes;
-use futures::{ready, FutureExt, Sink, SinkExt, Stream};
+use bytes::{Bytes, BytesMut};
+use derive_builder::Builder;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{ready, FutureExt, Sink, SinkExt, Stream, StreamExt};
 use pin_project_lite::pin_project;
 use tracing::{debug, error, warn};
 
-use crate::errors::CodecError;
+use super::ack::AckConfig;
+use super::affinity::CpuPinning;
+use super::backlog::AcceptBacklog;
+use super::budget::{BudgetConfig, GlobalMemory};
+use super::conn::{ConnConfig, Events};
+use super::drain::Drain;
+use super::driver::TaskMode;
+use super::keepalive::KeepaliveConfig;
+use super::limiter::{RateLimitConfig, RateLimiter};
+use super::linger::Linger;
+use super::pmtu::PmtuConfig;
+use super::pong::{
+    AdvertisementProvider, PongCache, PongCacheConfig, PongHook, SharedAdvertisement,
+    MAX_ADVERTISEMENT,
+};
+use super::preconn::{
+    HandshakeOrder, HandshakeOrderConfig, HandshakeState, OfflinePacket, Verdict,
+};
+use super::qos::DscpConfig;
+use super::query::{QueryInfo, SharedQueryInfo};
+use super::resend::ResendLimitConfig;
+use super::resilience::SocketErrorConfig;
+use super::rto::RtoConfig;
+use super::shedder::{Class, ShedCounter, Shedder};
+use super::sockbuf::SocketBufferConfig;
+use super::tarpit::{Tarpit, TarpitConfig};
+use super::tick::DriveMode;
+use super::trace::SessionTraces;
+use super::verbosity::{peer_debug, PeerVerbosity};
+use super::watchdog::WatchdogConfig;
+use super::watermark::WatermarkConfig;
+use crate::codec::CodecConfig;
+use crate::errors::{CodecError, ConfigError};
+use crate::event::{Downgrade, ServerEvent};
+use crate::packet::version::{self, Capabilities};
 use crate::packet::{connected, unconnected, PackType, Packet};
+use crate::rt::TaskNaming;
+use crate::stats::{DropCounter, DropReason};
 use crate::Peer;
 
-#[derive(Debug, Clone)]
-pub(super) struct Config {
+/// The minimum mtu required by raknet
+pub(super) const MIN_MTU: u16 = 576;
+/// The maximum mtu, the datagrams larger than it will be fragmented by the IP layer
+pub(super) const MAX_MTU: u16 = 1500;
+/// The max pings waiting for the [`PongHook`], the pings beyond it are not answered
+const MAX_PENDING_PONGS: usize = 1024;
+/// Limit the max count of the addresses tracked by the handshakes and the `IncompatibleProtocol`
+/// responses, the least recently seen one will be dropped
+const MAX_TRACKED_ADDRESSES: usize = 4096;
+
+/// Server config
+#[derive(Debug, Clone, Builder)]
+#[builder(build_fn(private, name = "build_unchecked", error = "ConfigError"))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Config {
     sever_guid: u64,
+    #[builder(default, setter(into))]
     advertisement: Bytes,
+    // Respond the legacy query protocol on the same port with the information, None means
+    // disabled.
+    #[builder(default)]
+    query: Option<QueryInfo>,
+    #[builder(default = "MIN_MTU")]
     min_mtu: u16,
+    #[builder(default = "1400")]
     max_mtu: u16,
-    // Supported raknet versions, sorted
+    // The inbound datagrams larger than it are dropped and counted before decoding, it should
+    // be at least the max mtu
+    #[builder(default = "MAX_MTU as usize")]
+    max_datagram_size: usize,
+    // Limits of the codec of each connection
+    #[builder(default)]
+    codec: CodecConfig,
+    // Supported raknet versions, sorted, each of them must be known by the protocol version
+    // registry
+    #[builder(default = "version::known_versions()")]
     support_version: Vec<u8>,
+    // Limit the max count of connected clients, 0 means no limit.
+    // Clients will receive `NoFreeIncomingConnections` if the limit is reached.
+    #[builder(default)]
+    max_connections: usize,
+    // How to respond `IncompatibleProtocol` to the clients
+    #[builder(default)]
+    incompatible: IncompatibleConfig,
+    // Rate limit the unconnected ping and open connection requests per source ip
+    #[builder(default)]
+    rate_limit: RateLimitConfig,
+    // Delay the pongs to the sources exceeding the rate limit instead of ignoring their pings
+    #[builder(default)]
+    tarpit: TarpitConfig,
+    // Let a connected client continue its connection from a new address (e.g. NAT rebinding)
+    // when it handshakes again with the same GUID and the migration token issued to its previous
+    // address, instead of starting a new connection
+    #[builder(default)]
+    migration: bool,
+    // Surface a new connection to the application only after its `ConnectionRequest` arrives,
+    // so the half-open sessions never reach the application code
+    #[builder(default)]
+    deferred_accept: bool,
+    // Which refusals are silently dropped instead of sent to the unknown sources
+    #[builder(default)]
+    silent_drop: SilentDropConfig,
+    // Tolerance of the offline packets arriving out of the handshake order
+    #[builder(default)]
+    handshake_order: HandshakeOrderConfig,
+    // The minimum mtu and protocol version expected from the handshakes, and whether the
+    // degraded ones are accepted
+    #[builder(default)]
+    downgrade: DowngradeConfig,
+    // Limit the max inbound packets per second, 0 means no limit.
+    // Load will be shed in order of handshakes, data and acks when the budget is exceeded.
+    #[builder(default)]
+    receive_budget: usize,
+    // How the acks, resends and flushes of each connection are driven
+    #[builder(default)]
+    drive_mode: DriveMode,
+    // Spawn a task for each connection or multiplex them in shared driver tasks
+    #[builder(default)]
+    task_mode: TaskMode,
+    // Pin the thread of each shard to a CPU core when bound by `ServerBuilder::bind_sharded`
+    #[builder(default)]
+    cpu_pinning: CpuPinning,
+    // Name the spawned tasks for tokio-console
+    #[builder(default)]
+    task_naming: TaskNaming,
+    // Bytes granted to each connection per round when flushing connections sharing the socket
+    #[builder(default = "MAX_MTU as usize")]
+    flush_quantum: usize,
+    // The max ordered frames of a channel in flight, so that the other channels keep flushing
+    // while one is head-of-line blocked awaiting retransmissions. 0 means no limit
+    #[builder(default)]
+    channel_window: usize,
+    // Acknowledgement policy of each connection
+    #[builder(default)]
+    ack: AckConfig,
+    // Watermarks of the send queue of each connection
+    #[builder(default)]
+    send_watermark: WatermarkConfig,
+    // DSCP marking of the outbound datagrams by message priority
+    #[builder(default)]
+    dscp: DscpConfig,
+    // Size the socket buffers by the bandwidth-delay product of the expected peers, None keeps
+    // the kernel defaults. The effective sizes are reported in the event loop statistics
+    #[builder(default)]
+    socket_buffers: Option<SocketBufferConfig>,
+    // Stamp the received datagrams in the kernel by `SO_TIMESTAMPING` where it is supported, for
+    // the RTT samples and the receive timestamps of the messages
+    #[builder(default)]
+    rx_timestamps: bool,
+    // Keepalive strategy of each connection
+    #[builder(default)]
+    keepalive: KeepaliveConfig,
+    // Path MTU discovery of each connection after it is established
+    #[builder(default)]
+    pmtu: PmtuConfig,
+    // Memory budget of each connection
+    #[builder(default)]
+    memory_budget: BudgetConfig,
+    // The max bytes buffered by all connections, the budget of each connection is tightened and
+    // new handshakes are refused when it is approached. 0 means unlimited
+    #[builder(default)]
+    memory_ceiling: usize,
+    // The max connections completed the handshake but not accepted by the application yet, the
+    // handshakes are delayed when it is reached. 0 means unlimited
+    #[builder(default)]
+    accept_backlog: usize,
+    // How long a completed handshake holds its accept backlog slot without its connection being
+    // made, e.g. the client never sent a datagram after `OpenConnectionReply2`
+    #[builder(default = "Duration::from_secs(10)")]
+    handshake_timeout: Duration,
+    // What to do with the pending data when a connection handle is dropped without being closed
+    #[builder(default)]
+    linger: Linger,
+    // Bounds of the retransmission timeout of each connection
+    #[builder(default)]
+    rto: RtoConfig,
+    // Max attempts per reliable frame and resend bytes per second of each connection
+    #[builder(default)]
+    resend_limit: ResendLimitConfig,
+    // How to recover from the socket errors without stopping the server
+    #[builder(default)]
+    socket_error: SocketErrorConfig,
+    // How long the assembled pong is cached before it is rebuilt from the advertisement
+    #[builder(default)]
+    pong_cache: PongCacheConfig,
+    // The additional addresses only answering the pings from the cached pong, e.g. `[::]:19133`
+    // beside `0.0.0.0:19132` in the Bedrock convention. The connections are only served on the
+    // primary address
+    #[builder(default)]
+    pong_addrs: Vec<SocketAddr>,
+    // Report the connections making no forward progress before they time out
+    #[builder(default)]
+    watchdog: WatchdogConfig,
+}
+
+impl ConfigBuilder {
+    /// Preset for the Minecraft Bedrock servers hosting many players
+    pub fn bedrock_server() -> Self {
+        let mut builder = Self::default();
+        builder
+            .support_version(vec![10, 11])
+            .max_mtu(1400)
+            .query(Some(QueryInfo::default()))
+            .drive_mode(DriveMode::Tick(Duration::from_millis(10)))
+            .task_mode(TaskMode::Shared);
+        builder
+    }
+
+    /// Preset for the games in a local network, where the path is short and rarely drops packets
+    pub fn lan_game() -> Self {
+        let mut builder = Self::default();
+        builder
+            .max_mtu(1492)
+            .ack(AckConfig::default().with_flush_delay(Duration::from_millis(1)));
+        builder
+    }
+
+    /// Preset for the lossy mobile networks, it keeps the datagrams small to avoid the
+    /// fragmentation in the tunnels and tolerates more unanswered pings
+    pub fn lossy_mobile() -> Self {
+        let mut builder = Self::default();
+        builder
+            .max_mtu(1200)
+            .keepalive(KeepaliveConfig::default().with_max_unanswered(6));
+        builder
+    }
+
+    /// Build the config and check the combination of the fields
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`ConfigError`] of the first invalid field or combination
+    pub fn build(&self) -> Result<Config, ConfigError> {
+        let mut config = self.build_unchecked()?;
+        if config.min_mtu < MIN_MTU {
+            return Err(ConfigError::MtuTooSmall(config.min_mtu, MIN_MTU));
+        }
+        if config.max_mtu > MAX_MTU {
+            return Err(ConfigError::MtuTooLarge(config.max_mtu, MAX_MTU));
+        }
+        if config.min_mtu > config.max_mtu {
+            return Err(ConfigError::MtuRange(config.min_mtu, config.max_mtu));
+        }
+        if config.max_datagram_size < usize::from(config.max_mtu) {
+            return Err(ConfigError::MaxDatagramSize(
+                config.max_datagram_size,
+                config.max_mtu,
+            ));
+        }
+        if config.advertisement.len() > MAX_ADVERTISEMENT {
+            return Err(ConfigError::AdvertisementTooLarge(
+                config.advertisement.len(),
+                MAX_ADVERTISEMENT,
+            ));
+        }
+        if config.support_version.is_empty() {
+            return Err(ConfigError::NoSupportVersion);
+        }
+        if let Some(&unknown) = config
+            .support_version
+            .iter()
+            .find(|&&ver| version::lookup(ver).is_none())
+        {
+            return Err(ConfigError::UnknownVersion(unknown));
+        }
+        config.support_version.sort_unstable();
+        config.support_version.dedup();
+        config.rto.validate()?;
+        config.ack.validate(config.rto.min())?;
+        config.send_watermark.validate()?;
+        config.rate_limit.validate()?;
+        config.codec.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// The settings of each connection
+    pub(super) fn conn_config(&self) -> ConnConfig {
+        ConnConfig {
+            codec: self.codec,
+            drive_mode: self.drive_mode,
+            channel_window: self.channel_window,
+            ack: self.ack,
+            keepalive: self.keepalive,
+            rto: self.rto,
+            resend_limit: self.resend_limit,
+            linger: self.linger,
+            watchdog: self.watchdog,
+            send_watermark: self.send_watermark,
+            memory_budget: self.memory_budget,
+            pmtu: self.pmtu,
+            max_mtu: self.max_mtu,
+        }
+    }
+
+    pub(super) fn max_datagram_size(&self) -> usize {
+        self.max_datagram_size
+    }
+
+    pub(super) fn flush_quantum(&self) -> usize {
+        self.flush_quantum
+    }
+
+    pub(super) fn migration(&self) -> bool {
+        self.migration
+    }
+
+    pub(super) fn deferred_accept(&self) -> bool {
+        self.deferred_accept
+    }
+
+    pub(super) fn rx_timestamps(&self) -> bool {
+        self.rx_timestamps
+    }
+
+    pub(super) fn socket_buffers(&self) -> Option<&SocketBufferConfig> {
+        self.socket_buffers.as_ref()
+    }
+
+    pub(super) fn dscp(&self) -> DscpConfig {
+        self.dscp
+    }
+
+    pub(super) fn socket_error(&self) -> SocketErrorConfig {
+        self.socket_error
+    }
+
+    pub(super) fn task_mode(&self) -> TaskMode {
+        self.task_mode
+    }
+
+    pub(super) fn task_naming(&self) -> &TaskNaming {
+        &self.task_naming
+    }
+
+    pub(super) fn cpu_pinning(&self) -> &CpuPinning {
+        &self.cpu_pinning
+    }
+
+    /// The advertisement of the pongs, it could be updated by the server handle
+    pub(super) fn advertisement(&self) -> SharedAdvertisement {
+        let advertisement = SharedAdvertisement::default();
+        advertisement.set(self.advertisement.clone());
+        advertisement
+    }
+
+    /// The information of the query protocol, it could be updated by the server handle. None
+    /// means the query protocol is disabled
+    pub(super) fn query(&self) -> Option<SharedQueryInfo> {
+        self.query.clone().map(SharedQueryInfo::new)
+    }
+
+    pub(super) fn pong_addrs(&self) -> &[SocketAddr] {
+        &self.pong_addrs
+    }
+
+    /// The cache of the pongs answered on the secondary addresses
+    pub(super) fn pong_cache(
+        &self,
+        advertisement: SharedAdvertisement,
+    ) -> Result<PongCache<SharedAdvertisement>, ConfigError> {
+        PongCache::new(
+            self.sever_guid,
+            advertisement,
+            self.pong_cache,
+            Instant::now(),
+        )
+    }
+
+    /// The cache of the pongs answered before the offline handler, None if the pings need the
+    /// offline handler, i.e. they are rate limited or shed
+    pub(super) fn fast_pong(
+        &self,
+        advertisement: SharedAdvertisement,
+    ) -> Option<PongCache<SharedAdvertisement>> {
+        if self.rate_limit.is_enabled() || self.receive_budget != 0 {
+            return None;
+        }
+        // the advertisement is checked by the builder
+        self.pong_cache(advertisement).ok()
+    }
+}
+
+/// Control the `IncompatibleProtocol` responses, which could be abused by probe floods.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct IncompatibleConfig {
+    // Whether to respond `IncompatibleProtocol` at all, silently drop the request if false
+    respond: bool,
+    // Limit the max count of responses sent to a source ip, 0 means no limit
+    max_replies_per_source: usize,
+    // The version advertised in the response, None means the latest supported version
+    advertised_version: Option<u8>,
+}
+
+impl Default for IncompatibleConfig {
+    fn default() -> Self {
+        Self {
+            respond: true,
+            max_replies_per_source: 0,
+            advertised_version: None,
+        }
+    }
+}
+
+impl IncompatibleConfig {
+    /// Whether to respond `IncompatibleProtocol` at all, the request is silently dropped if false
+    #[must_use]
+    pub fn with_respond(mut self, respond: bool) -> Self {
+        self.respond = respond;
+        self
+    }
+
+    /// Limit the max count of responses sent to a source ip, 0 means no limit
+    #[must_use]
+    pub fn with_max_replies_per_source(mut self, max_replies_per_source: usize) -> Self {
+        self.max_replies_per_source = max_replies_per_source;
+        self
+    }
+
+    /// The version advertised in the response, None means the latest supported version
+    #[must_use]
+    pub fn with_advertised_version(mut self, advertised_version: Option<u8>) -> Self {
+        self.advertised_version = advertised_version;
+        self
+    }
+}
+
+/// The replies refusing the requests of the clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    IncompatibleProtocol,
+    AlreadyConnected,
+    NoFreeIncomingConnections,
+    ConnectionRequestFailed,
+}
+
+/// Silently drop the requests to be refused from the unknown sources, which are neither
+/// connected nor handshaking, so that the server is invisible to the scanners in hostile
+/// networks while the known clients still get the refusals.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SilentDropConfig {
+    incompatible_protocol: bool,
+    already_connected: bool,
+    no_free_incoming_connections: bool,
+    connection_request_failed: bool,
+}
+
+impl SilentDropConfig {
+    /// Silently drop all kinds of refusals to the unknown sources
+    pub fn all() -> Self {
+        Self {
+            incompatible_protocol: true,
+            already_connected: true,
+            no_free_incoming_connections: true,
+            connection_request_failed: true,
+        }
+    }
+
+    #[must_use]
+    pub fn with(mut self, refusal: Refusal, silent: bool) -> Self {
+        match refusal {
+            Refusal::IncompatibleProtocol => self.incompatible_protocol = silent,
+            Refusal::AlreadyConnected => self.already_connected = silent,
+            Refusal::NoFreeIncomingConnections => self.no_free_incoming_connections = silent,
+            Refusal::ConnectionRequestFailed => self.connection_request_failed = silent,
+        }
+        self
+    }
+
+    fn drops(self, refusal: Refusal) -> bool {
+        match refusal {
+            Refusal::IncompatibleProtocol => self.incompatible_protocol,
+            Refusal::AlreadyConnected => self.already_connected,
+            Refusal::NoFreeIncomingConnections => self.no_free_incoming_connections,
+            Refusal::ConnectionRequestFailed => self.connection_request_failed,
+        }
+    }
+}
+
+/// Detect the handshakes degraded below the expected mtu or protocol version, e.g. by a middlebox
+/// clamping the padding of `OpenConnectionRequest1` or a spoofer offering an old version. The
+/// minimums could be above the ones the server supports, so that the degraded handshakes are
+/// still accepted in the lenient mode. Both the accepted and the refused ones are surfaced by
+/// [`ServerEvent::HandshakeDowngraded`].
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct DowngradeConfig {
+    // The minimum mtu expected from the peers, 0 means no expectation
+    min_mtu: u16,
+    // The minimum protocol version expected from the peers, 0 means no expectation
+    min_version: u8,
+    // Accept the degraded handshakes and report them, refuse them by `IncompatibleProtocol`
+    // otherwise
+    lenient: bool,
+}
+
+impl Default for DowngradeConfig {
+    fn default() -> Self {
+        Self {
+            min_mtu: 0,
+            min_version: 0,
+            lenient: true,
+        }
+    }
+}
+
+impl DowngradeConfig {
+    #[must_use]
+    pub fn with_min_mtu(mut self, min_mtu: u16) -> Self {
+        self.min_mtu = min_mtu;
+        self
+    }
+
+    #[must_use]
+    pub fn with_min_version(mut self, min_version: u8) -> Self {
+        self.min_version = min_version;
+        self
+    }
+
+    #[must_use]
+    pub fn with_lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// The downgrade of the handshake advertising the version and the mtu, the version is
+    /// checked first. None if it meets the expectation.
+    fn check(self, protocol_version: u8, mtu: u16) -> Option<Downgrade> {
+        if protocol_version < self.min_version {
+            return Some(Downgrade::ProtocolVersion {
+                advertised: protocol_version,
+                minimum: self.min_version,
+            });
+        }
+        if mtu < self.min_mtu {
+            return Some(Downgrade::Mtu {
+                advertised: mtu,
+                minimum: self.min_mtu,
+            });
+        }
+        None
+    }
+}
+
+/// The connected peers indexed by the address and by the GUID, a GUID is held by one address at
+/// a time.
+#[derive(Debug, Default)]
+struct ConnectedPeers {
+    peers: HashMap<SocketAddr, Peer>,
+    guids: HashMap<u64, SocketAddr>,
+}
+
+impl ConnectedPeers {
+    fn get(&self, addr: &SocketAddr) -> Option<&Peer> {
+        self.peers.get(addr)
+    }
+
+    fn contains_key(&self, addr: &SocketAddr) -> bool {
+        self.peers.contains_key(addr)
+    }
+
+    fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// The address of the connection holding the GUID
+    fn holder(&self, guid: u64) -> Option<SocketAddr> {
+        self.guids.get(&guid).copied()
+    }
+
+    fn insert(&mut self, peer: Peer) {
+        self.guids.insert(peer.guid, peer.addr);
+        self.peers.insert(peer.addr, peer);
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) -> Option<Peer> {
+        let peer = self.peers.remove(addr)?;
+        if self.guids.get(&peer.guid) == Some(addr) {
+            self.guids.remove(&peer.guid);
+        }
+        Some(peer)
+    }
 }
 
 pin_project! {
     /// OfflineHandler takes the codec frame and perform offline handshake.
-    struct OfflineHandler<F> {
+    #[project = OfflineHandlerProj]
+    pub(super) struct OfflineHandler<F> {
         #[pin]
         frame: F,
         config: Config,
         pending: lru::LruCache<SocketAddr, u8>,
-        connected: HashMap<SocketAddr, Peer>,
+        handshakes: HandshakeOrder,
+        connected: ConnectedPeers,
+        // Key the migration tokens, so that they could not be derived from the GUID and the address
+        migration_key: RandomState,
+        // Count of `IncompatibleProtocol` responses sent to each source ip
+        incompatible_replied: lru::LruCache<IpAddr, usize>,
+        limiter: RateLimiter,
+        // The pongs delayed for the rate limited sources, sent when the timer fires
+        tarpit: Tarpit<(Packet<Bytes>, SocketAddr)>,
+        tarpit_timer: Option<Pin<Box<tokio::time::Sleep>>>,
+        shedder: Shedder,
+        // Shared with the server handle, receive the unconnected user messages
+        advertised: Events<(Bytes, SocketAddr)>,
+        // Shared with the server handle, stop answering the pings and refuse the new handshakes
+        // while draining
+        drain: Drain,
+        // Shared with the server handle, report the events of the server
+        events: Events<ServerEvent>,
+        // Shared with the server handle, the advertisement of the pongs
+        advertisement: SharedAdvertisement,
+        // Decide the pong of each ping instead of the advertisement if set
+        pong_hook: Option<Arc<dyn PongHook>>,
+        // The pongs made by the hook, with the addr and the timestamp of the ping
+        pending_pongs: FuturesUnordered<BoxFuture<'static, (Option<Bytes>, SocketAddr, i64)>>,
+        traces: SessionTraces,
+        // Shared with the server handle to elevate the logs of some peers
+        verbosity: PeerVerbosity,
+        // Count the packets discarded before the connections are established
+        drops: Arc<DropCounter>,
+        // The bytes buffered by all connections
+        memory: Arc<GlobalMemory>,
+        // The connections waiting to be accepted by the application
+        backlog: Arc<AcceptBacklog>,
+        // The addresses of the connections torn down by the connection tasks, e.g. timed out
+        closed: flume::Receiver<SocketAddr>,
+        closer: flume::Sender<SocketAddr>,
+    }
+}
+
+pub(super) trait HandleOffline: Sized {
+    fn handle_offline(self, config: Config) -> OfflineHandler<Self>;
+}
+
+impl<F> HandleOffline for F {
+    fn handle_offline(self, config: Config) -> OfflineHandler<Self> {
+        let tracked = NonZeroUsize::new(MAX_TRACKED_ADDRESSES).expect("non zero");
+        let (closed_tx, closed_rx) = flume::unbounded();
+        OfflineHandler {
+            frame: self,
+            pending: lru::LruCache::new(tracked),
+            handshakes: HandshakeOrder::new(config.handshake_order),
+            connected: ConnectedPeers::default(),
+            migration_key: RandomState::new(),
+            incompatible_replied: lru::LruCache::new(tracked),
+            limiter: RateLimiter::new(config.rate_limit),
+            tarpit: Tarpit::new(config.tarpit),
+            tarpit_timer: None,
+            shedder: Shedder::new(config.receive_budget),
+            advertised: Events::default(),
+            drain: Drain::default(),
+            events: Events::default(),
+            advertisement: config.advertisement(),
+            pong_hook: None,
+            pending_pongs: FuturesUnordered::new(),
+            traces: SessionTraces::default(),
+            verbosity: PeerVerbosity::default(),
+            drops: Arc::default(),
+            memory: Arc::new(GlobalMemory::new(config.memory_ceiling)),
+            backlog: Arc::new(AcceptBacklog::new(config.accept_backlog)),
+            closed: closed_rx,
+            closer: closed_tx,
+            config,
+        }
+    }
+}
+
+impl<F> OfflineHandler<F> {
+    /// Get the counters of the shed packets, shared with the server handle
+    pub(super) fn shed_counter(&self) -> Arc<ShedCounter> {
+        self.shedder.counter()
+    }
+
+    /// Get the unconnected user messages (advertise system) from any address, shared with the
+    /// server handle which subscribes them
+    pub(super) fn unconnected_messages(&self) -> Events<(Bytes, SocketAddr)> {
+        self.advertised.clone()
+    }
+
+    /// Get the sender to notify the teardown of a connection by its address, e.g. it timed out or
+    /// its session is removed, so that the address could handshake again
+    pub(super) fn closer(&self) -> flume::Sender<SocketAddr> {
+        self.closer.clone()
+    }
+
+    /// Get the accept backlog, the connections made for the completed handshakes claim their
+    /// slots from it
+    pub(super) fn backlog(&self) -> Arc<AcceptBacklog> {
+        Arc::clone(&self.backlog)
+    }
+
+    /// Get the memory buffered by all connections, the budget of each connection charges it
+    pub(super) fn memory(&self) -> Arc<GlobalMemory> {
+        Arc::clone(&self.memory)
+    }
+
+    /// Get the verbosity of the peers, shared with the connections and the server handle
+    pub(super) fn verbosity(&self) -> PeerVerbosity {
+        self.verbosity.clone()
+    }
+
+    /// Share the draining switch and the advertisement with the layers answering the pings
+    /// before the handler
+    pub(super) fn share_pongs(&mut self, drain: Drain, advertisement: SharedAdvertisement) {
+        self.drain = drain;
+        self.advertisement = advertisement;
+    }
+
+    /// Share the counters of the discarded packets with the raw datagram layers and the server
+    /// handle
+    pub(super) fn share_drops(&mut self, drops: Arc<DropCounter>) {
+        self.drops = drops;
+    }
+
+    /// Share the events of the server with the socket, which reports its errors
+    pub(super) fn share_events(&mut self, events: Events<ServerEvent>) {
+        self.events = events;
+    }
+
+    /// Decide the pong of each ping by the hook, e.g. per-region MOTD or hiding from some ips.
+    /// The pings arrived before the hook is set are answered with the advertisement in config.
+    pub(super) fn set_pong_hook(&mut self, hook: Arc<dyn PongHook>) {
+        self.pong_hook = Some(hook);
     }
 }
 
+/// Whether the packet carries a `DisconnectNotification` of the peer. The data is untrusted, so
+/// the id is checked without decoding the frames.
+fn is_disconnect_notification<B: AsRef<[u8]>>(pack: &connected::Packet<B>) -> bool {
+    let connected::Packet::FrameSet(frame_set) = pack else {
+        return false;
+    };
+    frame_set.frames.iter().any(|frame| {
+        frame.fragment.is_none()
+            && frame.body.as_ref().first() == Some(&u8::from(PackType::DisconnectNotification))
+    })
+}
+
+/// Make an unconnected user message (advertise system), it is sent to an address without
+/// establishing a connection.
+pub(super) fn make_advertise_system<B>(data: Bytes) -> Packet<B> {
+    Packet::Unconnected(unconnected::Packet::AdvertiseSystem { data })
+}
+
 impl<F> OfflineHandler<F>
 where
     F: Sink<(Packet<Bytes>, SocketAddr), Error = CodecError>,
 {
+    /// Check if we should send the refusal to the addr, the unknown sources are refused
+    /// silently if it is configured.
+    fn should_refuse(config: &Config, refusal: Refusal, known: bool, addr: SocketAddr) -> bool {
+        if !known && config.silent_drop.drops(refusal) {
+            debug!("silently drop the {refusal:?} to unknown source {addr}");
+            return false;
+        }
+        true
+    }
+
+    /// Check if we should respond `IncompatibleProtocol` to the addr, and count the response.
+    fn should_reply_incompatible(
+        config: &Config,
+        replied: &mut lru::LruCache<IpAddr, usize>,
+        known: bool,
+        addr: SocketAddr,
+    ) -> bool {
+        if !Self::should_refuse(config, Refusal::IncompatibleProtocol, known, addr) {
+            return false;
+        }
+        if !config.incompatible.respond {
+            return false;
+        }
+        let max = config.incompatible.max_replies_per_source;
+        if max == 0 {
+            return true;
+        }
+        let cnt = replied.get_or_insert_mut(addr.ip(), || 0);
+        if *cnt >= max {
+            return false;
+        }
+        *cnt += 1;
+        true
+    }
+
+    /// Check the order of the handshake packet, count and log it if it is rejected.
+    fn admits_in_order(
+        handshakes: &mut HandshakeOrder,
+        drops: &DropCounter,
+        addr: SocketAddr,
+        packet: OfflinePacket,
+    ) -> bool {
+        match handshakes.check(addr, packet) {
+            Verdict::Accept => true,
+            Verdict::Tolerate => {
+                debug!("tolerate out-of-order {packet:?} from {addr}");
+                true
+            }
+            Verdict::Reject => {
+                debug!("reject out-of-order {packet:?} from {addr}");
+                drops.record(DropReason::OutOfOrder, 1);
+                false
+            }
+        }
+    }
+
+    /// The migration token of the connection of the GUID at the address, sent only to that
+    /// address by the `OpenConnectionReply2`
+    fn migration_token(key: &RandomState, guid: u64, addr: SocketAddr) -> u64 {
+        key.hash_one((guid, addr))
+    }
+
+    /// Forget the previous address of the connected client with the GUID, so that its
+    /// connection could be continued from the new address. The client proves that it owns the
+    /// connection by the migration token issued to the previous address, the GUID alone is
+    /// visible to anyone on the path.
+    fn migrate(
+        connected: &mut ConnectedPeers,
+        handshakes: &mut HandshakeOrder,
+        traces: &mut SessionTraces,
+        key: &RandomState,
+        guid: u64,
+        token: Option<u64>,
+        addr: SocketAddr,
+    ) {
+        let Some(old) = connected.holder(guid).filter(|&old| old != addr) else {
+            return;
+        };
+        if token != Some(OfflineHandler::<F>::migration_token(key, guid, old)) {
+            debug!("client {guid} at {addr} does not own the connection at {old}, keep it");
+            return;
+        }
+        debug!("client {guid} moved from {old} to {addr}");
+        connected.remove(&old);
+        handshakes.reset(&old);
+        traces.disconnected(old, "migrated to a new address", true);
+    }
+
+    /// Forget the connection or the handshake of the address, so that it could handshake again
+    fn forget(
+        connected: &mut ConnectedPeers,
+        pending: &mut lru::LruCache<SocketAddr, u8>,
+        handshakes: &mut HandshakeOrder,
+        traces: &mut SessionTraces,
+        addr: SocketAddr,
+        reason: &'static str,
+    ) {
+        connected.remove(&addr);
+        pending.pop(&addr);
+        handshakes.reset(&addr);
+        traces.handshake_failed(addr, reason);
+        traces.disconnected(addr, reason, true);
+    }
+
     fn make_incompatible_version(config: &Config) -> Packet<Bytes> {
         Packet::Unconnected(unconnected::Packet::IncompatibleProtocol {
-            server_protocol: *config.support_version.last().unwrap(),
+            server_protocol: config
+                .incompatible
+                .advertised_version
+                .unwrap_or_else(|| *config.support_version.last().unwrap()),
             magic: (),
             server_guid: config.sever_guid,
         })
@@ -52,6 +893,13 @@ where
         })
     }
 
+    fn make_no_free_incoming_connections(config: &Config) -> Packet<Bytes> {
+        Packet::Unconnected(unconnected::Packet::NoFreeIncomingConnections {
+            magic: (),
+            server_guid: config.sever_guid,
+        })
+    }
+
     fn make_connection_request_failed(config: &Config) -> Packet<Bytes> {
         Packet::Unconnected(unconnected::Packet::ConnectionRequestFailed {
             magic: (),
@@ -60,117 +908,562 @@ where
     }
 }
 
+impl<F> OfflineHandlerProj<'_, F>
+where
+    F: Sink<(Packet<Bytes>, SocketAddr), Error = CodecError>,
+{
+    /// Forget the connections torn down by the connection tasks and the handshakes timed out
+    fn forget_closed(&mut self) {
+        while let Ok(addr) = self.closed.try_recv() {
+            OfflineHandler::<F>::forget(
+                self.connected,
+                self.pending,
+                self.handshakes,
+                self.traces,
+                addr,
+                "connection closed",
+            );
+            self.backlog.forget(&addr);
+        }
+        for addr in self
+            .backlog
+            .expire(Instant::now(), self.config.handshake_timeout)
+        {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "handshake of {addr} timed out, release its accept backlog slot"
+            );
+            OfflineHandler::<F>::forget(
+                self.connected,
+                self.pending,
+                self.handshakes,
+                self.traces,
+                addr,
+                "handshake timed out",
+            );
+        }
+    }
+
+    /// Send the pongs made by the hook
+    fn poll_hooked_pongs(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        while !self.pending_pongs.is_empty() {
+            // take a pong only when the sink could accept it, or it would be lost on pending
+            if let Err(err) = ready!(self.frame.as_mut().poll_ready(cx)) {
+                error!("failed send pong, error {err}");
+                break;
+            }
+            let Poll::Ready(Some((data, addr, send_timestamp))) =
+                self.pending_pongs.poll_next_unpin(cx)
+            else {
+                break;
+            };
+            let Some(data) = data else {
+                peer_debug!(
+                    self.verbosity,
+                    addr,
+                    "the pong hook hides the server from {addr}"
+                );
+                continue;
+            };
+            if data.len() > MAX_ADVERTISEMENT {
+                warn!(
+                    "the pong hook made {} bytes for {addr}, larger than {MAX_ADVERTISEMENT}",
+                    data.len()
+                );
+                continue;
+            }
+            let pong = self.make_pong(send_timestamp, data);
+            if let Err(err) = self.frame.as_mut().start_send((pong, addr)) {
+                error!("failed send pong to {addr}, error {err}");
+            }
+            if let Poll::Ready(Err(err)) = self.frame.as_mut().poll_flush(cx) {
+                error!("failed send pong to {addr}, error {err}");
+            }
+        }
+        Poll::Ready(())
+    }
+
+    /// Send the delayed pongs of the tarpit which are due
+    fn poll_delayed_pongs(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        while self
+            .tarpit
+            .next_deadline()
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            // the same as the hooked pongs, pop the delayed pong only when the sink is ready
+            if let Err(err) = ready!(self.frame.as_mut().poll_ready(cx)) {
+                error!("failed send delayed pong, error {err}");
+                break;
+            }
+            let Some((pong, addr)) = self.tarpit.pop_expired(Instant::now()) else {
+                break;
+            };
+            if let Err(err) = self.frame.as_mut().start_send((pong, addr)) {
+                error!("failed send delayed pong to {addr}, error {err}");
+            }
+            if let Poll::Ready(Err(err)) = self.frame.as_mut().poll_flush(cx) {
+                error!("failed send delayed pong to {addr}, error {err}");
+            }
+        }
+        Poll::Ready(())
+    }
+
+    /// Arm the timer of the next delayed pong, returns true if it has fired already
+    fn tarpit_fired(&mut self, cx: &mut Context<'_>) -> bool {
+        let Some(deadline) = self.tarpit.next_deadline() else {
+            return false;
+        };
+        let timer = self
+            .tarpit_timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline.into())));
+        if timer.deadline() != deadline.into() {
+            timer.as_mut().reset(deadline.into());
+        }
+        timer.poll_unpin(cx).is_ready()
+    }
+
+    /// Get the peer of the connected address, its handshake is forgotten if it is disconnecting
+    fn connected_peer(
+        &mut self,
+        pack: &connected::Packet<BytesMut>,
+        addr: SocketAddr,
+    ) -> Option<Peer> {
+        let peer = self.connected.get(&addr)?.clone();
+        if is_disconnect_notification(pack) {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "{addr} disconnected, forget its handshake"
+            );
+            OfflineHandler::<F>::forget(
+                self.connected,
+                self.pending,
+                self.handshakes,
+                self.traces,
+                addr,
+                "disconnect notification from peer",
+            );
+            self.backlog.forget(&addr);
+        }
+        Some(peer)
+    }
+
+    /// Check the unconnected packet against the draining and the rate limit, the pong of a
+    /// rate limited ping is delayed by the tarpit if it is enabled.
+    fn admits(&mut self, pack: &unconnected::Packet, addr: SocketAddr) -> bool {
+        if self.drain.is_draining() && matches!(pack, unconnected::Packet::UnconnectedPing { .. }) {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "draining, ignore the ping from {addr}"
+            );
+            return false;
+        }
+        if !matches!(
+            pack,
+            unconnected::Packet::UnconnectedPing { .. }
+                | unconnected::Packet::OpenConnectionRequest1 { .. }
+                | unconnected::Packet::OpenConnectionRequest2 { .. }
+                | unconnected::Packet::AdvertiseSystem { .. }
+        ) || self.limiter.check(addr.ip())
+        {
+            return true;
+        }
+        if let unconnected::Packet::UnconnectedPing { send_timestamp, .. } = *pack {
+            let pong = self.make_pong(send_timestamp, self.advertisement.advertisement());
+            if self.tarpit.push((pong, addr), Instant::now()) {
+                peer_debug!(
+                    self.verbosity,
+                    addr,
+                    "rate limit exceeded for {addr}, delay the pong"
+                );
+                return false;
+            }
+        }
+        peer_debug!(
+            self.verbosity,
+            addr,
+            "rate limit exceeded for {addr}, ignore {:?}",
+            pack.pack_type()
+        );
+        self.drops.record(DropReason::RateLimited, 1);
+        false
+    }
+
+    /// Handle the unconnected packet, returns the reply to the addr if there is one
+    fn on_unconnected(
+        &mut self,
+        pack: unconnected::Packet,
+        addr: SocketAddr,
+    ) -> Option<Packet<Bytes>> {
+        if !self.admits(&pack, addr) {
+            return None;
+        }
+        let known = self.connected.contains_key(&addr) || self.pending.contains(&addr);
+        match pack {
+            unconnected::Packet::UnconnectedPing { send_timestamp, .. } => {
+                self.on_ping(send_timestamp, addr)
+            }
+            unconnected::Packet::OpenConnectionRequest1 {
+                protocol_version,
+                mtu,
+                ..
+            } => self.on_request1(protocol_version, mtu, known, addr),
+            unconnected::Packet::OpenConnectionRequest2 {
+                mtu,
+                client_guid,
+                migration_token,
+                ..
+            } => self.on_request2(mtu, client_guid, migration_token, known, addr),
+            unconnected::Packet::AdvertiseSystem { data } => {
+                peer_debug!(
+                    self.verbosity,
+                    addr,
+                    "received unconnected message from {addr}"
+                );
+                self.advertised.emit((data, addr));
+                None
+            }
+            _ => {
+                warn!(
+                    "received a package({:?}) that should not be received on the server.",
+                    pack.pack_type()
+                );
+                None
+            }
+        }
+    }
+
+    /// Answer the ping with the advertisement, or leave it to the pong hook if it is set
+    fn on_ping(&mut self, send_timestamp: i64, addr: SocketAddr) -> Option<Packet<Bytes>> {
+        let Some(hook) = self.pong_hook.as_ref() else {
+            return Some(self.make_pong(send_timestamp, self.advertisement.advertisement()));
+        };
+        if self.pending_pongs.len() >= MAX_PENDING_PONGS {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "too many pings waiting for the pong hook, ignore {addr}"
+            );
+            return None;
+        }
+        let pong = hook.on_ping(addr);
+        self.pending_pongs
+            .push(Box::pin(async move { (pong.await, addr, send_timestamp) }));
+        None
+    }
+
+    fn on_request1(
+        &mut self,
+        protocol_version: u8,
+        mtu: u16,
+        known: bool,
+        addr: SocketAddr,
+    ) -> Option<Packet<Bytes>> {
+        if !OfflineHandler::<F>::admits_in_order(
+            self.handshakes,
+            self.drops,
+            addr,
+            OfflinePacket::Request1,
+        ) {
+            return None;
+        }
+        if self
+            .config
+            .support_version
+            .binary_search(&protocol_version)
+            .is_err()
+            || (addr.is_ipv6() && !version::supports(protocol_version, Capabilities::IPV6))
+        {
+            self.traces.handshake_started(addr, protocol_version, mtu);
+            self.traces.handshake_failed(addr, "incompatible protocol");
+            return self.reply_incompatible(known, addr);
+        }
+        if self.drain.is_draining() {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "draining, refuse the connection from {addr}"
+            );
+            return self.refuse(Refusal::NoFreeIncomingConnections, known, addr);
+        }
+        if let Some(downgrade) = self.config.downgrade.check(protocol_version, mtu) {
+            let refused = !self.config.downgrade.lenient;
+            self.events.emit(ServerEvent::HandshakeDowngraded {
+                addr,
+                downgrade,
+                refused,
+            });
+            if refused {
+                peer_debug!(
+                    self.verbosity,
+                    addr,
+                    "refuse the degraded handshake from {addr}: {downgrade:?}"
+                );
+                self.traces.handshake_started(addr, protocol_version, mtu);
+                self.traces.handshake_failed(addr, "degraded handshake");
+                return self.reply_incompatible(known, addr);
+            }
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "accept the degraded handshake from {addr}: {downgrade:?}"
+            );
+        }
+        if let Some(why) = self.exhausted() {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "{why}, refuse the connection from {addr}"
+            );
+            return self.refuse(Refusal::NoFreeIncomingConnections, known, addr);
+        }
+        if self.pending.put(addr, protocol_version).is_some() {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "received duplicate open connection request 1 from {addr}"
+            );
+        }
+        self.traces.handshake_started(addr, protocol_version, mtu);
+        self.handshakes.advance(addr, HandshakeState::Reply1Sent);
+        // max_mtu >= final_mtu >= min_mtu, the versions not probing the mtu by the padding start
+        // at the min mtu
+        let final_mtu = if version::supports(protocol_version, Capabilities::MTU_PADDING) {
+            self.config.max_mtu.min(self.config.min_mtu.max(mtu))
+        } else {
+            self.config.min_mtu
+        };
+        Some(Packet::Unconnected(
+            unconnected::Packet::OpenConnectionReply1 {
+                magic: (),
+                server_guid: self.config.sever_guid,
+                use_encryption: false, // must set to false first
+                mtu: final_mtu,
+            },
+        ))
+    }
+
+    fn on_request2(
+        &mut self,
+        mtu: u16,
+        client_guid: u64,
+        migration_token: Option<u64>,
+        known: bool,
+        addr: SocketAddr,
+    ) -> Option<Packet<Bytes>> {
+        if !OfflineHandler::<F>::admits_in_order(
+            self.handshakes,
+            self.drops,
+            addr,
+            OfflinePacket::Request2,
+        ) {
+            return None;
+        }
+        let Some(protocol_version) = self.pending.pop(&addr) else {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "received open connection request 2 from {addr} without open connection request 1"
+            );
+            self.traces.handshake_failed(addr, "incompatible protocol");
+            return self.reply_incompatible(known, addr);
+        };
+        if self.config.migration {
+            OfflineHandler::<F>::migrate(
+                self.connected,
+                self.handshakes,
+                self.traces,
+                self.migration_key,
+                client_guid,
+                migration_token,
+                addr,
+            );
+        }
+        // the GUID identifies the client, it could not be connected from two addresses at once
+        if let Some(holder) = self
+            .connected
+            .holder(client_guid)
+            .filter(|&holder| holder != addr)
+        {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "guid {client_guid} of {addr} is held by {holder}, refuse it"
+            );
+            self.traces.handshake_failed(addr, "guid already connected");
+            return self.refuse(Refusal::AlreadyConnected, known, addr);
+        }
+        // client should adjust the mtu
+        if mtu < self.config.min_mtu
+            || mtu > self.config.max_mtu
+            || self.connected.contains_key(&addr)
+        {
+            self.traces
+                .handshake_failed(addr, "mtu out of range or already connected");
+            return self.refuse(Refusal::AlreadyConnected, known, addr);
+        }
+        // the server may become full or short of memory while the client is handshaking
+        if let Some(why) = self.exhausted() {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "{why}, refuse the connection from {addr}"
+            );
+            self.traces.handshake_failed(addr, why);
+            return self.refuse(Refusal::NoFreeIncomingConnections, known, addr);
+        }
+        // the application is not accepting fast enough, leave the request unanswered so that the
+        // client retries it later
+        if !self.backlog.try_reserve(addr, Instant::now()) {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "accept backlog is full, delay the handshake of {addr}"
+            );
+            self.pending.put(addr, protocol_version);
+            return None;
+        }
+        self.connected.insert(Peer {
+            addr,
+            mtu,
+            guid: client_guid,
+        });
+        self.traces.connected(addr, mtu);
+        self.handshakes.advance(addr, HandshakeState::Reply2Sent);
+        Some(Packet::Unconnected(
+            unconnected::Packet::OpenConnectionReply2 {
+                magic: (),
+                server_guid: self.config.sever_guid,
+                client_address: addr,
+                mtu,
+                encryption_enabled: false, // must set to false
+                migration_token: self.config.migration.then(|| {
+                    OfflineHandler::<F>::migration_token(self.migration_key, client_guid, addr)
+                }),
+            },
+        ))
+    }
+
+    /// Why the server could not take a new connection, if it is full or short of memory
+    fn exhausted(&self) -> Option<&'static str> {
+        if self.config.max_connections != 0 && self.connected.len() >= self.config.max_connections {
+            return Some("server is full");
+        }
+        if !self.memory.admits_handshake() {
+            return Some("memory pressure is critical");
+        }
+        None
+    }
+
+    /// Make the refusal to the addr, None if it is refused silently
+    fn refuse(&self, refusal: Refusal, known: bool, addr: SocketAddr) -> Option<Packet<Bytes>> {
+        if !OfflineHandler::<F>::should_refuse(self.config, refusal, known, addr) {
+            return None;
+        }
+        Some(match refusal {
+            Refusal::IncompatibleProtocol => {
+                OfflineHandler::<F>::make_incompatible_version(self.config)
+            }
+            Refusal::AlreadyConnected => OfflineHandler::<F>::make_already_connected(self.config),
+            Refusal::NoFreeIncomingConnections => {
+                OfflineHandler::<F>::make_no_free_incoming_connections(self.config)
+            }
+            Refusal::ConnectionRequestFailed => {
+                OfflineHandler::<F>::make_connection_request_failed(self.config)
+            }
+        })
+    }
+
+    /// Make the `IncompatibleProtocol` response to the addr, None if it is not responded
+    fn reply_incompatible(&mut self, known: bool, addr: SocketAddr) -> Option<Packet<Bytes>> {
+        if !OfflineHandler::<F>::should_reply_incompatible(
+            self.config,
+            self.incompatible_replied,
+            known,
+            addr,
+        ) {
+            peer_debug!(
+                self.verbosity,
+                addr,
+                "ignore incompatible protocol response to {addr}"
+            );
+            return None;
+        }
+        Some(OfflineHandler::<F>::make_incompatible_version(self.config))
+    }
+
+    fn make_pong(&self, send_timestamp: i64, data: Bytes) -> Packet<Bytes> {
+        Packet::Unconnected(unconnected::Packet::UnconnectedPong {
+            send_timestamp,
+            server_guid: self.config.sever_guid,
+            magic: (),
+            data,
+        })
+    }
+}
+
 impl<F> Stream for OfflineHandler<F>
 where
-    F: Stream<Item = (Packet<Bytes>, SocketAddr)>
+    F: Stream<Item = Result<(Packet<BytesMut>, SocketAddr), CodecError>>
         + Sink<(Packet<Bytes>, SocketAddr), Error = CodecError>,
 {
-    type Item = (connected::Packet<Bytes>, Peer);
+    type Item = (connected::Packet<BytesMut>, Peer);
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
+        this.forget_closed();
         loop {
-            let Some((packet, addr)) = ready!(this.frame.as_mut().poll_next(cx)) else {
+            ready!(this.poll_hooked_pongs(cx));
+            ready!(this.poll_delayed_pongs(cx));
+            if this.tarpit_fired(cx) {
+                continue;
+            }
+            let Some(res) = ready!(this.frame.as_mut().poll_next(cx)) else {
                 return Poll::Ready(None);
             };
-            let pack = match packet {
-                Packet::Unconnected(pack) => pack,
-                Packet::Connected(pack) => {
-                    if let Some(peer) = this.connected.get(&addr) {
-                        return Poll::Ready(Some((pack, peer.clone())));
-                    }
-                    debug!("ignore connected packet from unconnected client {addr}");
-                    // TODO: Send DETECT_LOST_CONNECTION ?
-                    let mut send = this
-                        .frame
-                        .send((Self::make_connection_request_failed(this.config), addr));
-                    if let Err(err) = ready!(send.poll_unpin(cx)) {
-                        error!("failed send connection request failed to {addr}, error {err}");
-                    }
+            let (packet, addr) = match res {
+                Ok(received) => received,
+                Err(err) => {
+                    debug!("raknet codec error: {err}, ignore this packet");
+                    this.drops.record(DropReason::of(&err), 1);
                     continue;
                 }
             };
-            match pack {
-                unconnected::Packet::UnconnectedPing { send_timestamp, .. } => {
-                    unconnected::Packet::UnconnectedPong {
-                        send_timestamp,
-                        server_guid: this.config.sever_guid,
-                        magic: (),
-                        data: this.config.advertisement.clone(),
-                    }
-                }
-                unconnected::Packet::OpenConnectionRequest1 {
-                    protocol_version,
-                    mtu,
-                    ..
-                } => {
-                    if this
-                        .config
-                        .support_version
-                        .binary_search(&protocol_version)
-                        .is_err()
-                    {
-                        let mut send = this
-                            .frame
-                            .send((Self::make_incompatible_version(this.config), addr));
-                        if let Err(err) = ready!(send.poll_unpin(cx)) {
-                            error!("failed send incompatible version to {addr}, error {err}");
-                        }
-                        continue;
-                    }
-                    if this.pending.put(addr, protocol_version).is_some() {
-                        debug!("received duplicate open connection request 1 from {addr}");
-                    }
-                    // max_mtu >= final_mtu >= min_mtu
-                    let final_mtu = this.config.max_mtu.min(this.config.min_mtu.max(mtu));
-                    unconnected::Packet::OpenConnectionReply1 {
-                        magic: (),
-                        server_guid: this.config.sever_guid,
-                        use_encryption: false, // must set to false first
-                        mtu: final_mtu,
-                    }
-                }
-                unconnected::Packet::OpenConnectionRequest2 { mtu, .. } => {
-                    if this.pending.pop(&addr).is_none() {
-                        debug!("received open connection request 2 from {addr} without open connection request 1");
-                        let mut send = this
-                            .frame
-                            .send((Self::make_incompatible_version(this.config), addr));
-                        if let Err(err) = ready!(send.poll_unpin(cx)) {
-                            error!("failed send incompatible version to {addr}, error {err}");
-                        }
-                        continue;
-                    };
-                    // client should adjust the mtu
-                    if mtu < this.config.min_mtu
-                        || mtu > this.config.max_mtu
-                        || this.connected.contains_key(&addr)
-                    {
-                        let mut send = this
-                            .frame
-                            .send((Self::make_already_connected(this.config), addr));
-                        if let Err(err) = ready!(send.poll_unpin(cx)) {
-                            error!("failed send already connected to {addr}, error {err}");
-                        }
-                        continue;
-                    }
-                    this.connected.insert(addr, Peer { addr, mtu });
-                    unconnected::Packet::OpenConnectionReply2 {
-                        magic: (),
-                        server_guid: this.config.sever_guid,
-                        client_address: addr,
-                        mtu,
-                        encryption_enabled: false, // must set to false
+            if !this.shedder.admit(Class::of(&packet)) {
+                peer_debug!(
+                    this.verbosity,
+                    addr,
+                    "shed {:?} from {addr}",
+                    packet.pack_type()
+                );
+                continue;
+            }
+            let reply = match packet {
+                Packet::Unconnected(pack) => this.on_unconnected(pack, addr),
+                Packet::Connected(pack) => {
+                    if let Some(peer) = this.connected_peer(&pack, addr) {
+                        return Poll::Ready(Some((pack, peer)));
                     }
-                }
-                _ => {
-                    warn!(
-                        "received a package({:?}) that should not be received on the server.",
-                        pack.pack_type()
+                    peer_debug!(
+                        this.verbosity,
+                        addr,
+                        "ignore connected packet from unconnected client {addr}"
                     );
-                    continue;
+                    let known = this.pending.contains(&addr);
+                    // TODO: Send DETECT_LOST_CONNECTION ?
+                    this.refuse(Refusal::ConnectionRequestFailed, known, addr)
                 }
             };
+            let Some(reply) = reply else {
+                continue;
+            };
+            let pack_type = reply.pack_type();
+            let mut send = this.frame.send((reply, addr));
+            if let Err(err) = ready!(send.poll_unpin(cx)) {
+                error!("failed send {pack_type:?} to {addr}, error {err}");
+            }
         }
     }
 }
@@ -192,9 +1485,21 @@ where
         let this = self.project();
         if let Packet::Connected(connected::Packet::FrameSet(frame_set)) = &packet {
             if frame_set.first_pack_type() == PackType::DisconnectNotification {
-                debug!("disconnect from {}, clean it's frame parts buffer", addr);
-                this.connected.remove(&addr);
-                this.pending.pop(&addr);
+                peer_debug!(
+                    this.verbosity,
+                    addr,
+                    "disconnect from {}, clean it's frame parts buffer",
+                    addr
+                );
+                Self::forget(
+                    this.connected,
+                    this.pending,
+                    this.handshakes,
+                    this.traces,
+                    addr,
+                    "disconnect notification",
+                );
+                this.backlog.forget(&addr);
             }
         };
         this.frame.start_send((packet, addr))
@@ -208,3 +1513,646 @@ where
         self.project().frame.poll_close(cx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::server::ack::MIN_RTO;
+    use crate::server::budget::{Buffer, MemoryBudget};
+
+    /// A frame yielding the packets then ending, the packets sent to it are kept
+    #[derive(Debug, Default)]
+    struct MockFrame {
+        inbound: VecDeque<Result<(Packet<BytesMut>, SocketAddr), CodecError>>,
+        sent: Vec<(Packet<Bytes>, SocketAddr)>,
+    }
+
+    impl MockFrame {
+        fn new(inbound: impl IntoIterator<Item = (Packet<BytesMut>, SocketAddr)>) -> Self {
+            Self {
+                inbound: inbound.into_iter().map(Ok).collect(),
+                sent: Vec::new(),
+            }
+        }
+    }
+
+    impl Stream for MockFrame {
+        type Item = Result<(Packet<BytesMut>, SocketAddr), CodecError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.inbound.pop_front())
+        }
+    }
+
+    impl Sink<(Packet<Bytes>, SocketAddr)> for MockFrame {
+        type Error = CodecError;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            item: (Packet<Bytes>, SocketAddr),
+        ) -> Result<(), Self::Error> {
+            self.sent.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn config() -> Config {
+        ConfigBuilder::default()
+            .sever_guid(114_514)
+            .build()
+            .unwrap()
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn request1(protocol_version: u8) -> Packet<BytesMut> {
+        Packet::Unconnected(unconnected::Packet::OpenConnectionRequest1 {
+            magic: (),
+            protocol_version,
+            mtu: 1400,
+        })
+    }
+
+    fn request2(client_guid: u64) -> Packet<BytesMut> {
+        migrating_request2(client_guid, None)
+    }
+
+    fn migrating_request2(client_guid: u64, migration_token: Option<u64>) -> Packet<BytesMut> {
+        Packet::Unconnected(unconnected::Packet::OpenConnectionRequest2 {
+            magic: (),
+            server_address: addr(19132),
+            mtu: 1400,
+            client_guid,
+            migration_token,
+        })
+    }
+
+    /// The migration token of the last `OpenConnectionReply2` sent to the addr
+    fn issued_token(frame: &MockFrame, to: SocketAddr) -> Option<u64> {
+        frame
+            .sent
+            .iter()
+            .rev()
+            .find_map(|(packet, addr)| match packet {
+                Packet::Unconnected(unconnected::Packet::OpenConnectionReply2 {
+                    migration_token,
+                    ..
+                }) if *addr == to => *migration_token,
+                _ => None,
+            })
+    }
+
+    fn replies(frame: &MockFrame) -> Vec<(PackType, SocketAddr)> {
+        frame
+            .sent
+            .iter()
+            .map(|(packet, addr)| (packet.pack_type(), *addr))
+            .collect()
+    }
+
+    fn disconnect_notification() -> Packet<BytesMut> {
+        Packet::Connected(connected::Packet::FrameSet(connected::FrameSet {
+            seq_num: connected::Uint24le(0),
+            frames: vec![connected::Frame {
+                flags: connected::Flags::new(connected::Reliability::Reliable, false),
+                reliable_frame_index: Some(connected::Uint24le(0)),
+                seq_frame_index: None,
+                ordered: None,
+                fragment: None,
+                body: BytesMut::from(&[u8::from(PackType::DisconnectNotification)][..]),
+            }],
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_offline_handshake_again_after_teardown() {
+        let handshake = [(request1(11), addr(1)), (request2(1), addr(1))];
+        let mut handler = MockFrame::new(handshake.clone()).handle_offline(config());
+        assert!(handler.next().await.is_none());
+
+        // timed out
+        handler.closer().send(addr(1)).unwrap();
+        handler.frame.inbound.extend(handshake.clone().map(Ok));
+        assert!(handler.next().await.is_none());
+
+        // disconnected by the peer
+        handler
+            .frame
+            .inbound
+            .push_back(Ok((disconnect_notification(), addr(1))));
+        assert!(handler.next().await.is_some());
+        handler.frame.inbound.extend(handshake.clone().map(Ok));
+        assert!(handler.next().await.is_none());
+
+        // still connected
+        handler.frame.inbound.extend(handshake.map(Ok));
+        assert!(handler.next().await.is_none());
+        assert_eq!(
+            replies(&handler.frame),
+            [
+                PackType::OpenConnectionReply1,
+                PackType::OpenConnectionReply2,
+            ]
+            .repeat(3)
+            .into_iter()
+            .chain([PackType::OpenConnectionReply1, PackType::AlreadyConnected])
+            .map(|pack_type| (pack_type, addr(1)))
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_count_undecodable() {
+        let mut frame = MockFrame::new([(request1(11), addr(1))]);
+        frame
+            .inbound
+            .push_front(Err(CodecError::MagicNotMatched(0, 0)));
+        frame
+            .inbound
+            .push_front(Err(CodecError::InvalidPacketType(0xff)));
+        let mut handler = frame.handle_offline(config());
+        assert!(handler.next().await.is_none());
+        let drops = handler.drops.snapshot();
+        assert_eq!(drops.malformed, 1);
+        assert_eq!(drops.bad_magic, 1);
+        assert_eq!(
+            replies(&handler.frame),
+            [(PackType::OpenConnectionReply1, addr(1))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_end_the_traces() {
+        let mut handler = MockFrame::new([
+            (request1(1), addr(1)),
+            (request2(1), addr(2)),
+            (request1(11), addr(3)),
+            (request2(3), addr(3)),
+        ])
+        .handle_offline(
+            ConfigBuilder::default()
+                .sever_guid(114_514)
+                .migration(true)
+                .build()
+                .unwrap(),
+        );
+        handler.traces = SessionTraces::new(true);
+        assert!(handler.next().await.is_none());
+        assert!(handler.traces.handshakes.is_empty());
+        assert_eq!(handler.traces.connections.len(), 1);
+
+        // the client moves to another address
+        let token = issued_token(&handler.frame, addr(3));
+        handler.frame.inbound.extend(
+            [
+                (request1(11), addr(4)),
+                (migrating_request2(3, token), addr(4)),
+            ]
+            .map(Ok),
+        );
+        assert!(handler.next().await.is_none());
+        assert!(handler.traces.connections.contains_key(&addr(4)));
+        assert_eq!(handler.traces.connections.len(), 1);
+
+        handler.closer().send(addr(4)).unwrap();
+        assert!(handler.next().await.is_none());
+        assert!(handler.traces.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_offline_release_abandoned_handshake() {
+        let timeout = Duration::from_millis(50);
+        let mut builder = ConfigBuilder::default();
+        builder
+            .sever_guid(114_514)
+            .accept_backlog(1)
+            .handshake_timeout(timeout);
+        let frame = MockFrame::new([
+            (request1(11), addr(1)),
+            (request2(1), addr(1)),
+            (request1(11), addr(2)),
+            (request2(2), addr(2)),
+        ]);
+        let mut handler = frame.handle_offline(builder.build().unwrap());
+        assert!(handler.next().await.is_none());
+        // the slot is held by the first handshake, the second one is left unanswered
+        assert_eq!(handler.backlog().pending(), 1);
+        assert_eq!(
+            replies(&handler.frame),
+            [
+                (PackType::OpenConnectionReply1, addr(1)),
+                (PackType::OpenConnectionReply2, addr(1)),
+                (PackType::OpenConnectionReply1, addr(2)),
+            ]
+        );
+
+        // the first client never sends a datagram after the handshake
+        tokio::time::sleep(timeout).await;
+        handler
+            .frame
+            .inbound
+            .extend([(request1(11), addr(2)), (request2(2), addr(2))].map(Ok));
+        assert!(handler.next().await.is_none());
+        assert_eq!(handler.backlog().pending(), 1);
+        assert_eq!(
+            replies(&handler.frame)[3..],
+            [
+                (PackType::OpenConnectionReply1, addr(2)),
+                (PackType::OpenConnectionReply2, addr(2)),
+            ]
+        );
+        // and the abandoned handshake is forgotten
+        assert!(!handler.connected.contains_key(&addr(1)));
+    }
+
+    #[tokio::test]
+    async fn test_offline_refuse_when_full() {
+        let mut builder = ConfigBuilder::default();
+        builder.sever_guid(114_514).max_connections(1);
+        let frame = MockFrame::new([
+            (request1(11), addr(1)),
+            (request2(1), addr(1)),
+            (request1(11), addr(2)),
+        ]);
+        let mut handler = frame.handle_offline(builder.build().unwrap());
+        assert!(handler.next().await.is_none());
+        assert_eq!(
+            replies(&handler.frame),
+            vec![
+                (PackType::OpenConnectionReply1, addr(1)),
+                (PackType::OpenConnectionReply2, addr(1)),
+                (PackType::NoFreeIncomingConnections, addr(2)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_refuse_guid_held_by_another_address() {
+        let frame = MockFrame::new([
+            (request1(11), addr(1)),
+            (request2(1), addr(1)),
+            (request1(11), addr(2)),
+            (request2(1), addr(2)),
+        ]);
+        let mut handler = frame.handle_offline(config());
+        assert!(handler.next().await.is_none());
+        assert_eq!(
+            replies(&handler.frame)[2..],
+            [
+                (PackType::OpenConnectionReply1, addr(2)),
+                (PackType::AlreadyConnected, addr(2)),
+            ]
+        );
+        assert!(!handler.connected.contains_key(&addr(2)));
+
+        // the GUID is released with the connection holding it
+        handler
+            .frame
+            .inbound
+            .push_back(Ok((disconnect_notification(), addr(1))));
+        handler
+            .frame
+            .inbound
+            .extend([(request1(11), addr(2)), (request2(1), addr(2))].map(Ok));
+        while handler.next().await.is_some() {}
+        assert_eq!(
+            replies(&handler.frame)[4..],
+            [
+                (PackType::OpenConnectionReply1, addr(2)),
+                (PackType::OpenConnectionReply2, addr(2)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_migrate_with_token() {
+        let frame = MockFrame::new([(request1(11), addr(1)), (request2(1), addr(1))]);
+        let mut handler = frame.handle_offline(
+            ConfigBuilder::default()
+                .sever_guid(114_514)
+                .migration(true)
+                .build()
+                .unwrap(),
+        );
+        assert!(handler.next().await.is_none());
+        let token = issued_token(&handler.frame, addr(1)).unwrap();
+
+        // the GUID alone or a forged token does not prove the ownership
+        for forged in [None, Some(token ^ 1)] {
+            handler.frame.inbound.extend(
+                [
+                    (request1(11), addr(2)),
+                    (migrating_request2(1, forged), addr(2)),
+                ]
+                .map(Ok),
+            );
+            assert!(handler.next().await.is_none());
+            assert_eq!(
+                replies(&handler.frame).last(),
+                Some(&(PackType::AlreadyConnected, addr(2)))
+            );
+            assert_eq!(handler.connected.holder(1), Some(addr(1)));
+        }
+
+        handler.frame.inbound.extend(
+            [
+                (request1(11), addr(2)),
+                (migrating_request2(1, Some(token)), addr(2)),
+            ]
+            .map(Ok),
+        );
+        assert!(handler.next().await.is_none());
+        assert_eq!(handler.connected.holder(1), Some(addr(2)));
+        assert!(!handler.connected.contains_key(&addr(1)));
+        // a new token is issued to the new address, the old one is void
+        let moved = issued_token(&handler.frame, addr(2)).unwrap();
+        assert_ne!(moved, token);
+    }
+
+    #[tokio::test]
+    async fn test_offline_refuse_under_memory_pressure() {
+        let mut builder = ConfigBuilder::default();
+        builder.sever_guid(114_514).memory_ceiling(1000);
+        let frame = MockFrame::new([(request1(11), addr(1))]);
+        let mut handler = frame.handle_offline(builder.build().unwrap());
+        assert!(handler.next().await.is_none());
+
+        // the connections buffered too much before the request 2 arrives
+        let mut budget = MemoryBudget::new(BudgetConfig::default(), handler.memory());
+        assert_eq!(budget.grow(Buffer::Resend, 900), None);
+        handler.frame.inbound.push_back(Ok((request2(1), addr(1))));
+        assert!(handler.next().await.is_none());
+        assert!(!handler.connected.contains_key(&addr(1)));
+
+        // admitted once the memory is released
+        drop(budget);
+        handler
+            .frame
+            .inbound
+            .extend([Ok((request1(11), addr(1))), Ok((request2(1), addr(1)))]);
+        assert!(handler.next().await.is_none());
+        assert_eq!(
+            replies(&handler.frame),
+            vec![
+                (PackType::OpenConnectionReply1, addr(1)),
+                (PackType::NoFreeIncomingConnections, addr(1)),
+                (PackType::OpenConnectionReply1, addr(1)),
+                (PackType::OpenConnectionReply2, addr(1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_incompatible_responses() {
+        let probes = || MockFrame::new(vec![(request1(1), addr(1)); 3]);
+
+        let mut builder = ConfigBuilder::default();
+        builder
+            .sever_guid(114_514)
+            .incompatible(IncompatibleConfig {
+                respond: true,
+                max_replies_per_source: 2,
+                advertised_version: Some(9),
+            });
+        let mut limited = probes().handle_offline(builder.build().unwrap());
+        assert!(limited.next().await.is_none());
+        let incompatible = Packet::Unconnected(unconnected::Packet::IncompatibleProtocol {
+            server_protocol: 9,
+            magic: (),
+            server_guid: 114_514,
+        });
+        assert_eq!(
+            limited.frame.sent,
+            vec![(incompatible.clone(), addr(1)), (incompatible, addr(1))]
+        );
+
+        builder.incompatible(IncompatibleConfig {
+            respond: false,
+            ..IncompatibleConfig::default()
+        });
+        let mut silent = probes().handle_offline(builder.build().unwrap());
+        assert!(silent.next().await.is_none());
+        assert!(silent.frame.sent.is_empty());
+
+        // the latest supported version is advertised by default
+        let mut advertised = probes().handle_offline(config());
+        assert!(advertised.next().await.is_none());
+        assert_eq!(advertised.frame.sent.len(), 3);
+        assert!(matches!(
+            advertised.frame.sent[0].0,
+            Packet::Unconnected(unconnected::Packet::IncompatibleProtocol {
+                server_protocol: 11,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_offline_unconnected_messages() {
+        let addr: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let messages = || {
+            MockFrame::new([
+                (make_advertise_system(Bytes::from_static(b"first")), addr),
+                (make_advertise_system(Bytes::from_static(b"second")), addr),
+            ])
+        };
+
+        // ignored while no one is receiving them
+        let mut ignored = messages().handle_offline(config());
+        assert!(ignored.next().await.is_none());
+        assert!(ignored.frame.sent.is_empty());
+
+        let mut received = messages().handle_offline(config());
+        let rx = received.unconnected_messages().subscribe(1);
+        assert!(received.next().await.is_none());
+        assert_eq!(rx.try_recv().unwrap(), (Bytes::from_static(b"first"), addr));
+        // the receiver was full
+        assert!(rx.try_recv().is_err());
+        // never answered
+        assert!(received.frame.sent.is_empty());
+    }
+
+    #[test]
+    fn test_config_presets_are_valid() {
+        for builder in [
+            ConfigBuilder::bedrock_server(),
+            ConfigBuilder::lan_game(),
+            ConfigBuilder::lossy_mobile(),
+        ] {
+            let config = builder.clone().sever_guid(114_514).build().unwrap();
+            assert!(config.min_mtu <= config.max_mtu);
+        }
+        assert_eq!(
+            ConfigBuilder::default().build().unwrap_err(),
+            ConfigError::UninitializedField("sever_guid")
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_config_serde() {
+        use crate::server::{ResendExceeded, ResendLimitConfig, RtoConfig, WatchdogConfig};
+
+        let config = ConfigBuilder::bedrock_server()
+            .sever_guid(114_514)
+            .rto(
+                RtoConfig::default()
+                    .with_bounds(Duration::from_millis(200), Duration::from_secs(5)),
+            )
+            .resend_limit(
+                ResendLimitConfig::default()
+                    .with_max_attempts(8)
+                    .with_on_exceeded(ResendExceeded::Disconnect),
+            )
+            .watchdog(WatchdogConfig::default())
+            .build()
+            .unwrap();
+        let json = serde_json::to_string(&config).unwrap();
+        let decoded: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+        assert_eq!(decoded.rto.min(), Duration::from_millis(200));
+
+        // the missing fields of the nested configs are filled by the defaults
+        let rto: RtoConfig = serde_json::from_str(r#"{"max":{"secs":5,"nanos":0}}"#).unwrap();
+        assert_eq!(rto.min(), MIN_RTO);
+    }
+
+    #[test]
+    fn test_downgrade_check() {
+        assert_eq!(DowngradeConfig::default().check(9, MIN_MTU), None);
+        let config = DowngradeConfig::default()
+            .with_min_version(10)
+            .with_min_mtu(1200);
+        assert_eq!(config.check(11, 1400), None);
+        assert_eq!(
+            config.check(11, 1000),
+            Some(Downgrade::Mtu {
+                advertised: 1000,
+                minimum: 1200
+            })
+        );
+        // the version is reported first
+        assert_eq!(
+            config.check(9, 1000),
+            Some(Downgrade::ProtocolVersion {
+                advertised: 9,
+                minimum: 10
+            })
+        );
+    }
+
+    #[test]
+    fn test_silent_drop_config() {
+        let config = SilentDropConfig::default();
+        assert!(!config.drops(Refusal::IncompatibleProtocol));
+        let config = config.with(Refusal::NoFreeIncomingConnections, true);
+        assert!(config.drops(Refusal::NoFreeIncomingConnections));
+        assert!(!config.drops(Refusal::AlreadyConnected));
+
+        let all = SilentDropConfig::all().with(Refusal::AlreadyConnected, false);
+        assert!(all.drops(Refusal::IncompatibleProtocol));
+        assert!(all.drops(Refusal::ConnectionRequestFailed));
+        assert!(!all.drops(Refusal::AlreadyConnected));
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let mut builder = ConfigBuilder::default();
+        builder.sever_guid(114_514);
+        assert_eq!(
+            builder.clone().min_mtu(500).build().unwrap_err(),
+            ConfigError::MtuTooSmall(500, MIN_MTU)
+        );
+        assert_eq!(
+            builder.clone().max_mtu(9000).build().unwrap_err(),
+            ConfigError::MtuTooLarge(9000, MAX_MTU)
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .min_mtu(1400)
+                .max_mtu(1200)
+                .build()
+                .unwrap_err(),
+            ConfigError::MtuRange(1400, 1200)
+        );
+        assert_eq!(
+            builder.clone().max_datagram_size(1000).build().unwrap_err(),
+            ConfigError::MaxDatagramSize(1000, 1400)
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .advertisement(vec![0; MAX_ADVERTISEMENT + 1])
+                .build()
+                .unwrap_err(),
+            ConfigError::AdvertisementTooLarge(MAX_ADVERTISEMENT + 1, MAX_ADVERTISEMENT)
+        );
+        assert_eq!(
+            builder.clone().support_version(vec![]).build().unwrap_err(),
+            ConfigError::NoSupportVersion
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .support_version(vec![11, 42])
+                .build()
+                .unwrap_err(),
+            ConfigError::UnknownVersion(42)
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .ack(AckConfig::default().with_flush_delay(MIN_RTO))
+                .build()
+                .unwrap_err(),
+            ConfigError::AckDelayExceedsRto(MIN_RTO, MIN_RTO)
+        );
+        let rto =
+            RtoConfig::default().with_bounds(Duration::from_millis(50), Duration::from_secs(1));
+        assert_eq!(
+            builder
+                .clone()
+                .ack(AckConfig::default().with_flush_delay(Duration::from_millis(50)))
+                .rto(rto)
+                .build()
+                .unwrap_err(),
+            ConfigError::AckDelayExceedsRto(Duration::from_millis(50), Duration::from_millis(50))
+        );
+        assert_eq!(
+            builder
+                .clone()
+                .send_watermark(WatermarkConfig::new(1024, 4096))
+                .build()
+                .unwrap_err(),
+            ConfigError::Watermark(4096, 1024)
+        );
+        let config = builder.support_version(vec![11, 10, 11]).build().unwrap();
+        assert_eq!(config.support_version, vec![10, 11]);
+    }
+}
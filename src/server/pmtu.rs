@@ -0,0 +1,298 @@
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use super::offline::MIN_MTU;
+use crate::event::Event;
+use crate::packet::connected::{max_body_size, Reliability};
+use crate::packet::PackType;
+
+/// Path MTU discovery after the connection is established
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PmtuConfig {
+    // Whether to probe the path for a larger mtu than negotiated
+    enabled: bool,
+    // Search the path mtu again after this interval since the last search completed
+    probe_interval: Duration,
+    // A probe size is regarded as unsupported after this many probes of it are lost
+    max_probes: usize,
+    // Stop searching when the gap between the confirmed mtu and the upper bound is less than it
+    min_step: u16,
+    // Reduce the mtu after this many datagrams near the mtu are lost in a row while smaller ones
+    // are acknowledged, 0 means disabled
+    blackhole_threshold: usize,
+    // Datagrams within this many bytes of the mtu are regarded as near the mtu
+    blackhole_margin: u16,
+}
+
+impl Default for PmtuConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            probe_interval: Duration::from_secs(600),
+            max_probes: 3,
+            min_step: 16,
+            blackhole_threshold: 3,
+            blackhole_margin: 64,
+        }
+    }
+}
+
+impl PmtuConfig {
+    /// Whether to probe the path for a larger mtu than negotiated
+    #[must_use]
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Search the path mtu again after this interval since the last search completed
+    #[must_use]
+    pub fn with_probe_interval(mut self, probe_interval: Duration) -> Self {
+        self.probe_interval = probe_interval;
+        self
+    }
+
+    /// Regard a probe size as unsupported after this many probes of it are lost
+    #[must_use]
+    pub fn with_max_probes(mut self, max_probes: usize) -> Self {
+        self.max_probes = max_probes;
+        self
+    }
+
+    /// Reduce the mtu after this many datagrams within `margin` bytes of it are lost in a row
+    /// while smaller ones are acknowledged, 0 means disabled
+    #[must_use]
+    pub fn with_blackhole(mut self, threshold: usize, margin: u16) -> Self {
+        self.blackhole_threshold = threshold;
+        self.blackhole_margin = margin;
+        self
+    }
+}
+
+/// What the connection should do for path MTU discovery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum PmtuAction {
+    /// Nothing to do for now
+    Idle,
+    /// Send an unreliable probe datagram padded to this size
+    Probe(u16),
+}
+
+/// Search the largest mtu supported by the path between the negotiated mtu and the upper bound.
+/// Each probe is a datagram padded to the probe size, it is acknowledged like any other datagram
+/// if the path supports the size, and never retransmitted.
+#[derive(Debug)]
+pub(super) struct Pmtu {
+    config: PmtuConfig,
+    // The confirmed mtu of the path
+    mtu: u16,
+    // The upper bound of the search, sizes above it are known to be unsupported
+    ceiling: u16,
+    max_mtu: u16,
+    // The size being probed and the count of lost probes of it
+    probing: Option<(u16, usize)>,
+    in_flight: bool,
+    next_probe: Instant,
+    // Datagrams near the mtu lost in a row, and the largest datagram acknowledged since the first
+    // of them was lost
+    lost_near_mtu: usize,
+    largest_acked: u16,
+}
+
+impl Pmtu {
+    pub(super) fn new(config: PmtuConfig, negotiated: u16, max_mtu: u16, now: Instant) -> Self {
+        Self {
+            config,
+            mtu: negotiated,
+            ceiling: max_mtu.max(negotiated),
+            max_mtu: max_mtu.max(negotiated),
+            probing: None,
+            in_flight: false,
+            next_probe: now,
+            lost_near_mtu: 0,
+            largest_acked: 0,
+        }
+    }
+
+    /// The mtu used for the fragmentation
+    pub(super) fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// The instant a probe may be sent, None if it is disabled or a probe is in flight
+    pub(super) fn next_probe(&self) -> Option<Instant> {
+        (self.config.enabled && !self.in_flight).then_some(self.next_probe)
+    }
+
+    /// Check what should be done at now
+    pub(super) fn poll(&mut self, now: Instant) -> PmtuAction {
+        if !self.config.enabled || self.in_flight || now < self.next_probe {
+            return PmtuAction::Idle;
+        }
+        let size = match self.probing {
+            Some((size, _)) => size,
+            None => {
+                if self.ceiling.saturating_sub(self.mtu) < self.config.min_step {
+                    // the search completed, search again later in case the path changed
+                    self.ceiling = self.max_mtu;
+                    self.next_probe = now + self.config.probe_interval;
+                    return PmtuAction::Idle;
+                }
+                let size = self.mtu + (self.ceiling - self.mtu).div_ceil(2);
+                self.probing = Some((size, 0));
+                size
+            }
+        };
+        self.in_flight = true;
+        PmtuAction::Probe(size)
+    }
+
+    /// The probe of size is acknowledged, returns the new mtu if it is raised.
+    pub(super) fn on_probe_acked(&mut self, size: u16, now: Instant) -> Option<u16> {
+        self.in_flight = false;
+        self.probing = None;
+        self.next_probe = now;
+        if size <= self.mtu {
+            return None;
+        }
+        self.mtu = size;
+        Some(size)
+    }
+
+    /// The probe of size is lost
+    pub(super) fn on_probe_lost(&mut self, size: u16, now: Instant) {
+        self.in_flight = false;
+        self.next_probe = now;
+        let Some((probing, lost)) = self.probing.as_mut() else {
+            return;
+        };
+        if *probing != size {
+            return;
+        }
+        *lost += 1;
+        if *lost >= self.config.max_probes {
+            self.ceiling = size - 1;
+            self.probing = None;
+        }
+    }
+
+    fn near_mtu(&self, size: u16) -> bool {
+        size.saturating_add(self.config.blackhole_margin) >= self.mtu
+    }
+
+    /// A datagram of size is acknowledged
+    pub(super) fn on_datagram_acked(&mut self, size: u16) {
+        if self.near_mtu(size) {
+            self.lost_near_mtu = 0;
+            return;
+        }
+        self.largest_acked = self.largest_acked.max(size);
+    }
+
+    /// A datagram of size is lost. If only the datagrams near the mtu are being lost, the path
+    /// is regarded as a blackhole for them (e.g. broken tunnels), the mtu is reduced to the
+    /// largest acknowledged size and probed again after the interval.
+    pub(super) fn on_datagram_lost(&mut self, size: u16, now: Instant) -> Option<Event> {
+        if self.config.blackhole_threshold == 0 || !self.near_mtu(size) {
+            return None;
+        }
+        if self.lost_near_mtu == 0 {
+            self.largest_acked = 0;
+        }
+        self.lost_near_mtu += 1;
+        // nothing is acknowledged means the whole path is down rather than a blackhole
+        if self.lost_near_mtu < self.config.blackhole_threshold || self.largest_acked == 0 {
+            return None;
+        }
+        let reduced = self.largest_acked.max(MIN_MTU);
+        if reduced >= self.mtu {
+            return None;
+        }
+        let event = Event::MtuReduced {
+            from: self.mtu,
+            to: reduced,
+        };
+        self.mtu = reduced;
+        self.ceiling = reduced;
+        self.probing = None;
+        self.in_flight = false;
+        self.next_probe = now + self.config.probe_interval;
+        self.lost_near_mtu = 0;
+        Some(event)
+    }
+}
+
+/// Make the body of a probe frame, it is a `ConnectedPing` padded so that the datagram carrying
+/// the unreliable frame alone fills the mtu of `size`, including the IP and UDP headers.
+pub(super) fn probe_body(size: u16, timestamp: i64) -> Bytes {
+    let len = max_body_size(size, Reliability::Unreliable, false);
+    let mut body = BytesMut::with_capacity(len);
+    body.put_u8(PackType::ConnectedPing.into());
+    body.put_i64(timestamp);
+    body.resize(len, 0);
+    body.freeze()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pmtu_search() {
+        let now = Instant::now();
+        let mut pmtu = Pmtu::new(PmtuConfig::default(), 1000, 1400, now);
+        // the path supports 1300 bytes
+        while let PmtuAction::Probe(size) = pmtu.poll(now) {
+            assert_eq!(pmtu.poll(now), PmtuAction::Idle, "one probe in flight");
+            if size <= 1300 {
+                pmtu.on_probe_acked(size, now);
+            } else {
+                pmtu.on_probe_lost(size, now);
+            }
+        }
+        assert!(pmtu.mtu() <= 1300 && pmtu.mtu() > 1300 - 16);
+
+        // search again after the interval
+        let later = now + Duration::from_secs(600);
+        assert!(matches!(pmtu.poll(later), PmtuAction::Probe(size) if size > pmtu.mtu()));
+    }
+
+    #[test]
+    fn test_pmtu_blackhole() {
+        let now = Instant::now();
+        let mut pmtu = Pmtu::new(PmtuConfig::default(), 1400, 1400, now);
+        // ordinary loss of small datagrams
+        for _ in 0..5 {
+            assert_eq!(pmtu.on_datagram_lost(600, now), None);
+        }
+        // the whole path is down
+        for _ in 0..5 {
+            assert_eq!(pmtu.on_datagram_lost(1400, now), None);
+        }
+        pmtu.on_datagram_acked(1392);
+        assert_eq!(pmtu.on_datagram_lost(1400, now), None);
+        pmtu.on_datagram_acked(1000);
+        pmtu.on_datagram_acked(1200);
+        assert_eq!(pmtu.on_datagram_lost(1350, now), None);
+        assert_eq!(
+            pmtu.on_datagram_lost(1400, now),
+            Some(Event::MtuReduced {
+                from: 1400,
+                to: 1200
+            })
+        );
+        assert_eq!(pmtu.mtu(), 1200);
+        assert_eq!(pmtu.poll(now), PmtuAction::Idle);
+    }
+
+    #[test]
+    fn test_pmtu_probe_body() {
+        let body = probe_body(1400, 42);
+        assert_eq!(body.len(), 1400 - 28 - 4 - 3);
+        assert_eq!(body[0], 0x00);
+        assert_eq!(&body[1..9], &42_i64.to_be_bytes());
+    }
+}
@@ -0,0 +1,515 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures::{ready, FutureExt, Sink, SinkExt, Stream};
+use pin_project_lite::pin_project;
+use tokio::net::UdpSocket;
+use tracing::{debug, error, warn};
+
+use super::drain::Drain;
+use super::offline::MAX_MTU;
+use crate::errors::ConfigError;
+use crate::packet::connected::UDP_HEADER_SIZE;
+use crate::packet::{PackType, MAGIC};
+
+/// The bytes of a pong before the advertisement: id, timestamp, server guid and magic
+const PONG_HEADER: usize = 1 + 8 + 8 + 16;
+
+/// The bytes of a ping: id, timestamp, magic and client guid
+const PING_SIZE: usize = 1 + 8 + 16 + 8;
+
+/// The max bytes of the advertisement carried by a pong, the pong fits in a datagram of the max
+/// mtu including the IP and UDP headers
+pub(super) const MAX_ADVERTISEMENT: usize = MAX_MTU as usize - UDP_HEADER_SIZE - PONG_HEADER;
+
+/// A preformatted `UnconnectedPong` answering the pings straight from the received datagrams.
+/// Ping floods are the most common traffic a public server sees, so the reply is made on the
+/// stack without decoding the ping or allocating: only the echoed timestamp is patched.
+#[derive(Debug, Clone)]
+pub(super) struct PongTemplate {
+    buf: [u8; MAX_MTU as usize],
+    len: usize,
+}
+
+impl PongTemplate {
+    pub(super) fn new(server_guid: u64, advertisement: &[u8]) -> Result<Self, ConfigError> {
+        let mut buf = [0; MAX_MTU as usize];
+        buf[0] = PackType::UnconnectedPong.into();
+        buf[9..17].copy_from_slice(&server_guid.to_be_bytes());
+        buf[17..PONG_HEADER].copy_from_slice(&MAGIC);
+        let mut template = Self {
+            buf,
+            len: PONG_HEADER,
+        };
+        template.refresh(advertisement)?;
+        Ok(template)
+    }
+
+    /// Replace the advertisement (MOTD) of the following pongs, the template is left untouched
+    /// if it does not fit in a datagram.
+    pub(super) fn refresh(&mut self, advertisement: &[u8]) -> Result<(), ConfigError> {
+        if advertisement.len() > MAX_ADVERTISEMENT {
+            return Err(ConfigError::AdvertisementTooLarge(
+                advertisement.len(),
+                MAX_ADVERTISEMENT,
+            ));
+        }
+        self.len = PONG_HEADER + advertisement.len();
+        self.buf[PONG_HEADER..self.len].copy_from_slice(advertisement);
+        Ok(())
+    }
+
+    /// Make the pong to the datagram if it is a valid `UnconnectedPing`, None otherwise and the
+    /// datagram should go through the codec.
+    pub(super) fn respond(&mut self, datagram: &[u8]) -> Option<&[u8]> {
+        if !is_ping(datagram) {
+            return None;
+        }
+        // the timestamp is echoed as is
+        self.buf[1..9].copy_from_slice(&datagram[1..9]);
+        Some(&self.buf[..self.len])
+    }
+}
+
+fn is_ping(datagram: &[u8]) -> bool {
+    datagram.len() == PING_SIZE
+        && (datagram[0] == u8::from(PackType::UnconnectedPing1)
+            || datagram[0] == u8::from(PackType::UnconnectedPing2))
+        && datagram[9..25] == MAGIC
+}
+
+pin_project! {
+    /// Answer the pings from the [`PongCache`] straight from the raw datagrams, the other
+    /// datagrams are passed to the codec. It bypasses the offline handler, so it is only placed
+    /// when the pings need nothing from it: no rate limit, receive budget or pong hook. The pings
+    /// are passed to the offline handler while draining, which ignores them.
+    pub(super) struct FastPong<F, P> {
+        #[pin]
+        frame: F,
+        cache: PongCache<P>,
+        drain: Drain,
+    }
+}
+
+pub(super) trait FastPonged: Sized {
+    fn fast_ponged<P>(self, cache: PongCache<P>, drain: Drain) -> FastPong<Self, P>;
+}
+
+impl<F> FastPonged for F {
+    fn fast_ponged<P>(self, cache: PongCache<P>, drain: Drain) -> FastPong<Self, P> {
+        FastPong {
+            frame: self,
+            cache,
+            drain,
+        }
+    }
+}
+
+impl<F, P, E> Stream for FastPong<F, P>
+where
+    P: AdvertisementProvider,
+    F: Stream<Item = Result<(BytesMut, SocketAddr), E>> + Sink<(BytesMut, SocketAddr), Error = E>,
+    E: std::fmt::Display,
+{
+    type Item = Result<(BytesMut, SocketAddr), E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let Some((raw, addr)) = ready!(this.frame.as_mut().poll_next(cx)?) else {
+                return Poll::Ready(None);
+            };
+            if this.drain.is_draining() {
+                return Poll::Ready(Some(Ok((raw, addr))));
+            }
+            let Some(pong) = this.cache.respond(&raw, Instant::now()) else {
+                return Poll::Ready(Some(Ok((raw, addr))));
+            };
+            let mut send = this.frame.send((BytesMut::from(pong), addr));
+            if let Err(err) = ready!(send.poll_unpin(cx)) {
+                error!("failed send pong to {addr}, error {err}");
+            }
+        }
+    }
+}
+
+impl<F, P, T> Sink<T> for FastPong<F, P>
+where
+    F: Sink<T>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.project().frame.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx)
+    }
+}
+
+/// Provide the advertisement of the pongs, e.g. the MOTD with the count of online players
+pub(super) trait AdvertisementProvider {
+    /// Bumped whenever the advertisement changes
+    fn epoch(&self) -> u64;
+
+    /// Assemble the advertisement, only called when the epoch is bumped or the cached one
+    /// expires
+    fn advertisement(&self) -> Bytes;
+}
+
+/// An advertisement shared by the server handle, setting it bumps the epoch
+#[derive(Debug, Clone, Default)]
+pub(super) struct SharedAdvertisement {
+    epoch: Arc<AtomicU64>,
+    data: Arc<RwLock<Bytes>>,
+}
+
+impl SharedAdvertisement {
+    pub(super) fn set(&self, data: Bytes) {
+        *self.data.write().expect("advertisement lock poisoned") = data;
+        self.epoch.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl AdvertisementProvider for SharedAdvertisement {
+    fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    fn advertisement(&self) -> Bytes {
+        self.data
+            .read()
+            .expect("advertisement lock poisoned")
+            .clone()
+    }
+}
+
+/// Pong cache config
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PongCacheConfig {
+    // The max staleness of the cached pong, it is rebuilt after it even if the epoch is not
+    // bumped, so that a provider forgetting to bump the epoch is still reflected. None means
+    // it is only rebuilt by the epoch.
+    ttl: Option<Duration>,
+}
+
+impl Default for PongCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+impl PongCacheConfig {
+    #[must_use]
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+}
+
+/// Cache the pong assembled from the [`AdvertisementProvider`], it is only rebuilt when the
+/// epoch of the provider is bumped or it is older than the TTL, instead of assembling the
+/// advertisement per ping under load.
+#[derive(Debug)]
+pub(super) struct PongCache<P> {
+    template: PongTemplate,
+    provider: P,
+    config: PongCacheConfig,
+    epoch: u64,
+    built_at: Instant,
+}
+
+impl<P: AdvertisementProvider> PongCache<P> {
+    pub(super) fn new(
+        server_guid: u64,
+        provider: P,
+        config: PongCacheConfig,
+        now: Instant,
+    ) -> Result<Self, ConfigError> {
+        let epoch = provider.epoch();
+        let template = PongTemplate::new(server_guid, &provider.advertisement())?;
+        Ok(Self {
+            template,
+            provider,
+            config,
+            epoch,
+            built_at: now,
+        })
+    }
+
+    /// Make the pong to the datagram like [`PongTemplate::respond`], rebuilding the cached one
+    /// first if it is stale.
+    pub(super) fn respond(&mut self, datagram: &[u8], now: Instant) -> Option<&[u8]> {
+        if !is_ping(datagram) {
+            return None;
+        }
+        let epoch = self.provider.epoch();
+        let expired = self
+            .config
+            .ttl
+            .is_some_and(|ttl| now.saturating_duration_since(self.built_at) >= ttl);
+        if epoch != self.epoch || expired {
+            if let Err(err) = self.template.refresh(&self.provider.advertisement()) {
+                warn!("keep the cached pong, failed to refresh it: {err}");
+            }
+            self.epoch = epoch;
+            self.built_at = now;
+        }
+        self.template.respond(datagram)
+    }
+}
+
+/// Answer the pings on a secondary port (e.g. 19133 beside 19132 in the Bedrock convention)
+/// from the cached pong, while the connections are only served on the primary port. The other
+/// datagrams are ignored, so are the pings while the server is draining. It returns when the
+/// socket fails.
+pub(super) async fn serve_secondary_pongs<P: AdvertisementProvider>(
+    socket: UdpSocket,
+    mut cache: PongCache<P>,
+    drain: Drain,
+) -> io::Result<()> {
+    let mut buf = [0; MAX_MTU as usize];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // caused by the ICMP messages of the earlier pongs
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+                ) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+        if drain.is_draining() {
+            continue;
+        }
+        let Some(pong) = cache.respond(&buf[..len], Instant::now()) else {
+            debug!("ignore the datagram from {addr} on the secondary pong port");
+            continue;
+        };
+        if let Err(err) = socket.send_to(pong, addr).await {
+            warn!("failed send pong to {addr} on the secondary pong port, error {err}");
+        }
+    }
+}
+
+/// Decide the pong of each ping asynchronously, for the advanced usages like per-region MOTD,
+/// hiding the server from some ips or A/B testing the server listing. The pings are answered in
+/// the order the hook resolves.
+pub trait PongHook: Send + Sync {
+    /// Make the advertisement of the pong to the ping from addr, None means no reply
+    fn on_ping(&self, addr: SocketAddr) -> BoxFuture<'static, Option<Bytes>>;
+}
+
+impl<T, Fut> PongHook for T
+where
+    T: Fn(SocketAddr) -> Fut + Send + Sync,
+    Fut: Future<Output = Option<Bytes>> + Send + 'static,
+{
+    fn on_ping(&self, addr: SocketAddr) -> BoxFuture<'static, Option<Bytes>> {
+        Box::pin(self(addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{Bytes, BytesMut};
+
+    use super::*;
+    use crate::packet::{unconnected, Packet};
+    use crate::server::session::Sessions;
+
+    fn encode(packet: unconnected::Packet) -> BytesMut {
+        let mut buf = BytesMut::new();
+        Packet::<Bytes>::Unconnected(packet).write(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_pong_template_works() {
+        let mut template = PongTemplate::new(114_514, b"MCPE;motd").unwrap();
+        let ping = encode(unconnected::Packet::UnconnectedPing {
+            send_timestamp: 1919,
+            magic: (),
+            client_guid: 810,
+        });
+        let reply = template.respond(&ping).map(BytesMut::from);
+        let expected = encode(unconnected::Packet::UnconnectedPong {
+            send_timestamp: 1919,
+            server_guid: 114_514,
+            magic: (),
+            data: Bytes::from_static(b"MCPE;motd"),
+        });
+        assert_eq!(reply, Some(expected));
+
+        template.refresh(b"MCPE;new motd").unwrap();
+        let refreshed = template.respond(&ping).unwrap();
+        assert!(refreshed.ends_with(b"MCPE;new motd"));
+        // the largest pong fills a datagram of the max mtu
+        template.refresh(&[0; MAX_ADVERTISEMENT]).unwrap();
+        assert_eq!(
+            template.respond(&ping).unwrap().len(),
+            crate::packet::connected::max_datagram_size(MAX_MTU)
+        );
+        assert_eq!(
+            template.refresh(&[0; MAX_ADVERTISEMENT + 1]),
+            Err(ConfigError::AdvertisementTooLarge(
+                MAX_ADVERTISEMENT + 1,
+                MAX_ADVERTISEMENT
+            ))
+        );
+
+        // not a ping or broken
+        assert!(template.respond(&ping[..PING_SIZE - 1]).is_none());
+        let open = encode(unconnected::Packet::OpenConnectionRequest1 {
+            magic: (),
+            protocol_version: 11,
+            mtu: 1400,
+        });
+        assert!(template.respond(&open).is_none());
+    }
+
+    #[derive(Default)]
+    struct CountedProvider {
+        inner: SharedAdvertisement,
+        assembled: std::cell::Cell<usize>,
+    }
+
+    impl AdvertisementProvider for CountedProvider {
+        fn epoch(&self) -> u64 {
+            self.inner.epoch()
+        }
+
+        fn advertisement(&self) -> Bytes {
+            self.assembled.set(self.assembled.get() + 1);
+            self.inner.advertisement()
+        }
+    }
+
+    #[test]
+    fn test_pong_cache_rebuild() {
+        let now = Instant::now();
+        let ping = encode(unconnected::Packet::UnconnectedPing {
+            send_timestamp: 1919,
+            magic: (),
+            client_guid: 810,
+        });
+        let provider = CountedProvider::default();
+        provider.inner.set(Bytes::from_static(b"MCPE;0 online"));
+        let config = PongCacheConfig::default().with_ttl(Some(Duration::from_secs(5)));
+        let mut cache = PongCache::new(114_514, provider, config, now).unwrap();
+        for _ in 0..10 {
+            assert!(cache.respond(&ping, now).is_some());
+        }
+        assert!(cache.respond(b"not a ping", now).is_none());
+        assert_eq!(cache.provider.assembled.get(), 1);
+
+        cache
+            .provider
+            .inner
+            .set(Bytes::from_static(b"MCPE;1 online"));
+        let reply = cache.respond(&ping, now).unwrap();
+        assert!(reply.ends_with(b"MCPE;1 online"));
+        assert_eq!(cache.provider.assembled.get(), 2);
+
+        // rebuilt after the ttl even if the epoch is not bumped
+        cache.respond(&ping, now + Duration::from_secs(5));
+        assert_eq!(cache.provider.assembled.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_serve_secondary_pongs() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let advertisement = SharedAdvertisement::default();
+        advertisement.set(Bytes::from_static(b"MCPE;secondary"));
+        let cache = PongCache::new(
+            114_514,
+            advertisement.clone(),
+            PongCacheConfig::default().with_ttl(None),
+            Instant::now(),
+        )
+        .unwrap();
+        let drain = Drain::default();
+        let responder = tokio::spawn(serve_secondary_pongs(server, cache, drain.clone()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let open = encode(unconnected::Packet::OpenConnectionRequest1 {
+            magic: (),
+            protocol_version: 11,
+            mtu: 1400,
+        });
+        client.send_to(&open, server_addr).await.unwrap();
+        let ping = encode(unconnected::Packet::UnconnectedPing {
+            send_timestamp: 1919,
+            magic: (),
+            client_guid: 810,
+        });
+        client.send_to(&ping, server_addr).await.unwrap();
+        // only the ping is answered
+        let mut buf = [0; MAX_MTU as usize];
+        let (len, addr) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(addr, server_addr);
+        let expected = encode(unconnected::Packet::UnconnectedPong {
+            send_timestamp: 1919,
+            server_guid: 114_514,
+            magic: (),
+            data: Bytes::from_static(b"MCPE;secondary"),
+        });
+        assert_eq!(&buf[..len], &expected[..]);
+
+        // follows the advertisement of the primary port
+        advertisement.set(Bytes::from_static(b"MCPE;updated"));
+        client.send_to(&ping, server_addr).await.unwrap();
+        let (updated, _) = client.recv_from(&mut buf).await.unwrap();
+        assert!(buf[..updated].ends_with(b"MCPE;updated"));
+
+        // hidden while draining
+        let _progress = drain.drain(Sessions::default());
+        client.send_to(&ping, server_addr).await.unwrap();
+        let silent =
+            tokio::time::timeout(Duration::from_millis(100), client.recv_from(&mut buf)).await;
+        assert!(silent.is_err());
+        responder.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pong_hook_closure() {
+        let hook = |addr: SocketAddr| async move {
+            addr.ip()
+                .is_loopback()
+                .then(|| Bytes::from_static(b"MCPE;local"))
+        };
+        let local = "127.0.0.1:19132".parse().unwrap();
+        let remote = "1.1.1.1:19132".parse().unwrap();
+        assert_eq!(
+            hook.on_ping(local).await,
+            Some(Bytes::from_static(b"MCPE;local"))
+        );
+        assert_eq!(hook.on_ping(remote).await, None);
+    }
+}
@@ -0,0 +1,199 @@
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+
+/// Handshake order config
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct HandshakeOrderConfig {
+    // Out-of-order packets tolerated from an address, e.g. the requests retransmitted after the
+    // replies are lost. The rest are rejected until the handshake is restarted
+    tolerance: u8,
+    // Limit the max count of tracked addresses, the least recently seen one will be dropped
+    max_addresses: usize,
+}
+
+impl Default for HandshakeOrderConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: 3,
+            max_addresses: 4096,
+        }
+    }
+}
+
+impl HandshakeOrderConfig {
+    #[must_use]
+    pub fn with_tolerance(mut self, tolerance: u8) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    #[must_use]
+    pub fn with_max_addresses(mut self, max_addresses: usize) -> Self {
+        self.max_addresses = max_addresses;
+        self
+    }
+}
+
+/// The offline packets driving the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum OfflinePacket {
+    Request1,
+    Request2,
+}
+
+/// The handshake state of an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum HandshakeState {
+    /// `OpenConnectionRequest1` seen and `OpenConnectionReply1` sent
+    Reply1Sent,
+    /// `OpenConnectionRequest2` seen and `OpenConnectionReply2` sent
+    Reply2Sent,
+}
+
+/// Whether to handle an offline packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Verdict {
+    /// In order
+    Accept,
+    /// Out of order but within the tolerance, handle it as usual
+    Tolerate,
+    /// Out of order beyond the tolerance, drop it silently
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    state: Option<HandshakeState>,
+    violations: u8,
+}
+
+/// Track the handshake state of each address and check the offline packets against it, so that
+/// a packet such as `OpenConnectionRequest2` without `OpenConnectionRequest1` could not confuse
+/// the offline handler.
+#[derive(Debug)]
+pub(super) struct HandshakeOrder {
+    config: HandshakeOrderConfig,
+    entries: LruCache<SocketAddr, Entry>,
+}
+
+impl HandshakeOrder {
+    pub(super) fn new(config: HandshakeOrderConfig) -> Self {
+        Self {
+            config,
+            entries: LruCache::new(
+                NonZeroUsize::new(config.max_addresses).expect("max_addresses > 0"),
+            ),
+        }
+    }
+
+    /// Check the packet from the address against its handshake state
+    pub(super) fn check(&mut self, addr: SocketAddr, packet: OfflinePacket) -> Verdict {
+        let entry = self.entries.get_or_insert_mut(addr, || Entry {
+            state: None,
+            violations: 0,
+        });
+        // a request 1 after the handshake completed restarts it, e.g. the client reconnects
+        let in_order = matches!(
+            (entry.state, packet),
+            (
+                None | Some(HandshakeState::Reply2Sent),
+                OfflinePacket::Request1
+            ) | (Some(HandshakeState::Reply1Sent), OfflinePacket::Request2)
+        );
+        if in_order {
+            entry.violations = 0;
+            return Verdict::Accept;
+        }
+        if entry.violations >= self.config.tolerance {
+            return Verdict::Reject;
+        }
+        entry.violations += 1;
+        Verdict::Tolerate
+    }
+
+    /// Move the address to the state after the reply is sent
+    pub(super) fn advance(&mut self, addr: SocketAddr, state: HandshakeState) {
+        let entry = self.entries.get_or_insert_mut(addr, || Entry {
+            state: None,
+            violations: 0,
+        });
+        entry.state = Some(state);
+    }
+
+    /// Forget the address, e.g. it is disconnected, so that it could handshake again
+    pub(super) fn reset(&mut self, addr: &SocketAddr) {
+        self.entries.pop(addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handshake_order_works() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut order = HandshakeOrder::new(HandshakeOrderConfig::default());
+        assert_eq!(order.check(addr, OfflinePacket::Request1), Verdict::Accept);
+        order.advance(addr, HandshakeState::Reply1Sent);
+        // the reply 1 is lost and the client retransmits
+        assert_eq!(
+            order.check(addr, OfflinePacket::Request1),
+            Verdict::Tolerate
+        );
+        assert_eq!(order.check(addr, OfflinePacket::Request2), Verdict::Accept);
+        order.advance(addr, HandshakeState::Reply2Sent);
+        assert_eq!(
+            order.entries.peek(&addr).and_then(|entry| entry.state),
+            Some(HandshakeState::Reply2Sent)
+        );
+
+        // the client restarts the handshake
+        assert_eq!(order.check(addr, OfflinePacket::Request1), Verdict::Accept);
+        order.advance(addr, HandshakeState::Reply1Sent);
+
+        order.reset(&addr);
+        assert_eq!(
+            order.entries.peek(&addr).and_then(|entry| entry.state),
+            None
+        );
+        assert_eq!(order.check(addr, OfflinePacket::Request1), Verdict::Accept);
+    }
+
+    #[test]
+    fn test_handshake_order_forgives_after_accept() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut order = HandshakeOrder::new(HandshakeOrderConfig::default().with_tolerance(1));
+        for _ in 0..3 {
+            assert_eq!(order.check(addr, OfflinePacket::Request1), Verdict::Accept);
+            order.advance(addr, HandshakeState::Reply1Sent);
+            // a retransmission in each handshake stays within the tolerance
+            assert_eq!(
+                order.check(addr, OfflinePacket::Request1),
+                Verdict::Tolerate
+            );
+            assert_eq!(order.check(addr, OfflinePacket::Request2), Verdict::Accept);
+            order.advance(addr, HandshakeState::Reply2Sent);
+        }
+    }
+
+    #[test]
+    fn test_handshake_order_rejects_beyond_tolerance() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut order = HandshakeOrder::new(HandshakeOrderConfig::default().with_tolerance(1));
+        // request 2 without request 1
+        assert_eq!(
+            order.check(addr, OfflinePacket::Request2),
+            Verdict::Tolerate
+        );
+        assert_eq!(order.check(addr, OfflinePacket::Request2), Verdict::Reject);
+
+        let mut strict = HandshakeOrder::new(HandshakeOrderConfig::default().with_tolerance(0));
+        assert_eq!(strict.check(addr, OfflinePacket::Request2), Verdict::Reject);
+        assert_eq!(strict.check(addr, OfflinePacket::Request1), Verdict::Accept);
+    }
+}
@@ -0,0 +1,189 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use socket2::SockRef;
+
+use crate::message::Priority;
+
+/// The max value of a DSCP codepoint (6 bits)
+const MAX_DSCP: u8 = 0b11_1111;
+
+/// DSCP marking of the outbound datagrams
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct DscpConfig {
+    // The DSCP value of each priority class, in order of immediate, high, medium and low.
+    // None means the datagrams of the class are not marked, i.e. sent with the DSCP 0.
+    classes: [Option<u8>; 4],
+}
+
+impl DscpConfig {
+    /// Mark all datagrams with the same DSCP value
+    ///
+    /// # Panics
+    ///
+    /// Panics if the DSCP value does not fit in 6 bits
+    pub fn uniform(dscp: u8) -> Self {
+        assert!(dscp <= MAX_DSCP, "DSCP must fit in 6 bits");
+        Self {
+            classes: [Some(dscp); 4],
+        }
+    }
+
+    /// Mark the datagrams of the priority class with the DSCP value
+    ///
+    /// # Panics
+    ///
+    /// Panics if the DSCP value does not fit in 6 bits
+    #[must_use]
+    pub fn with_class(mut self, priority: Priority, dscp: Option<u8>) -> Self {
+        assert!(
+            !matches!(dscp, Some(dscp) if dscp > MAX_DSCP),
+            "DSCP must fit in 6 bits"
+        );
+        self.classes[Self::index(priority)] = dscp;
+        self
+    }
+
+    fn index(priority: Priority) -> usize {
+        match priority {
+            Priority::Immediate => 0,
+            Priority::High => 1,
+            Priority::Medium => 2,
+            Priority::Low => 3,
+        }
+    }
+
+    fn dscp_of(&self, priority: Priority) -> Option<u8> {
+        self.classes[Self::index(priority)]
+    }
+}
+
+/// Mark the datagrams sent from a socket. The DSCP value is a socket option, so it is only
+/// updated when the priority class of the next datagram requires a different value.
+#[derive(Debug)]
+pub(super) struct DscpMarker {
+    config: DscpConfig,
+    // The DSCP value of the socket, unmarked sockets start with 0
+    current: u8,
+}
+
+impl DscpMarker {
+    pub(super) fn new(config: DscpConfig) -> Self {
+        Self { config, current: 0 }
+    }
+
+    /// The socket is replaced, the new one starts unmarked
+    pub(super) fn forget(&mut self) {
+        self.current = 0;
+    }
+
+    /// The DSCP value the datagrams of the priority class are marked with
+    pub(super) fn dscp_of(&self, priority: Priority) -> u8 {
+        self.config.dscp_of(priority).unwrap_or(0)
+    }
+
+    /// Prepare the socket before sending a datagram of the priority class
+    pub(super) fn mark(&mut self, socket: SockRef<'_>, priority: Priority) -> io::Result<()> {
+        let dscp = self.dscp_of(priority);
+        if self.current == dscp {
+            return Ok(());
+        }
+        // DSCP takes the upper 6 bits of the TOS field (the traffic class in IPv6), the rest 2
+        // bits are used by ECN
+        let tos = u32::from(dscp) << 2;
+        if socket.local_addr()?.is_ipv6() {
+            set_tclass_v6(&socket, tos)?;
+        } else {
+            socket.set_tos(tos)?;
+        }
+        self.current = dscp;
+        Ok(())
+    }
+}
+
+/// The priority class of the datagram being sent, set by the flush scheduler and taken by the
+/// socket. The layers over the socket pass the datagrams one at a time, so it is the class of the
+/// datagram the socket is queuing. The others, e.g. the offline replies, take the default class.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Marking(Arc<Mutex<Priority>>);
+
+impl Marking {
+    pub(super) fn set(&self, priority: Priority) {
+        *self.0.lock().expect("marking lock poisoned") = priority;
+    }
+
+    pub(super) fn take(&self) -> Priority {
+        std::mem::take(&mut *self.0.lock().expect("marking lock poisoned"))
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_tclass_v6(socket: &SockRef<'_>, tclass: u32) -> io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_tclass_v6(_socket: &SockRef<'_>, _tclass: u32) -> io::Result<()> {
+    tracing::debug!("DSCP marking of IPv6 sockets is not supported on this platform");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+
+    use super::*;
+
+    #[test]
+    fn test_dscp_marker_works() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = DscpConfig::default()
+            .with_class(Priority::Immediate, Some(46))
+            .with_class(Priority::Low, Some(8));
+        let mut marker = DscpMarker::new(config);
+
+        marker
+            .mark(SockRef::from(&socket), Priority::Immediate)
+            .unwrap();
+        assert_eq!(SockRef::from(&socket).tos().unwrap(), 46 << 2);
+        // unmarked class resets the value
+        marker
+            .mark(SockRef::from(&socket), Priority::Medium)
+            .unwrap();
+        assert_eq!(SockRef::from(&socket).tos().unwrap(), 0);
+        marker.mark(SockRef::from(&socket), Priority::Low).unwrap();
+        assert_eq!(SockRef::from(&socket).tos().unwrap(), 8 << 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dscp_marker_ipv6() {
+        let Ok(socket) = UdpSocket::bind("[::1]:0") else {
+            // no ipv6 in the environment
+            return;
+        };
+        let mut marker = DscpMarker::new(DscpConfig::uniform(46));
+        marker.mark(SockRef::from(&socket), Priority::High).unwrap();
+        assert_eq!(SockRef::from(&socket).tclass_v6().unwrap(), 46 << 2);
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{ready, FutureExt, Sink, SinkExt, Stream};
+use pin_project_lite::pin_project;
+use tracing::{debug, error};
+
+/// The magic of the legacy query (GameSpy4/UT3) protocol, it never collides with raknet
+/// datagrams since 0xfe is not an offline packet id.
+const QUERY_MAGIC: [u8; 2] = [0xfe, 0xfd];
+
+const TYPE_HANDSHAKE: u8 = 0x09;
+const TYPE_STAT: u8 = 0x00;
+
+// magic (2 bytes) + type (1 byte) + session id (4 bytes)
+const HEADER_SIZE: usize = 7;
+// header + challenge token (4 bytes)
+const BASIC_STAT_SIZE: usize = 11;
+// basic stat + padding (4 bytes)
+const FULL_STAT_SIZE: usize = 15;
+
+// The challenge tokens are rotated every 30 seconds, the previous one is still accepted
+const TOKEN_PERIOD: Duration = Duration::from_secs(30);
+
+/// The server information reported to the query protocol
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct QueryInfo {
+    hostname: String,
+    game_type: String,
+    game_id: String,
+    version: String,
+    plugins: String,
+    map: String,
+    num_players: usize,
+    max_players: usize,
+    host_port: u16,
+    host_ip: String,
+    players: Vec<String>,
+}
+
+impl Default for QueryInfo {
+    fn default() -> Self {
+        // the values reported by the vanilla bedrock server
+        Self {
+            hostname: "Dedicated Server".to_string(),
+            game_type: "SMP".to_string(),
+            game_id: "MINECRAFTPE".to_string(),
+            version: String::new(),
+            plugins: String::new(),
+            map: "Bedrock level".to_string(),
+            num_players: 0,
+            max_players: 10,
+            host_port: 19132,
+            host_ip: "0.0.0.0".to_string(),
+            players: Vec::new(),
+        }
+    }
+}
+
+impl QueryInfo {
+    #[must_use]
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = hostname.into();
+        self
+    }
+
+    #[must_use]
+    pub fn with_game_type(mut self, game_type: impl Into<String>) -> Self {
+        self.game_type = game_type.into();
+        self
+    }
+
+    #[must_use]
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    #[must_use]
+    pub fn with_map(mut self, map: impl Into<String>) -> Self {
+        self.map = map.into();
+        self
+    }
+
+    #[must_use]
+    pub fn with_max_players(mut self, max_players: usize) -> Self {
+        self.max_players = max_players;
+        self
+    }
+
+    /// The names of the online players, their count is reported as the number of players
+    #[must_use]
+    pub fn with_players(mut self, players: Vec<String>) -> Self {
+        self.num_players = players.len();
+        self.players = players;
+        self
+    }
+
+    /// The address of the game server reported to the query clients
+    #[must_use]
+    pub fn with_host(mut self, host_ip: impl Into<String>, host_port: u16) -> Self {
+        self.host_ip = host_ip.into();
+        self.host_port = host_port;
+        self
+    }
+
+    fn write_basic(&self, buf: &mut BytesMut) {
+        put_str(buf, &self.hostname);
+        put_str(buf, &self.game_type);
+        put_str(buf, &self.map);
+        put_str(buf, &self.num_players.to_string());
+        put_str(buf, &self.max_players.to_string());
+        buf.put_u16_le(self.host_port);
+        put_str(buf, &self.host_ip);
+    }
+
+    fn write_full(&self, buf: &mut BytesMut) {
+        buf.put_slice(b"splitnum\0\x80\0");
+        for (key, value) in [
+            ("hostname", self.hostname.as_str()),
+            ("gametype", &self.game_type),
+            ("game_id", &self.game_id),
+            ("version", &self.version),
+            ("plugins", &self.plugins),
+            ("map", &self.map),
+            ("numplayers", &self.num_players.to_string()),
+            ("maxplayers", &self.max_players.to_string()),
+            ("hostport", &self.host_port.to_string()),
+            ("hostip", &self.host_ip),
+        ] {
+            put_str(buf, key);
+            put_str(buf, value);
+        }
+        buf.put_u8(0);
+        buf.put_slice(b"\x01player_\0\0");
+        for player in &self.players {
+            put_str(buf, player);
+        }
+        buf.put_u8(0);
+    }
+}
+
+fn put_str(buf: &mut BytesMut, s: &str) {
+    buf.put_slice(s.as_bytes());
+    buf.put_u8(0);
+}
+
+/// The query information shared by the server handle
+#[derive(Debug, Clone, Default)]
+pub(super) struct SharedQueryInfo {
+    info: Arc<RwLock<QueryInfo>>,
+}
+
+impl SharedQueryInfo {
+    pub(super) fn new(info: QueryInfo) -> Self {
+        Self {
+            info: Arc::new(RwLock::new(info)),
+        }
+    }
+
+    pub(super) fn set(&self, info: QueryInfo) {
+        *self.info.write().expect("query info lock poisoned") = info;
+    }
+}
+
+/// Stateless challenge tokens derived from the source ip and the current period
+#[derive(Debug)]
+struct ChallengeTokens {
+    key: RandomState,
+    start: Instant,
+}
+
+impl ChallengeTokens {
+    fn new() -> Self {
+        Self {
+            key: RandomState::new(),
+            start: Instant::now(),
+        }
+    }
+
+    fn period(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_secs() / TOKEN_PERIOD.as_secs()
+    }
+
+    fn token_of(&self, ip: IpAddr, period: u64) -> i32 {
+        let mut hasher = self.key.build_hasher();
+        ip.hash(&mut hasher);
+        period.hash(&mut hasher);
+        hasher.finish() as i32
+    }
+
+    fn issue(&self, ip: IpAddr, now: Instant) -> i32 {
+        self.token_of(ip, self.period(now))
+    }
+
+    fn verify(&self, ip: IpAddr, token: i32, now: Instant) -> bool {
+        let period = self.period(now);
+        self.token_of(ip, period) == token || (period > 0 && self.token_of(ip, period - 1) == token)
+    }
+}
+
+pin_project! {
+    /// Respond the legacy query protocol on the raknet port so that server listing sites work
+    /// out of the box, should be placed before the packets are decoded.
+    pub(super) struct QueryResponder<F> {
+        #[pin]
+        frame: F,
+        info: SharedQueryInfo,
+        tokens: ChallengeTokens,
+    }
+}
+
+pub(super) trait Queried: Sized {
+    fn queried(self, info: SharedQueryInfo) -> QueryResponder<Self>;
+}
+
+impl<F> Queried for F {
+    fn queried(self, info: SharedQueryInfo) -> QueryResponder<Self> {
+        QueryResponder {
+            frame: self,
+            info,
+            tokens: ChallengeTokens::new(),
+        }
+    }
+}
+
+/// Make the response of a query datagram, None means the datagram should be ignored.
+fn respond(
+    info: &QueryInfo,
+    tokens: &ChallengeTokens,
+    ip: IpAddr,
+    mut raw: &[u8],
+    now: Instant,
+) -> Option<BytesMut> {
+    if raw.len() < HEADER_SIZE {
+        return None;
+    }
+    raw.advance(QUERY_MAGIC.len());
+    let kind = raw.get_u8();
+    let session_id = raw.get_i32();
+    let mut buf = BytesMut::new();
+    buf.put_u8(kind);
+    buf.put_i32(session_id);
+    match kind {
+        TYPE_HANDSHAKE => {
+            put_str(&mut buf, &tokens.issue(ip, now).to_string());
+        }
+        TYPE_STAT => {
+            if raw.len() < BASIC_STAT_SIZE - HEADER_SIZE {
+                return None;
+            }
+            if !tokens.verify(ip, raw.get_i32(), now) {
+                return None;
+            }
+            if raw.len() >= FULL_STAT_SIZE - BASIC_STAT_SIZE {
+                info.write_full(&mut buf);
+            } else {
+                info.write_basic(&mut buf);
+            }
+        }
+        _ => return None,
+    }
+    Some(buf)
+}
+
+impl<F, E> Stream for QueryResponder<F>
+where
+    F: Stream<Item = Result<(BytesMut, SocketAddr), E>> + Sink<(BytesMut, SocketAddr), Error = E>,
+    E: std::fmt::Display,
+{
+    type Item = Result<(BytesMut, SocketAddr), E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let Some((raw, addr)) = ready!(this.frame.as_mut().poll_next(cx)?) else {
+                return Poll::Ready(None);
+            };
+            if !raw.starts_with(&QUERY_MAGIC) {
+                return Poll::Ready(Some(Ok((raw, addr))));
+            }
+            let info = this.info.info.read().expect("query info lock poisoned");
+            let resp = respond(&info, this.tokens, addr.ip(), &raw, Instant::now());
+            drop(info);
+            let Some(resp) = resp else {
+                debug!("ignore invalid query from {addr}");
+                continue;
+            };
+            let mut send = this.frame.send((resp, addr));
+            if let Err(err) = ready!(send.poll_unpin(cx)) {
+                error!("failed send query response to {addr}, error {err}");
+            }
+        }
+    }
+}
+
+impl<F, T> Sink<T> for QueryResponder<F>
+where
+    F: Sink<T>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.project().frame.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    /// A socket with queued inbound datagrams which records the outbound datagrams
+    #[derive(Default)]
+    struct Socket {
+        inbound: VecDeque<(BytesMut, SocketAddr)>,
+        outbound: Vec<(BytesMut, SocketAddr)>,
+    }
+
+    impl Stream for Socket {
+        type Item = Result<(BytesMut, SocketAddr), Infallible>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.inbound.pop_front().map(Ok))
+        }
+    }
+
+    impl Sink<(BytesMut, SocketAddr)> for Socket {
+        type Error = Infallible;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            item: (BytesMut, SocketAddr),
+        ) -> Result<(), Self::Error> {
+            self.outbound.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_query_handshake_and_stat() {
+        let tokens = ChallengeTokens::new();
+        let info = QueryInfo::default();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        let resp = respond(&info, &tokens, ip, b"\xfe\xfd\x09\0\0\0\x01", now).unwrap();
+        assert_eq!(&resp[..5], b"\x09\0\0\0\x01");
+        let token: i32 = std::str::from_utf8(&resp[5..resp.len() - 1])
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let mut stat = b"\xfe\xfd\x00\0\0\0\x01".to_vec();
+        stat.extend_from_slice(&token.to_be_bytes());
+        let basic = respond(&info, &tokens, ip, &stat, now).unwrap();
+        assert!(
+            basic.starts_with(b"\x00\0\0\0\x01Dedicated Server\0SMP\0Bedrock level\x000\x0010\0")
+        );
+        assert!(basic.ends_with(b"0.0.0.0\0"));
+
+        stat.extend_from_slice(&[0; 4]);
+        let full = respond(&info, &tokens, ip, &stat, now).unwrap();
+        assert!(full.starts_with(b"\x00\0\0\0\x01splitnum\0\x80\0hostname\0Dedicated Server\0"));
+        assert!(full.ends_with(b"\x01player_\0\0\0"));
+
+        // tokens are bound to the source ip and expire after two periods
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        assert!(respond(&info, &tokens, other, &stat, now).is_none());
+        assert!(respond(&info, &tokens, ip, &stat, now + TOKEN_PERIOD).is_some());
+        assert!(respond(&info, &tokens, ip, &stat, now + TOKEN_PERIOD * 2).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_query_responder_works() {
+        let addr: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let mut socket = Socket::default();
+        socket
+            .inbound
+            .push_back((BytesMut::from(&b"\xfe\xfd\x09\0\0\0\x01"[..]), addr));
+        socket
+            .inbound
+            .push_back((BytesMut::from(&b"\x01raknet"[..]), addr));
+
+        let mut responder = socket.queried(SharedQueryInfo::default());
+        assert_eq!(
+            responder.next().await.unwrap().unwrap().0,
+            &b"\x01raknet"[..]
+        );
+        assert!(responder.next().await.is_none());
+        let outbound = responder.frame.outbound;
+        assert_eq!(outbound.len(), 1);
+        assert!(outbound[0].0.starts_with(b"\x09\0\0\0\x01"));
+    }
+}
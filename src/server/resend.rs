@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use crate::event::{DisconnectReason, Event};
+use crate::packet::connected::Frame;
+
+/// What to do when the retransmission limits of a connection are exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ResendExceeded {
+    /// Give up the frames and report their receipts lost by [`Event::ReceiptLost`]. Abandoning
+    /// an ordered frame blocks its channel at the peer, so it suits the unordered reliable
+    /// messages.
+    #[default]
+    Abandon,
+    /// Close the connection with [`DisconnectReason::RetransmissionLimit`]
+    Disconnect,
+}
+
+/// Retransmission limits of each connection, so that a pathological peer could not consume
+/// unbounded resend bandwidth
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ResendLimitConfig {
+    // The max times a reliable frame is sent, including the first one. 0 means no limit
+    max_attempts: u32,
+    // The max retransmitted bytes per second, 0 means no limit
+    bytes_per_sec: u32,
+    // What to do with the frames exceeding either limit
+    on_exceeded: ResendExceeded,
+}
+
+impl ResendLimitConfig {
+    #[must_use]
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    #[must_use]
+    pub fn with_bytes_per_sec(mut self, bytes_per_sec: u32) -> Self {
+        self.bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    #[must_use]
+    pub fn with_on_exceeded(mut self, on_exceeded: ResendExceeded) -> Self {
+        self.on_exceeded = on_exceeded;
+        self
+    }
+}
+
+/// The reliable frames of a datagram waiting for its ack
+#[derive(Debug)]
+struct Sent {
+    frames: Vec<Frame<Bytes>>,
+    sent_at: Instant,
+    // Whether the frames have been sent before, its ack is ambiguous for the RTT sampling
+    retransmitted: bool,
+}
+
+/// Track the reliable frames in flight by datagram. A frame may be carried by more than one
+/// datagram after repacking, so it is acknowledged as soon as any of them is acked, and only the
+/// frames still unacked are handed back for retransmission when a datagram is lost.
+#[derive(Debug, Default)]
+pub(super) struct ResendMap {
+    datagrams: HashMap<u32, Sent>,
+    // Reliable frame indices not acknowledged by any datagram yet, and the times they are sent
+    unacked: HashMap<u32, u32>,
+}
+
+impl ResendMap {
+    pub(super) fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Record the frames sent in the datagram of the sequence number, the unreliable frames are
+    /// not kept since they are never resent. Returns the bytes of the frames kept.
+    pub(super) fn record(
+        &mut self,
+        seq_num: u32,
+        frames: impl IntoIterator<Item = Frame<Bytes>>,
+        now: Instant,
+        retransmitted: bool,
+    ) -> usize {
+        let frames: Vec<_> = frames
+            .into_iter()
+            .filter(|frame| frame.reliable_frame_index.is_some())
+            .collect();
+        if frames.is_empty() {
+            return 0;
+        }
+        let kept = frames.iter().map(Frame::size).sum();
+        for idx in frames.iter().filter_map(|frame| frame.reliable_frame_index) {
+            *self.unacked.entry(idx.0).or_default() += 1;
+        }
+        self.datagrams.insert(
+            seq_num,
+            Sent {
+                frames,
+                sent_at: now,
+                retransmitted,
+            },
+        );
+        kept
+    }
+
+    /// The datagram is acknowledged, returns when it was sent and whether it was a
+    /// retransmission, None if it is not tracked.
+    pub(super) fn on_ack(&mut self, seq_num: u32) -> Option<(Instant, bool)> {
+        let sent = self.datagrams.remove(&seq_num)?;
+        for frame in &sent.frames {
+            if let Some(idx) = frame.reliable_frame_index {
+                self.unacked.remove(&idx.0);
+            }
+        }
+        Some((sent.sent_at, sent.retransmitted))
+    }
+
+    /// The datagram is lost, by a nack or the retransmission timeout. Returns its frames still
+    /// unacked, which should be bundled into new datagrams.
+    pub(super) fn on_lost(&mut self, seq_num: u32) -> Vec<Frame<Bytes>> {
+        let Some(sent) = self.datagrams.remove(&seq_num) else {
+            return Vec::new();
+        };
+        sent.frames
+            .into_iter()
+            .filter(|frame| {
+                frame
+                    .reliable_frame_index
+                    .is_some_and(|idx| self.unacked.contains_key(&idx.0))
+            })
+            .collect()
+    }
+
+    /// The times the reliable frame has been sent, 0 if it is acknowledged or not tracked
+    pub(super) fn attempts(&self, reliable_frame_index: u32) -> u32 {
+        self.unacked
+            .get(&reliable_frame_index)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Stop tracking the abandoned frame, the datagrams still carrying it will not resend it
+    fn abandon(&mut self, reliable_frame_index: u32) {
+        self.unacked.remove(&reliable_frame_index);
+    }
+}
+
+/// The decision on the frames of a lost datagram
+#[derive(Debug, Default)]
+pub(super) struct Verdict {
+    /// Bundle them into new datagrams
+    pub(super) resend: Vec<Frame<Bytes>>,
+    /// Given up by [`ResendExceeded::Abandon`]
+    pub(super) abandoned: Vec<Frame<Bytes>>,
+    /// Close the connection by [`ResendExceeded::Disconnect`]
+    pub(super) disconnect: Option<DisconnectReason>,
+}
+
+impl Verdict {
+    /// The events reporting the receipts of the abandoned frames lost
+    pub(super) fn receipts_lost(&self) -> impl Iterator<Item = Event> + '_ {
+        self.abandoned
+            .iter()
+            .filter_map(|frame| frame.reliable_frame_index)
+            .map(|idx| Event::ReceiptLost {
+                reliable_frame_index: idx.0,
+            })
+    }
+}
+
+/// Enforce the [`ResendLimitConfig`] on the lost frames of a connection. The retransmitted bytes
+/// are limited by a token bucket holding at most one second of the budget.
+#[derive(Debug)]
+pub(super) struct ResendLimiter {
+    config: ResendLimitConfig,
+    tokens: f64,
+    last: Instant,
+}
+
+impl ResendLimiter {
+    pub(super) fn new(config: ResendLimitConfig, now: Instant) -> Self {
+        Self {
+            config,
+            tokens: f64::from(config.bytes_per_sec),
+            last: now,
+        }
+    }
+
+    /// Decide which of the lost frames returned by [`ResendMap::on_lost`] are resent
+    pub(super) fn judge(
+        &mut self,
+        map: &mut ResendMap,
+        lost: Vec<Frame<Bytes>>,
+        now: Instant,
+    ) -> Verdict {
+        self.refill(now);
+        let mut verdict = Verdict::default();
+        for frame in lost {
+            let attempts = frame
+                .reliable_frame_index
+                .map_or(0, |idx| map.attempts(idx.0));
+            let exhausted = self.config.max_attempts != 0 && attempts >= self.config.max_attempts;
+            if !exhausted && self.take(frame.size()) {
+                verdict.resend.push(frame);
+                continue;
+            }
+            match self.config.on_exceeded {
+                ResendExceeded::Abandon => {
+                    if let Some(idx) = frame.reliable_frame_index {
+                        map.abandon(idx.0);
+                    }
+                    verdict.abandoned.push(frame);
+                }
+                ResendExceeded::Disconnect => {
+                    verdict.disconnect = Some(DisconnectReason::RetransmissionLimit);
+                    verdict.resend.clear();
+                    return verdict;
+                }
+            }
+        }
+        verdict
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        let budget = f64::from(self.config.bytes_per_sec);
+        self.tokens = (self.tokens + elapsed * budget).min(budget);
+    }
+
+    /// Try to take the bytes from the budget
+    fn take(&mut self, bytes: usize) -> bool {
+        if self.config.bytes_per_sec == 0 {
+            return true;
+        }
+        let bytes = bytes as f64;
+        if self.tokens < bytes {
+            return false;
+        }
+        self.tokens -= bytes;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::packet::connected::{Flags, Reliability, Uint24le};
+
+    fn frame(reliable_frame_index: Option<u32>) -> Frame<Bytes> {
+        let reliability = if reliable_frame_index.is_some() {
+            Reliability::Reliable
+        } else {
+            Reliability::Unreliable
+        };
+        Frame {
+            flags: Flags::parse((reliability as u8) << 5),
+            reliable_frame_index: reliable_frame_index.map(Uint24le),
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            body: Bytes::from_static(&[0; 7]),
+        }
+    }
+
+    fn indices(frames: &[Frame<Bytes>]) -> Vec<u32> {
+        frames
+            .iter()
+            .filter_map(|frame| frame.reliable_frame_index)
+            .map(|idx| idx.0)
+            .collect()
+    }
+
+    #[test]
+    fn test_resend_only_unacked_frames() {
+        let now = Instant::now();
+        let mut map = ResendMap::default();
+        let kept = map.record(0, [frame(Some(0)), frame(None), frame(Some(1))], now, false);
+        assert_eq!(kept, 2 * frame(Some(0)).size());
+        // frame 1 is repacked with frame 2 after a timeout of datagram 0
+        map.record(1, [frame(Some(1)), frame(Some(2))], now, true);
+        assert_eq!(map.record(2, [frame(None)], now, false), 0);
+        assert_eq!(map.datagrams.len(), 2);
+
+        assert_eq!(map.on_ack(1), Some((now, true)));
+        assert_eq!(indices(&map.on_lost(0)), vec![0]);
+        assert!(map.on_lost(0).is_empty());
+        assert!(map.on_ack(2).is_none());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_resend_max_attempts() {
+        let now = Instant::now();
+        let mut map = ResendMap::default();
+        let config = ResendLimitConfig::default().with_max_attempts(2);
+        let mut limiter = ResendLimiter::new(config, now);
+
+        map.record(0, [frame(Some(0)), frame(Some(1))], now, false);
+        let lost = map.on_lost(0);
+        let verdict = limiter.judge(&mut map, lost, now);
+        assert_eq!(indices(&verdict.resend), vec![0, 1]);
+        assert!(verdict.abandoned.is_empty());
+
+        // the second attempt is the last one
+        map.record(1, verdict.resend, now, true);
+        let again = map.on_lost(1);
+        let exhausted = limiter.judge(&mut map, again, now);
+        assert!(exhausted.resend.is_empty());
+        assert_eq!(indices(&exhausted.abandoned), vec![0, 1]);
+        assert_eq!(
+            exhausted.receipts_lost().collect::<Vec<_>>(),
+            vec![
+                Event::ReceiptLost {
+                    reliable_frame_index: 0
+                },
+                Event::ReceiptLost {
+                    reliable_frame_index: 1
+                }
+            ]
+        );
+        assert_eq!(map.attempts(0), 0);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_resend_byte_budget() {
+        let now = Instant::now();
+        let mut map = ResendMap::default();
+        let size = frame(Some(0)).size();
+        let config = ResendLimitConfig::default()
+            .with_bytes_per_sec(u32::try_from(size * 2).unwrap())
+            .with_on_exceeded(ResendExceeded::Disconnect);
+        let mut limiter = ResendLimiter::new(config, now);
+
+        map.record(0, [frame(Some(0)), frame(Some(1))], now, false);
+        let lost = map.on_lost(0);
+        let verdict = limiter.judge(&mut map, lost, now);
+        assert_eq!(indices(&verdict.resend), vec![0, 1]);
+        assert_eq!(verdict.disconnect, None);
+
+        // the budget is spent
+        map.record(1, verdict.resend, now, true);
+        let again = map.on_lost(1);
+        let spent = limiter.judge(&mut map, again, now);
+        assert!(spent.resend.is_empty());
+        assert_eq!(
+            spent.disconnect,
+            Some(DisconnectReason::RetransmissionLimit)
+        );
+
+        // refilled after a second
+        map.record(2, [frame(Some(2))], now, false);
+        let expired = map.on_lost(2);
+        let later = now + Duration::from_secs(1);
+        let refilled = limiter.judge(&mut map, expired, later);
+        assert_eq!(indices(&refilled.resend), vec![2]);
+    }
+}
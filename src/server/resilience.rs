@@ -0,0 +1,254 @@
+use std::io;
+use std::time::Duration;
+
+/// What to do when the socket keeps failing after the retries are exhausted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SocketErrorPolicy {
+    /// Keep retrying at the max backoff
+    Retry,
+    /// Rebind the socket to the same address
+    #[default]
+    Rebind,
+    /// Surface a [`crate::event::ServerEvent::SocketError`] once, then keep retrying at the max
+    /// backoff
+    Surface,
+}
+
+/// Socket error resilience config
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SocketErrorConfig {
+    // What to do after the retries are exhausted
+    policy: SocketErrorPolicy,
+    // The max consecutive retries of the transient errors before applying the policy
+    max_retries: u32,
+    // The backoff before the first retry, doubled on each consecutive failure
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for SocketErrorConfig {
+    fn default() -> Self {
+        Self {
+            policy: SocketErrorPolicy::default(),
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl SocketErrorConfig {
+    #[must_use]
+    pub fn with_policy(mut self, policy: SocketErrorPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    #[must_use]
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// The backoff before the first retry, doubled on each consecutive failure up to `max`
+    #[must_use]
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+}
+
+/// How the server loop should recover from a socket error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Recovery {
+    /// Carry on with the next datagram
+    Ignore,
+    /// Retry the socket operation after the backoff
+    Retry(Duration),
+    /// Rebind the socket to the same address
+    Rebind,
+    /// Surface the error to the application and carry on
+    Surface,
+}
+
+/// Decide the recovery of the socket errors, so that a transient error never kills the server
+/// loop.
+///
+/// The errors caused by the ICMP messages of earlier datagrams (e.g. `ECONNREFUSED` on Linux,
+/// `WSAECONNRESET` on Windows) say nothing about the socket and are ignored, so are the errors
+/// about a single datagram (e.g. `EINVAL` for a destination of port 0, `EMSGSIZE`), which a
+/// remote peer could trigger at will. The errors which may recover by themselves (e.g. `EPERM`
+/// under conntrack pressure, the interface is down) are retried with backoff before applying the
+/// [`SocketErrorPolicy`], the other socket-level errors apply it at once.
+#[derive(Debug)]
+pub(super) struct SocketRecovery {
+    config: SocketErrorConfig,
+    // Consecutive failures since the last success
+    failures: u32,
+    surfaced: bool,
+}
+
+impl SocketRecovery {
+    pub(super) fn new(config: SocketErrorConfig) -> Self {
+        Self {
+            config,
+            failures: 0,
+            surfaced: false,
+        }
+    }
+
+    /// The socket operation succeeded
+    pub(super) fn on_success(&mut self) {
+        self.failures = 0;
+        self.surfaced = false;
+    }
+
+    /// The socket operation failed with the error
+    pub(super) fn on_error(&mut self, err: &io::Error) -> Recovery {
+        if is_message_size(err) {
+            return Recovery::Ignore;
+        }
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::InvalidInput => return Recovery::Ignore,
+            io::ErrorKind::PermissionDenied
+            | io::ErrorKind::NetworkDown
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::OutOfMemory
+            | io::ErrorKind::TimedOut => {
+                self.failures = self.failures.saturating_add(1);
+                if self.failures <= self.config.max_retries {
+                    return Recovery::Retry(self.backoff());
+                }
+            }
+            _ => self.failures = self.failures.saturating_add(1),
+        }
+        match self.config.policy {
+            SocketErrorPolicy::Retry => Recovery::Retry(self.config.max_backoff),
+            SocketErrorPolicy::Rebind => {
+                // give the rebound socket a full round of retries
+                self.failures = 0;
+                Recovery::Rebind
+            }
+            SocketErrorPolicy::Surface => {
+                if self.surfaced {
+                    return Recovery::Retry(self.config.max_backoff);
+                }
+                self.surfaced = true;
+                Recovery::Surface
+            }
+        }
+    }
+
+    /// The backoff once the retries are exhausted
+    pub(super) fn max_backoff(&self) -> Duration {
+        self.config.max_backoff
+    }
+
+    fn backoff(&self) -> Duration {
+        let exp = self.failures.saturating_sub(1).min(16);
+        self.config
+            .initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.config.max_backoff)
+    }
+}
+
+/// Whether the datagram is larger than the socket could send or receive at once
+fn is_message_size(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        err.raw_os_error() == Some(libc::EMSGSIZE)
+    }
+    #[cfg(windows)]
+    {
+        // WSAEMSGSIZE
+        err.raw_os_error() == Some(10040)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = err;
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn error(kind: io::ErrorKind) -> io::Error {
+        io::Error::from(kind)
+    }
+
+    #[test]
+    fn test_socket_recovery_retry_then_rebind() {
+        let ms = Duration::from_millis;
+        let config = SocketErrorConfig::default().with_max_retries(3);
+        let mut recovery = SocketRecovery::new(config);
+        // the icmp of an earlier datagram
+        assert_eq!(
+            recovery.on_error(&error(io::ErrorKind::ConnectionRefused)),
+            Recovery::Ignore
+        );
+        let denied = error(io::ErrorKind::PermissionDenied);
+        assert_eq!(recovery.on_error(&denied), Recovery::Retry(ms(10)));
+        assert_eq!(recovery.on_error(&denied), Recovery::Retry(ms(20)));
+        assert_eq!(recovery.on_error(&denied), Recovery::Retry(ms(40)));
+        assert_eq!(recovery.on_error(&denied), Recovery::Rebind);
+        assert_eq!(recovery.on_error(&denied), Recovery::Retry(ms(10)));
+
+        recovery.on_success();
+        // not transient, apply the policy at once
+        assert_eq!(
+            recovery.on_error(&error(io::ErrorKind::Unsupported)),
+            Recovery::Rebind
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_recovery_ignore_datagram_errors() {
+        let mut recovery = SocketRecovery::new(SocketErrorConfig::default());
+        // a remote peer could trigger these by the addresses or the sizes it makes us send to
+        for _ in 0..3 {
+            assert_eq!(
+                recovery.on_error(&error(io::ErrorKind::InvalidInput)),
+                Recovery::Ignore
+            );
+            assert_eq!(
+                recovery.on_error(&io::Error::from_raw_os_error(libc::EMSGSIZE)),
+                Recovery::Ignore
+            );
+        }
+        // they never count as the failures of the socket
+        assert_eq!(
+            recovery.on_error(&error(io::ErrorKind::PermissionDenied)),
+            Recovery::Retry(Duration::from_millis(10))
+        );
+    }
+
+    #[test]
+    fn test_socket_recovery_surface() {
+        let config = SocketErrorConfig::default()
+            .with_max_retries(0)
+            .with_policy(SocketErrorPolicy::Surface);
+        let mut recovery = SocketRecovery::new(config);
+        let down = error(io::ErrorKind::NetworkDown);
+        assert_eq!(recovery.on_error(&down), Recovery::Surface);
+        assert_eq!(
+            recovery.on_error(&down),
+            Recovery::Retry(Duration::from_secs(1))
+        );
+        recovery.on_success();
+        assert_eq!(recovery.on_error(&down), Recovery::Surface);
+    }
+}
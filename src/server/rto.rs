@@ -0,0 +1,174 @@
+use std::time::{Duration, Instant};
+
+use super::ack::MIN_RTO;
+use crate::errors::ConfigError;
+
+/// Bounds of the retransmission timeout
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RtoConfig {
+    // The retransmission timeout never goes below it, the acks of the peer must be flushed
+    // within it
+    min: Duration,
+    // The retransmission timeout never goes above it, including the backoff
+    max: Duration,
+    // The retransmission timeout before the first round trip is measured
+    initial: Duration,
+}
+
+impl Default for RtoConfig {
+    fn default() -> Self {
+        Self {
+            min: MIN_RTO,
+            max: Duration::from_secs(10),
+            initial: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RtoConfig {
+    /// Clamp the retransmission timeout into `min..=max`
+    #[must_use]
+    pub fn with_bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    pub(super) fn min(&self) -> Duration {
+        self.min
+    }
+
+    pub(super) fn validate(&self) -> Result<(), ConfigError> {
+        if self.min > self.max {
+            return Err(ConfigError::RtoRange(self.min, self.max));
+        }
+        Ok(())
+    }
+}
+
+/// Estimate the round trip time and the retransmission timeout of a connection as RFC 6298,
+/// applying Karn's algorithm: the retransmitted datagrams are never sampled since their acks are
+/// ambiguous, and the backed off timeout is kept until a fresh sample arrives.
+#[derive(Debug)]
+pub(super) struct RttEstimator {
+    config: RtoConfig,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    // Doubled on each timeout, reset by a valid sample
+    backoff: u32,
+}
+
+impl RttEstimator {
+    pub(super) fn new(config: RtoConfig) -> Self {
+        Self {
+            config,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            backoff: 0,
+        }
+    }
+
+    /// A datagram sent at `sent_at` is acknowledged at now. Returns false if it is not sampled
+    /// because it has been retransmitted.
+    pub(super) fn on_acked(&mut self, sent_at: Instant, now: Instant, retransmitted: bool) -> bool {
+        if retransmitted {
+            return false;
+        }
+        let rtt = now.saturating_duration_since(sent_at);
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let diff = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + diff / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        self.backoff = 0;
+        true
+    }
+
+    /// The retransmission timer fired, back off the timeout exponentially
+    pub(super) fn on_timeout(&mut self) {
+        // 2^16 times is long beyond any sane max bound
+        self.backoff = (self.backoff + 1).min(16);
+    }
+
+    /// The current retransmission timeout, clamped by [`RtoConfig`]
+    pub(super) fn rto(&self) -> Duration {
+        let base = self
+            .srtt
+            .map_or(self.config.initial, |srtt| srtt + self.rttvar * 4);
+        base.saturating_mul(1 << self.backoff)
+            .clamp(self.config.min, self.config.max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rtt_estimator_works() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let mut estimator = RttEstimator::new(RtoConfig::default());
+        assert_eq!(estimator.rto(), ms(1000));
+
+        assert!(estimator.on_acked(now, now + ms(100), false));
+        assert_eq!(estimator.srtt, Some(ms(100)));
+        // 100 + 4 * 50
+        assert_eq!(estimator.rto(), ms(300));
+
+        assert!(estimator.on_acked(now, now + ms(200), false));
+        assert_eq!(estimator.srtt, Some(ms(112) + Duration::from_micros(500)));
+        // 112.5 + 4 * (37.5 + 25)
+        assert_eq!(estimator.rto(), ms(362) + Duration::from_micros(500));
+    }
+
+    #[test]
+    fn test_rtt_estimator_karn() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let mut estimator = RttEstimator::new(RtoConfig::default());
+        estimator.on_acked(now, now + ms(100), false);
+
+        estimator.on_timeout();
+        estimator.on_timeout();
+        assert_eq!(estimator.rto(), ms(1200));
+        // the ack of a retransmitted datagram neither samples nor resets the backoff
+        assert!(!estimator.on_acked(now, now + ms(1000), true));
+        assert_eq!(estimator.srtt, Some(ms(100)));
+        assert_eq!(estimator.rto(), ms(1200));
+
+        for _ in 0..100 {
+            estimator.on_timeout();
+        }
+        assert_eq!(estimator.rto(), ms(10_000));
+        estimator.on_acked(now, now + ms(100), false);
+        assert_eq!(estimator.rto(), ms(250));
+    }
+
+    #[test]
+    fn test_rto_bounds() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let config = RtoConfig::default().with_bounds(ms(200), ms(500));
+        let mut estimator = RttEstimator::new(config);
+        assert_eq!(estimator.rto(), ms(500));
+        for _ in 0..10 {
+            estimator.on_acked(now, now + ms(10), false);
+        }
+        assert_eq!(estimator.rto(), ms(200));
+        assert_eq!(
+            RtoConfig::default()
+                .with_bounds(ms(500), ms(200))
+                .validate(),
+            Err(ConfigError::RtoRange(ms(500), ms(200)))
+        );
+    }
+}
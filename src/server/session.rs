@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use super::Outgoing;
+use crate::event::{DisconnectReason, Event};
+use crate::message::Message;
+use crate::stats::{ConnectionStats, StatsRecorder};
+
+/// A session found in the [`Sessions`], the application layers (e.g. auth and anticheat) could
+/// act on it without holding the connection.
+#[derive(Debug, Clone)]
+pub struct Session {
+    addr: SocketAddr,
+    guid: u64,
+    dst: flume::Sender<Outgoing>,
+    recorder: Arc<StatsRecorder>,
+}
+
+impl Session {
+    pub(super) fn new(
+        addr: SocketAddr,
+        guid: u64,
+        dst: flume::Sender<Outgoing>,
+        recorder: Arc<StatsRecorder>,
+    ) -> Self {
+        Self {
+            addr,
+            guid,
+            dst,
+            recorder,
+        }
+    }
+
+    /// The address of the session when it was found
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The GUID the client sent in the handshake
+    pub fn guid(&self) -> u64 {
+        self.guid
+    }
+
+    /// Get the statistics of the connection of the session
+    pub fn stats(&self) -> ConnectionStats {
+        self.recorder.snapshot()
+    }
+
+    /// Queue the message to the session, returns false if the connection is closed.
+    pub fn send(&self, msg: Message) -> bool {
+        self.dst.send(Ok(msg)).is_ok()
+    }
+
+    /// Close the session with the reason, returns false if the connection is closed.
+    pub fn kick(&self, reason: DisconnectReason) -> bool {
+        self.dst.send(Err(reason)).is_ok()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    by_addr: HashMap<SocketAddr, Session>,
+    by_guid: HashMap<u64, SocketAddr>,
+}
+
+/// The sessions of a server keyed by both the address and the client GUID, lookups by either
+/// of them are O(1). It is shared by the server handle and the connection tasks.
+#[derive(Debug, Clone, Default)]
+pub struct Sessions {
+    table: Arc<RwLock<Table>>,
+}
+
+impl Sessions {
+    /// Register the session, returns the previous sessions sharing its address or GUID, which
+    /// are replaced and should be closed.
+    pub(super) fn insert(&self, session: Session) -> Vec<Session> {
+        let mut table = self.table.write().expect("sessions lock poisoned");
+        let mut replaced = Vec::new();
+        if let Some(prev) = table.by_addr.remove(&session.addr) {
+            table.by_guid.remove(&prev.guid);
+            replaced.push(prev);
+        }
+        if let Some(addr) = table.by_guid.remove(&session.guid) {
+            replaced.extend(table.by_addr.remove(&addr));
+        }
+        table.by_guid.insert(session.guid, session.addr);
+        table.by_addr.insert(session.addr, session);
+        replaced
+    }
+
+    /// Move the session of the GUID to the new address, returns the
+    /// [`Event::AddressChanged`] if it was at another address.
+    pub(super) fn migrate(&self, guid: u64, new: SocketAddr) -> Option<Event> {
+        let mut table = self.table.write().expect("sessions lock poisoned");
+        let old = *table.by_guid.get(&guid)?;
+        if old == new {
+            return None;
+        }
+        let mut session = table.by_addr.remove(&old)?;
+        session.addr = new;
+        table.by_guid.insert(guid, new);
+        table.by_addr.insert(new, session);
+        Some(Event::AddressChanged { old, new })
+    }
+
+    pub(super) fn remove(&self, addr: &SocketAddr) -> Option<Session> {
+        let mut table = self.table.write().expect("sessions lock poisoned");
+        let session = table.by_addr.remove(addr)?;
+        table.by_guid.remove(&session.guid);
+        Some(session)
+    }
+
+    /// Find the session of the address
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the sessions
+    pub fn by_addr(&self, addr: &SocketAddr) -> Option<Session> {
+        self.table
+            .read()
+            .expect("sessions lock poisoned")
+            .by_addr
+            .get(addr)
+            .cloned()
+    }
+
+    /// Find the session of the client GUID
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the sessions
+    pub fn by_guid(&self, guid: u64) -> Option<Session> {
+        let table = self.table.read().expect("sessions lock poisoned");
+        let addr = table.by_guid.get(&guid)?;
+        table.by_addr.get(addr).cloned()
+    }
+
+    /// Get the count of the sessions
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while holding the sessions
+    pub fn len(&self) -> usize {
+        self.table
+            .read()
+            .expect("sessions lock poisoned")
+            .by_addr
+            .len()
+    }
+
+    /// Whether there is no session
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session(port: u16, guid: u64) -> (Session, flume::Receiver<Outgoing>) {
+        let (tx, rx) = flume::unbounded();
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let recorder = Arc::new(StatsRecorder::new(1));
+        (Session::new(addr, guid, tx, recorder), rx)
+    }
+
+    #[test]
+    fn test_sessions_lookup() {
+        let sessions = Sessions::default();
+        let (first, rx) = session(1, 100);
+        let addr = first.addr();
+        assert!(sessions.insert(first).is_empty());
+        assert_eq!(sessions.by_guid(100).map(|found| found.addr()), Some(addr));
+
+        let found = sessions.by_addr(&addr).unwrap();
+        assert_eq!(found.guid(), 100);
+        assert!(found.kick(DisconnectReason::Kicked));
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap_err(),
+            DisconnectReason::Kicked
+        );
+
+        // the same client reconnects from another port
+        let (second, _rx) = session(2, 100);
+        let replaced = sessions.insert(second);
+        assert_eq!(replaced.len(), 1);
+        assert_eq!(replaced[0].addr(), addr);
+        assert!(sessions.by_addr(&addr).is_none());
+        assert_eq!(sessions.len(), 1);
+
+        // the NAT of the client rebound the mapping
+        let rebound = SocketAddr::from(([127, 0, 0, 1], 3));
+        assert_eq!(
+            sessions.migrate(100, rebound),
+            Some(Event::AddressChanged {
+                old: SocketAddr::from(([127, 0, 0, 1], 2)),
+                new: rebound,
+            })
+        );
+        assert_eq!(sessions.by_guid(100).unwrap().addr(), rebound);
+        assert!(sessions.migrate(100, rebound).is_none());
+        assert!(sessions.migrate(200, rebound).is_none());
+
+        let removed = sessions.remove(&rebound);
+        assert!(removed.is_some());
+        assert!(sessions.by_guid(100).is_none());
+        assert_eq!(sessions.len(), 0);
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::packet::{connected, Packet};
+
+const SHED_WINDOW: Duration = Duration::from_secs(1);
+
+/// The class of inbound packets when shedding load. Classes are shed in order: handshake packets
+/// first, then the data of established connections, acknowledgements last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Class {
+    Handshake,
+    Data,
+    Ack,
+}
+
+impl Class {
+    pub(super) fn of<B>(packet: &Packet<B>) -> Self {
+        match packet {
+            Packet::Unconnected(_) => Class::Handshake,
+            Packet::Connected(connected::Packet::FrameSet(_)) => Class::Data,
+            Packet::Connected(connected::Packet::Ack(_) | connected::Packet::Nack(_)) => Class::Ack,
+        }
+    }
+
+    /// The percentage of the budget this class is allowed to use in a window
+    fn level(self) -> usize {
+        match self {
+            Class::Handshake => 50,
+            Class::Data => 90,
+            Class::Ack => 100,
+        }
+    }
+}
+
+/// Counters of the shed packets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ShedStats {
+    /// Shed handshake packets
+    pub handshake: u64,
+    /// Shed data packets of established connections
+    pub data: u64,
+    /// Shed ack or nack packets
+    pub ack: u64,
+}
+
+/// The counters of the shed packets, shared with the server handle
+#[derive(Debug, Default)]
+pub(super) struct ShedCounter {
+    handshake: AtomicU64,
+    data: AtomicU64,
+    ack: AtomicU64,
+}
+
+impl ShedCounter {
+    fn record(&self, class: Class) {
+        let counter = match class {
+            Class::Handshake => &self.handshake,
+            Class::Data => &self.data,
+            Class::Ack => &self.ack,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self) -> ShedStats {
+        ShedStats {
+            handshake: self.handshake.load(Ordering::Relaxed),
+            data: self.data.load(Ordering::Relaxed),
+            ack: self.ack.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Global receive budget. Once the inbound packets in the current window exceed a class's level
+/// of the budget, the packets of that class will be shed deterministically.
+#[derive(Debug)]
+pub(super) struct Shedder {
+    // Max inbound packets per second, 0 means no limit
+    budget: usize,
+    received: usize,
+    window_start: Instant,
+    stats: Arc<ShedCounter>,
+}
+
+impl Shedder {
+    pub(super) fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            received: 0,
+            window_start: Instant::now(),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Get the counters of the shed packets, they follow the shedder
+    pub(super) fn counter(&self) -> Arc<ShedCounter> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Check if a packet of the class is admitted
+    pub(super) fn admit(&mut self, class: Class) -> bool {
+        self.admit_at(class, Instant::now())
+    }
+
+    fn admit_at(&mut self, class: Class, now: Instant) -> bool {
+        if self.budget == 0 {
+            return true;
+        }
+        if now.saturating_duration_since(self.window_start) >= SHED_WINDOW {
+            self.window_start = now;
+            self.received = 0;
+        }
+        if self.received * 100 >= self.budget * class.level() {
+            self.stats.record(class);
+            return false;
+        }
+        self.received += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shedder_prefer_acks() {
+        let mut shedder = Shedder::new(10);
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(shedder.admit_at(Class::Handshake, now));
+        }
+        assert!(!shedder.admit_at(Class::Handshake, now));
+        for _ in 0..4 {
+            assert!(shedder.admit_at(Class::Data, now));
+        }
+        assert!(!shedder.admit_at(Class::Data, now));
+        assert!(shedder.admit_at(Class::Ack, now));
+        assert!(!shedder.admit_at(Class::Ack, now));
+        assert_eq!(
+            shedder.stats.snapshot(),
+            ShedStats {
+                handshake: 1,
+                data: 1,
+                ack: 1
+            }
+        );
+
+        // next window
+        let now = now + SHED_WINDOW;
+        assert!(shedder.admit_at(Class::Handshake, now));
+    }
+
+    #[test]
+    fn test_shedder_no_limit() {
+        let mut shedder = Shedder::new(0);
+        for _ in 0..10000 {
+            assert!(shedder.admit(Class::Handshake));
+        }
+        assert_eq!(shedder.stats.snapshot(), ShedStats::default());
+    }
+}
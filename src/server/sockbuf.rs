@@ -0,0 +1,132 @@
+use std::io;
+use std::time::Duration;
+
+use socket2::SockRef;
+use tracing::{debug, warn};
+
+use crate::stats::EventLoopRecorder;
+
+/// Size the socket buffers by the bandwidth-delay product of the expected peers, the default
+/// buffers of the kernel are sized for a few flows and drop the datagrams of a burst from many
+/// peers.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SocketBufferConfig {
+    // The count of peers expected to be connected at the same time
+    expected_peers: usize,
+    // The expected bandwidth of each peer in bytes per second
+    bandwidth: u64,
+    // The expected round trip time of the peers
+    rtt: Duration,
+}
+
+impl Default for SocketBufferConfig {
+    fn default() -> Self {
+        Self {
+            expected_peers: 1000,
+            bandwidth: 64 * 1024,
+            rtt: Duration::from_millis(100),
+        }
+    }
+}
+
+impl SocketBufferConfig {
+    #[must_use]
+    pub fn with_expected_peers(mut self, expected_peers: usize) -> Self {
+        self.expected_peers = expected_peers;
+        self
+    }
+
+    #[must_use]
+    pub fn with_bandwidth(mut self, bandwidth: u64) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    #[must_use]
+    pub fn with_rtt(mut self, rtt: Duration) -> Self {
+        self.rtt = rtt;
+        self
+    }
+
+    /// The requested size of each buffer, the bandwidth-delay product of a peer times the
+    /// expected peers
+    pub(super) fn target(&self) -> usize {
+        let bdp = u128::from(self.bandwidth) * self.rtt.as_micros() / 1_000_000;
+        let target = bdp.saturating_mul(self.expected_peers as u128);
+        usize::try_from(target).unwrap_or(usize::MAX)
+    }
+}
+
+/// Apply the buffer sizes to the socket if configured, then record the effective sizes read back
+/// from the kernel, which clamps the requests to `net.core.rmem_max` and `net.core.wmem_max` on
+/// Linux.
+pub(super) fn tune_socket_buffers(
+    socket: SockRef<'_>,
+    config: Option<&SocketBufferConfig>,
+    recorder: &EventLoopRecorder,
+) -> io::Result<()> {
+    if let Some(config) = config {
+        let target = config.target();
+        socket.set_recv_buffer_size(target)?;
+        socket.set_send_buffer_size(target)?;
+        debug!("requested socket buffers of {target} bytes");
+    }
+    let recv = socket.recv_buffer_size()?;
+    let send = socket.send_buffer_size()?;
+    if let Some(target) = config.map(SocketBufferConfig::target) {
+        if recv < target || send < target {
+            warn!(
+                "socket buffers are clamped by the kernel to {recv} (recv) and {send} (send) \
+                 bytes below the requested {target} bytes, raise net.core.rmem_max and \
+                 net.core.wmem_max to avoid drops under burst"
+            );
+        }
+    }
+    recorder.record_socket_buffers(recv, send);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+
+    use super::*;
+
+    #[test]
+    fn test_socket_buffer_target() {
+        let config = SocketBufferConfig::default()
+            .with_expected_peers(100)
+            .with_bandwidth(10_000)
+            .with_rtt(Duration::from_millis(50));
+        assert_eq!(config.target(), 50_000);
+
+        let huge = config
+            .with_expected_peers(usize::MAX)
+            .with_bandwidth(u64::MAX);
+        assert_eq!(huge.target(), usize::MAX);
+    }
+
+    #[test]
+    fn test_tune_socket_buffers() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let recorder = EventLoopRecorder::default();
+
+        // only reports the defaults if not configured
+        tune_socket_buffers(SockRef::from(&socket), None, &recorder).unwrap();
+        let defaults = recorder.snapshot();
+        assert!(defaults.recv_buffer_size.is_some_and(|size| size > 0));
+        assert!(defaults.send_buffer_size.is_some_and(|size| size > 0));
+
+        let config = SocketBufferConfig::default()
+            .with_expected_peers(4)
+            .with_bandwidth(8192)
+            .with_rtt(Duration::from_secs(1));
+        tune_socket_buffers(SockRef::from(&socket), Some(&config), &recorder).unwrap();
+        let tuned = recorder.snapshot();
+        let target = config.target() as u64;
+        assert!(tuned.recv_buffer_size.is_some_and(|size| size >= target));
+        assert!(tuned.send_buffer_size.is_some_and(|size| size >= target));
+    }
+}
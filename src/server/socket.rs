@@ -0,0 +1,433 @@
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, Stream};
+use socket2::SockRef;
+use tokio::net::UdpSocket;
+use tokio::time::Sleep;
+use tracing::{debug, warn};
+
+use super::batch_io::{poll_send_batch, MAX_BATCH};
+use super::conn::Events;
+use super::qos::{DscpMarker, Marking};
+use super::resilience::{Recovery, SocketErrorConfig, SocketRecovery};
+use super::sockbuf::{tune_socket_buffers, SocketBufferConfig};
+use super::timestamp::{enable_rx_timestamps, enable_rxq_overflow, poll_recv_timestamped};
+use crate::event::ServerEvent;
+use crate::message::Priority;
+use crate::stats::EventLoopRecorder;
+
+/// When the datagram being processed arrived, read by the connection router to stamp the
+/// messages it carries. The layers over the socket pass the datagrams one at a time, so it is
+/// the arrival of the datagram the router is handling.
+#[derive(Debug, Clone)]
+pub(super) struct Arrival(Arc<Mutex<Instant>>);
+
+impl Default for Arrival {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+}
+
+impl Arrival {
+    pub(super) fn get(&self) -> Instant {
+        *self.0.lock().expect("arrival lock poisoned")
+    }
+
+    fn set(&self, at: Instant) {
+        *self.0.lock().expect("arrival lock poisoned") = at;
+    }
+}
+
+/// The options of the server socket, applied once it is bound and again once it is rebound
+#[derive(Debug, Clone, Copy)]
+pub(super) struct SocketSetup {
+    pub(super) rx_timestamps: bool,
+    pub(super) buffers: Option<SocketBufferConfig>,
+}
+
+impl SocketSetup {
+    pub(super) fn apply(
+        &self,
+        socket: &UdpSocket,
+        event_loop: &EventLoopRecorder,
+    ) -> io::Result<()> {
+        if self.rx_timestamps && !enable_rx_timestamps(socket)? {
+            debug!("the kernel timestamps are not supported, stamp the datagrams once read");
+        }
+        // the kernel reports the count only once a datagram is dropped
+        if enable_rxq_overflow(socket)? {
+            event_loop.record_socket_drops(0);
+        }
+        tune_socket_buffers(SockRef::from(socket), self.buffers.as_ref(), event_loop)
+    }
+}
+
+/// The shared states of a server the socket is built with
+#[derive(Debug)]
+pub(super) struct SocketParts {
+    pub(super) arrival: Arrival,
+    pub(super) max_datagram_size: usize,
+    pub(super) marking: Marking,
+    pub(super) marker: DscpMarker,
+    pub(super) setup: SocketSetup,
+    pub(super) socket_error: SocketErrorConfig,
+    pub(super) events: Events<ServerEvent>,
+    pub(super) event_loop: Arc<EventLoopRecorder>,
+}
+
+/// The raw datagram socket at the bottom of the server stack. The errors of the socket are
+/// recovered by the [`SocketRecovery`] instead of being passed to the layers over it, so that
+/// they never stop the server loop.
+#[derive(Debug)]
+pub(super) struct Socket {
+    socket: Arc<UdpSocket>,
+    // The address the socket is bound to, with the port resolved, which it is rebound to
+    local_addr: SocketAddr,
+    arrival: Arrival,
+    // The buffer size of each received datagram, the larger datagrams are truncated to it
+    recv_size: usize,
+    // The datagrams being sent, queued by `start_send` and sent in batches by `poll_flush`
+    outbox: Vec<(Bytes, SocketAddr)>,
+    // The priority classes of the datagrams in the outbox
+    priorities: Vec<Priority>,
+    marking: Marking,
+    marker: DscpMarker,
+    setup: SocketSetup,
+    recovery: SocketRecovery,
+    // The socket operations are held until the deadline after a transient error
+    backoff: Option<Pin<Box<Sleep>>>,
+    // The socket failed and is waiting to be rebound
+    rebinding: bool,
+    events: Events<ServerEvent>,
+    event_loop: Arc<EventLoopRecorder>,
+}
+
+impl Socket {
+    /// Make the socket receiving the datagrams up to `max_datagram_size`, one more byte is
+    /// read so that the oversized datagrams are told apart from the ones of the max size
+    pub(super) fn new(socket: Arc<UdpSocket>, parts: SocketParts) -> io::Result<Self> {
+        Ok(Self {
+            local_addr: socket.local_addr()?,
+            socket,
+            arrival: parts.arrival,
+            recv_size: parts.max_datagram_size + 1,
+            outbox: Vec::with_capacity(MAX_BATCH),
+            priorities: Vec::with_capacity(MAX_BATCH),
+            marking: parts.marking,
+            marker: parts.marker,
+            setup: parts.setup,
+            recovery: SocketRecovery::new(parts.socket_error),
+            backoff: None,
+            rebinding: false,
+            events: parts.events,
+            event_loop: parts.event_loop,
+        })
+    }
+
+    /// Wait for the backoff of the socket operations and rebind the failed socket
+    fn poll_backoff(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(backoff) = &mut self.backoff {
+                ready!(backoff.as_mut().poll(cx));
+                self.backoff = None;
+            }
+            if !self.rebinding {
+                return Poll::Ready(());
+            }
+            match self.rebind() {
+                Ok(()) => {
+                    warn!("the socket is rebound to {}", self.local_addr);
+                    self.rebinding = false;
+                    self.events.emit(ServerEvent::Rebound {
+                        local_addr: self.local_addr,
+                    });
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to rebind the socket to {}, error {err}",
+                        self.local_addr
+                    );
+                    let backoff = self.recovery.max_backoff();
+                    self.backoff = Some(Box::pin(tokio::time::sleep(backoff)));
+                }
+            }
+        }
+    }
+
+    /// Bind a new socket to the same address, the failed one is closed first to release the
+    /// address
+    fn rebind(&mut self) -> io::Result<()> {
+        // a socket of the same family holds the place until the address is bound again
+        let unspecified = match self.local_addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        self.socket = Arc::new(bind_nonblocking(unspecified)?);
+        let socket = bind_nonblocking(self.local_addr)?;
+        self.setup.apply(&socket, &self.event_loop)?;
+        self.socket = Arc::new(socket);
+        self.marker.forget();
+        Ok(())
+    }
+
+    /// Recover from the error of a socket operation
+    fn recover(&mut self, err: &io::Error) -> Recovery {
+        let recovery = self.recovery.on_error(err);
+        match recovery {
+            Recovery::Ignore => debug!("ignore the socket error: {err}"),
+            Recovery::Retry(backoff) => {
+                debug!("socket error: {err}, retry in {backoff:?}");
+                self.backoff = Some(Box::pin(tokio::time::sleep(backoff)));
+            }
+            Recovery::Rebind => {
+                warn!(
+                    "socket error: {err}, rebind the socket to {}",
+                    self.local_addr
+                );
+                self.rebinding = true;
+            }
+            Recovery::Surface => {
+                warn!("socket error: {err}, the server keeps retrying it");
+                self.events
+                    .emit(ServerEvent::SocketError { kind: err.kind() });
+            }
+        }
+        recovery
+    }
+}
+
+fn bind_nonblocking(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = std::net::UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+impl Stream for Socket {
+    type Item = io::Result<(BytesMut, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            ready!(this.poll_backoff(cx));
+            let mut buf = BytesMut::zeroed(this.recv_size);
+            match ready!(poll_recv_timestamped(
+                &this.socket,
+                cx,
+                &mut buf,
+                &this.event_loop
+            )) {
+                Ok((len, addr, arrived)) => {
+                    this.recovery.on_success();
+                    buf.truncate(len);
+                    this.arrival.set(arrived);
+                    this.event_loop.record_recv(1);
+                    return Poll::Ready(Some(Ok((buf, addr))));
+                }
+                Err(err) => {
+                    this.recover(&err);
+                }
+            }
+        }
+    }
+}
+
+impl Sink<(Bytes, SocketAddr)> for Socket {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.outbox.len() < MAX_BATCH {
+            return Poll::Ready(Ok(()));
+        }
+        self.poll_flush(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: (Bytes, SocketAddr)) -> Result<(), Self::Error> {
+        self.outbox.push(item);
+        let priority = self.marking.take();
+        self.priorities.push(priority);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = &mut *self;
+        while !this.outbox.is_empty() {
+            ready!(this.poll_backoff(cx));
+            // the socket is marked once for each run of the datagrams of the same DSCP value
+            let dscp = this.marker.dscp_of(this.priorities[0]);
+            let run = this
+                .priorities
+                .iter()
+                .take_while(|&&priority| this.marker.dscp_of(priority) == dscp)
+                .count();
+            if let Err(err) = this
+                .marker
+                .mark(SockRef::from(&*this.socket), this.priorities[0])
+            {
+                debug!("failed to mark the datagrams with DSCP {dscp}: {err}");
+            }
+            match ready!(poll_send_batch(
+                &this.socket,
+                cx,
+                &this.outbox[..run],
+                &this.event_loop
+            )) {
+                Ok(sent) => {
+                    this.recovery.on_success();
+                    this.outbox.drain(..sent);
+                    this.priorities.drain(..sent);
+                }
+                Err(err) => {
+                    // the failed datagram is dropped like it is lost on the path, unless it is
+                    // retried after the backoff
+                    if !matches!(this.recover(&err), Recovery::Retry(_)) {
+                        this.outbox.remove(0);
+                        this.priorities.remove(0);
+                    }
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::SinkExt;
+
+    use super::*;
+    use crate::server::qos::DscpConfig;
+    use crate::server::resilience::SocketErrorPolicy;
+
+    fn parts(dscp: DscpConfig, socket_error: SocketErrorConfig) -> SocketParts {
+        SocketParts {
+            arrival: Arrival::default(),
+            max_datagram_size: 1500,
+            marking: Marking::default(),
+            marker: DscpMarker::new(dscp),
+            setup: SocketSetup {
+                rx_timestamps: false,
+                buffers: None,
+            },
+            socket_error,
+            events: Events::default(),
+            event_loop: Arc::new(EventLoopRecorder::default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socket_marks_datagrams() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = DscpConfig::default()
+            .with_class(Priority::Immediate, Some(46))
+            .with_class(Priority::Low, Some(8));
+        let parts = parts(config, SocketErrorConfig::default());
+        let marking = parts.marking.clone();
+        let mut socket = Socket::new(Arc::clone(&udp), parts).unwrap();
+
+        marking.set(Priority::Immediate);
+        socket.send((Bytes::from_static(b"a"), addr)).await.unwrap();
+        assert_eq!(SockRef::from(&*udp).tos().unwrap(), 46 << 2);
+        // the marking is taken by the datagram, the next one takes the default class
+        socket.send((Bytes::from_static(b"b"), addr)).await.unwrap();
+        assert_eq!(SockRef::from(&*udp).tos().unwrap(), 0);
+        marking.set(Priority::Low);
+        socket.send((Bytes::from_static(b"c"), addr)).await.unwrap();
+        assert_eq!(SockRef::from(&*udp).tos().unwrap(), 8 << 2);
+
+        let mut buf = [0; 8];
+        for expected in [b"a", b"b", b"c"] {
+            let len = peer.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], expected);
+        }
+    }
+
+    /// Swap the descriptor of the socket for `/dev/null`, every later operation on it fails
+    /// with `ENOTSOCK` like a socket broken under the server
+    #[cfg(unix)]
+    fn break_socket(udp: &UdpSocket) {
+        use std::os::fd::AsRawFd;
+
+        let null = std::fs::File::open("/dev/null").unwrap();
+        let ret = unsafe { libc::dup2(null.as_raw_fd(), udp.as_raw_fd()) };
+        assert!(ret >= 0, "{}", io::Error::last_os_error());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket_surface_errors() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = SocketErrorConfig::default().with_policy(SocketErrorPolicy::Surface);
+        let parts = parts(DscpConfig::default(), config);
+        let events = parts.events.subscribe(8);
+        let mut socket = Socket::new(Arc::clone(&udp), parts).unwrap();
+
+        // the port 0 is never a valid destination, but it says nothing about the socket
+        let invalid = SocketAddr::from(([127, 0, 0, 1], 0));
+        socket
+            .send((Bytes::from_static(b"a"), invalid))
+            .await
+            .unwrap();
+        socket.send((Bytes::from_static(b"b"), addr)).await.unwrap();
+        let mut buf = [0; 8];
+        let len = peer.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"b");
+        assert!(events.try_recv().is_err());
+
+        break_socket(&udp);
+        socket.send((Bytes::from_static(b"c"), addr)).await.unwrap();
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ServerEvent::SocketError { .. }
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket_rebind() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let local_addr = udp.local_addr().unwrap();
+        let parts = parts(DscpConfig::default(), SocketErrorConfig::default());
+        let events = parts.events.subscribe(8);
+        let mut socket = Socket::new(Arc::clone(&udp), parts).unwrap();
+
+        // a remote peer could make us send to an invalid address, never rebind for it
+        let invalid = SocketAddr::from(([127, 0, 0, 1], 0));
+        socket
+            .send((Bytes::from_static(b"a"), invalid))
+            .await
+            .unwrap();
+        assert!(events.try_recv().is_err());
+
+        break_socket(&udp);
+        drop(udp);
+        socket.send((Bytes::from_static(b"b"), addr)).await.unwrap();
+        socket.send((Bytes::from_static(b"c"), addr)).await.unwrap();
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ServerEvent::Rebound { local_addr: rebound } if rebound == local_addr
+        ));
+        // the datagram failed on the broken socket is dropped, the next one is sent from the
+        // same address
+        let mut buf = [0; 8];
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"c");
+        assert_eq!(from, local_addr);
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long the id of an unreliable split is held after it is sent, the peer should have given
+/// up reassembling it by then
+pub(super) const UNRELIABLE_SPLIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+enum Holder {
+    // Count of the fragments not acknowledged yet
+    Reliable(u32),
+    // The id is released at the deadline
+    Unreliable(Instant),
+}
+
+/// Allocate the 16-bit parted ids of the outgoing splits. An id is reused only after all
+/// fragments of its split are acknowledged, or after the timeout if the split is unreliable, so
+/// the fragments of two splits sharing an id are never mixed up by the peer, no matter how many
+/// splits are sent in a session.
+#[derive(Debug)]
+pub(super) struct SplitIds {
+    next: u16,
+    in_use: HashMap<u16, Holder>,
+    unreliable_timeout: Duration,
+}
+
+impl Default for SplitIds {
+    fn default() -> Self {
+        Self::new(UNRELIABLE_SPLIT_TIMEOUT)
+    }
+}
+
+impl SplitIds {
+    pub(super) fn new(unreliable_timeout: Duration) -> Self {
+        Self {
+            next: 0,
+            in_use: HashMap::new(),
+            unreliable_timeout,
+        }
+    }
+
+    /// Count of the ids in use
+    pub(super) fn in_use(&self) -> usize {
+        self.in_use.len()
+    }
+
+    /// Allocate an id for a split of `parts` fragments. Returns None if all ids are in use, the
+    /// split should be delayed until some of them are released.
+    pub(super) fn allocate(&mut self, parts: u32, reliable: bool, now: Instant) -> Option<u16> {
+        if self.in_use.len() > usize::from(u16::MAX) {
+            return None;
+        }
+        let mut id = self.next;
+        while self.in_use.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        self.next = id.wrapping_add(1);
+        let holder = if reliable {
+            Holder::Reliable(parts)
+        } else {
+            Holder::Unreliable(now + self.unreliable_timeout)
+        };
+        self.in_use.insert(id, holder);
+        Some(id)
+    }
+
+    /// A fragment of the split is acknowledged, or abandoned by the resend limit so that it is
+    /// never acknowledged, the id is released once all of them are settled.
+    pub(super) fn on_fragment_settled(&mut self, id: u16) {
+        let Some(Holder::Reliable(remaining)) = self.in_use.get_mut(&id) else {
+            return;
+        };
+        *remaining = remaining.saturating_sub(1);
+        if *remaining == 0 {
+            self.in_use.remove(&id);
+        }
+    }
+
+    /// Release the ids of the unreliable splits timed out at now
+    pub(super) fn expire(&mut self, now: Instant) {
+        self.in_use.retain(|_, holder| match holder {
+            Holder::Reliable(_) => true,
+            Holder::Unreliable(deadline) => *deadline > now,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_ids_reuse() {
+        let now = Instant::now();
+        let mut ids = SplitIds::default();
+        assert_eq!(ids.allocate(2, true, now), Some(0));
+        assert_eq!(ids.allocate(3, false, now), Some(1));
+        ids.on_fragment_settled(0);
+        assert_eq!(ids.in_use(), 2);
+        ids.on_fragment_settled(0);
+        ids.expire(now + UNRELIABLE_SPLIT_TIMEOUT / 2);
+        assert_eq!(ids.in_use(), 1);
+        ids.expire(now + UNRELIABLE_SPLIT_TIMEOUT);
+        assert_eq!(ids.in_use(), 0);
+    }
+
+    #[test]
+    fn test_split_ids_wrap() {
+        let now = Instant::now();
+        let mut ids = SplitIds::default();
+        for id in 0..=u16::MAX {
+            assert_eq!(ids.allocate(1, true, now), Some(id));
+        }
+        // every id is held by an unacknowledged split
+        assert_eq!(ids.allocate(1, true, now), None);
+        ids.on_fragment_settled(7);
+        ids.on_fragment_settled(3);
+        // the wrapped allocation skips the ids still in use
+        assert_eq!(ids.allocate(1, true, now), Some(3));
+        assert_eq!(ids.allocate(1, true, now), Some(7));
+        assert_eq!(ids.allocate(1, true, now), None);
+    }
+}
@@ -0,0 +1,196 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use futures::{ready, Sink, Stream};
+use pin_project_lite::pin_project;
+
+/// The direction of a tapped datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the peer
+    Inbound,
+    /// Sent to the peer
+    Outbound,
+}
+
+/// A raw datagram captured by a tap
+#[derive(Debug, Clone)]
+pub struct Tapped {
+    /// Whether it is received or sent
+    pub direction: Direction,
+    /// The address of the peer
+    pub addr: SocketAddr,
+    /// The datagram before the datagram hook
+    pub data: Bytes,
+    /// When it is captured
+    pub at: Instant,
+}
+
+/// A non-blocking tap of the raw datagrams for the debugging tools. The subscriber could be
+/// attached and detached at runtime, the datagrams are dropped if the subscriber falls behind.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Tap {
+    // Checked on every datagram, so that nothing is copied while no one is subscribing
+    attached: Arc<AtomicBool>,
+    subscriber: Arc<Mutex<Option<flume::Sender<Tapped>>>>,
+}
+
+impl Tap {
+    /// Subscribe the datagrams, at most `capacity` datagrams are buffered. The previous
+    /// subscriber will be detached.
+    pub(super) fn subscribe(&self, capacity: usize) -> flume::Receiver<Tapped> {
+        let (tx, rx) = flume::bounded(capacity);
+        *self.subscriber.lock().expect("tap lock poisoned") = Some(tx);
+        self.attached.store(true, Ordering::Release);
+        rx
+    }
+
+    /// Detach the subscriber, dropping the receiver has the same effect.
+    pub(super) fn detach(&self) {
+        self.attached.store(false, Ordering::Release);
+        self.subscriber.lock().expect("tap lock poisoned").take();
+    }
+
+    /// Copy the datagram to the subscriber if any, `data` is only called while subscribing
+    pub(super) fn capture(
+        &self,
+        direction: Direction,
+        addr: SocketAddr,
+        data: impl FnOnce() -> Bytes,
+    ) {
+        if !self.attached.load(Ordering::Acquire) {
+            return;
+        }
+        let mut subscriber = self.subscriber.lock().expect("tap lock poisoned");
+        let Some(tx) = subscriber.as_ref() else {
+            return;
+        };
+        let tapped = Tapped {
+            direction,
+            addr,
+            data: data(),
+            at: Instant::now(),
+        };
+        if let Err(flume::TrySendError::Disconnected(_)) = tx.try_send(tapped) {
+            self.attached.store(false, Ordering::Release);
+            subscriber.take();
+        }
+    }
+}
+
+pin_project! {
+    /// Copy the raw datagrams to the [`Tap`], should be placed right above the datagram hook so
+    /// that the captured datagrams could be replayed by `inject_datagram`.
+    pub(super) struct TapLayer<F> {
+        #[pin]
+        frame: F,
+        tap: Tap,
+    }
+}
+
+pub(super) trait Tapping: Sized {
+    fn tapping(self, tap: Tap) -> TapLayer<Self>;
+}
+
+impl<F> Tapping for F {
+    fn tapping(self, tap: Tap) -> TapLayer<Self> {
+        TapLayer { frame: self, tap }
+    }
+}
+
+impl<F, E> Stream for TapLayer<F>
+where
+    F: Stream<Item = Result<(BytesMut, SocketAddr), E>>,
+{
+    type Item = Result<(BytesMut, SocketAddr), E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let Some((raw, addr)) = ready!(this.frame.poll_next(cx)?) else {
+            return Poll::Ready(None);
+        };
+        this.tap
+            .capture(Direction::Inbound, addr, || Bytes::copy_from_slice(&raw));
+        Poll::Ready(Some(Ok((raw, addr))))
+    }
+}
+
+impl<F> Sink<(BytesMut, SocketAddr)> for TapLayer<F>
+where
+    F: Sink<(BytesMut, SocketAddr)>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        (data, addr): (BytesMut, SocketAddr),
+    ) -> Result<(), Self::Error> {
+        let this = self.project();
+        this.tap
+            .capture(Direction::Outbound, addr, || Bytes::copy_from_slice(&data));
+        this.frame.start_send((data, addr))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use futures::{SinkExt, StreamExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tap_works() {
+        let addr: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let tap = Tap::default();
+        let inbound = futures::stream::iter([
+            Ok::<_, Infallible>((BytesMut::from(&b"first"[..]), addr)),
+            Ok((BytesMut::from(&b"second"[..]), addr)),
+            Ok((BytesMut::from(&b"third"[..]), addr)),
+        ]);
+        let mut layer = inbound.tapping(tap.clone());
+
+        // nothing is captured before subscribing
+        layer.next().await.unwrap().unwrap();
+        let rx = tap.subscribe(1);
+        layer.next().await.unwrap().unwrap();
+        // the subscriber falls behind
+        layer.next().await.unwrap().unwrap();
+        let tapped = rx.try_recv().unwrap();
+        assert_eq!(tapped.direction, Direction::Inbound);
+        assert_eq!(tapped.data, &b"second"[..]);
+        assert!(rx.try_recv().is_err());
+
+        let mut sink = Vec::new().tapping(tap.clone());
+        sink.send((BytesMut::from(&b"pong"[..]), addr))
+            .await
+            .unwrap();
+        assert_eq!(rx.try_recv().unwrap().direction, Direction::Outbound);
+
+        // detach by dropping the receiver
+        drop(rx);
+        sink.send((BytesMut::from(&b"pong"[..]), addr))
+            .await
+            .unwrap();
+        assert!(!tap.attached.load(Ordering::Acquire));
+        assert!(tap.subscriber.lock().unwrap().is_none());
+    }
+}
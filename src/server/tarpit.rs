@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Tarpit config
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct TarpitConfig {
+    // The max replies queued for all the rate limited sources, 0 means disabled and the
+    // requests exceeding the rate limit are silently dropped
+    capacity: usize,
+    // How long the replies to the rate limited sources are delayed
+    delay: Duration,
+}
+
+impl Default for TarpitConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 0,
+            delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl TarpitConfig {
+    #[must_use]
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    #[must_use]
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A shared low-priority queue holding the replies to the sources exceeding the offline rate
+/// limit. The replies are released after the delay instead of immediately, so the scanners are
+/// slowed down while the legitimate server list pings still get their pongs. The replies are
+/// dropped when the queue is full.
+#[derive(Debug)]
+pub(super) struct Tarpit<T> {
+    config: TarpitConfig,
+    // Sorted by the deadlines since the delay is fixed
+    queue: VecDeque<(Instant, T)>,
+}
+
+impl<T> Tarpit<T> {
+    pub(super) fn new(config: TarpitConfig) -> Self {
+        Self {
+            config,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Delay the reply, returns false if it is dropped because the tarpit is disabled or full.
+    pub(super) fn push(&mut self, reply: T, now: Instant) -> bool {
+        if self.len() >= self.config.capacity {
+            return false;
+        }
+        self.queue.push_back((now + self.config.delay, reply));
+        true
+    }
+
+    /// Pop the next reply whose delay has elapsed
+    pub(super) fn pop_expired(&mut self, now: Instant) -> Option<T> {
+        let &(deadline, _) = self.queue.front()?;
+        if deadline > now {
+            return None;
+        }
+        self.queue.pop_front().map(|(_, reply)| reply)
+    }
+
+    /// When the next reply is released
+    pub(super) fn next_deadline(&self) -> Option<Instant> {
+        self.queue.front().map(|&(deadline, _)| deadline)
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tarpit_delays_replies() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let mut tarpit = Tarpit::new(TarpitConfig::default().with_capacity(2).with_delay(ms(100)));
+        assert!(tarpit.push(1, now));
+        assert!(tarpit.push(2, now + ms(10)));
+        // full
+        assert!(!tarpit.push(3, now + ms(10)));
+        assert_eq!(tarpit.len(), 2);
+        assert_eq!(tarpit.next_deadline(), Some(now + ms(100)));
+
+        assert_eq!(tarpit.pop_expired(now + ms(99)), None);
+        assert_eq!(tarpit.pop_expired(now + ms(100)), Some(1));
+        assert_eq!(tarpit.pop_expired(now + ms(100)), None);
+        assert_eq!(tarpit.pop_expired(now + ms(110)), Some(2));
+        assert_eq!(tarpit.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_tarpit_disabled() {
+        let mut tarpit = Tarpit::new(TarpitConfig::default());
+        assert!(!tarpit.push(1, Instant::now()));
+        assert_eq!(tarpit.len(), 0);
+    }
+}
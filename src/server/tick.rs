@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+/// How the acks, resends and flushes of a connection are driven
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DriveMode {
+    /// Perform the pending work whenever a packet wakes up the connection
+    #[default]
+    PerPacket,
+    /// Perform the pending work on a single per-connection tick, trading a bit of latency for far
+    /// fewer timer entries and wakeups on massive servers
+    Tick(Duration),
+}
+
+/// Decide when the pending work of a connection should be performed. It does not own a timer,
+/// the connection task sleeps until [`Ticker::next_wakeup`] by itself. The immediate messages
+/// are flushed without asking it, so they never consume the tick.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Ticker {
+    mode: DriveMode,
+    last_tick: Option<Instant>,
+}
+
+impl Ticker {
+    pub(super) fn new(mode: DriveMode) -> Self {
+        Self {
+            mode,
+            last_tick: None,
+        }
+    }
+
+    /// Check whether the pending work should be performed now, the tick is consumed if so.
+    pub(super) fn due(&mut self, now: Instant) -> bool {
+        let DriveMode::Tick(interval) = self.mode else {
+            return true;
+        };
+        if self
+            .last_tick
+            .is_some_and(|last| now.saturating_duration_since(last) < interval)
+        {
+            return false;
+        }
+        self.last_tick = Some(now);
+        true
+    }
+
+    /// The next instant the connection should be woken up to perform the pending work, None
+    /// means it is driven by packets.
+    pub(super) fn next_wakeup(&self, now: Instant) -> Option<Instant> {
+        let DriveMode::Tick(interval) = self.mode else {
+            return None;
+        };
+        Some(
+            self.last_tick
+                .map_or(now, |last| (last + interval).max(now)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ticker_per_packet() {
+        let mut ticker = Ticker::new(DriveMode::PerPacket);
+        let now = Instant::now();
+        assert!(ticker.due(now));
+        assert!(ticker.due(now));
+        assert!(ticker.next_wakeup(now).is_none());
+    }
+
+    #[test]
+    fn test_ticker_tick() {
+        let interval = Duration::from_millis(10);
+        let mut ticker = Ticker::new(DriveMode::Tick(interval));
+        let now = Instant::now();
+        assert_eq!(ticker.next_wakeup(now), Some(now));
+        assert!(ticker.due(now));
+        assert!(!ticker.due(now + Duration::from_millis(5)));
+        assert_eq!(
+            ticker.next_wakeup(now + Duration::from_millis(5)),
+            Some(now + interval)
+        );
+        assert!(ticker.due(now + interval));
+        // late wakeups should not be scheduled in the past
+        let late = now + Duration::from_millis(100);
+        assert_eq!(ticker.next_wakeup(late), Some(late));
+    }
+}
@@ -0,0 +1,303 @@
+use std::io;
+use std::net::SocketAddr;
+use std::task::{ready, Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+use crate::stats::EventLoopRecorder;
+
+/// Ask the kernel to stamp the received datagrams by `SO_TIMESTAMPING` where it is supported,
+/// which removes the scheduling jitter of the event loop from the RTT samples and the receive
+/// timestamps of the messages. Returns whether it is enabled, the datagrams are stamped when they
+/// are read otherwise.
+pub(super) fn enable_rx_timestamps(socket: &UdpSocket) -> io::Result<bool> {
+    imp::enable(socket)
+}
+
+/// Ask the kernel to report the count of the datagrams it dropped for the socket by
+/// `SO_RXQ_OVFL` where it is supported, the count is recorded as the datagrams are received.
+/// Returns whether it is enabled.
+pub(super) fn enable_rxq_overflow(socket: &UdpSocket) -> io::Result<bool> {
+    imp::enable_overflow(socket)
+}
+
+/// Receive a datagram along with when it arrived, which is stamped by the kernel if it is
+/// enabled by [`enable_rx_timestamps`], otherwise the time it is read. The drops reported along
+/// with the datagram are recorded to the recorder.
+pub(super) fn poll_recv_timestamped(
+    socket: &UdpSocket,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+    recorder: &EventLoopRecorder,
+) -> Poll<io::Result<(usize, SocketAddr, Instant)>> {
+    loop {
+        ready!(socket.poll_recv_ready(cx))?;
+        match socket.try_io(Interest::READABLE, || imp::recv(socket, buf)) {
+            Ok((len, addr, stamp, drops)) => {
+                if let Some(drops) = drops {
+                    recorder.record_socket_drops(drops);
+                }
+                let now = Instant::now();
+                let arrived = stamp.map_or(now, |stamp| to_instant(stamp, now, SystemTime::now()));
+                return Poll::Ready(Ok((len, addr, arrived)));
+            }
+            // the readiness is cleared, wait for the next one
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Poll::Ready(Err(err)),
+        }
+    }
+}
+
+/// Map the wall clock timestamp of the kernel to the monotonic clock by its age. A timestamp
+/// from the future, e.g. the wall clock stepped back, is taken as now.
+fn to_instant(stamp: SystemTime, now: Instant, wall: SystemTime) -> Instant {
+    wall.duration_since(stamp)
+        .ok()
+        .and_then(|age| now.checked_sub(age))
+        .unwrap_or(now)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::os::fd::AsRawFd;
+    use std::time::{Duration, SystemTime};
+
+    use socket2::SockAddr;
+    use tokio::net::UdpSocket;
+
+    pub(super) fn enable(socket: &UdpSocket) -> io::Result<bool> {
+        let flags: libc::c_uint =
+            libc::SOF_TIMESTAMPING_RX_SOFTWARE | libc::SOF_TIMESTAMPING_SOFTWARE;
+        // SAFETY: the option value points to a c_uint of the given length
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_TIMESTAMPING,
+                std::ptr::addr_of!(flags).cast(),
+                std::mem::size_of::<libc::c_uint>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            // the kernel is built without the timestamping
+            if err.raw_os_error() == Some(libc::ENOPROTOOPT) {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    pub(super) fn enable_overflow(socket: &UdpSocket) -> io::Result<bool> {
+        let on: libc::c_int = 1;
+        // SAFETY: the option value points to a c_int of the given length
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RXQ_OVFL,
+                std::ptr::addr_of!(on).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOPROTOOPT) {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    pub(super) fn recv(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<SystemTime>, Option<u32>)> {
+        let mut iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        // aligned for the cmsghdr, large enough for the 3 timespecs of SCM_TIMESTAMPING and the
+        // u32 of SO_RXQ_OVFL
+        let mut control = [0_u64; 16];
+        // SAFETY: the header points to the address storage, the iovec and the control buffer,
+        // which outlive the call, and the address length is set by the kernel
+        let ((len, stamp, drops), addr) = unsafe {
+            SockAddr::try_init(|storage, storage_len| {
+                let mut msg: libc::msghdr = std::mem::zeroed();
+                msg.msg_name = storage.cast();
+                msg.msg_namelen = *storage_len;
+                msg.msg_iov = &mut iovec;
+                msg.msg_iovlen = 1;
+                msg.msg_control = control.as_mut_ptr().cast();
+                msg.msg_controllen = std::mem::size_of_val(&control);
+                let len = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+                if len < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                *storage_len = msg.msg_namelen;
+                Ok((len as usize, software_stamp(&msg), socket_drops(&msg)))
+            })
+        }?;
+        let addr = addr
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an IP address"))?;
+        Ok((len, addr, stamp, drops))
+    }
+
+    /// Find the total count of the dropped datagrams in the control messages, the kernel only
+    /// reports it once any datagram is dropped
+    ///
+    /// # Safety
+    ///
+    /// The control buffer of the header must be filled by `recvmsg`
+    unsafe fn socket_drops(msg: &libc::msghdr) -> Option<u32> {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SO_RXQ_OVFL {
+                return Some(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast()));
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+        None
+    }
+
+    /// Find the software timestamp in the control messages
+    ///
+    /// # Safety
+    ///
+    /// The control buffer of the header must be filled by `recvmsg`
+    unsafe fn software_stamp(msg: &libc::msghdr) -> Option<SystemTime> {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPING
+            {
+                // the software timestamp is the first, the others are of the hardware
+                let stamps: [libc::timespec; 3] =
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast());
+                let software = stamps[0];
+                if software.tv_sec == 0 && software.tv_nsec == 0 {
+                    return None;
+                }
+                return Some(
+                    SystemTime::UNIX_EPOCH
+                        + Duration::new(software.tv_sec as u64, software.tv_nsec as u32),
+                );
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::SystemTime;
+
+    use tokio::net::UdpSocket;
+
+    /// `SO_TIMESTAMPING` is only available on Linux
+    pub(super) fn enable(_: &UdpSocket) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// `SO_RXQ_OVFL` is only available on Linux
+    pub(super) fn enable_overflow(_: &UdpSocket) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    pub(super) fn recv(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<SystemTime>, Option<u32>)> {
+        let (len, addr) = socket.try_recv_from(buf)?;
+        Ok((len, addr, None, None))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::future::poll_fn;
+
+    use super::*;
+
+    #[test]
+    fn test_to_instant() {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        assert_eq!(
+            to_instant(wall - Duration::from_millis(5), now, wall),
+            now - Duration::from_millis(5)
+        );
+        // the wall clock stepped back
+        assert_eq!(to_instant(wall + Duration::from_secs(1), now, wall), now);
+    }
+
+    #[tokio::test]
+    async fn test_recv_timestamped() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // the kernel might be built without the timestamping
+        let enabled = enable_rx_timestamps(&receiver).unwrap();
+        assert!(!enabled || cfg!(target_os = "linux"));
+
+        let before = Instant::now();
+        sender
+            .send_to(b"hello", receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        let recorder = EventLoopRecorder::default();
+        let (len, addr, arrived) =
+            poll_fn(|cx| poll_recv_timestamped(&receiver, cx, &mut buf, &recorder))
+                .await
+                .unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(addr, sender.local_addr().unwrap());
+        // the kernel timestamp is mapped by the wall clock, allow its precision
+        assert!(arrived + Duration::from_millis(10) >= before);
+        assert!(arrived <= Instant::now());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_recv_socket_drops() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket2::SockRef::from(&receiver)
+            .set_recv_buffer_size(4096)
+            .unwrap();
+        assert!(enable_rxq_overflow(&receiver).unwrap());
+
+        // overflow the receive buffer before reading any
+        let addr = receiver.local_addr().unwrap();
+        for _ in 0..64 {
+            sender.send_to(&[0; 1024], addr).await.unwrap();
+        }
+        let mut buf = [0; 1024];
+        while tokio::time::timeout(Duration::from_millis(10), receiver.recv(&mut buf))
+            .await
+            .is_ok()
+        {}
+        // the count is carried by the datagrams queued after the drops
+        sender.send_to(&[0; 1024], addr).await.unwrap();
+        let recorder = EventLoopRecorder::default();
+        poll_fn(|cx| poll_recv_timestamped(&receiver, cx, &mut buf, &recorder))
+            .await
+            .unwrap();
+        assert!(recorder
+            .snapshot()
+            .socket_drops
+            .is_some_and(|drops| drops > 0));
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+
+use tracing::field::Empty;
+use tracing::{info_span, Span};
+
+/// Limit the max count of traced handshakes, the least recently started one will be dropped
+const MAX_TRACED_HANDSHAKES: usize = 1024;
+
+/// Spans of the connection lifecycle, only emitted with the `otel` feature. The fields follow the
+/// OpenTelemetry semantic conventions (`otel.name`, `otel.kind`, `otel.status_code`,
+/// `client.address`), so they are exported as is when the embedder installs a
+/// `tracing-opentelemetry` layer with an OTLP exporter.
+#[derive(Debug)]
+pub(super) struct SessionTraces {
+    enabled: bool,
+    pub(super) handshakes: lru::LruCache<SocketAddr, Span>,
+    pub(super) connections: HashMap<SocketAddr, Span>,
+}
+
+impl Default for SessionTraces {
+    fn default() -> Self {
+        Self::new(cfg!(feature = "otel"))
+    }
+}
+
+impl SessionTraces {
+    pub(super) fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            handshakes: lru::LruCache::new(
+                NonZeroUsize::new(MAX_TRACED_HANDSHAKES).expect("non zero"),
+            ),
+            connections: HashMap::new(),
+        }
+    }
+
+    /// The peer sent the `OpenConnectionRequest1`
+    pub(super) fn handshake_started(&mut self, addr: SocketAddr, protocol_version: u8, mtu: u16) {
+        if !self.enabled {
+            return;
+        }
+        let span = info_span!(
+            target: "raknet::otel",
+            "raknet.handshake",
+            otel.name = "raknet.handshake",
+            otel.kind = "server",
+            otel.status_code = Empty,
+            client.address = %addr.ip(),
+            client.port = addr.port(),
+            raknet.protocol_version = protocol_version,
+            raknet.mtu = mtu,
+            raknet.failure_reason = Empty,
+        );
+        self.handshakes.put(addr, span);
+    }
+
+    /// The handshake of the peer is refused
+    pub(super) fn handshake_failed(&mut self, addr: SocketAddr, reason: &'static str) {
+        let Some(span) = self.handshakes.pop(&addr) else {
+            return;
+        };
+        span.record("otel.status_code", "ERROR");
+        span.record("raknet.failure_reason", reason);
+    }
+
+    /// The peer is connected with the final mtu, the connection span lasts until it is
+    /// disconnected.
+    pub(super) fn connected(&mut self, addr: SocketAddr, mtu: u16) {
+        if !self.enabled {
+            return;
+        }
+        let handshake = self.handshakes.pop(&addr);
+        if let Some(handshake) = &handshake {
+            handshake.record("otel.status_code", "OK");
+        }
+        let span = info_span!(
+            target: "raknet::otel",
+            "raknet.connection",
+            otel.name = "raknet.connection",
+            otel.kind = "server",
+            otel.status_code = Empty,
+            client.address = %addr.ip(),
+            client.port = addr.port(),
+            raknet.mtu = mtu,
+            raknet.disconnect_reason = Empty,
+        );
+        if let Some(handshake) = handshake {
+            span.follows_from(&handshake);
+        }
+        self.connections.insert(addr, span);
+    }
+
+    /// The connection is closed, the error status is set if it is not closed gracefully
+    pub(super) fn disconnected(&mut self, addr: SocketAddr, reason: &'static str, graceful: bool) {
+        let Some(span) = self.connections.remove(&addr) else {
+            return;
+        };
+        span.record("otel.status_code", if graceful { "OK" } else { "ERROR" });
+        span.record("raknet.disconnect_reason", reason);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_traces_lifecycle() {
+        let addr: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let mut traces = SessionTraces::new(true);
+        traces.handshake_started(addr, 11, 1400);
+        traces.connected(addr, 1400);
+        assert!(traces.handshakes.is_empty());
+        assert_eq!(traces.connections.len(), 1);
+        traces.disconnected(addr, "disconnect notification", true);
+        assert!(traces.connections.is_empty());
+
+        traces.handshake_started(addr, 11, 1400);
+        traces.handshake_failed(addr, "server is full");
+        assert!(traces.handshakes.is_empty());
+
+        let mut disabled = SessionTraces::new(false);
+        disabled.handshake_started(addr, 11, 1400);
+        disabled.connected(addr, 1400);
+        assert!(disabled.handshakes.is_empty());
+        assert!(disabled.connections.is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use tracing::Level;
+
+/// The target of the logs of the elevated peers
+pub(super) const PEER_TARGET: &str = "raknet::peer";
+
+/// Elevate the log verbosity of some peers at runtime, e.g. to debug the connection issues of a
+/// single player in production without raising the global log level. The logs of an elevated
+/// peer up to its level are emitted at INFO with the target `raknet::peer`, so they pass the
+/// usual filters. It is shared by the server handle and the connection tasks.
+#[derive(Debug, Clone, Default)]
+pub(super) struct PeerVerbosity {
+    levels: Arc<RwLock<HashMap<SocketAddr, Level>>>,
+    // The count of the elevated peers, checked without the lock on every log
+    elevated: Arc<AtomicUsize>,
+}
+
+impl PeerVerbosity {
+    /// Emit the logs of the peer up to the level, e.g. [`Level::TRACE`] for all of them
+    pub(super) fn elevate(&self, addr: SocketAddr, level: Level) {
+        let mut levels = self.levels.write().expect("verbosity lock poisoned");
+        levels.insert(addr, level);
+        self.elevated.store(levels.len(), Ordering::Release);
+    }
+
+    /// Restore the global verbosity of the peer
+    pub(super) fn reset(&self, addr: &SocketAddr) {
+        let mut levels = self.levels.write().expect("verbosity lock poisoned");
+        levels.remove(addr);
+        self.elevated.store(levels.len(), Ordering::Release);
+    }
+
+    /// Whether the logs of the peer at the level are elevated
+    pub(super) fn elevated(&self, addr: &SocketAddr, level: Level) -> bool {
+        // skip the lock while no peer is elevated
+        if self.elevated.load(Ordering::Acquire) == 0 {
+            return false;
+        }
+        let levels = self.levels.read().expect("verbosity lock poisoned");
+        levels.get(addr).is_some_and(|elevated| level <= *elevated)
+    }
+}
+
+/// Log at DEBUG, or at INFO with the target `raknet::peer` if the peer is elevated
+macro_rules! peer_debug {
+    ($verbosity:expr, $addr:expr, $($arg:tt)+) => {
+        if $verbosity.elevated(&$addr, tracing::Level::DEBUG) {
+            tracing::info!(
+                target: $crate::server::verbosity::PEER_TARGET,
+                peer = %$addr,
+                $($arg)+
+            );
+        } else {
+            tracing::debug!($($arg)+);
+        }
+    };
+}
+
+pub(super) use peer_debug;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peer_verbosity_works() {
+        let verbosity = PeerVerbosity::default();
+        let player: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        assert!(!verbosity.elevated(&player, Level::DEBUG));
+
+        verbosity.elevate(player, Level::DEBUG);
+        assert!(verbosity.elevated(&player, Level::DEBUG));
+        assert!(verbosity.elevated(&player, Level::WARN));
+        assert!(!verbosity.elevated(&player, Level::TRACE));
+        assert!(!verbosity.elevated(&other, Level::DEBUG));
+
+        verbosity.clone().reset(&player);
+        assert!(!verbosity.elevated(&player, Level::DEBUG));
+    }
+}
@@ -0,0 +1,140 @@
+use std::time::{Duration, Instant};
+
+use crate::event::StallDiagnostic;
+
+/// Watchdog config
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct WatchdogConfig {
+    // The connection is stalled if the datagrams in flight have been waiting for an ack for this
+    // many retransmission timeouts, 0 means disabled
+    rto_multiple: u32,
+    // The connection is stalled if an ordering channel is blocked by a missing frame for this
+    // long, None means disabled
+    channel_stall: Option<Duration>,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            rto_multiple: 4,
+            channel_stall: Some(Duration::from_secs(3)),
+        }
+    }
+}
+
+impl WatchdogConfig {
+    /// Report the connection stalled if the datagrams in flight have been waiting for an ack
+    /// for this many retransmission timeouts, 0 means disabled
+    #[must_use]
+    pub fn with_rto_multiple(mut self, rto_multiple: u32) -> Self {
+        self.rto_multiple = rto_multiple;
+        self
+    }
+
+    /// Report the connection stalled if an ordering channel is blocked by a missing frame for
+    /// this long, None means disabled
+    #[must_use]
+    pub fn with_channel_stall(mut self, channel_stall: Option<Duration>) -> Self {
+        self.channel_stall = channel_stall;
+        self
+    }
+}
+
+/// The progress of a connection inspected by the [`Watchdog`]
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Progress {
+    /// Since when the datagrams in flight have been waiting without any of them acknowledged
+    pub(super) oldest_unacked: Option<Instant>,
+    pub(super) rto: Duration,
+    pub(super) datagrams_in_flight: usize,
+    pub(super) bytes_in_flight: u64,
+    /// The ordering channel blocked by a missing frame for the longest, and since when
+    pub(super) blocked_channel: Option<(u8, Instant)>,
+}
+
+/// Detect the connections making no forward progress before they time out, so that the state
+/// at the time could be reported along with the eventual timeout. A stall is reported once until
+/// the connection makes progress again.
+#[derive(Debug)]
+pub(super) struct Watchdog {
+    config: WatchdogConfig,
+    reported: bool,
+}
+
+impl Watchdog {
+    pub(super) fn new(config: WatchdogConfig) -> Self {
+        Self {
+            config,
+            reported: false,
+        }
+    }
+
+    /// Inspect the progress at now, returns the diagnostic if the connection just stalled.
+    pub(super) fn check(&mut self, progress: &Progress, now: Instant) -> Option<StallDiagnostic> {
+        let unacked_for = progress
+            .oldest_unacked
+            .map(|sent_at| now.saturating_duration_since(sent_at));
+        let stalled_channel = progress
+            .blocked_channel
+            .map(|(channel, since)| (channel, now.saturating_duration_since(since)));
+
+        let unacked_stalled = self.config.rto_multiple != 0
+            && unacked_for.is_some_and(|elapsed| {
+                elapsed >= progress.rto.saturating_mul(self.config.rto_multiple)
+            });
+        let channel_stalled = self
+            .config
+            .channel_stall
+            .zip(stalled_channel)
+            .is_some_and(|(limit, (_, elapsed))| elapsed >= limit);
+        if !unacked_stalled && !channel_stalled {
+            self.reported = false;
+            return None;
+        }
+        if self.reported {
+            return None;
+        }
+        self.reported = true;
+        Some(StallDiagnostic {
+            unacked_for,
+            rto: progress.rto,
+            datagrams_in_flight: progress.datagrams_in_flight,
+            bytes_in_flight: progress.bytes_in_flight,
+            stalled_channel,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_reports_once() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let mut watchdog = Watchdog::new(WatchdogConfig::default());
+        let mut progress = Progress {
+            oldest_unacked: Some(now),
+            rto: ms(100),
+            datagrams_in_flight: 3,
+            bytes_in_flight: 1200,
+            blocked_channel: None,
+        };
+        assert!(watchdog.check(&progress, now + ms(399)).is_none());
+        let diagnostic = watchdog.check(&progress, now + ms(400)).unwrap();
+        assert_eq!(diagnostic.unacked_for, Some(ms(400)));
+        assert_eq!(diagnostic.datagrams_in_flight, 3);
+        assert!(watchdog.check(&progress, now + ms(500)).is_none());
+
+        // progress resumed, then a channel is blocked
+        progress.oldest_unacked = None;
+        assert!(watchdog.check(&progress, now + ms(600)).is_none());
+        progress.blocked_channel = Some((2, now));
+        let blocked = watchdog.check(&progress, now + ms(3000)).unwrap();
+        assert_eq!(blocked.stalled_channel, Some((2, ms(3000))));
+        assert_eq!(blocked.unacked_for, None);
+    }
+}
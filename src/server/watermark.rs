@@ -0,0 +1,102 @@
+use crate::errors::ConfigError;
+use crate::event::Event;
+
+/// Thresholds of the unacked and unsent bytes of a connection
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct WatermarkConfig {
+    // Emit HighWatermark when the pending bytes rise to it, 0 means disabled
+    high: usize,
+    // Emit LowWatermark when the pending bytes fall to it after HighWatermark was emitted
+    low: usize,
+}
+
+impl WatermarkConfig {
+    /// Emit `HighWatermark` when the pending bytes rise to `high`, and `LowWatermark` when they
+    /// fall to `low` afterwards. The high watermark 0 means disabled
+    pub fn new(high: usize, low: usize) -> Self {
+        Self { high, low }
+    }
+
+    pub(super) fn validate(&self) -> Result<(), ConfigError> {
+        if self.low > self.high {
+            return Err(ConfigError::Watermark(self.low, self.high));
+        }
+        Ok(())
+    }
+}
+
+/// Track the pending bytes of the send queue and report the crossing of watermarks with
+/// hysteresis, so that the events will not flap around a single threshold.
+#[derive(Debug)]
+pub(super) struct Watermark {
+    config: WatermarkConfig,
+    above: bool,
+}
+
+impl Watermark {
+    pub(super) fn new(config: WatermarkConfig) -> Self {
+        debug_assert!(
+            config.low <= config.high,
+            "low watermark should not be larger than high watermark"
+        );
+        Self {
+            config,
+            above: false,
+        }
+    }
+
+    /// Update the count of the unacked and unsent bytes
+    pub(super) fn update(&mut self, pending_bytes: usize) -> Option<Event> {
+        if self.config.high == 0 {
+            return None;
+        }
+        if !self.above && pending_bytes >= self.config.high {
+            self.above = true;
+            return Some(Event::HighWatermark {
+                pending_bytes: pending_bytes as u64,
+            });
+        }
+        if self.above && pending_bytes <= self.config.low {
+            self.above = false;
+            return Some(Event::LowWatermark {
+                pending_bytes: pending_bytes as u64,
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watermark_works() {
+        let mut watermark = Watermark::new(WatermarkConfig {
+            high: 1000,
+            low: 200,
+        });
+        assert_eq!(watermark.update(500), None);
+        assert_eq!(
+            watermark.update(1000),
+            Some(Event::HighWatermark {
+                pending_bytes: 1000
+            })
+        );
+        assert_eq!(watermark.update(2000), None);
+        assert_eq!(watermark.update(500), None);
+        assert_eq!(
+            watermark.update(100),
+            Some(Event::LowWatermark { pending_bytes: 100 })
+        );
+        assert_eq!(watermark.update(100), None);
+    }
+
+    #[test]
+    fn test_watermark_disabled() {
+        let mut watermark = Watermark::new(WatermarkConfig::default());
+        assert_eq!(watermark.update(usize::MAX), None);
+    }
+}
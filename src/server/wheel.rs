@@ -0,0 +1,178 @@
+use std::time::{Duration, Instant};
+
+/// The default resolution of the wheel, a timer fires at most this late
+pub(super) const DEFAULT_RESOLUTION: Duration = Duration::from_millis(10);
+
+/// The default count of slots, covering 5 seconds at the default resolution in one round
+pub(super) const DEFAULT_SLOTS: usize = 512;
+
+/// Identify a timer in the wheel to cancel it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) struct TimerId {
+    slot: usize,
+    id: u64,
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    id: u64,
+    // The absolute tick it fires at
+    at: u64,
+    value: T,
+}
+
+/// A hashed timing wheel holding the retransmission timers of the datagrams in flight of a
+/// connection task. Inserting and cancelling are O(1) on average no matter how many datagrams
+/// are in flight, and the task sleeps until [`TimerWheel::next_wakeup`] instead of polling a
+/// timer future per datagram.
+#[derive(Debug)]
+pub(super) struct TimerWheel<T> {
+    resolution: Duration,
+    start: Instant,
+    // The last tick expired
+    current: u64,
+    slots: Vec<Vec<Entry<T>>>,
+    next_id: u64,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub(super) fn new(resolution: Duration, slots: usize, now: Instant) -> Self {
+        assert!(!resolution.is_zero(), "resolution must be positive");
+        assert!(slots > 0, "slots must be positive");
+        Self {
+            resolution,
+            start: now,
+            current: 0,
+            slots: std::iter::repeat_with(Vec::new).take(slots).collect(),
+            next_id: 0,
+            len: 0,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn tick_of(&self, instant: Instant) -> u64 {
+        let elapsed = instant.saturating_duration_since(self.start);
+        (elapsed.as_nanos() / self.resolution.as_nanos()) as u64
+    }
+
+    /// Schedule the value to fire at the deadline, rounded up to the resolution. The deadlines
+    /// in the past fire on the next tick.
+    pub(super) fn insert(&mut self, deadline: Instant, value: T) -> TimerId {
+        let elapsed = deadline.saturating_duration_since(self.start);
+        let at =
+            (elapsed.as_nanos().div_ceil(self.resolution.as_nanos()) as u64).max(self.current + 1);
+        let slot = (at % self.slots.len() as u64) as usize;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.slots[slot].push(Entry { id, at, value });
+        self.len += 1;
+        TimerId { slot, id }
+    }
+
+    /// Cancel the timer, returns the value if it has not fired yet.
+    pub(super) fn cancel(&mut self, timer: TimerId) -> Option<T> {
+        let slot = &mut self.slots[timer.slot];
+        let pos = slot.iter().position(|entry| entry.id == timer.id)?;
+        self.len -= 1;
+        Some(slot.swap_remove(pos).value)
+    }
+
+    /// Fire the timers due at now in the order of the slots.
+    pub(super) fn expire(&mut self, now: Instant, mut fire: impl FnMut(T)) {
+        let now_tick = self.tick_of(now);
+        if now_tick <= self.current {
+            return;
+        }
+        let slots = self.slots.len() as u64;
+        // visit every slot once at most, the entries remember their absolute ticks
+        let ticks = (now_tick - self.current).min(slots);
+        for tick in (now_tick - ticks + 1)..=now_tick {
+            let slot = &mut self.slots[(tick % slots) as usize];
+            let mut idx = 0;
+            while idx < slot.len() {
+                if slot[idx].at <= now_tick {
+                    self.len -= 1;
+                    fire(slot.swap_remove(idx).value);
+                } else {
+                    idx += 1;
+                }
+            }
+        }
+        self.current = now_tick;
+    }
+
+    /// The next instant the owner should wake up to expire the timers, which is the earliest
+    /// armed deadline rounded up to the resolution. None if there is no timer.
+    pub(super) fn next_wakeup(&self) -> Option<Instant> {
+        let at = self.slots.iter().flatten().map(|entry| entry.at).min()?;
+        Some(self.start + self.resolution * u32::try_from(at).unwrap_or(u32::MAX))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expired(wheel: &mut TimerWheel<u32>, now: Instant) -> Vec<u32> {
+        let mut fired = Vec::new();
+        wheel.expire(now, |value| fired.push(value));
+        fired.sort_unstable();
+        fired
+    }
+
+    #[test]
+    fn test_timer_wheel_works() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let mut wheel = TimerWheel::new(ms(10), 8, now);
+        assert!(wheel.next_wakeup().is_none());
+
+        wheel.insert(now + ms(15), 1);
+        let cancelled = wheel.insert(now + ms(25), 2);
+        // more than one round away
+        wheel.insert(now + ms(200), 3);
+        wheel.insert(now, 4);
+        assert_eq!(wheel.len(), 4);
+        assert_eq!(wheel.next_wakeup(), Some(now + ms(10)));
+
+        assert_eq!(expired(&mut wheel, now + ms(10)), vec![4]);
+        assert_eq!(wheel.next_wakeup(), Some(now + ms(20)));
+        // rounded up to the resolution, never fires early
+        assert!(expired(&mut wheel, now + ms(19)).is_empty());
+        assert_eq!(expired(&mut wheel, now + ms(20)), vec![1]);
+
+        assert_eq!(wheel.cancel(cancelled), Some(2));
+        assert_eq!(wheel.cancel(cancelled), None);
+        // sleep until the armed deadline rather than the next tick
+        assert_eq!(wheel.next_wakeup(), Some(now + ms(200)));
+        assert!(expired(&mut wheel, now + ms(190)).is_empty());
+        assert_eq!(expired(&mut wheel, now + ms(200)), vec![3]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_timer_wheel_long_pause() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let mut wheel = TimerWheel::new(ms(10), 4, now);
+        for i in 0..10 {
+            wheel.insert(now + ms(10) * i, i);
+        }
+        wheel.insert(now + ms(1000), 100);
+        // many rounds elapsed since the last expiry
+        assert_eq!(
+            expired(&mut wheel, now + ms(500)),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert_eq!(wheel.len(), 1);
+        assert_eq!(expired(&mut wheel, now + ms(1000)), vec![100]);
+    }
+}
@@ -0,0 +1,779 @@
+#[cfg(feature = "server")]
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+#[cfg(feature = "server")]
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(feature = "server")]
+use std::time::Instant;
+
+#[cfg(feature = "server")]
+use crate::errors::CodecError;
+
+/// Statistics of a connection
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionStats {
+    /// Flow statistics of each ordering channel, indexed by channel
+    pub channels: Vec<ChannelStats>,
+    /// Flow statistics of the messages without ordering channel
+    pub unordered: ChannelStats,
+    /// Congestion controller statistics
+    pub congestion: CongestionStats,
+    /// Path statistics
+    pub path: PathStats,
+    /// Reordering statistics of the ordered frames received
+    pub ordering: OrderingStats,
+    /// Counters of the discarded data
+    pub drops: DropStats,
+    /// Outgoing split messages holding a parted id, until all their fragments are acknowledged
+    /// or abandoned, or the timeout of an unreliable split. No more than 65536 could be pending.
+    pub pending_splits: u64,
+}
+
+/// Congestion controller statistics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CongestionStats {
+    /// Congestion window in bytes
+    pub cwnd: u64,
+    /// Bytes sent but not acknowledged
+    pub bytes_in_flight: u64,
+    /// Slow start threshold in bytes, 0 means it has not been set
+    pub ss_thresh: u64,
+    /// Whether the congestion controller is in slow start
+    pub slow_start: bool,
+}
+
+/// Path statistics measured by the reliability layer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PathStats {
+    /// The mtu negotiated in the handshake, or discovered by the path mtu probes later
+    pub mtu: u16,
+    /// Smoothed ratio of the datagrams lost to the datagrams sent, in 0.0..=1.0
+    pub loss_rate: f32,
+}
+
+/// Flow statistics of an ordering channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelStats {
+    /// Count of messages sent
+    pub sent_messages: u64,
+    /// Bytes of messages sent
+    pub sent_bytes: u64,
+    /// Count of messages received
+    pub received_messages: u64,
+    /// Bytes of messages received
+    pub received_bytes: u64,
+}
+
+/// Reordering statistics of the ordered frames received on all channels, which help to tune
+/// the channel usage and to detect the pathological peers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OrderingStats {
+    /// How many frame indices ahead of the read index the frames arrive, 0 means in order
+    pub reorder_depth: Histogram,
+    /// How long the frames wait for the missing ones before delivery, in microseconds
+    pub wait_micros: Histogram,
+}
+
+/// The count of buckets of a [`Histogram`]
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+/// A histogram of the samples in power of two buckets, `buckets[0]` counts the zeros and
+/// `buckets[i]` counts the samples in `2^(i-1)..2^i`, the last bucket takes all the larger ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Histogram {
+    /// Count of samples in each bucket
+    pub buckets: [u64; HISTOGRAM_BUCKETS],
+    /// Count of samples
+    pub count: u64,
+    /// Sum of samples
+    pub sum: u64,
+    /// The largest sample
+    pub max: u64,
+}
+
+impl Histogram {
+    #[cfg(feature = "server")]
+    fn bucket(value: u64) -> usize {
+        ((u64::BITS - value.leading_zeros()) as usize).min(HISTOGRAM_BUCKETS - 1)
+    }
+
+    /// The mean of samples, 0.0 if there is no sample
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    /// Estimate the quantile in `0.0..=1.0` (e.g. 0.99 for p99) by the upper bound of the bucket
+    /// it falls in, 0 if there is no sample
+    pub fn quantile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, cnt) in self.buckets.iter().enumerate() {
+            seen += cnt;
+            if seen >= rank {
+                let upper = if i == 0 { 0 } else { (1 << i) - 1 };
+                return if i == HISTOGRAM_BUCKETS - 1 {
+                    self.max
+                } else {
+                    upper.min(self.max)
+                };
+            }
+        }
+        self.max
+    }
+}
+
+/// Record the samples of a [`Histogram`]
+#[cfg(feature = "server")]
+#[derive(Debug, Default)]
+struct HistogramRecorder {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+#[cfg(feature = "server")]
+impl HistogramRecorder {
+    fn record(&self, value: u64) {
+        self.buckets[Histogram::bucket(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Why a piece of data is discarded silently
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DropReason {
+    /// The packet can not be decoded
+    Malformed,
+    /// The magic of the unconnected packet does not match
+    BadMagic,
+    /// The frame set has a replayed sequence number
+    Replayed,
+    /// The reliable frame has been received
+    Duplicate,
+    /// The ordered frame index is older than the read index
+    StaleOrdered,
+    /// The source exceeds the rate limit
+    RateLimited,
+    /// The parted frames exceed the size limit
+    Oversized,
+    /// The datagram is dropped by the packet filter or the datagram hook
+    Filtered,
+    /// The ordered frame references a channel beyond the limit
+    ChannelExceeded,
+    /// The datagram exceeds the max datagram size
+    TooLarge,
+    /// The offline packet arrives out of the handshake order
+    OutOfOrder,
+}
+
+#[cfg(feature = "server")]
+impl DropReason {
+    const COUNT: usize = 11;
+
+    /// Classify the decoding error
+    pub(crate) fn of(err: &CodecError) -> Self {
+        match err {
+            CodecError::MagicNotMatched(..) => DropReason::BadMagic,
+            CodecError::PartedSizeExceed(..) | CodecError::ReassembledSizeExceed(..) => {
+                DropReason::Oversized
+            }
+            CodecError::ChannelExceeded(..) => DropReason::ChannelExceeded,
+            _ => DropReason::Malformed,
+        }
+    }
+}
+
+/// Counters of the discarded data by reason, which help operators to tell attacks from bugs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DropStats {
+    /// Packets can not be decoded
+    pub malformed: u64,
+    /// Unconnected packets with a mismatched magic
+    pub bad_magic: u64,
+    /// Frame sets with replayed sequence numbers
+    pub replayed: u64,
+    /// Duplicate reliable frames
+    pub duplicate: u64,
+    /// Ordered frames older than the read index
+    pub stale_ordered: u64,
+    /// Packets from the sources exceeding the rate limit
+    pub rate_limited: u64,
+    /// Parted frames exceeding the size limit
+    pub oversized: u64,
+    /// Datagrams dropped by the packet filter or the datagram hook
+    pub filtered: u64,
+    /// Ordered frames referencing a channel beyond the limit
+    pub channel_exceeded: u64,
+    /// Datagrams exceeding the max datagram size
+    pub too_large: u64,
+    /// Offline packets out of the handshake order
+    pub out_of_order: u64,
+}
+
+impl DropStats {
+    /// Get the counter of the reason
+    pub fn get(&self, reason: DropReason) -> u64 {
+        match reason {
+            DropReason::Malformed => self.malformed,
+            DropReason::BadMagic => self.bad_magic,
+            DropReason::Replayed => self.replayed,
+            DropReason::Duplicate => self.duplicate,
+            DropReason::StaleOrdered => self.stale_ordered,
+            DropReason::RateLimited => self.rate_limited,
+            DropReason::Oversized => self.oversized,
+            DropReason::Filtered => self.filtered,
+            DropReason::ChannelExceeded => self.channel_exceeded,
+            DropReason::TooLarge => self.too_large,
+            DropReason::OutOfOrder => self.out_of_order,
+        }
+    }
+}
+
+/// Count the discarded data by reason
+#[cfg(feature = "server")]
+#[derive(Debug, Default)]
+pub(crate) struct DropCounter {
+    counts: [AtomicU64; DropReason::COUNT],
+}
+
+#[cfg(feature = "server")]
+impl DropCounter {
+    pub(crate) fn record(&self, reason: DropReason, count: u64) {
+        self.counts[reason as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> DropStats {
+        let load = |reason: DropReason| self.counts[reason as usize].load(Ordering::Relaxed);
+        DropStats {
+            malformed: load(DropReason::Malformed),
+            bad_magic: load(DropReason::BadMagic),
+            replayed: load(DropReason::Replayed),
+            duplicate: load(DropReason::Duplicate),
+            stale_ordered: load(DropReason::StaleOrdered),
+            rate_limited: load(DropReason::RateLimited),
+            oversized: load(DropReason::Oversized),
+            filtered: load(DropReason::Filtered),
+            channel_exceeded: load(DropReason::ChannelExceeded),
+            too_large: load(DropReason::TooLarge),
+            out_of_order: load(DropReason::OutOfOrder),
+        }
+    }
+}
+
+/// Bandwidth in bytes per second
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bandwidth {
+    /// Bytes sent per second
+    pub send: f64,
+    /// Bytes received per second
+    pub receive: f64,
+}
+
+impl ChannelStats {
+    /// Report the bandwidth since an earlier snapshot of this channel
+    pub fn bandwidth_since(&self, earlier: &ChannelStats, elapsed: Duration) -> Bandwidth {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return Bandwidth::default();
+        }
+        Bandwidth {
+            send: self.sent_bytes.saturating_sub(earlier.sent_bytes) as f64 / secs,
+            receive: self.received_bytes.saturating_sub(earlier.received_bytes) as f64 / secs,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Default)]
+struct ChannelCounter {
+    sent_messages: AtomicU64,
+    sent_bytes: AtomicU64,
+    received_messages: AtomicU64,
+    received_bytes: AtomicU64,
+}
+
+#[cfg(feature = "server")]
+impl ChannelCounter {
+    fn snapshot(&self) -> ChannelStats {
+        ChannelStats {
+            sent_messages: self.sent_messages.load(Ordering::Relaxed),
+            sent_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            received_messages: self.received_messages.load(Ordering::Relaxed),
+            received_bytes: self.received_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Statistics of the socket event loop of a server, accumulated since it is started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EventLoopStats {
+    /// Datagrams received from the socket
+    pub datagrams_received: u64,
+    /// Datagrams sent to the socket
+    pub datagrams_sent: u64,
+    /// Receiving syscalls, each of them may receive a batch of datagrams (recvmmsg)
+    pub recv_syscalls: u64,
+    /// Sending syscalls, each of them may send a batch of datagrams (sendmmsg)
+    pub send_syscalls: u64,
+    /// Datagrams dropped by the kernel because the socket receive buffer is full, reported by
+    /// `SO_RXQ_OVFL`. None if the platform does not report it.
+    pub socket_drops: Option<u64>,
+    /// Effective size of the socket receive buffer in bytes as reported by the kernel after
+    /// clamping, Linux reports twice the requested size for its bookkeeping. None if unknown.
+    pub recv_buffer_size: Option<u64>,
+    /// Effective size of the socket send buffer in bytes as reported by the kernel after
+    /// clamping. None if unknown.
+    pub send_buffer_size: Option<u64>,
+}
+
+/// Rates of the socket event loop between two [`EventLoopStats`] snapshots
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EventLoopRates {
+    /// Datagrams received per second
+    pub received_per_sec: f64,
+    /// Datagrams sent per second
+    pub sent_per_sec: f64,
+    /// Syscalls, receiving and sending, per second
+    pub syscalls_per_sec: f64,
+    /// Average datagrams received by a syscall, it stays at 1 without batching
+    pub avg_recv_batch: f64,
+    /// Average datagrams sent by a syscall, it stays at 1 without batching
+    pub avg_send_batch: f64,
+    /// Datagrams dropped by the kernel per second, a sign of hitting the kernel limits
+    pub socket_drops_per_sec: f64,
+}
+
+impl EventLoopStats {
+    /// Report the rates since an earlier snapshot of the event loop
+    pub fn rates_since(&self, earlier: &EventLoopStats, elapsed: Duration) -> EventLoopRates {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return EventLoopRates::default();
+        }
+        let received = self
+            .datagrams_received
+            .saturating_sub(earlier.datagrams_received) as f64;
+        let sent = self.datagrams_sent.saturating_sub(earlier.datagrams_sent) as f64;
+        let recv_syscalls = self.recv_syscalls.saturating_sub(earlier.recv_syscalls) as f64;
+        let send_syscalls = self.send_syscalls.saturating_sub(earlier.send_syscalls) as f64;
+        let average = |datagrams: f64, syscalls: f64| {
+            if syscalls > 0.0 {
+                datagrams / syscalls
+            } else {
+                0.0
+            }
+        };
+        let socket_drops = self
+            .socket_drops
+            .zip(earlier.socket_drops)
+            .map_or(0, |(now, before)| now.saturating_sub(before));
+        EventLoopRates {
+            received_per_sec: received / secs,
+            sent_per_sec: sent / secs,
+            syscalls_per_sec: (recv_syscalls + send_syscalls) / secs,
+            avg_recv_batch: average(received, recv_syscalls),
+            avg_send_batch: average(sent, send_syscalls),
+            socket_drops_per_sec: socket_drops as f64 / secs,
+        }
+    }
+}
+
+/// Record the statistics of the socket event loop, shared between the IO tasks and the user.
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub(crate) struct EventLoopRecorder {
+    datagrams_received: AtomicU64,
+    datagrams_sent: AtomicU64,
+    recv_syscalls: AtomicU64,
+    send_syscalls: AtomicU64,
+    // u64::MAX means unknown, for the following fields
+    socket_drops: AtomicU64,
+    recv_buffer_size: AtomicU64,
+    send_buffer_size: AtomicU64,
+}
+
+#[cfg(feature = "server")]
+impl Default for EventLoopRecorder {
+    fn default() -> Self {
+        Self {
+            datagrams_received: AtomicU64::new(0),
+            datagrams_sent: AtomicU64::new(0),
+            recv_syscalls: AtomicU64::new(0),
+            send_syscalls: AtomicU64::new(0),
+            socket_drops: AtomicU64::new(u64::MAX),
+            recv_buffer_size: AtomicU64::new(u64::MAX),
+            send_buffer_size: AtomicU64::new(u64::MAX),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl EventLoopRecorder {
+    /// A receiving syscall returned the count of datagrams
+    pub(crate) fn record_recv(&self, datagrams: usize) {
+        self.recv_syscalls.fetch_add(1, Ordering::Relaxed);
+        self.datagrams_received
+            .fetch_add(datagrams as u64, Ordering::Relaxed);
+    }
+
+    /// A sending syscall sent the count of datagrams
+    pub(crate) fn record_send(&self, datagrams: usize) {
+        self.send_syscalls.fetch_add(1, Ordering::Relaxed);
+        self.datagrams_sent
+            .fetch_add(datagrams as u64, Ordering::Relaxed);
+    }
+
+    /// The kernel reported the total count of dropped datagrams of the socket, as the ancillary
+    /// data of `SO_RXQ_OVFL`
+    pub(crate) fn record_socket_drops(&self, total: u32) {
+        self.socket_drops.store(u64::from(total), Ordering::Relaxed);
+    }
+
+    /// The effective sizes of the socket buffers read back from the kernel
+    pub(crate) fn record_socket_buffers(&self, recv: usize, send: usize) {
+        self.recv_buffer_size.store(recv as u64, Ordering::Relaxed);
+        self.send_buffer_size.store(send as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> EventLoopStats {
+        let known = |value: &AtomicU64| {
+            let value = value.load(Ordering::Relaxed);
+            (value != u64::MAX).then_some(value)
+        };
+        EventLoopStats {
+            datagrams_received: self.datagrams_received.load(Ordering::Relaxed),
+            datagrams_sent: self.datagrams_sent.load(Ordering::Relaxed),
+            recv_syscalls: self.recv_syscalls.load(Ordering::Relaxed),
+            send_syscalls: self.send_syscalls.load(Ordering::Relaxed),
+            socket_drops: known(&self.socket_drops),
+            recv_buffer_size: known(&self.recv_buffer_size),
+            send_buffer_size: known(&self.send_buffer_size),
+        }
+    }
+}
+
+/// Record the statistics of a connection, shared between the connection tasks and the user.
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub(crate) struct StatsRecorder {
+    channels: Vec<ChannelCounter>,
+    unordered: ChannelCounter,
+    cwnd: AtomicU64,
+    bytes_in_flight: AtomicU64,
+    ss_thresh: AtomicU64,
+    slow_start: AtomicBool,
+    mtu: AtomicU16,
+    // f32 bits
+    loss_rate: AtomicU32,
+    drops: DropCounter,
+    reorder_depth: HistogramRecorder,
+    wait_micros: HistogramRecorder,
+    // The ordering channel blocked by a missing frame for the longest, and since when
+    blocked_channel: Mutex<Option<(u8, Instant)>>,
+    // The bytes held by the ordering windows and the reassembly of the codec, charged to the
+    // memory budget of the connection
+    reorder_buffered: AtomicU64,
+    reassembly_buffered: AtomicU64,
+    pending_splits: AtomicU64,
+}
+
+#[cfg(feature = "server")]
+impl StatsRecorder {
+    pub(crate) fn new(max_channels: usize) -> Self {
+        Self {
+            channels: std::iter::repeat_with(ChannelCounter::default)
+                .take(max_channels)
+                .collect(),
+            unordered: ChannelCounter::default(),
+            cwnd: AtomicU64::new(0),
+            bytes_in_flight: AtomicU64::new(0),
+            ss_thresh: AtomicU64::new(0),
+            slow_start: AtomicBool::new(true),
+            mtu: AtomicU16::new(0),
+            loss_rate: AtomicU32::new(0.0_f32.to_bits()),
+            drops: DropCounter::default(),
+            reorder_depth: HistogramRecorder::default(),
+            wait_micros: HistogramRecorder::default(),
+            blocked_channel: Mutex::new(None),
+            reorder_buffered: AtomicU64::new(0),
+            reassembly_buffered: AtomicU64::new(0),
+            pending_splits: AtomicU64::new(0),
+        }
+    }
+
+    fn counter(&self, channel: Option<u8>) -> &ChannelCounter {
+        channel
+            .and_then(|channel| self.channels.get(usize::from(channel)))
+            .unwrap_or(&self.unordered)
+    }
+
+    pub(crate) fn record_received(&self, channel: Option<u8>, bytes: usize) {
+        let counter = self.counter(channel);
+        counter.received_messages.fetch_add(1, Ordering::Relaxed);
+        counter
+            .received_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_sent(&self, channel: Option<u8>, bytes: usize) {
+        let counter = self.counter(channel);
+        counter.sent_messages.fetch_add(1, Ordering::Relaxed);
+        counter
+            .sent_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped(&self, reason: DropReason, count: u64) {
+        self.drops.record(reason, count);
+    }
+
+    /// An ordered frame arrives `depth` frame indices ahead of the read index
+    pub(crate) fn record_reorder_depth(&self, depth: u32) {
+        self.reorder_depth.record(u64::from(depth));
+    }
+
+    /// An ordered frame is delivered after waiting for the missing ones
+    pub(crate) fn record_ordered_wait(&self, wait: Duration) {
+        self.wait_micros
+            .record(u64::try_from(wait.as_micros()).unwrap_or(u64::MAX));
+    }
+
+    /// The ordering channel blocked by a missing frame for the longest after a frame set is
+    /// ordered, None if no channel is blocked
+    pub(crate) fn record_blocked_channel(&self, blocked: Option<(u8, Instant)>) {
+        *self
+            .blocked_channel
+            .lock()
+            .expect("blocked channel lock poisoned") = blocked;
+    }
+
+    pub(crate) fn blocked_channel(&self) -> Option<(u8, Instant)> {
+        *self
+            .blocked_channel
+            .lock()
+            .expect("blocked channel lock poisoned")
+    }
+
+    /// The bytes of the ordered frames waiting for the missing ones
+    pub(crate) fn record_reorder_buffered(&self, bytes: usize) {
+        self.reorder_buffered.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The bytes of the fragments waiting to be reassembled
+    pub(crate) fn record_reassembly_buffered(&self, bytes: usize) {
+        self.reassembly_buffered
+            .store(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reorder_buffered(&self) -> usize {
+        self.reorder_buffered.load(Ordering::Relaxed) as usize
+    }
+
+    pub(crate) fn reassembly_buffered(&self) -> usize {
+        self.reassembly_buffered.load(Ordering::Relaxed) as usize
+    }
+
+    pub(crate) fn record_congestion(&self, stats: CongestionStats) {
+        self.cwnd.store(stats.cwnd, Ordering::Relaxed);
+        self.bytes_in_flight
+            .store(stats.bytes_in_flight, Ordering::Relaxed);
+        self.ss_thresh.store(stats.ss_thresh, Ordering::Relaxed);
+        self.slow_start.store(stats.slow_start, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_pending_splits(&self, splits: usize) {
+        self.pending_splits.store(splits as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_mtu(&self, mtu: u16) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_loss_rate(&self, loss_rate: f32) {
+        self.loss_rate.store(loss_rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn mtu(&self) -> u16 {
+        self.mtu.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_in_flight(&self) -> u64 {
+        self.bytes_in_flight.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn loss_rate(&self) -> f32 {
+        f32::from_bits(self.loss_rate.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            channels: self.channels.iter().map(ChannelCounter::snapshot).collect(),
+            unordered: self.unordered.snapshot(),
+            congestion: CongestionStats {
+                cwnd: self.cwnd.load(Ordering::Relaxed),
+                bytes_in_flight: self.bytes_in_flight.load(Ordering::Relaxed),
+                ss_thresh: self.ss_thresh.load(Ordering::Relaxed),
+                slow_start: self.slow_start.load(Ordering::Relaxed),
+            },
+            path: PathStats {
+                mtu: self.mtu(),
+                loss_rate: self.loss_rate(),
+            },
+            ordering: OrderingStats {
+                reorder_depth: self.reorder_depth.snapshot(),
+                wait_micros: self.wait_micros.snapshot(),
+            },
+            drops: self.drops.snapshot(),
+            pending_splits: self.pending_splits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats_recorder_works() {
+        let recorder = StatsRecorder::new(2);
+        recorder.record_received(Some(0), 100);
+        recorder.record_received(Some(0), 50);
+        recorder.record_sent(Some(1), 10);
+        recorder.record_received(None, 1);
+        // channels out of range are counted as unordered
+        recorder.record_received(Some(5), 1);
+
+        let stats = recorder.snapshot();
+        assert_eq!(stats.channels.len(), 2);
+        assert_eq!(stats.channels[0].received_messages, 2);
+        assert_eq!(stats.channels[0].received_bytes, 150);
+        assert_eq!(stats.channels[1].sent_messages, 1);
+        assert_eq!(stats.channels[1].sent_bytes, 10);
+        assert_eq!(stats.unordered.received_messages, 2);
+
+        recorder.record_dropped(DropReason::Duplicate, 3);
+        recorder.record_dropped(DropReason::of(&CodecError::MagicNotMatched(0, 0)), 1);
+        let drops = recorder.snapshot().drops;
+        assert_eq!(drops.duplicate, 3);
+        assert_eq!(drops.get(DropReason::BadMagic), 1);
+        assert_eq!(drops.malformed, 0);
+
+        recorder.record_mtu(1400);
+        recorder.record_loss_rate(0.25);
+        assert_eq!(
+            recorder.snapshot().path,
+            PathStats {
+                mtu: 1400,
+                loss_rate: 0.25
+            }
+        );
+
+        let bandwidth =
+            stats.channels[0].bandwidth_since(&ChannelStats::default(), Duration::from_secs(2));
+        assert_eq!(
+            bandwidth,
+            Bandwidth {
+                send: 0.0,
+                receive: 75.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_histogram_works() {
+        let recorder = StatsRecorder::new(1);
+        assert_eq!(recorder.snapshot().ordering.reorder_depth.quantile(0.5), 0);
+        for depth in [0, 0, 0, 0, 0, 0, 0, 3, 6, 900] {
+            recorder.record_reorder_depth(depth);
+        }
+        recorder.record_ordered_wait(Duration::from_millis(20));
+
+        let ordering = recorder.snapshot().ordering;
+        let depth = ordering.reorder_depth;
+        assert_eq!(depth.count, 10);
+        assert_eq!(depth.buckets[0], 7);
+        assert_eq!(depth.buckets[2], 1);
+        assert_eq!(depth.buckets[3], 1);
+        assert_eq!(depth.buckets[10], 1);
+        assert_eq!(depth.max, 900);
+        assert!((depth.mean() - 90.9).abs() < f64::EPSILON);
+        assert_eq!(depth.quantile(0.5), 0);
+        assert_eq!(depth.quantile(0.8), 3);
+        assert_eq!(depth.quantile(0.9), 7);
+        // bounded by the max
+        assert_eq!(depth.quantile(1.0), 900);
+        assert_eq!(ordering.wait_micros.sum, 20_000);
+
+        let mut huge = Histogram::default();
+        huge.buckets[HISTOGRAM_BUCKETS - 1] = 1;
+        huge.count = 1;
+        huge.max = u64::MAX;
+        assert_eq!(Histogram::bucket(u64::MAX), HISTOGRAM_BUCKETS - 1);
+        assert_eq!(huge.quantile(0.99), u64::MAX);
+    }
+
+    #[test]
+    fn test_event_loop_recorder_works() {
+        let recorder = EventLoopRecorder::default();
+        let earlier = recorder.snapshot();
+        assert_eq!(earlier.socket_drops, None);
+
+        recorder.record_recv(16);
+        recorder.record_recv(4);
+        recorder.record_send(10);
+        recorder.record_socket_drops(7);
+        let stats = recorder.snapshot();
+        assert_eq!(stats.datagrams_received, 20);
+        assert_eq!(stats.recv_syscalls, 2);
+        assert_eq!(stats.socket_drops, Some(7));
+        assert_eq!(stats.recv_buffer_size, None);
+
+        recorder.record_socket_buffers(212_992, 65_536);
+        let buffered = recorder.snapshot();
+        assert_eq!(buffered.recv_buffer_size, Some(212_992));
+        assert_eq!(buffered.send_buffer_size, Some(65_536));
+
+        let rates = buffered.rates_since(&earlier, Duration::from_secs(2));
+        assert_eq!(
+            rates,
+            EventLoopRates {
+                received_per_sec: 10.0,
+                sent_per_sec: 5.0,
+                syscalls_per_sec: 1.5,
+                avg_recv_batch: 10.0,
+                avg_send_batch: 10.0,
+                // unknown at the earlier snapshot
+                socket_drops_per_sec: 0.0,
+            }
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Fabricate the protocol inputs of the reliability layer, so that the downstream crates could
+//! unit test their integrations against realistic frame sets, acks and split sequences. Only
+//! available with the `test-util` feature.
+
+use bytes::Bytes;
+
+use crate::wire::{
+    connected, AckOrNack, Flags, Fragment, Frame, FrameSet, Ordered, Reliability, Uint24le,
+};
+
+fn frame(body: Bytes) -> Frame<Bytes> {
+    Frame {
+        flags: Flags::new(Reliability::Unreliable, false),
+        reliable_frame_index: None,
+        seq_frame_index: None,
+        ordered: None,
+        fragment: None,
+        body,
+    }
+}
+
+/// Set the flags by the indices and the fragment the frame carries, so that it is encoded and
+/// decoded as is
+fn flagged(frame: Frame<Bytes>) -> Frame<Bytes> {
+    let reliability = match (
+        frame.reliable_frame_index.is_some(),
+        frame.seq_frame_index.is_some(),
+        frame.ordered.is_some(),
+    ) {
+        (true, true, _) => Reliability::ReliableSequenced,
+        (true, false, true) => Reliability::ReliableOrdered,
+        (true, false, false) => Reliability::Reliable,
+        (false, true, _) => Reliability::UnreliableSequenced,
+        (false, false, _) => Reliability::Unreliable,
+    };
+    Frame {
+        flags: Flags::new(reliability, frame.fragment.is_some()),
+        ..frame
+    }
+}
+
+/// Wrap the frames into a frame set with the sequence number
+pub fn frame_set(
+    seq_num: u32,
+    frames: impl IntoIterator<Item = Frame<Bytes>>,
+) -> connected::Packet<Bytes> {
+    connected::Packet::FrameSet(FrameSet {
+        seq_num: Uint24le(seq_num),
+        frames: frames.into_iter().collect(),
+    })
+}
+
+/// A frame set of empty frames with the reliable frame indices, useful to exercise the
+/// deduplication
+pub fn reliable_frame_set(indices: impl IntoIterator<Item = u32>) -> connected::Packet<Bytes> {
+    frame_set(
+        0,
+        indices.into_iter().map(|idx| {
+            flagged(Frame {
+                reliable_frame_index: Some(Uint24le(idx)),
+                ..frame(Bytes::new())
+            })
+        }),
+    )
+}
+
+/// A frame set of empty reliable ordered frames with the `(channel, ordered frame index)`,
+/// useful to exercise the ordering. The reliable frame index of each frame is the same as its
+/// ordered frame index.
+pub fn ordered_frame_set(indices: impl IntoIterator<Item = (u8, u32)>) -> connected::Packet<Bytes> {
+    frame_set(
+        0,
+        indices.into_iter().map(|(channel, frame_index)| {
+            flagged(Frame {
+                reliable_frame_index: Some(Uint24le(frame_index)),
+                ordered: Some(Ordered {
+                    frame_index: Uint24le(frame_index),
+                    channel,
+                }),
+                ..frame(Bytes::new())
+            })
+        }),
+    )
+}
+
+/// A reliable frame of the message body, ordered on the channel if there is one, useful to
+/// exercise the statistics of each channel. All of its frame indices are 0.
+pub fn message_frame(channel: Option<u8>, body: Bytes) -> Frame<Bytes> {
+    flagged(Frame {
+        reliable_frame_index: Some(Uint24le(0)),
+        ordered: channel.map(|channel| Ordered {
+            frame_index: Uint24le(0),
+            channel,
+        }),
+        ..frame(body)
+    })
+}
+
+/// Split the body into parted frames of at most `part_size` bytes sharing the `parted_id`, in
+/// order. Shuffle or drop some of them to exercise the reassembly.
+///
+/// # Panics
+///
+/// Panics if `part_size` is 0.
+pub fn split(body: &Bytes, parted_id: u16, part_size: usize) -> Vec<Frame<Bytes>> {
+    assert!(part_size > 0, "part size must be positive");
+    let parted_size = body.len().div_ceil(part_size).max(1) as u32;
+    (0..parted_size)
+        .map(|parted_index| {
+            let start = parted_index as usize * part_size;
+            let end = (start + part_size).min(body.len());
+            flagged(Frame {
+                fragment: Some(Fragment {
+                    parted_size,
+                    parted_id,
+                    parted_index,
+                }),
+                ..frame(body.slice(start..end))
+            })
+        })
+        .collect()
+}
+
+fn records(seq_nums: impl IntoIterator<Item = u32>) -> AckOrNack {
+    let mut seq_nums: Vec<u32> = seq_nums.into_iter().collect();
+    seq_nums.sort_unstable();
+    seq_nums.dedup();
+    AckOrNack::extend_from(seq_nums.into_iter(), u16::MAX).unwrap_or(AckOrNack {
+        records: Vec::new(),
+    })
+}
+
+/// An ack of the sequence numbers, in any order and duplicates allowed
+pub fn ack(seq_nums: impl IntoIterator<Item = u32>) -> connected::Packet<Bytes> {
+    connected::Packet::Ack(records(seq_nums))
+}
+
+/// A nack of the sequence numbers, in any order and duplicates allowed
+pub fn nack(seq_nums: impl IntoIterator<Item = u32>) -> connected::Packet<Bytes> {
+    connected::Packet::Nack(records(seq_nums))
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::wire::{decode_frame_set, encode_frame_set, Record};
+
+    #[test]
+    fn test_frame_sets_round_trip() {
+        let body = Bytes::from_static(b"hello world");
+        let sets = [
+            reliable_frame_set([0, 1]),
+            ordered_frame_set([(0, 0), (1, 3)]),
+            frame_set(0, split(&body, 7, 4)),
+            frame_set(
+                1,
+                [
+                    message_frame(Some(2), body.clone()),
+                    message_frame(None, body.clone()),
+                ],
+            ),
+        ];
+        for set in sets {
+            let connected::Packet::FrameSet(mut expected) = set else {
+                unreachable!()
+            };
+            // the empty frames are not valid on the wire
+            for frame in &mut expected.frames {
+                if frame.body.is_empty() {
+                    frame.body = Bytes::from_static(b"x");
+                }
+            }
+            let mut buf = BytesMut::new();
+            encode_frame_set(expected.clone(), &mut buf);
+            let decoded = decode_frame_set(&mut buf).unwrap();
+            let decoded: Vec<_> = decoded
+                .frames
+                .into_iter()
+                .map(|frame| Frame {
+                    body: frame.body.freeze(),
+                    ..frame
+                })
+                .collect();
+            assert_eq!(decoded, expected.frames);
+        }
+        let parts = split(&body, 7, 4);
+        assert!(parts.iter().all(|part| part.flags.parted()));
+        assert_eq!(ordered_frame_set([(0, 0)]), ordered_frame_set([(0, 0)]));
+    }
+
+    #[test]
+    fn test_split_and_ack() {
+        let body = Bytes::from_static(b"hello world");
+        let parts = split(&body, 7, 4);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[2].body, Bytes::from_static(b"rld"));
+        assert_eq!(
+            parts[1].fragment,
+            Some(Fragment {
+                parted_size: 3,
+                parted_id: 7,
+                parted_index: 1,
+            })
+        );
+        let joined: Vec<u8> = parts.iter().flat_map(|part| part.body.to_vec()).collect();
+        assert_eq!(joined, body);
+
+        assert_eq!(
+            ack([4, 0, 1, 2, 2]),
+            connected::Packet::Ack(AckOrNack {
+                records: vec![
+                    Record::Range(Uint24le(0), Uint24le(2)),
+                    Record::Single(Uint24le(4)),
+                ],
+            })
+        );
+        assert_eq!(
+            nack([]),
+            connected::Packet::Nack(AckOrNack {
+                records: Vec::new()
+            })
+        );
+    }
+}
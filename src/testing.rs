@@ -0,0 +1,178 @@
+//! A known-good echo endpoint for the downstream crates to benchmark and integration test
+//! against programmatically, only available with the `test-util` feature.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+/// Options of the echo server
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EchoConfig {
+    /// The max connections served at the same time, the rest wait to be served. 0 means no
+    /// limit.
+    pub max_connections: usize,
+}
+
+/// Statistics of the echo server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EchoStats {
+    /// Connections accepted
+    pub accepted: u64,
+    /// Connections being served
+    pub active: u64,
+    /// Messages echoed
+    pub messages: u64,
+    /// Bytes echoed
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Access the statistics of a running echo server
+#[derive(Debug, Clone, Default)]
+pub struct EchoHandle {
+    counters: Arc<Counters>,
+}
+
+impl EchoHandle {
+    /// Get the statistics of the echo server
+    pub fn stats(&self) -> EchoStats {
+        EchoStats {
+            accepted: self.counters.accepted.load(Ordering::Relaxed),
+            active: self.counters.active.load(Ordering::Relaxed),
+            messages: self.counters.messages.load(Ordering::Relaxed),
+            bytes: self.counters.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Echo every message received on the incoming connections back to the sender. The returned
+/// future serves the connections concurrently until the incoming stream ends and all
+/// connections are closed, poll it on any runtime and watch it by the [`EchoHandle`].
+pub fn echo_server<S, IO>(incoming: S, config: EchoConfig) -> (impl Future<Output = ()>, EchoHandle)
+where
+    S: Stream<Item = IO>,
+    IO: Stream<Item = Bytes> + Sink<Bytes>,
+{
+    let handle = EchoHandle::default();
+    let counters = Arc::clone(&handle.counters);
+    let limit = (config.max_connections != 0).then_some(config.max_connections);
+    let server = incoming.for_each_concurrent(limit, move |io| {
+        let counters = Arc::clone(&counters);
+        async move {
+            counters.accepted.fetch_add(1, Ordering::Relaxed);
+            counters.active.fetch_add(1, Ordering::Relaxed);
+            let (mut dst, mut src) = io.split();
+            while let Some(msg) = src.next().await {
+                let len = msg.len() as u64;
+                if dst.send(msg).await.is_err() {
+                    break;
+                }
+                counters.messages.fetch_add(1, Ordering::Relaxed);
+                counters.bytes.fetch_add(len, Ordering::Relaxed);
+            }
+            counters.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    });
+    (server, handle)
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::channel::mpsc;
+
+    use super::*;
+
+    /// An in-memory connection
+    struct Duplex {
+        src: mpsc::UnboundedReceiver<Bytes>,
+        dst: mpsc::UnboundedSender<Bytes>,
+    }
+
+    impl Stream for Duplex {
+        type Item = Bytes;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+            self.src.poll_next_unpin(cx)
+        }
+    }
+
+    impl Sink<Bytes> for Duplex {
+        type Error = mpsc::SendError;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            self.dst.poll_ready_unpin(cx)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+            self.dst.start_send_unpin(item)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            self.dst.poll_flush_unpin(cx)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            self.dst.poll_close_unpin(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo_server_works() {
+        let (client_tx, server_rx) = mpsc::unbounded();
+        let (server_tx, mut client_rx) = mpsc::unbounded();
+        let conn = Duplex {
+            src: server_rx,
+            dst: server_tx,
+        };
+        let (server, handle) = echo_server(futures::stream::iter([conn]), EchoConfig::default());
+
+        client_tx
+            .unbounded_send(Bytes::from_static(b"hello"))
+            .unwrap();
+        client_tx
+            .unbounded_send(Bytes::from_static(b"raknet"))
+            .unwrap();
+        drop(client_tx);
+        server.await;
+
+        assert_eq!(
+            client_rx.next().await.unwrap(),
+            Bytes::from_static(b"hello")
+        );
+        assert_eq!(
+            client_rx.next().await.unwrap(),
+            Bytes::from_static(b"raknet")
+        );
+        assert_eq!(
+            handle.stats(),
+            EchoStats {
+                accepted: 1,
+                active: 0,
+                messages: 2,
+                bytes: 11,
+            }
+        );
+    }
+}
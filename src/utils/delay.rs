@@ -0,0 +1,144 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, OnceLock, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::task::AtomicWaker;
+
+/// The deadlines of all delays are watched by a background thread, so the delays work on any
+/// runtime (or none) at the cost of a thread per process.
+static TIMER: OnceLock<mpsc::Sender<Entry>> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Shared {
+    fired: AtomicBool,
+    waker: AtomicWaker,
+}
+
+struct Entry {
+    deadline: Instant,
+    // the dropped delays are skipped
+    shared: Weak<Shared>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.deadline.cmp(&other.deadline)
+    }
+}
+
+fn timer() -> &'static mpsc::Sender<Entry> {
+    TIMER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Entry>();
+        std::thread::Builder::new()
+            .name("raknet-timer".to_string())
+            .spawn(move || {
+                let mut entries = BinaryHeap::<Reverse<Entry>>::new();
+                loop {
+                    let now = Instant::now();
+                    while let Some(Reverse(entry)) = entries.peek() {
+                        if entry.deadline > now {
+                            break;
+                        }
+                        if let Some(shared) = entry.shared.upgrade() {
+                            shared.fired.store(true, Ordering::Release);
+                            shared.waker.wake();
+                        }
+                        entries.pop();
+                    }
+                    let received = match entries.peek() {
+                        Some(Reverse(entry)) => {
+                            rx.recv_timeout(entry.deadline.saturating_duration_since(now))
+                        }
+                        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    match received {
+                        Ok(entry) => entries.push(Reverse(entry)),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            })
+            .expect("failed to spawn the timer thread");
+        tx
+    })
+}
+
+/// A runtime agnostic future resolved after the duration
+#[derive(Debug)]
+pub(crate) struct Delay {
+    deadline: Instant,
+    shared: Option<Arc<Shared>>,
+}
+
+impl Delay {
+    pub(crate) fn new(duration: Duration) -> Self {
+        Self {
+            deadline: Instant::now() + duration,
+            shared: None,
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        let shared = self.shared.get_or_insert_with(|| {
+            let shared = Arc::new(Shared::default());
+            let entry = Entry {
+                deadline,
+                shared: Arc::downgrade(&shared),
+            };
+            // the timer thread never exits while the sender is alive
+            let _ = timer().send(entry);
+            shared
+        });
+        shared.waker.register(cx.waker());
+        if shared.fired.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delay_without_runtime() {
+        let start = Instant::now();
+        futures::executor::block_on(async {
+            let long = Delay::new(Duration::from_millis(30));
+            let short = Delay::new(Duration::from_millis(10));
+            short.await;
+            assert!(start.elapsed() >= Duration::from_millis(10));
+            long.await;
+        });
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+}
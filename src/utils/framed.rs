@@ -0,0 +1,62 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// The max size of a frame by default, a larger length prefix is regarded as corrupted
+const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// Split a byte stream, e.g. a connection adapted to [`AsyncRead`] and [`AsyncWrite`], into the
+/// messages prefixed by their u32 LE lengths. The TCP-style protocols framing their messages in
+/// this way could port to the raknet transport without changing their framing.
+pub trait SizePrefixed: AsyncRead + AsyncWrite + Sized {
+    /// Frame the stream by the u32 LE length prefixes, the frames larger than 8 MiB are refused.
+    fn size_prefixed(self) -> Framed<Self, LengthDelimitedCodec> {
+        self.size_prefixed_with_max(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Frame the stream by the u32 LE length prefixes, reading or writing a frame larger than
+    /// `max_frame_size` fails with [`std::io::ErrorKind::InvalidData`] or
+    /// [`std::io::ErrorKind::InvalidInput`].
+    fn size_prefixed_with_max(self, max_frame_size: usize) -> Framed<Self, LengthDelimitedCodec>;
+}
+
+impl<T: AsyncRead + AsyncWrite> SizePrefixed for T {
+    fn size_prefixed_with_max(self, max_frame_size: usize) -> Framed<Self, LengthDelimitedCodec> {
+        LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .little_endian()
+            .max_frame_length(max_frame_size)
+            .new_framed(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_size_prefixed_works() {
+        let (local, mut remote) = tokio::io::duplex(64);
+        let mut framed = local.size_prefixed_with_max(16);
+
+        framed.send(Bytes::from_static(b"hello")).await.unwrap();
+        let mut buf = [0; 9];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x05\x00\x00\x00hello");
+
+        // the frames arrived in pieces
+        remote.write_all(b"\x03\x00").await.unwrap();
+        remote.write_all(b"\x00\x00ab").await.unwrap();
+        remote.write_all(b"c\x00\x00\x00\x00").await.unwrap();
+        assert_eq!(framed.next().await.unwrap().unwrap(), &b"abc"[..]);
+        assert_eq!(framed.next().await.unwrap().unwrap(), &b""[..]);
+
+        // larger than the max frame size
+        assert!(framed.send(Bytes::from(vec![0; 17])).await.is_err());
+        remote.write_all(b"\x11\x00\x00\x00").await.unwrap();
+        assert!(framed.next().await.unwrap().is_err());
+    }
+}
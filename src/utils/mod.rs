@@ -0,0 +1,20 @@
+/// Runtime agnostic timer
+mod delay;
+/// Size-prefixed message mode over the byte streams
+mod framed;
+/// Client connection pool
+#[cfg(feature = "client")]
+mod pool;
+/// Dispatch the messages by their ordering channels
+mod router;
+/// Request/response correlation helper
+mod rpc;
+/// Large transfer helper
+mod transfer;
+
+pub use framed::SizePrefixed;
+#[cfg(feature = "client")]
+pub use pool::{ClientPool, Connect, Health};
+pub use router::ChannelRouter;
+pub use rpc::{Reply, Request, Rpc};
+pub use transfer::{CancelHandle, SendLarge, Transfer};
@@ -0,0 +1,207 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::future::join_all;
+use tracing::debug;
+
+use crate::errors::Error;
+
+const DEFAULT_MAX_RTT: Duration = Duration::from_secs(1);
+
+/// Connect to a server address, closures returning a future of the connection implement it.
+pub trait Connect {
+    /// The connection
+    type Conn;
+    /// The future resolving to the connection
+    type Fut: Future<Output = Result<Self::Conn, Error>>;
+
+    /// Connect to the address
+    fn connect(&self, addr: SocketAddr) -> Self::Fut;
+}
+
+impl<F, Fut, C> Connect for F
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<C, Error>>,
+{
+    type Conn = C;
+    type Fut = Fut;
+
+    fn connect(&self, addr: SocketAddr) -> Self::Fut {
+        self(addr)
+    }
+}
+
+/// The health of a pooled connection
+pub trait Health {
+    /// The round trip time measured by the connected pings, None if it has not been measured
+    fn rtt(&self) -> Option<Duration>;
+
+    /// Whether the connection is closed
+    fn is_closed(&self) -> bool;
+}
+
+struct Slot<T> {
+    addr: SocketAddr,
+    conn: Option<T>,
+}
+
+/// Maintain a fixed number of connections to each of the server addresses, for the proxies and
+/// load-testing tools talking to many backends. Call [`ClientPool::check`] periodically to
+/// replace the closed or slow connections, and [`ClientPool::get`] to pick a connection in
+/// round robin.
+pub struct ClientPool<C: Connect> {
+    connector: C,
+    slots: Vec<Slot<C::Conn>>,
+    max_rtt: Duration,
+    next: usize,
+}
+
+impl<C: Connect> std::fmt::Debug for ClientPool<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientPool")
+            .field("slots", &self.slots.len())
+            .field(
+                "connected",
+                &self.slots.iter().filter(|slot| slot.conn.is_some()).count(),
+            )
+            .field("max_rtt", &self.max_rtt)
+            .finish()
+    }
+}
+
+impl<C> ClientPool<C>
+where
+    C: Connect,
+    C::Conn: Health,
+{
+    /// Create a pool keeping `per_addr` connections to each address, nothing is connected until
+    /// the first [`ClientPool::check`].
+    pub fn new(connector: C, addrs: impl IntoIterator<Item = SocketAddr>, per_addr: usize) -> Self {
+        let slots = addrs
+            .into_iter()
+            .flat_map(|addr| (0..per_addr).map(move |_| Slot { addr, conn: None }))
+            .collect();
+        Self {
+            connector,
+            slots,
+            max_rtt: DEFAULT_MAX_RTT,
+            next: 0,
+        }
+    }
+
+    /// Replace the connections whose round trip time exceeds it, 1 second by default
+    #[must_use]
+    pub fn max_rtt(mut self, max_rtt: Duration) -> Self {
+        self.max_rtt = max_rtt;
+        self
+    }
+
+    /// Count of the connected slots
+    pub fn connected(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.conn.is_some()).count()
+    }
+
+    /// Drop the closed and slow connections and reconnect the empty slots concurrently. The
+    /// slots failed to reconnect are retried in the next check.
+    pub async fn check(&mut self) {
+        for slot in &mut self.slots {
+            let healthy = slot.conn.as_ref().is_some_and(|conn| {
+                !conn.is_closed() && !conn.rtt().is_some_and(|rtt| rtt > self.max_rtt)
+            });
+            if !healthy && slot.conn.take().is_some() {
+                debug!("[pool] drop the unhealthy connection to {}", slot.addr);
+            }
+        }
+        let connector = &self.connector;
+        let reconnects = self
+            .slots
+            .iter_mut()
+            .filter(|slot| slot.conn.is_none())
+            .map(|slot| async move {
+                match connector.connect(slot.addr).await {
+                    Ok(conn) => slot.conn = Some(conn),
+                    Err(err) => debug!("[pool] failed to connect to {}, error {err}", slot.addr),
+                }
+            });
+        join_all(reconnects).await;
+    }
+
+    /// Get the next open connection in round robin, None if nothing is connected.
+    pub fn get(&mut self) -> Option<&mut C::Conn> {
+        let len = self.slots.len();
+        let idx = (0..len)
+            .map(|offset| (self.next + offset) % len)
+            .find(|&idx| {
+                self.slots[idx]
+                    .conn
+                    .as_ref()
+                    .is_some_and(|conn| !conn.is_closed())
+            })?;
+        self.next = (idx + 1) % len;
+        self.slots[idx].conn.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    struct Conn {
+        addr: SocketAddr,
+        rtt: Option<Duration>,
+        closed: bool,
+    }
+
+    impl Health for Conn {
+        fn rtt(&self) -> Option<Duration> {
+            self.rtt
+        }
+
+        fn is_closed(&self) -> bool {
+            self.closed
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_pool_works() {
+        let up: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let down: SocketAddr = "127.0.0.1:19133".parse().unwrap();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connector = {
+            let attempts = Arc::clone(&attempts);
+            move |addr: SocketAddr| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    if addr == down {
+                        return Err(Error::ConnectionClosed("refused"));
+                    }
+                    Ok(Conn {
+                        addr,
+                        rtt: None,
+                        closed: false,
+                    })
+                }
+            }
+        };
+        let mut pool = ClientPool::new(connector, [up, down], 2);
+        assert!(pool.get().is_none());
+
+        pool.check().await;
+        assert_eq!(pool.connected(), 2);
+        assert_eq!(attempts.load(Ordering::Relaxed), 4);
+        assert_eq!(pool.get().unwrap().addr, up);
+
+        // one is closed, the other is too slow
+        pool.get().unwrap().closed = true;
+        pool.get().unwrap().rtt = Some(Duration::from_secs(2));
+        pool.check().await;
+        assert_eq!(pool.connected(), 2);
+        assert_eq!(attempts.load(Ordering::Relaxed), 8);
+        assert!(pool.get().unwrap().rtt.is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+
+type Handler = Box<dyn FnMut(Bytes) + Send>;
+
+/// A routing table dispatching the received messages to the handlers by their ordering channel,
+/// e.g. channel 0 to the chat handler and channel 1 to the movement handler, so that the
+/// consumers need not match over the channels by hand.
+#[derive(Default)]
+pub struct ChannelRouter {
+    handlers: HashMap<u8, Handler>,
+    fallback: Option<Handler>,
+}
+
+impl std::fmt::Debug for ChannelRouter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut channels = self.handlers.keys().collect::<Vec<_>>();
+        channels.sort_unstable();
+        f.debug_struct("ChannelRouter")
+            .field("channels", &channels)
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+impl ChannelRouter {
+    /// Create an empty routing table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle the messages on the channel, replacing the previous handler of it
+    #[must_use]
+    pub fn route(mut self, channel: u8, handler: impl FnMut(Bytes) + Send + 'static) -> Self {
+        self.handlers.insert(channel, Box::new(handler));
+        self
+    }
+
+    /// Handle the messages on the channels without a handler, they are dropped if it is not set
+    #[must_use]
+    pub fn fallback(mut self, handler: impl FnMut(Bytes) + Send + 'static) -> Self {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Dispatch a message received on the channel, returns false if it is dropped because no
+    /// handler accepts it.
+    pub fn dispatch(&mut self, channel: u8, data: Bytes) -> bool {
+        let Some(handler) = self.handlers.get_mut(&channel).or(self.fallback.as_mut()) else {
+            return false;
+        };
+        handler(data);
+        true
+    }
+
+    /// Dispatch the messages with their channels until the stream ends, returns the count of
+    /// the dropped messages.
+    pub async fn run(mut self, messages: impl Stream<Item = (u8, Bytes)>) -> usize {
+        let mut dropped = 0;
+        let mut messages = std::pin::pin!(messages);
+        while let Some((channel, data)) = messages.next().await {
+            if !self.dispatch(channel, data) {
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_channel_router_works() {
+        let chat = Arc::new(Mutex::new(Vec::new()));
+        let movement = Arc::new(Mutex::new(Vec::new()));
+        let router = ChannelRouter::new()
+            .route(0, {
+                let chat = Arc::clone(&chat);
+                move |data| chat.lock().unwrap().push(data)
+            })
+            .route(1, {
+                let movement = Arc::clone(&movement);
+                move |data| movement.lock().unwrap().push(data)
+            });
+        let messages = futures::stream::iter([
+            (0, Bytes::from_static(b"hello")),
+            (1, Bytes::from_static(b"forward")),
+            (2, Bytes::from_static(b"unknown")),
+            (0, Bytes::from_static(b"bye")),
+        ]);
+        assert_eq!(block_on(router.run(messages)), 1);
+        assert_eq!(
+            *chat.lock().unwrap(),
+            vec![Bytes::from_static(b"hello"), Bytes::from_static(b"bye")]
+        );
+        assert_eq!(
+            *movement.lock().unwrap(),
+            vec![Bytes::from_static(b"forward")]
+        );
+    }
+
+    #[test]
+    fn test_channel_router_fallback() {
+        let others = Arc::new(Mutex::new(Vec::new()));
+        let mut router = ChannelRouter::new().route(0, |_| {}).fallback({
+            let others = Arc::clone(&others);
+            move |data| others.lock().unwrap().push(data)
+        });
+        assert!(router.dispatch(0, Bytes::from_static(b"chat")));
+        assert!(router.dispatch(7, Bytes::from_static(b"other")));
+        assert_eq!(*others.lock().unwrap(), vec![Bytes::from_static(b"other")]);
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::channel::oneshot;
+use futures::{ready, Sink, SinkExt, Stream};
+use pin_project_lite::pin_project;
+use tracing::debug;
+
+use super::delay::Delay;
+use crate::errors::Error;
+use crate::message::Message;
+
+const KIND_REQUEST: u8 = 0;
+const KIND_REPLY: u8 = 1;
+
+// kind (1 byte) + correlation id (4 bytes)
+const HEADER_SIZE: usize = 5;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request received from the peer, reply it with [`Rpc::reply`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The correlation id of this request
+    pub id: u32,
+    /// The payload
+    pub data: Bytes,
+}
+
+pin_project! {
+    /// A request/response layer over a connection. Every message sent through this layer is
+    /// tagged with a kind and a correlation id, the replies are routed to the pending requests and
+    /// the requests from the peer are yielded by the stream.
+    ///
+    /// The replies are only routed while the stream is polled, so keep polling it while waiting
+    /// for replies.
+    pub struct Rpc<T> {
+        #[pin]
+        io: T,
+        next_id: u32,
+        timeout: Duration,
+        pending: HashMap<u32, oneshot::Sender<Bytes>>,
+    }
+}
+
+impl<T> std::fmt::Debug for Rpc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Rpc")
+            .field("next_id", &self.next_id)
+            .field("timeout", &self.timeout)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl<T> Rpc<T> {
+    /// Create a request/response layer over the connection
+    pub fn new(io: T) -> Self {
+        Self {
+            io,
+            next_id: 0,
+            timeout: DEFAULT_TIMEOUT,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Set how long to wait for a reply, 10 seconds by default
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Get the inner connection back, the pending requests will fail.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    fn tag(kind: u8, id: u32, msg: Message) -> Message {
+        let mut data = BytesMut::with_capacity(HEADER_SIZE + msg.data.len());
+        data.put_u8(kind);
+        data.put_u32(id);
+        data.put(msg.data);
+        Message {
+            data: data.freeze(),
+            ..msg
+        }
+    }
+}
+
+impl<T> Rpc<T>
+where
+    T: Sink<Message, Error = Error> + Unpin,
+{
+    /// Send a request, the returned [`Reply`] resolves to the matching reply from the peer.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request could not be sent.
+    pub async fn request(&mut self, msg: Message) -> Result<Reply, Error> {
+        // clean up the requests which have timed out or been dropped
+        self.pending.retain(|_, tx| !tx.is_canceled());
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.io.send(Self::tag(KIND_REQUEST, id, msg)).await?;
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.insert(id, tx);
+        Ok(Reply {
+            rx,
+            delay: Delay::new(self.timeout),
+        })
+    }
+
+    /// Reply a request received from the peer
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the reply could not be sent.
+    pub async fn reply(&mut self, id: u32, msg: Message) -> Result<(), Error> {
+        self.io.send(Self::tag(KIND_REPLY, id, msg)).await
+    }
+}
+
+impl<T> Stream for Rpc<T>
+where
+    T: Stream<Item = Bytes>,
+{
+    type Item = Request;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let Some(mut data) = ready!(this.io.as_mut().poll_next(cx)) else {
+                return Poll::Ready(None);
+            };
+            if data.len() < HEADER_SIZE {
+                debug!("[rpc] ignore a message without correlation header");
+                continue;
+            }
+            let kind = data.get_u8();
+            let id = data.get_u32();
+            match kind {
+                KIND_REQUEST => return Poll::Ready(Some(Request { id, data })),
+                KIND_REPLY => {
+                    let Some(tx) = this.pending.remove(&id) else {
+                        debug!("[rpc] ignore a reply {id} without pending request");
+                        continue;
+                    };
+                    // the request may have timed out
+                    let _ = tx.send(data);
+                }
+                _ => debug!("[rpc] ignore a message with unknown kind {kind}"),
+            }
+        }
+    }
+}
+
+pin_project! {
+    /// The pending reply of a request, it does not depend on the async runtime
+    #[derive(Debug)]
+    pub struct Reply {
+        rx: oneshot::Receiver<Bytes>,
+        #[pin]
+        delay: Delay,
+    }
+}
+
+impl Future for Reply {
+    type Output = Result<Bytes, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Poll::Ready(res) = Pin::new(this.rx).poll(cx) {
+            return Poll::Ready(res.map_err(|_| Error::ConnectionClosed("rpc layer dropped")));
+        }
+        ready!(this.delay.poll(cx));
+        Poll::Ready(Err(Error::RequestTimeout))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    use super::*;
+
+    /// An in-memory connection
+    struct Endpoint {
+        tx: mpsc::UnboundedSender<Bytes>,
+        rx: mpsc::UnboundedReceiver<Bytes>,
+    }
+
+    fn pair() -> (Endpoint, Endpoint) {
+        let (tx1, rx1) = mpsc::unbounded();
+        let (tx2, rx2) = mpsc::unbounded();
+        (Endpoint { tx: tx1, rx: rx2 }, Endpoint { tx: tx2, rx: rx1 })
+    }
+
+    impl Stream for Endpoint {
+        type Item = Bytes;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_next_unpin(cx)
+        }
+    }
+
+    impl Sink<Message> for Endpoint {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+            self.tx
+                .unbounded_send(item.data)
+                .map_err(|_| Error::ConnectionClosed("peer dropped"))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rpc_works() {
+        let (client, server) = pair();
+        let mut client = Rpc::new(client);
+        let mut server = Rpc::new(server);
+
+        let first = client
+            .request(Message::new(Bytes::from_static(b"first")))
+            .await
+            .unwrap();
+        let second = client
+            .request(Message::new(Bytes::from_static(b"second")))
+            .await
+            .unwrap();
+
+        // reply in reverse order
+        let req1 = server.next().await.unwrap();
+        let req2 = server.next().await.unwrap();
+        assert_eq!(req1.data, Bytes::from_static(b"first"));
+        assert_eq!(req2.data, Bytes::from_static(b"second"));
+        server
+            .reply(req2.id, Message::new(Bytes::from_static(b"2")))
+            .await
+            .unwrap();
+        server
+            .reply(req1.id, Message::new(Bytes::from_static(b"1")))
+            .await
+            .unwrap();
+        drop(server);
+
+        // route the replies
+        assert!(client.next().await.is_none());
+        assert_eq!(first.await.unwrap(), Bytes::from_static(b"1"));
+        assert_eq!(second.await.unwrap(), Bytes::from_static(b"2"));
+    }
+
+    #[test]
+    fn test_rpc_timeout() {
+        // no tokio runtime
+        futures::executor::block_on(async {
+            let (client, _server) = pair();
+            let mut client = Rpc::new(client).timeout(Duration::from_millis(10));
+            let reply = client
+                .request(Message::new(Bytes::from_static(b"ping")))
+                .await
+                .unwrap();
+            assert!(matches!(reply.await, Err(Error::RequestTimeout)));
+        });
+    }
+}
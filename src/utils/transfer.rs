@@ -0,0 +1,205 @@
+use std::future::{Future, IntoFuture};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::{BufMut, BytesMut};
+use futures::{Sink, SinkExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::errors::Error;
+use crate::message::Message;
+
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Send a large payload from an [`AsyncRead`] through a message sink, e.g. resource packs and
+/// world downloads.
+pub trait SendLarge: Sink<Message, Error = Error> + Unpin + Sized {
+    /// Stream the reader into reliable ordered messages on the channel, the messages will be
+    /// fragmented by the connection. The next chunk is read only after the sink takes the
+    /// previous one, so the sink decides how many chunks are buffered, e.g. a connection queues
+    /// all of them until they are acknowledged.
+    fn send_large<R: AsyncRead + Unpin>(&mut self, reader: R, channel: u8)
+        -> Transfer<'_, Self, R>;
+}
+
+impl<S> SendLarge for S
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    fn send_large<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: R,
+        channel: u8,
+    ) -> Transfer<'_, Self, R> {
+        Transfer {
+            sink: self,
+            reader,
+            channel,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            progress: |_| {},
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+/// Cancel a running [`Transfer`], dropping the transfer future also cancels it.
+#[derive(Debug, Clone)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Cancel the transfer, the chunks have been sent will not be recalled.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A large transfer, await it to start the transfer. It resolves the total bytes sent.
+pub struct Transfer<'a, S, R, P = fn(u64)> {
+    sink: &'a mut S,
+    reader: R,
+    channel: u8,
+    chunk_size: usize,
+    progress: P,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<'a, S, R, P> std::fmt::Debug for Transfer<'a, S, R, P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transfer")
+            .field("channel", &self.channel)
+            .field("chunk_size", &self.chunk_size)
+            .field("cancelled", &self.cancelled)
+            .finish()
+    }
+}
+
+impl<'a, S, R, P> Transfer<'a, S, R, P> {
+    /// Set the max size of a chunk, it bounds the memory used by reading the reader.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chunk size is 0.
+    #[must_use]
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk_size > 0");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Set the progress callback, it will be called with the total bytes sent after each chunk
+    /// is sent.
+    pub fn on_progress<P2: FnMut(u64)>(self, progress: P2) -> Transfer<'a, S, R, P2> {
+        Transfer { progress, ..self }
+    }
+
+    /// Get a handle to cancel this transfer
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle(Arc::clone(&self.cancelled))
+    }
+}
+
+impl<'a, S, R, P> IntoFuture for Transfer<'a, S, R, P>
+where
+    S: Sink<Message, Error = Error> + Unpin + 'a,
+    R: AsyncRead + Unpin + 'a,
+    P: FnMut(u64) + 'a,
+{
+    type Output = Result<u64, Error>;
+
+    type IntoFuture = impl Future<Output = Self::Output> + 'a;
+
+    fn into_future(mut self) -> Self::IntoFuture {
+        async move {
+            let mut sent = 0;
+            loop {
+                if self.cancelled.load(Ordering::Relaxed) {
+                    return Err(Error::TransferCancelled);
+                }
+                let mut chunk = BytesMut::with_capacity(self.chunk_size);
+                while chunk.len() < self.chunk_size {
+                    let remain = self.chunk_size - chunk.len();
+                    if self
+                        .reader
+                        .read_buf(&mut (&mut chunk).limit(remain))
+                        .await?
+                        == 0
+                    {
+                        break;
+                    }
+                }
+                if chunk.is_empty() {
+                    break;
+                }
+                let len = chunk.len() as u64;
+                self.sink
+                    .feed(Message::new(chunk.freeze()).channel(self.channel))
+                    .await?;
+                sent += len;
+                (self.progress)(sent);
+            }
+            self.sink.flush().await?;
+            Ok(sent)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::channel::mpsc;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::message::Reliability;
+
+    fn sink() -> (
+        impl Sink<Message, Error = Error> + Unpin,
+        mpsc::UnboundedReceiver<Message>,
+    ) {
+        let (tx, rx) = mpsc::unbounded();
+        (
+            tx.sink_map_err(|_| Error::ConnectionClosed("receiver dropped")),
+            rx,
+        )
+    }
+
+    #[test]
+    fn test_send_large_works() {
+        let (mut sink, rx) = sink();
+        let data = (0..100_u8).collect::<Vec<_>>();
+        let mut progress = Vec::new();
+        let sent = block_on(
+            sink.send_large(data.as_slice(), 3)
+                .chunk_size(30)
+                .on_progress(|sent| progress.push(sent))
+                .into_future(),
+        )
+        .unwrap();
+        drop(sink);
+
+        assert_eq!(sent, 100);
+        assert_eq!(progress, vec![30, 60, 90, 100]);
+        let messages = block_on(rx.collect::<Vec<_>>());
+        assert_eq!(messages.len(), 4);
+        assert!(messages
+            .iter()
+            .all(|msg| msg.channel == 3 && msg.reliability == Reliability::ReliableOrdered));
+        let received = messages
+            .iter()
+            .flat_map(|msg| msg.data.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_send_large_cancelled() {
+        let (mut sink, _rx) = sink();
+        let data = [0; 100];
+        let transfer = sink.send_large(data.as_slice(), 0);
+        transfer.cancel_handle().cancel();
+        assert!(matches!(
+            block_on(transfer.into_future()),
+            Err(Error::TransferCancelled)
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+//! Low-level codec of the raknet datagrams without the connection machinery, only available with
+//! the `wire` feature. It is useful for the packet analysis tools, proxies and test generators.
+
+use bytes::{Buf, BytesMut};
+
+pub use crate::errors::CodecError;
+pub use crate::packet::connected::{
+    AckOrNack, Flags, Fragment, Frame, FrameSet, Ordered, Record, Reliability, Uint24le,
+};
+pub use crate::packet::{connected, unconnected, PackType, Packet, MAGIC};
+
+/// Decode a datagram, returns None if the datagram is empty.
+///
+/// # Errors
+///
+/// Returns an error if the datagram is malformed or of an unknown type.
+pub fn decode(datagram: &mut BytesMut) -> Result<Option<Packet<BytesMut>>, CodecError> {
+    Packet::read(datagram)
+}
+
+/// Decode an offline (unconnected) packet.
+///
+/// # Errors
+///
+/// Returns an error if the datagram is malformed or it is not an offline packet.
+pub fn decode_offline(datagram: &mut BytesMut) -> Result<unconnected::Packet, CodecError> {
+    let id = datagram.first().copied();
+    match Packet::read(datagram)? {
+        Some(Packet::Unconnected(packet)) => Ok(packet),
+        Some(Packet::Connected(_)) => Err(CodecError::InvalidPacketType(id.unwrap_or_default())),
+        None => Err(CodecError::InvalidPacketLength("offline packet")),
+    }
+}
+
+/// Decode a frame set datagram.
+///
+/// # Errors
+///
+/// Returns an error if the datagram is malformed or it is not a frame set.
+pub fn decode_frame_set(datagram: &mut BytesMut) -> Result<FrameSet<BytesMut>, CodecError> {
+    let id = datagram.first().copied();
+    match Packet::read(datagram)? {
+        Some(Packet::Connected(connected::Packet::FrameSet(frame_set))) => Ok(frame_set),
+        Some(_) => Err(CodecError::InvalidPacketType(id.unwrap_or_default())),
+        None => Err(CodecError::InvalidPacketLength("frame set")),
+    }
+}
+
+/// Encode a packet into buf.
+pub fn encode(packet: Packet<impl Buf>, buf: &mut BytesMut) {
+    packet.write(buf);
+}
+
+/// Encode an offline (unconnected) packet into buf.
+pub fn encode_offline(packet: unconnected::Packet, buf: &mut BytesMut) {
+    Packet::<BytesMut>::Unconnected(packet).write(buf);
+}
+
+/// Encode a frame set into buf, the frame set should contain at least one frame.
+pub fn encode_frame_set(frame_set: FrameSet<impl Buf>, buf: &mut BytesMut) {
+    Packet::Connected(connected::Packet::FrameSet(frame_set)).write(buf);
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_offline_round_trip() {
+        let ping = unconnected::Packet::UnconnectedPing {
+            send_timestamp: 1,
+            magic: (),
+            client_guid: 2,
+        };
+        let mut buf = BytesMut::new();
+        encode_offline(ping.clone(), &mut buf);
+        assert_eq!(decode_offline(&mut buf.clone()).unwrap(), ping);
+        assert!(decode_frame_set(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_frame_set_round_trip() {
+        let frame_set = FrameSet {
+            seq_num: Uint24le(42),
+            frames: vec![Frame {
+                flags: Flags::parse((Reliability::ReliableOrdered as u8) << 5),
+                reliable_frame_index: Some(Uint24le(0)),
+                seq_frame_index: None,
+                ordered: Some(Ordered {
+                    frame_index: Uint24le(0),
+                    channel: 0,
+                }),
+                fragment: None,
+                body: Bytes::from_static(b"\xfehello"),
+            }],
+        };
+        let mut buf = BytesMut::new();
+        encode_frame_set(frame_set, &mut buf);
+        let decoded = decode_frame_set(&mut buf).unwrap();
+        assert_eq!(decoded.seq_num, Uint24le(42));
+        assert_eq!(decoded.frames[0].body, b"\xfehello"[..]);
+        assert!(decode_offline(&mut BytesMut::new()).is_err());
+    }
+}